@@ -0,0 +1,31 @@
+// replays every kernel recorded in the cache db (see features::process_replay for how to record
+// a test run) and prints the ones whose source changed. Exits non-zero when any did, or failed
+use rustgrad_2::features::process_replay::{recorded, replay};
+
+fn main() -> Result<(), anyhow::Error> {
+    let kernels = recorded()?;
+    let (mut changed, mut failed) = (0, 0);
+    for r in &kernels {
+        match replay(r) {
+            Ok(None) => {}
+            Ok(Some(diff)) => {
+                println!("{}\n", diff);
+                changed += 1;
+            }
+            Err(e) => {
+                println!("*** {} {} failed to replay: {}\n", r.device, r.name, e);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "replayed {} kernels, {} changed, {} failed",
+        kernels.len(),
+        changed,
+        failed
+    );
+    if changed + failed > 0 {
+        std::process::exit(1);
+    }
+    Ok(())
+}
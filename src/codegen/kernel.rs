@@ -0,0 +1,346 @@
+// kernel optimisations. Every action rearranges the linearizer's loop nest before it's lowered,
+// after a legality check that leaves the kernel untouched when it fails
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    codegen::linearizer::{KernelAxis, Linearizer},
+    features::search::{beam_search, bufs_from_lin},
+    ops::{BinaryOps, BufferOps, LazyOp, LazyOpArg, Op, ReduceOps, UnaryOps},
+    prelude::{BEAM, NOOPT},
+    runtime::ops_clang::find_compiler,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OptOps {
+    /// unroll `amt` steps of a global axis into registers, one output each
+    Upcast,
+    /// unroll `amt` steps of a reduce axis into the accumulate
+    Unroll,
+    /// tile a global axis, the tile loop runs inside all the global loops
+    Local,
+    /// split `amt` off a reduce axis into a loop outside the rest of the reduce
+    Group,
+    /// pad an axis up to a multiple of `amt`, the tail is masked off
+    Padto,
+    /// swap two global axes, `amt` is the other axis
+    Swap,
+}
+
+/// One optimisation. `axis` counts from the first reduce for `Unroll`, from the first ungrouped
+/// reduce for `Group` and from 0 for the rest; an `amt` of 0 means the whole axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Opt {
+    pub op: OptOps,
+    pub axis: usize,
+    pub amt: usize,
+}
+
+impl Opt {
+    pub fn new(op: OptOps, axis: usize, amt: usize) -> Self {
+        Self { op, axis, amt }
+    }
+}
+
+macro_rules! check {
+    ($cond:expr, $($msg:tt)+) => {
+        if !$cond {
+            return Err(anyhow!($($msg)+));
+        }
+    };
+}
+
+/// Whether `op` maps all zero inputs to zero, so padding through it is invisible to a sum.
+fn zero_preserving(op: &Op) -> bool {
+    match op {
+        Op::Unary(u) => matches!(
+            u,
+            UnaryOps::Neg | UnaryOps::Sqrt | UnaryOps::Sin | UnaryOps::Cast
+        ),
+        Op::Binary(b) => matches!(
+            b,
+            BinaryOps::Add | BinaryOps::Sub | BinaryOps::Mul | BinaryOps::Max | BinaryOps::CmpLt
+        ),
+        Op::Ternary(_) | Op::Buffer(_) => true,
+        Op::Reduce(_) => false,
+    }
+}
+
+impl Linearizer {
+    /// Split `amt` steps off axis `axis`. The new axis is the inner part, or the outer one when
+    /// `top` is set, and goes before `insert_before` (at the end when `None`).
+    fn shift_to(&mut self, axis: usize, amt: usize, top: bool, insert_before: Option<usize>) {
+        let ax = self.axes[axis];
+        let rest = ax.size / amt;
+        let (new, old) = if top {
+            (
+                KernelAxis {
+                    size: amt,
+                    stride: ax.stride * rest,
+                    ..ax
+                },
+                KernelAxis { size: rest, ..ax },
+            )
+        } else {
+            (
+                KernelAxis { size: amt, ..ax },
+                KernelAxis {
+                    size: rest,
+                    stride: ax.stride * amt,
+                    ..ax
+                },
+            )
+        };
+        self.axes[axis] = old;
+        match insert_before {
+            Some(i) => self.axes.insert(i, new),
+            None => self.axes.push(new),
+        }
+    }
+
+    /// Drop size-1 axes, keeping the local/group/upcast counts in step.
+    fn drop_ones(&mut self) {
+        let (first_reduce, first_upcast) = (self.first_reduce(), self.first_upcast());
+        let global_dims = self.global_dims();
+        let group_end = first_reduce + self.group_for_reduces;
+        let keep: Vec<usize> = (0..self.shape_len())
+            .filter(|i| self.axes[*i].size != 1)
+            .collect();
+        let count = |r: std::ops::Range<usize>| keep.iter().filter(|i| r.contains(i)).count();
+        self.local_dims = count(global_dims..first_reduce);
+        self.group_for_reduces = count(first_reduce..group_end);
+        self.upcasted = count(first_upcast..self.shape_len());
+        self.axes = keep.iter().map(|i| self.axes[*i]).collect();
+    }
+
+    /// Apply `opt` if it's legal for this kernel, otherwise return why not.
+    pub fn apply_opt(&mut self, opt: Opt) -> Result<(), anyhow::Error> {
+        check!(self.uops.is_empty(), "kernel is already linearized");
+        let (first_reduce, first_upcast) = (self.first_reduce(), self.first_upcast());
+        let axis = match opt.op {
+            OptOps::Unroll => first_reduce + opt.axis,
+            OptOps::Group => first_reduce + self.group_for_reduces + opt.axis,
+            _ => opt.axis,
+        };
+        check!(
+            axis < self.shape_len(),
+            "invalid axis {} for {:?}",
+            axis,
+            opt
+        );
+        let size = self.axes[axis].size;
+        let amt = if opt.amt == 0 { size } else { opt.amt };
+        match opt.op {
+            OptOps::Upcast | OptOps::Unroll | OptOps::Local | OptOps::Group => {
+                check!(amt > 1, "{:?} by {} does nothing", opt.op, amt);
+                check!(
+                    size.is_multiple_of(amt),
+                    "{} doesn't divide axis of size {}",
+                    amt,
+                    size
+                );
+            }
+            OptOps::Padto | OptOps::Swap => {}
+        }
+        match opt.op {
+            OptOps::Upcast => {
+                check!(
+                    axis < first_reduce,
+                    "can only upcast a global or local axis"
+                );
+                check!(amt <= 16, "upcast of {} is too big", amt);
+                self.shift_to(axis, amt, false, None);
+                self.upcasted += 1;
+            }
+            OptOps::Unroll => {
+                check!(
+                    axis >= first_reduce + self.group_for_reduces && axis < first_upcast,
+                    "can only unroll a reduce axis"
+                );
+                check!(amt <= 32, "unroll of {} is too big", amt);
+                self.shift_to(axis, amt, false, None);
+                self.upcasted += 1;
+            }
+            OptOps::Local => {
+                check!(axis < self.global_dims(), "can only tile a global axis");
+                self.shift_to(axis, amt, false, Some(first_reduce));
+                self.local_dims += 1;
+            }
+            OptOps::Group => {
+                check!(self.reduceop.is_some(), "can only group a reduce");
+                check!(
+                    axis >= first_reduce + self.group_for_reduces && axis < first_upcast,
+                    "can only group an ungrouped reduce axis"
+                );
+                self.shift_to(
+                    axis,
+                    amt,
+                    false,
+                    Some(first_reduce + self.group_for_reduces),
+                );
+                self.group_for_reduces += 1;
+            }
+            OptOps::Padto => self.padto(axis, amt)?,
+            OptOps::Swap => {
+                let global_dims = self.global_dims();
+                check!(
+                    axis < global_dims && opt.amt < global_dims && axis != opt.amt,
+                    "can only swap two different global axes"
+                );
+                self.axes.swap(axis, opt.amt);
+            }
+        }
+        self.drop_ones();
+        self.applied_opts.push(opt);
+        Ok(())
+    }
+
+    fn padto(&mut self, axis: usize, amt: usize) -> Result<(), anyhow::Error> {
+        let ax = self.axes[axis];
+        check!(axis < self.first_upcast(), "can't pad an upcast axis");
+        check!(
+            ax.stride == 1 && self.axes.iter().filter(|a| a.base == ax.base).count() == 1,
+            "can only pad an axis that hasn't been split"
+        );
+        let padded = ax.size.next_multiple_of(amt.max(1));
+        check!(
+            padded != ax.size,
+            "axis of size {} is already a multiple of {}",
+            ax.size,
+            amt
+        );
+        check!(
+            padded < 2 * ax.size,
+            "padding {} to {} is more than half padding",
+            ax.size,
+            padded
+        );
+        // the padded region reads zeros, integer division by them is undefined
+        check!(
+            !self.ast.lazyops().iter().any(|op| op.dtype().is_int()
+                && matches!(op.op, Op::Binary(BinaryOps::Div | BinaryOps::Mod))),
+            "can't pad a kernel with integer division"
+        );
+        if self.is_reduce_axis(axis) {
+            let reduceop = self.reduceop.clone().unwrap();
+            check!(
+                reduceop.op == Op::Reduce(ReduceOps::Sum)
+                    && reduceop.src[0]
+                        .lazyops()
+                        .iter()
+                        .all(|op| zero_preserving(&op.op)),
+                "padding the reduce would change its result"
+            );
+        }
+        let base_size = self.base_shape()[ax.base];
+        self.sts = self
+            .sts
+            .iter()
+            .map(|st| {
+                if st.shape[ax.base] != base_size {
+                    return st.clone();
+                }
+                let mut arg = vec![(0, 0); st.shape.len()];
+                arg[ax.base] = (0, padded - base_size);
+                st.pad(&arg)
+            })
+            .collect();
+        self.axes[axis].size = padded;
+        Ok(())
+    }
+
+    fn upcast_size(&self) -> usize {
+        self.full_shape()[self.first_upcast()..].iter().product()
+    }
+
+    fn is_load(&self, i: usize) -> bool {
+        self.bufs[i].op == Op::Buffer(BufferOps::Load)
+    }
+
+    /// The default CPU schedule: images first get 4 of an axis they're contiguous along, so they're
+    /// read and written a texel at a time. Then upcast the global axes some input broadcasts along
+    /// (each upcast reuses one load across several outputs), and unroll the innermost reduce.
+    pub fn hand_coded_optimizations(&mut self) {
+        for i in 0..self.bufs.len() {
+            if !matches!(&self.bufs[i].arg, Some(LazyOpArg::MemBuffer(mb)) if mb.dtype.image.is_some())
+            {
+                continue;
+            }
+            let unit = (0..self.first_upcast())
+                .find(|a| self.stride_of(i, *a) == 1 && self.axes[*a].size.is_multiple_of(4));
+            if let Some(axis) = unit {
+                let (first_reduce, global) = (self.first_reduce(), self.global_dims());
+                let opt = if axis < global {
+                    Opt::new(OptOps::Upcast, axis, 4)
+                } else if axis >= first_reduce {
+                    Opt::new(OptOps::Unroll, axis - first_reduce, 4)
+                } else {
+                    continue;
+                };
+                let _ = self.apply_opt(opt);
+            }
+        }
+        while self.upcast_size() < 32 {
+            let mut best: Option<(usize, usize, usize)> = None;
+            for axis in 0..self.global_dims() {
+                for amt in [4, 3] {
+                    if !self.axes[axis].size.is_multiple_of(amt) || self.upcast_size() * amt > 32 {
+                        continue;
+                    }
+                    let loads = (0..self.bufs.len()).filter(|i| self.is_load(*i));
+                    let strides: Vec<isize> = loads.map(|i| self.stride_of(i, axis)).collect();
+                    if !strides.contains(&0) {
+                        continue;
+                    }
+                    let score = strides.iter().filter(|s| **s != 0).count();
+                    if best.is_none_or(|(s, _, _)| score < s) {
+                        best = Some((score, axis, amt));
+                    }
+                }
+            }
+            let Some((_, axis, amt)) = best else { break };
+            if self.apply_opt(Opt::new(OptOps::Upcast, axis, amt)).is_err() {
+                break;
+            }
+        }
+
+        if self.reduceop.is_some() && self.first_reduce() < self.first_upcast() {
+            let axis = self.first_upcast() - 1 - self.first_reduce();
+            let size = self.full_shape()[self.first_upcast() - 1];
+            let amt = if size <= 32 && self.upcast_size() * size <= 256 {
+                0
+            } else {
+                4
+            };
+            let _ = self.apply_opt(Opt::new(OptOps::Unroll, axis, amt));
+        }
+
+        // an elementwise kernel with nothing to reuse still upcasts its contiguous output axis
+        if self.upcasted == 0 && self.reduceop.is_none() {
+            if let Some(axis) = (0..self.global_dims())
+                .rev()
+                .find(|a| self.stride_of(0, *a) == 1 && self.axes[*a].size.is_multiple_of(4))
+            {
+                let _ = self.apply_opt(Opt::new(OptOps::Upcast, axis, 4));
+            }
+        }
+    }
+}
+
+/// The linearizer for `ast` under this process's `BEAM` and `NOOPT`.
+pub fn get_linearizer(ast: LazyOp) -> Linearizer {
+    get_linearizer_with(ast, BEAM.value.max(0) as usize, NOOPT.clone() == 1)
+}
+
+/// The linearizer for `ast`: BEAM searched with width `beam` when that's at least 1 and a C
+/// compiler is around to time the candidates, hand-coded unless `noopt` otherwise.
+pub fn get_linearizer_with(ast: LazyOp, beam: usize, noopt: bool) -> Linearizer {
+    let mut k = Linearizer::new(ast);
+    if beam >= 1 && find_compiler().is_some() {
+        return beam_search(&k, &bufs_from_lin(&k), beam);
+    }
+    if !noopt {
+        k.hand_coded_optimizations();
+    }
+    k
+}
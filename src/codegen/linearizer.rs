@@ -0,0 +1,652 @@
+// lowers one kernel AST into uops. Axes are ordered [global..., local..., group..., reduce..., upcast...];
+// global and local axes get `gidx`/`lidx` loops around the whole kernel, group and reduce axes `ridx`
+// loops around the accumulate, and upcast axes are unrolled into straight-line code. When the last
+// upcast axis walks a buffer contiguously, its loads and stores become single vector ops
+use std::collections::HashMap;
+
+use crate::{
+    codegen::{
+        kernel::Opt,
+        uops::{graph_rewrite_uops, UArg, UOp, UOps},
+    },
+    dtype::DType,
+    helpers::colored,
+    ops::{BinaryOps, BufferOps, LazyOp, LazyOpArg, Op, ReduceOps, TernaryOps, UnaryOps},
+    rewrite::{graph_rewrite, AST_SIMPLIFY, UOP_SIMPLIFY},
+    shape::{symbolic::Node, view::View},
+};
+
+/// One loop of the kernel: `size` steps of `stride` along axis `base` of the views.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KernelAxis {
+    pub base: usize,
+    pub size: usize,
+    pub stride: usize,
+}
+
+#[derive(Clone)]
+pub struct Linearizer {
+    pub ast: LazyOp,
+    /// the Store/Load/Const leaves of the ast, output first
+    pub bufs: Vec<LazyOp>,
+    /// the view each leaf is indexed through, over the base axes (reduce axes last)
+    pub sts: Vec<View>,
+    /// the loop nest, outermost first. Optimisations split and reorder these, never the views
+    pub axes: Vec<KernelAxis>,
+    /// how many axes right before the first reduce are local tiles
+    pub local_dims: usize,
+    /// how many axes from the first reduce on are reduce groups
+    pub group_for_reduces: usize,
+    /// how many trailing axes are unrolled
+    pub upcasted: usize,
+    pub applied_opts: Vec<Opt>,
+    /// run the outermost global loop over `gstart..gend`, two int args, so disjoint ranges of it
+    /// can go to different threads
+    pub split_globals: bool,
+    /// the vector widths the target language has, like `CStyleLanguage::vector_widths`. Empty
+    /// allows any, others load and store element by element
+    pub vector_widths: Vec<usize>,
+    pub reduceop: Option<LazyOp>,
+    pub uops: Vec<UOp>,
+    cache: HashMap<String, usize>,
+    loop_uops: HashMap<String, usize>,
+}
+
+fn leaf_view(op: &LazyOp) -> &View {
+    match &op.arg {
+        Some(LazyOpArg::MemBuffer(m)) => &m.st,
+        Some(LazyOpArg::ConstBuffer(c)) => &c.st,
+        _ => panic!("{:?} is not a buffer op", op.op),
+    }
+}
+
+impl Linearizer {
+    pub fn new(ast: LazyOp) -> Self {
+        let ast = graph_rewrite(&ast, &AST_SIMPLIFY);
+        assert!(
+            matches!(ast.op, Op::Buffer(BufferOps::Store)),
+            "kernel ast must be rooted at a store, got {:?}",
+            ast.op
+        );
+        let mut bufs: Vec<LazyOp> = vec![];
+        for op in ast.lazyops() {
+            if let Op::Buffer(_) = op.op {
+                let leaf = LazyOp::new(op.op, vec![], op.arg.clone());
+                if !bufs.contains(&leaf) {
+                    bufs.push(leaf);
+                }
+            }
+        }
+        let reduceops: Vec<&LazyOp> = ast
+            .lazyops()
+            .into_iter()
+            .filter(|op| matches!(op.op, Op::Reduce(_)))
+            .collect();
+        assert!(reduceops.len() <= 1, "only one reduce per kernel");
+        let reduceop = reduceops.first().map(|r| (*r).clone());
+        let sts: Vec<View> = bufs.iter().map(|b| leaf_view(b).clone()).collect();
+        assert!(
+            sts.iter().all(|st| st.shape.len() == sts[0].shape.len()),
+            "all views of a kernel need the same number of dims"
+        );
+
+        let mut ret = Self {
+            ast,
+            bufs,
+            sts,
+            axes: vec![],
+            local_dims: 0,
+            group_for_reduces: 0,
+            upcasted: 0,
+            applied_opts: vec![],
+            split_globals: false,
+            vector_widths: vec![],
+            reduceop,
+            uops: vec![],
+            cache: HashMap::new(),
+            loop_uops: HashMap::new(),
+        };
+        // reduce axes go last
+        let full_shape = ret.base_shape();
+        let (mut order, reduce): (Vec<usize>, Vec<usize>) =
+            (0..full_shape.len()).partition(|i| ret.sts[0].shape[*i] == full_shape[*i]);
+        order.extend(reduce);
+        ret.sts = ret.sts.iter().map(|st| st.permute(&order)).collect();
+        ret.simplify_ones();
+        ret.axes = ret
+            .base_shape()
+            .into_iter()
+            .enumerate()
+            .map(|(base, size)| KernelAxis {
+                base,
+                size,
+                stride: 1,
+            })
+            .collect();
+        ret
+    }
+
+    /// The shape of the views, the largest size any of them has along each base axis.
+    pub fn base_shape(&self) -> Vec<usize> {
+        (0..self.sts[0].shape.len())
+            .map(|i| self.sts.iter().map(|st| st.shape[i]).max().unwrap_or(1))
+            .collect()
+    }
+
+    pub fn full_shape(&self) -> Vec<usize> {
+        self.axes.iter().map(|a| a.size).collect()
+    }
+
+    pub fn shape_len(&self) -> usize {
+        self.axes.len()
+    }
+
+    /// Whether kernel axis `i` walks an axis the output doesn't span.
+    pub fn is_reduce_axis(&self, i: usize) -> bool {
+        let base = self.axes[i].base;
+        self.sts[0].shape[base] != self.base_shape()[base]
+    }
+
+    /// The first reduce axis that isn't unrolled.
+    pub fn first_reduce(&self) -> usize {
+        (0..self.first_upcast())
+            .find(|i| self.is_reduce_axis(*i))
+            .unwrap_or(self.first_upcast())
+    }
+
+    pub fn first_upcast(&self) -> usize {
+        self.shape_len() - self.upcasted
+    }
+
+    pub fn global_dims(&self) -> usize {
+        self.first_reduce() - self.local_dims
+    }
+
+    /// Stride of buffer `i` along kernel axis `k`.
+    pub fn stride_of(&self, i: usize, k: usize) -> isize {
+        let (st, ax) = (&self.sts[i], self.axes[k]);
+        if st.shape[ax.base] == 1 {
+            0
+        } else {
+            st.strides[ax.base] * ax.stride as isize
+        }
+    }
+
+    /// How many elements of buffer `i` each load or store moves at once: the size of the last axis
+    /// when it's an upcast of 4 or 8 floats, contiguous in an unmasked view and starting at a
+    /// multiple of its size on every iteration, and a width the target has, otherwise 1. Images only
+    /// move whole texels.
+    pub fn vector_width(&self, i: usize) -> usize {
+        let Some(LazyOpArg::MemBuffer(mb)) = &self.bufs[i].arg else {
+            return 1;
+        };
+        if self.upcasted == 0 || !matches!(mb.dtype.scalar(), DType::FLOAT32 | DType::FLOAT64) {
+            return 1;
+        }
+        let last = self.shape_len() - 1;
+        let (st, w) = (&self.sts[i], self.axes[last].size);
+        let aligned = st.offset % w as isize == 0
+            && (0..last).all(|k| self.stride_of(i, k) % w as isize == 0);
+        let widths: &[usize] = if mb.dtype.image.is_some() {
+            &[4]
+        } else {
+            &[4, 8]
+        };
+        let supported = self.vector_widths.is_empty() || self.vector_widths.contains(&w);
+        if widths.contains(&w)
+            && supported
+            && st.mask.is_none()
+            && self.stride_of(i, last) == 1
+            && aligned
+        {
+            w
+        } else {
+            1
+        }
+    }
+
+    /// Where a vector load or store of a buffer of `dtype` starting at element `idx` goes: `idx`, or
+    /// for an image the int2 (x, y) of the texel.
+    fn render_vector_idx(&mut self, dtype: DType, idx: Node) -> usize {
+        let Some((_, width)) = dtype.image else {
+            return self.render_node(&idx);
+        };
+        let texel = idx.floordiv(4);
+        let x = self.render_node(&texel.clone().modulo(width as isize));
+        let y = self.render_node(&texel.floordiv(width as isize));
+        self.uop(
+            UOps::Vectorize,
+            Some(DType::INT32.vec(2)),
+            vec![x, y],
+            UArg::None,
+        )
+    }
+
+    /// Per base axis index from per kernel axis indices.
+    fn base_idxs(&self, idxs: &[Node]) -> Vec<Node> {
+        let mut base = vec![Node::Num(0); self.sts[0].shape.len()];
+        for (ax, i) in self.axes.iter().zip(idxs) {
+            base[ax.base] = base[ax.base].clone() + i.clone() * ax.stride as isize;
+        }
+        base
+    }
+
+    /// Drop the axes every view has as size 1.
+    fn simplify_ones(&mut self) {
+        let full_shape = self.base_shape();
+        let keep: Vec<usize> = (0..full_shape.len())
+            .filter(|i| full_shape[*i] != 1)
+            .collect();
+        self.sts = self
+            .sts
+            .iter()
+            .map(|st| {
+                let shape: Vec<usize> = keep.iter().map(|i| st.shape[*i]).collect();
+                st.reshape(&shape)
+                    .expect("removing size-1 axes is always a view")
+            })
+            .collect();
+    }
+
+    /// Whether `linearize` takes `gstart`/`gend` args: `split_globals` is set and there's an
+    /// outermost global loop to split.
+    pub fn is_split(&self) -> bool {
+        self.split_globals && self.global_dims() > 0 && self.axes[0].size > 1
+    }
+
+    /// `E_4_4` for elementwise kernels, `r_4_16` for reduces.
+    pub fn name(&self) -> String {
+        let prefix = if self.reduceop.is_some() { "r" } else { "E" };
+        std::iter::once(prefix.to_string())
+            .chain(self.full_shape().iter().map(|s| s.to_string()))
+            .collect::<Vec<_>>()
+            .join("_")
+    }
+
+    /// One colour per axis: blue global, cyan local, green group, red reduce, magenta unrolled
+    /// reduce and yellow upcast.
+    pub fn colors(&self) -> Vec<&'static str> {
+        let (first_reduce, first_upcast) = (self.first_reduce(), self.first_upcast());
+        (0..self.shape_len())
+            .map(|i| {
+                if i < self.global_dims() {
+                    "blue"
+                } else if i < first_reduce {
+                    "cyan"
+                } else if i < first_reduce + self.group_for_reduces {
+                    "green"
+                } else if i < first_upcast {
+                    "red"
+                } else if self.is_reduce_axis(i) {
+                    "magenta"
+                } else {
+                    "yellow"
+                }
+            })
+            .collect()
+    }
+
+    /// `name` with every axis size coloured by what kind of axis it is.
+    pub fn display_name(&self) -> String {
+        let prefix = if self.reduceop.is_some() { "r" } else { "E" };
+        std::iter::once(prefix.to_string())
+            .chain(
+                self.full_shape()
+                    .iter()
+                    .zip(self.colors())
+                    .map(|(s, c)| colored(s, Some(c), None)),
+            )
+            .collect::<Vec<_>>()
+            .join(&colored("_", Some("BLACK"), None))
+    }
+
+    fn buf_index(&self, leaf: &LazyOp) -> usize {
+        self.bufs
+            .iter()
+            .position(|b| b.op == leaf.op && b.arg == leaf.arg)
+            .expect("leaf isn't in the kernel")
+    }
+
+    /// Append a uop, reusing an identical one already in scope.
+    pub fn uop(&mut self, uop: UOps, dtype: Option<DType>, vin: Vec<usize>, arg: UArg) -> usize {
+        let cachable = matches!(
+            uop,
+            UOps::Const | UOps::Alu | UOps::Cast | UOps::Load | UOps::Gep | UOps::Vectorize
+        );
+        let key = format!("{:?}{:?}{:?}{:?}", uop, dtype, vin, arg);
+        if cachable {
+            if let Some(i) = self.cache.get(&key) {
+                return *i;
+            }
+        }
+        self.uops.push(UOp {
+            uop,
+            dtype,
+            vin,
+            arg,
+        });
+        let i = self.uops.len() - 1;
+        if cachable {
+            self.cache.insert(key, i);
+        }
+        i
+    }
+
+    pub fn const_uop(&mut self, val: f64, dtype: DType) -> usize {
+        self.uop(UOps::Const, Some(dtype), vec![], UArg::Const(val))
+    }
+
+    fn alu(&mut self, op: impl Into<Op>, dtype: DType, vin: Vec<usize>) -> usize {
+        self.uop(UOps::Alu, Some(dtype), vin, UArg::Op(op.into()))
+    }
+
+    /// Open a loop over `0..size` bound to `name`, returning its index variable. `bounds` runs it
+    /// over part of that range instead, given as the uops holding its start and end.
+    fn open_loop(&mut self, name: &str, size: usize, bounds: Option<(usize, usize)>) -> Node {
+        let var = Node::var(name, 0, size as isize - 1);
+        if let Node::Var(_) = var {
+            let (lo, hi) = bounds.unwrap_or_else(|| {
+                (
+                    self.const_uop(0.0, DType::INT32),
+                    self.const_uop(size as f64, DType::INT32),
+                )
+            });
+            let u = self.uop(
+                UOps::Loop,
+                Some(DType::INT32),
+                vec![lo, hi],
+                UArg::Name(name.to_string()),
+            );
+            self.loop_uops.insert(name.to_string(), u);
+        }
+        var
+    }
+
+    /// Close the scope opened at uop `start` and forget everything defined inside it.
+    fn end(&mut self, start: usize) {
+        self.uop(UOps::End, None, vec![start], UArg::None);
+        self.cache.retain(|_, v| *v < start);
+    }
+
+    /// Lower an index expression to int32 ALU uops.
+    pub fn render_node(&mut self, node: &Node) -> usize {
+        match node {
+            Node::Num(n) => self.const_uop(*n as f64, DType::INT32),
+            Node::Var(v) => *self
+                .loop_uops
+                .get(&v.expr)
+                .unwrap_or_else(|| panic!("no loop defines {}", v.expr)),
+            Node::Mul(x, a) => {
+                let (x, a) = (self.render_node(x), self.const_uop(*a as f64, DType::INT32));
+                self.alu(BinaryOps::Mul, DType::INT32, vec![x, a])
+            }
+            Node::Div(x, b) => {
+                let (x, b) = (self.render_node(x), self.const_uop(*b as f64, DType::INT32));
+                self.alu(BinaryOps::Div, DType::INT32, vec![x, b])
+            }
+            Node::Mod(x, b) => {
+                let (x, b) = (self.render_node(x), self.const_uop(*b as f64, DType::INT32));
+                self.alu(BinaryOps::Mod, DType::INT32, vec![x, b])
+            }
+            Node::Lt(x, b) => {
+                let (x, b) = (self.render_node(x), self.const_uop(*b as f64, DType::INT32));
+                self.alu(BinaryOps::CmpLt, DType::BOOL, vec![x, b])
+            }
+            Node::Sum(ns) => {
+                let mut acc = self.render_node(&ns[0]);
+                for n in &ns[1..] {
+                    let n = self.render_node(n);
+                    acc = self.alu(BinaryOps::Add, DType::INT32, vec![acc, n]);
+                }
+                acc
+            }
+            // bools are 0/1, so and is a multiply
+            Node::And(ns) => {
+                let mut acc = self.render_node(&ns[0]);
+                for n in &ns[1..] {
+                    let n = self.render_node(n);
+                    acc = self.alu(BinaryOps::Mul, DType::BOOL, vec![acc, n]);
+                }
+                acc
+            }
+        }
+    }
+
+    fn global_uop(&self, idx: usize) -> usize {
+        self.uops
+            .iter()
+            .position(|u| u.uop == UOps::DefineGlobal && u.arg == UArg::Buffer(idx))
+            .unwrap()
+    }
+
+    /// Lower `op` at the kernel axis indices `idxs`.
+    fn ast_parse(&mut self, op: &LazyOp, idxs: &[Node], acc: Option<usize>) -> usize {
+        match op.op {
+            Op::Buffer(BufferOps::Load) => {
+                let i = self.buf_index(op);
+                let dtype = op.dtype();
+                let Some(LazyOpArg::MemBuffer(mb)) = &op.arg else {
+                    unreachable!()
+                };
+                let buf = self.global_uop(mb.idx);
+                let w = self.vector_width(i);
+                let last = idxs.len().saturating_sub(1);
+                if let (true, Some(Node::Num(lane))) = (w > 1, idxs.last()) {
+                    // every lane loads the same vector, the uop cache keeps just one
+                    let mut first = idxs.to_vec();
+                    first[last] = Node::Num(0);
+                    let (idx, _) = self.sts[i].expr_node(&self.base_idxs(&first));
+                    let idx = self.render_vector_idx(mb.dtype, idx);
+                    let v = self.uop(UOps::Load, Some(dtype.vec(w)), vec![buf, idx], UArg::None);
+                    return self.uop(UOps::Gep, Some(dtype), vec![v], UArg::Index(*lane as usize));
+                }
+                let (idx, valid) = self.sts[i].expr_node(&self.base_idxs(idxs));
+                match valid {
+                    Node::Num(0) => self.const_uop(0.0, dtype),
+                    Node::Num(_) => {
+                        let idx = self.render_node(&idx);
+                        self.uop(UOps::Load, Some(dtype), vec![buf, idx], UArg::None)
+                    }
+                    valid => {
+                        let idx = self.render_node(&idx);
+                        let valid = self.render_node(&valid);
+                        let alt = self.const_uop(0.0, dtype);
+                        self.uop(
+                            UOps::Load,
+                            Some(dtype),
+                            vec![buf, idx, valid, alt],
+                            UArg::None,
+                        )
+                    }
+                }
+            }
+            Op::Buffer(BufferOps::Const) => {
+                let i = self.buf_index(op);
+                let (_, valid) = self.sts[i].expr_node(&self.base_idxs(idxs));
+                let Some(LazyOpArg::ConstBuffer(cb)) = &op.arg else {
+                    unreachable!()
+                };
+                let c = self.const_uop(cb.val, cb.dtype);
+                match valid {
+                    Node::Num(0) => self.const_uop(0.0, cb.dtype),
+                    Node::Num(_) => c,
+                    valid => {
+                        let valid = self.render_node(&valid);
+                        let zero = self.const_uop(0.0, cb.dtype);
+                        self.alu(TernaryOps::Where, cb.dtype, vec![valid, c, zero])
+                    }
+                }
+            }
+            Op::Buffer(BufferOps::Store) => panic!("store can only be the root"),
+            Op::Reduce(_) => acc.expect("reduce outside of a reduce kernel"),
+            Op::Unary(UnaryOps::Cast) => {
+                let x = self.ast_parse(&op.src[0], idxs, acc);
+                self.uop(UOps::Cast, Some(op.dtype()), vec![x], UArg::None)
+            }
+            _ => {
+                let vin: Vec<usize> = op
+                    .src
+                    .iter()
+                    .map(|s| self.ast_parse(s, idxs, acc))
+                    .collect();
+                self.alu(op.op, op.dtype(), vin)
+            }
+        }
+    }
+
+    /// The value stored at the kernel axis indices `out`, in the output's dtype.
+    fn store_val(&mut self, out: &[Node], acc: Option<usize>) -> usize {
+        let ast = self.ast.clone();
+        let val = self.ast_parse(&ast.src[0], out, acc);
+        if self.uops[val].dtype != Some(ast.dtype()) {
+            return self.uop(UOps::Cast, Some(ast.dtype()), vec![val], UArg::None);
+        }
+        val
+    }
+
+    pub fn linearize(&mut self) -> &[UOp] {
+        if !self.uops.is_empty() {
+            return &self.uops;
+        }
+        let mut globals: Vec<(usize, DType)> = self
+            .bufs
+            .iter()
+            .filter_map(|b| match &b.arg {
+                Some(LazyOpArg::MemBuffer(m)) => Some((m.idx, m.dtype)),
+                _ => None,
+            })
+            .collect();
+        globals.sort_by_key(|(i, _)| *i);
+        globals.dedup_by_key(|(i, _)| *i);
+        for (i, dtype) in globals {
+            self.uop(UOps::DefineGlobal, Some(dtype), vec![], UArg::Buffer(i));
+        }
+
+        let full_shape = self.full_shape();
+        let (first_reduce, first_upcast) = (self.first_reduce(), self.first_upcast());
+        let global_dims = self.global_dims();
+        let split = (self.is_split()).then(|| {
+            let [start, end] = ["gstart", "gend"].map(|n| {
+                self.uop(
+                    UOps::DefineVar,
+                    Some(DType::INT32),
+                    vec![],
+                    UArg::Name(n.to_string()),
+                )
+            });
+            (start, end)
+        });
+        let mut loops = vec![];
+        let mut idxs: Vec<Node> = vec![];
+        for (i, s) in full_shape[..first_reduce].iter().enumerate() {
+            let name = if i < global_dims {
+                format!("gidx{}", i)
+            } else {
+                format!("lidx{}", i - global_dims)
+            };
+            idxs.push(self.open_loop(&name, *s, if i == 0 { split } else { None }));
+            if idxs[i].vars().len() == 1 {
+                loops.push(self.uops.len() - 1);
+            }
+        }
+        idxs.resize(full_shape.len(), Node::Num(0));
+        let (unrolled, upcast): (Vec<usize>, Vec<usize>) =
+            (first_upcast..full_shape.len()).partition(|i| self.is_reduce_axis(*i));
+        let outs = expand_idxs(&idxs, &upcast, &full_shape);
+
+        let mut accs = vec![];
+        if let Some(reduceop) = self.reduceop.clone() {
+            let Op::Reduce(rop) = reduceop.op else {
+                unreachable!()
+            };
+            let dtype = reduceop.dtype();
+            let identity = match rop {
+                ReduceOps::Sum => 0.0,
+                ReduceOps::Max => dtype.min_value(),
+            };
+            accs = outs
+                .iter()
+                .map(|_| self.uop(UOps::DefineAcc, Some(dtype), vec![], UArg::Const(identity)))
+                .collect();
+            let mut rvars = vec![];
+            let mut rloops = vec![];
+            for (i, s) in full_shape[first_reduce..first_upcast].iter().enumerate() {
+                rvars.push(self.open_loop(&format!("ridx{}", i), *s, None));
+                if rvars[i].vars().len() == 1 {
+                    rloops.push(self.uops.len() - 1);
+                }
+            }
+            for (out, acc) in outs.iter().zip(accs.clone()) {
+                let mut ridxs = out.clone();
+                ridxs[first_reduce..first_upcast].clone_from_slice(&rvars);
+                let mut cur = acc;
+                for u in expand_idxs(&ridxs, &unrolled, &full_shape) {
+                    let val = self.ast_parse(&reduceop.src[0], &u, None);
+                    cur = self.alu(rop.binary(), dtype, vec![cur, val]);
+                }
+                self.uop(UOps::Phi, Some(dtype), vec![acc, cur], UArg::None);
+            }
+            for l in rloops.into_iter().rev() {
+                self.end(l);
+            }
+        }
+
+        let out_dtype = self.ast.dtype();
+        // the last axis is fastest in `outs`, so with vector stores each run of `w` outputs is one
+        let w = self.vector_width(0);
+        for (c, chunk) in outs.chunks(w).enumerate() {
+            let buf = self.global_uop(0);
+            if w > 1 {
+                let vals = (0..w)
+                    .map(|j| self.store_val(&chunk[j], accs.get(c * w + j).copied()))
+                    .collect();
+                let val = self.uop(UOps::Vectorize, Some(out_dtype.vec(w)), vals, UArg::None);
+                let (idx, _) = self.sts[0].expr_node(&self.base_idxs(&chunk[0]));
+                let idx = self.render_vector_idx(self.uops[buf].dtype.unwrap(), idx);
+                self.uop(UOps::Store, None, vec![buf, idx, val], UArg::None);
+                continue;
+            }
+            let (idx, valid) = self.sts[0].expr_node(&self.base_idxs(&chunk[0]));
+            if valid == Node::Num(0) {
+                continue;
+            }
+            let val = self.store_val(&chunk[0], accs.get(c).copied());
+            // a padded output only stores inside its mask
+            let guard = match valid {
+                Node::Num(_) => None,
+                valid => {
+                    let valid = self.render_node(&valid);
+                    Some(self.uop(UOps::If, None, vec![valid], UArg::None))
+                }
+            };
+            let idx = self.render_node(&idx);
+            self.uop(UOps::Store, None, vec![buf, idx, val], UArg::None);
+            if let Some(g) = guard {
+                self.end(g);
+            }
+        }
+        for l in loops.into_iter().rev() {
+            self.end(l);
+        }
+        self.uops = graph_rewrite_uops(&self.uops, &UOP_SIMPLIFY);
+        &self.uops
+    }
+}
+
+/// Every combination of constant indices along the unrolled `axes` of `idxs`, last axis fastest.
+fn expand_idxs(idxs: &[Node], axes: &[usize], full_shape: &[usize]) -> Vec<Vec<Node>> {
+    let mut ret = vec![idxs.to_vec()];
+    for a in axes {
+        ret = ret
+            .into_iter()
+            .flat_map(|idxs| {
+                (0..full_shape[*a]).map(move |v| {
+                    let mut idxs = idxs.clone();
+                    idxs[*a] = Node::Num(v as isize);
+                    idxs
+                })
+            })
+            .collect();
+    }
+    ret
+}
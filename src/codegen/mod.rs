@@ -0,0 +1,3 @@
+pub mod kernel;
+pub mod linearizer;
+pub mod uops;
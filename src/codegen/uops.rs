@@ -0,0 +1,253 @@
+// the linear, SSA-style kernel IR every backend consumes. A uop's operands are the positions of
+// earlier uops in the same list
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    rc::Rc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    dtype::DType,
+    ops::{BufferOps, Op, TernaryOps, UnaryOps},
+    rewrite::{graph_rewrite_cached, PatternMatcher, Rewritable},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum UOps {
+    /// kernel argument `data{idx}`, dtype is the element type
+    DefineGlobal,
+    /// int kernel argument named by the arg, passed in at every call
+    DefineVar,
+    /// an accumulator starting at the arg
+    DefineAcc,
+    /// `for (name = vin[0]; name < vin[1]; name++)`, also called RANGE
+    Loop,
+    /// closes the `Loop` or `If` in vin[0]
+    End,
+    /// runs up to the matching `End` only when vin[0] is true
+    If,
+    /// `buf[idx]`, or `valid ? buf[idx] : alt` with vin `[buf, idx, valid, alt]`. A vector dtype
+    /// loads that many elements starting at idx. Images are read a texel at a time at the int2
+    /// (x, y) in place of idx
+    Load,
+    /// `buf[idx] = val`, vin `[buf, idx, val]`. A vector val stores all of its elements from idx,
+    /// or into the texel at (x, y) of an image
+    Store,
+    Const,
+    Alu,
+    Cast,
+    /// writes vin[1] back into the accumulator vin[0]
+    Phi,
+    /// element `arg` of the vector vin[0]
+    Gep,
+    /// a vector of the scalars in vin
+    Vectorize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UArg {
+    None,
+    /// buffer index of a `DefineGlobal`
+    Buffer(usize),
+    /// variable name of a `Loop`
+    Name(String),
+    /// value of a `Const` or the start of a `DefineAcc`
+    Const(f64),
+    Op(Op),
+    /// lane of a `Gep`
+    Index(usize),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UOp {
+    pub uop: UOps,
+    pub dtype: Option<DType>,
+    pub vin: Vec<usize>,
+    pub arg: UArg,
+}
+
+impl Display for UOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let arg = match &self.arg {
+            UArg::None => String::new(),
+            UArg::Buffer(i) => format!("data{}", i),
+            UArg::Name(n) => n.clone(),
+            UArg::Const(c) => c.to_string(),
+            UArg::Op(op) => format!("{:?}", op),
+            UArg::Index(i) => i.to_string(),
+        };
+        write!(
+            f,
+            "{:<15} {:<16} {:<16} {}",
+            format!("{:?}", self.uop),
+            self.dtype.map_or(String::new(), |d| d.to_string()),
+            format!("{:?}", self.vin),
+            arg
+        )
+    }
+}
+
+pub fn print_uops(uops: &[UOp]) {
+    for (i, u) in uops.iter().enumerate() {
+        println!("{:4} {}", i, u);
+    }
+}
+
+/// Floating point ops the kernel runs, counting every trip of loops whose bounds are constants or
+/// `DefineVar`s valued in `vars`. Integer alus are mostly index math and don't count; a `MulAcc` is two.
+pub fn flops(uops: &[UOp], vars: &[(&str, usize)]) -> usize {
+    let konst = |i: usize| match (&uops[i].uop, &uops[i].arg) {
+        (UOps::Const, UArg::Const(c)) => Some(*c as usize),
+        (UOps::DefineVar, UArg::Name(n)) => vars.iter().find(|(v, _)| v == n).map(|(_, x)| *x),
+        _ => None,
+    };
+    let is_float = |u: &UOp| u.dtype.is_some_and(|d| d.is_float());
+    let mut mults = vec![1];
+    let mut ret = 0;
+    for u in uops {
+        let mult = *mults.last().unwrap();
+        match (u.uop, &u.arg) {
+            (UOps::Loop, _) => {
+                let trips = match (konst(u.vin[0]), konst(u.vin[1])) {
+                    (Some(s), Some(e)) => e.saturating_sub(s),
+                    _ => 1,
+                };
+                mults.push(mult * trips);
+            }
+            (UOps::End, _) if uops[u.vin[0]].uop == UOps::Loop => {
+                mults.pop();
+            }
+            (UOps::Alu, _)
+                if !is_float(u) && !u.vin.first().is_some_and(|v| is_float(&uops[*v])) => {}
+            (UOps::Alu, UArg::Op(Op::Ternary(TernaryOps::MulAcc))) => ret += 2 * mult,
+            (UOps::Alu, _) => ret += mult,
+            _ => {}
+        }
+    }
+    ret
+}
+
+#[derive(Debug)]
+pub struct UNodeInner {
+    pub uop: UOps,
+    pub dtype: Option<DType>,
+    pub arg: UArg,
+    pub src: Vec<UNode>,
+}
+
+/// A uop as a node of the graph its `vin`s make, for the rewrite engine. Equal only to itself.
+#[derive(Debug, Clone)]
+pub struct UNode(pub Rc<UNodeInner>);
+
+impl PartialEq for UNode {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl UNode {
+    pub fn new(uop: UOps, dtype: Option<DType>, arg: UArg, src: Vec<UNode>) -> Self {
+        Self(Rc::new(UNodeInner {
+            uop,
+            dtype,
+            arg,
+            src,
+        }))
+    }
+}
+
+impl Rewritable for UNode {
+    fn op(&self) -> Option<Op> {
+        match (self.0.uop, &self.0.arg) {
+            (UOps::Alu, UArg::Op(op)) => Some(*op),
+            (UOps::Cast, _) => Some(UnaryOps::Cast.into()),
+            (UOps::Const, _) => Some(BufferOps::Const.into()),
+            (UOps::Load, _) => Some(BufferOps::Load.into()),
+            (UOps::Store, _) => Some(BufferOps::Store.into()),
+            _ => None,
+        }
+    }
+
+    fn dtype(&self) -> Option<DType> {
+        self.0.dtype
+    }
+
+    fn src(&self) -> &[Self] {
+        &self.0.src
+    }
+
+    fn with_src(&self, src: Vec<Self>) -> Self {
+        UNode::new(self.0.uop, self.0.dtype, self.0.arg.clone(), src)
+    }
+
+    fn konst(&self) -> Option<f64> {
+        match (self.0.uop, &self.0.arg) {
+            (UOps::Const, UArg::Const(c)) => Some(*c),
+            _ => None,
+        }
+    }
+
+    fn const_like(&self, val: f64, dtype: DType) -> Self {
+        UNode::new(UOps::Const, Some(dtype), UArg::Const(val), vec![])
+    }
+
+    fn id(&self) -> Option<usize> {
+        Some(Rc::as_ptr(&self.0) as usize)
+    }
+}
+
+/// Rewrite the graph of `uops` with `pm` and lay it out again. The uops with effects stay in order;
+/// a pure one stays where it was if it's still used, and one the rewrite made goes right before its
+/// first user.
+pub fn graph_rewrite_uops(uops: &[UOp], pm: &PatternMatcher<UNode>) -> Vec<UOp> {
+    let pure = |u: UOps| {
+        matches!(
+            u,
+            UOps::Const | UOps::Alu | UOps::Cast | UOps::Gep | UOps::Vectorize
+        )
+    };
+    let mut cache = HashMap::new();
+    let mut nodes: Vec<UNode> = vec![];
+    for u in uops {
+        let src = u.vin.iter().map(|v| nodes[*v].clone()).collect();
+        let n = UNode::new(u.uop, u.dtype, u.arg.clone(), src);
+        nodes.push(graph_rewrite_cached(&n, pm, &mut cache));
+    }
+
+    let mut live = HashSet::new();
+    let mut stack: Vec<UNode> = uops
+        .iter()
+        .zip(&nodes)
+        .filter(|(u, _)| !pure(u.uop))
+        .map(|(_, n)| n.clone())
+        .collect();
+    while let Some(n) = stack.pop() {
+        if live.insert(n.id().unwrap()) {
+            stack.extend(n.src().iter().cloned());
+        }
+    }
+
+    fn emit(n: &UNode, out: &mut Vec<UOp>, pos: &mut HashMap<usize, usize>) -> usize {
+        if let Some(p) = pos.get(&n.id().unwrap()) {
+            return *p;
+        }
+        let vin = n.src().iter().map(|s| emit(s, out, pos)).collect();
+        out.push(UOp {
+            uop: n.0.uop,
+            dtype: n.0.dtype,
+            vin,
+            arg: n.0.arg.clone(),
+        });
+        pos.insert(n.id().unwrap(), out.len() - 1);
+        out.len() - 1
+    }
+    let (mut out, mut pos) = (vec![], HashMap::new());
+    for n in &nodes {
+        if live.contains(&n.id().unwrap()) {
+            emit(n, &mut out, &mut pos);
+        }
+    }
+    out
+}
@@ -0,0 +1,393 @@
+// devices. A device is an allocator for its memory, plus, when it can run kernels, a compiler from
+// uops to a binary and a runtime that loads that binary. Devices are named `<KIND>:<id>` and built
+// once per name by the registry
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+
+use crate::{
+    codegen::uops::UOp,
+    create_context_var,
+    dtype::DType,
+    helpers::{getenv, ContextVar, GlobalCounter},
+    runtime::{ops_clang, ops_disk::DiskDevice, ops_interp, ops_llvm},
+};
+
+/// Every device kind, in the order the default is looked for.
+pub const DEVICES: [&str; 4] = ["CLANG", "LLVM", "INTERP", "DISK"];
+
+/// Memory on some device.
+#[derive(Debug)]
+pub enum RawBuffer {
+    /// host memory, kernels on CPU devices run on it directly
+    Host(Vec<u8>),
+    /// `size` bytes at `offset` into the device's file
+    Disk { offset: u64, size: usize },
+}
+
+impl RawBuffer {
+    pub fn size(&self) -> usize {
+        match self {
+            RawBuffer::Host(b) => b.len(),
+            RawBuffer::Disk { size, .. } => *size,
+        }
+    }
+}
+
+pub trait Allocator: Send + Sync {
+    fn alloc(&self, size: usize) -> Result<RawBuffer, anyhow::Error>;
+    /// Host bytes into `dst`, which must be the same size.
+    fn copyin(&self, dst: &mut RawBuffer, src: &[u8]) -> Result<(), anyhow::Error>;
+    /// `src` into host bytes, which must be the same size.
+    fn copyout(&self, dst: &mut [u8], src: &RawBuffer) -> Result<(), anyhow::Error>;
+    fn free(&self, buf: RawBuffer) {
+        drop(buf);
+    }
+    /// Give back whatever memory is held for reuse.
+    fn free_cache(&self) {}
+}
+
+lazy_static! {
+    /// bytes an `LRUAllocator` holds for reuse at most
+    static ref LRU_LIMIT: usize = getenv("LRU_LIMIT".to_string(), Some((1usize << 30).to_string()))
+        .parse()
+        .unwrap();
+}
+
+/// Keeps freed buffers for the next allocation of the same size, most recently freed first. Past
+/// `limit` cached bytes the least recently freed go back to `inner`. Allocation failures free the
+/// whole cache and try again.
+pub struct LRUAllocator<A> {
+    inner: A,
+    cache: Mutex<VecDeque<RawBuffer>>,
+    limit: usize,
+}
+
+impl<A: Allocator> LRUAllocator<A> {
+    /// Caching up to `LRU_LIMIT` bytes, 1 GiB by default.
+    pub fn new(inner: A) -> Self {
+        Self::with_limit(inner, *LRU_LIMIT)
+    }
+
+    pub fn with_limit(inner: A, limit: usize) -> Self {
+        Self {
+            inner,
+            cache: Mutex::new(VecDeque::new()),
+            limit,
+        }
+    }
+
+    /// Bytes held for reuse.
+    pub fn cached(&self) -> usize {
+        self.cache.lock().unwrap().iter().map(|b| b.size()).sum()
+    }
+}
+
+impl<A: Allocator> Allocator for LRUAllocator<A> {
+    fn alloc(&self, size: usize) -> Result<RawBuffer, anyhow::Error> {
+        let mut cache = self.cache.lock().unwrap();
+        if let Some(i) = cache.iter().rposition(|b| b.size() == size) {
+            return Ok(cache.remove(i).unwrap());
+        }
+        drop(cache);
+        self.inner.alloc(size).or_else(|_| {
+            self.free_cache();
+            self.inner.alloc(size)
+        })
+    }
+
+    fn copyin(&self, dst: &mut RawBuffer, src: &[u8]) -> Result<(), anyhow::Error> {
+        self.inner.copyin(dst, src)
+    }
+
+    fn copyout(&self, dst: &mut [u8], src: &RawBuffer) -> Result<(), anyhow::Error> {
+        self.inner.copyout(dst, src)
+    }
+
+    fn free(&self, buf: RawBuffer) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.push_back(buf);
+        let mut cached: usize = cache.iter().map(|b| b.size()).sum();
+        while cached > self.limit {
+            let old = cache.pop_front().unwrap();
+            cached -= old.size();
+            self.inner.free(old);
+        }
+    }
+
+    fn free_cache(&self) {
+        for buf in self.cache.lock().unwrap().drain(..) {
+            self.inner.free(buf);
+        }
+    }
+}
+
+/// Plain host memory, for devices that compute on the CPU.
+pub struct MallocAllocator;
+
+impl Allocator for MallocAllocator {
+    fn alloc(&self, size: usize) -> Result<RawBuffer, anyhow::Error> {
+        Ok(RawBuffer::Host(vec![0; size]))
+    }
+
+    fn copyin(&self, dst: &mut RawBuffer, src: &[u8]) -> Result<(), anyhow::Error> {
+        match dst {
+            RawBuffer::Host(b) if b.len() == src.len() => b.copy_from_slice(src),
+            _ => return Err(anyhow!("can't copy {} bytes into {:?}", src.len(), dst)),
+        }
+        Ok(())
+    }
+
+    fn copyout(&self, dst: &mut [u8], src: &RawBuffer) -> Result<(), anyhow::Error> {
+        match src {
+            RawBuffer::Host(b) if b.len() == dst.len() => dst.copy_from_slice(b),
+            _ => return Err(anyhow!("can't copy {:?} into {} bytes", src, dst.len())),
+        }
+        Ok(())
+    }
+}
+
+pub trait Compiler: Send + Sync {
+    /// The source `compile_uops` builds, as text.
+    fn render(&self, name: &str, uops: &[UOp]) -> String;
+    /// Lower `uops` to the binary `Runtime::load` takes.
+    fn compile_uops(&self, name: &str, uops: &[UOp]) -> Result<Vec<u8>, anyhow::Error>;
+}
+
+/// A loaded kernel.
+pub trait Program: Send + Sync {
+    /// Run over `bufs`, in `DefineGlobal` order, with `vars` for the `DefineVar`s. Returns the wall
+    /// time in seconds when `wait` is set.
+    fn call(&self, bufs: &mut [&mut [u8]], vars: &[i32], wait: bool) -> Option<f64>;
+
+    /// Run a kernel linearized with `split_globals` over its whole outer loop of size `outer`, as up
+    /// to `threads` parts at once where the runtime can. Nothing runs in parallel by default.
+    fn call_split(
+        &self,
+        bufs: &mut [&mut [u8]],
+        outer: usize,
+        threads: usize,
+        wait: bool,
+    ) -> Option<f64> {
+        let _ = threads;
+        self.call(bufs, &[0, outer as i32], wait)
+    }
+}
+
+pub trait Runtime: Send + Sync {
+    fn load(&self, name: &str, lib: &[u8]) -> Result<Box<dyn Program>, anyhow::Error>;
+}
+
+pub trait Device: Send + Sync {
+    /// The canonical name, e.g. `CLANG:0`.
+    fn name(&self) -> &str;
+    fn allocator(&self) -> &dyn Allocator;
+    /// `None` for devices that only hold memory.
+    fn compiler(&self) -> Option<&dyn Compiler> {
+        None
+    }
+    fn runtime(&self) -> Option<&dyn Runtime> {
+        None
+    }
+    /// Wait for everything queued on the device. CPU devices run synchronously.
+    fn synchronize(&self) {}
+}
+
+/// A device that runs kernels: an allocator, a compiler and a runtime.
+pub struct Compiled {
+    pub name: String,
+    pub allocator: Box<dyn Allocator>,
+    pub compiler: Box<dyn Compiler>,
+    pub runtime: Box<dyn Runtime>,
+}
+
+impl Device for Compiled {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn allocator(&self) -> &dyn Allocator {
+        self.allocator.as_ref()
+    }
+
+    fn compiler(&self) -> Option<&dyn Compiler> {
+        Some(self.compiler.as_ref())
+    }
+
+    fn runtime(&self) -> Option<&dyn Runtime> {
+        Some(self.runtime.as_ref())
+    }
+}
+
+/// `clang` -> `CLANG:0`, `interp:1` -> `INTERP:1`. Anything after `DISK:` is a path and kept as is.
+pub fn canonicalize(device: &str) -> String {
+    let (kind, rest) = match device.split_once(':') {
+        Some((kind, rest)) => (kind.to_uppercase(), rest),
+        None => (device.to_uppercase(), "0"),
+    };
+    format!("{}:{}", kind, rest)
+}
+
+lazy_static! {
+    static ref OPENED: Mutex<HashMap<String, Arc<dyn Device>>> = Mutex::new(HashMap::new());
+}
+
+/// The device called `device`, opened the first time it's asked for.
+pub fn get_device(device: &str) -> Result<Arc<dyn Device>, anyhow::Error> {
+    let name = canonicalize(device);
+    let mut opened = OPENED.lock().unwrap();
+    if let Some(d) = opened.get(&name) {
+        return Ok(d.clone());
+    }
+    let (kind, rest) = name.split_once(':').unwrap();
+    let d: Arc<dyn Device> = match kind {
+        "CLANG" => Arc::new(ops_clang::device(&name)?),
+        "LLVM" => Arc::new(ops_llvm::device(&name)?),
+        "INTERP" => Arc::new(ops_interp::device(&name)),
+        "DISK" => Arc::new(DiskDevice::new(&name, rest)),
+        _ => {
+            return Err(anyhow!(
+                "unknown device {}, expected one of {:?}",
+                name,
+                DEVICES
+            ))
+        }
+    };
+    opened.insert(name, d.clone());
+    Ok(d)
+}
+
+/// The device new buffers go on: `DEVICE=<name>` if set, otherwise the first kind whose flag is
+/// on (`CLANG=1`, env or context), otherwise `CLANG` when there's a C compiler and `INTERP`, which
+/// needs no toolchain, when there isn't.
+pub fn default_device() -> String {
+    let device = getenv("DEVICE".to_string(), Some(String::new()));
+    if !device.is_empty() {
+        return canonicalize(&device);
+    }
+    DEVICES
+        .iter()
+        .find(|kind| create_context_var!(**kind, 0) == 1)
+        .map_or_else(
+            || {
+                let kind = if ops_clang::find_compiler().is_some() {
+                    "CLANG"
+                } else {
+                    "INTERP"
+                };
+                canonicalize(kind)
+            },
+            |kind| canonicalize(kind),
+        )
+}
+
+/// Empty the allocator caches of every open device.
+pub fn free_cache() {
+    for d in OPENED.lock().unwrap().values() {
+        d.allocator().free_cache();
+    }
+}
+
+/// `size` elements of `dtype` on a device. Allocated through the device's allocator, counted in
+/// `GlobalCounters::mem_used` while alive and handed back to the allocator when dropped.
+pub struct Buffer {
+    pub device: String,
+    pub size: usize,
+    pub dtype: DType,
+    dev: Arc<dyn Device>,
+    raw: Option<RawBuffer>,
+}
+
+impl std::fmt::Debug for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<buf device:{} size:{} dtype:{}>",
+            self.device, self.size, self.dtype
+        )
+    }
+}
+
+impl Buffer {
+    pub fn new(device: &str, size: usize, dtype: DType) -> Result<Self, anyhow::Error> {
+        let dev = get_device(device)?;
+        let raw = dev.allocator().alloc(size * dtype.itemsize())?;
+        GlobalCounter.lock().unwrap().mem_used += raw.size();
+        Ok(Self {
+            device: dev.name().to_string(),
+            size,
+            dtype,
+            dev,
+            raw: Some(raw),
+        })
+    }
+
+    pub fn nbytes(&self) -> usize {
+        self.size * self.dtype.itemsize()
+    }
+
+    pub fn raw(&self) -> &RawBuffer {
+        self.raw.as_ref().unwrap()
+    }
+
+    /// The memory itself, for kernels to run on. Only host buffers have any.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        match self.raw.as_mut().unwrap() {
+            RawBuffer::Host(b) => b,
+            raw => panic!("{:?} on {} isn't in host memory", raw, self.device),
+        }
+    }
+
+    pub fn copyin(&mut self, src: &[u8]) -> Result<(), anyhow::Error> {
+        self.dev.allocator().copyin(self.raw.as_mut().unwrap(), src)
+    }
+
+    pub fn copyout(&self, dst: &mut [u8]) -> Result<(), anyhow::Error> {
+        self.dev.allocator().copyout(dst, self.raw())
+    }
+
+    /// A copy of this buffer on `device`.
+    pub fn copy_to(&self, device: &str) -> Result<Buffer, anyhow::Error> {
+        let mut ret = Buffer::new(device, self.size, self.dtype)?;
+        transfer(
+            self.dev.as_ref(),
+            self.raw(),
+            ret.dev.as_ref(),
+            ret.raw.as_mut().unwrap(),
+        )?;
+        Ok(ret)
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        if let Some(raw) = self.raw.take() {
+            GlobalCounter.lock().unwrap().mem_used -= raw.size();
+            self.dev.allocator().free(raw);
+        }
+    }
+}
+
+/// Copy `src` on `src_dev` into `dst` on `dst_dev`, staging through host memory.
+pub fn transfer(
+    src_dev: &dyn Device,
+    src: &RawBuffer,
+    dst_dev: &dyn Device,
+    dst: &mut RawBuffer,
+) -> Result<(), anyhow::Error> {
+    anyhow::ensure!(
+        src.size() == dst.size(),
+        "can't copy {} bytes on {} into {} bytes on {}",
+        src.size(),
+        src_dev.name(),
+        dst.size(),
+        dst_dev.name()
+    );
+    let mut host = vec![0; src.size()];
+    src_dev.allocator().copyout(&mut host, src)?;
+    dst_dev.allocator().copyin(dst, &host)
+}
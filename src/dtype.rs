@@ -0,0 +1,165 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ScalarType {
+    Bool,
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Float16,
+    Float32,
+    Float64,
+}
+
+/// A scalar type, or a short vector of one when `count > 1`. Buffers can also be images: `height`
+/// rows of `width` RGBA texels, addressed in 2D and read and written a texel (4 elements) at a time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DType {
+    pub scalar: ScalarType,
+    pub count: usize,
+    /// (height, width) in texels of an image buffer
+    pub image: Option<(usize, usize)>,
+}
+
+impl DType {
+    pub const BOOL: DType = DType::new(ScalarType::Bool);
+    pub const INT8: DType = DType::new(ScalarType::Int8);
+    pub const UINT8: DType = DType::new(ScalarType::UInt8);
+    pub const INT16: DType = DType::new(ScalarType::Int16);
+    pub const UINT16: DType = DType::new(ScalarType::UInt16);
+    pub const INT32: DType = DType::new(ScalarType::Int32);
+    pub const UINT32: DType = DType::new(ScalarType::UInt32);
+    pub const INT64: DType = DType::new(ScalarType::Int64);
+    pub const UINT64: DType = DType::new(ScalarType::UInt64);
+    pub const FLOAT16: DType = DType::new(ScalarType::Float16);
+    pub const FLOAT32: DType = DType::new(ScalarType::Float32);
+    pub const FLOAT64: DType = DType::new(ScalarType::Float64);
+
+    pub const ALL: [DType; 12] = [
+        DType::BOOL,
+        DType::INT8,
+        DType::UINT8,
+        DType::INT16,
+        DType::UINT16,
+        DType::INT32,
+        DType::UINT32,
+        DType::INT64,
+        DType::UINT64,
+        DType::FLOAT16,
+        DType::FLOAT32,
+        DType::FLOAT64,
+    ];
+
+    pub const fn new(scalar: ScalarType) -> Self {
+        Self {
+            scalar,
+            count: 1,
+            image: None,
+        }
+    }
+
+    /// A float32 image of `height` x `width` texels, emulated on CPU as `height * width * 4` floats
+    /// stored texel after texel, row after row.
+    pub const fn imagef(height: usize, width: usize) -> Self {
+        Self {
+            scalar: ScalarType::Float32,
+            count: 1,
+            image: Some((height, width)),
+        }
+    }
+
+    pub fn vec(self, count: usize) -> Self {
+        assert_eq!(self.count, 1, "can't vectorize {}", self);
+        Self {
+            count,
+            image: None,
+            ..self
+        }
+    }
+
+    pub fn scalar(self) -> Self {
+        Self::new(self.scalar)
+    }
+
+    pub fn itemsize(&self) -> usize {
+        let size = match self.scalar {
+            ScalarType::Bool | ScalarType::Int8 | ScalarType::UInt8 => 1,
+            ScalarType::Int16 | ScalarType::UInt16 | ScalarType::Float16 => 2,
+            ScalarType::Int32 | ScalarType::UInt32 | ScalarType::Float32 => 4,
+            ScalarType::Int64 | ScalarType::UInt64 | ScalarType::Float64 => 8,
+        };
+        size * self.count
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(
+            self.scalar,
+            ScalarType::Float16 | ScalarType::Float32 | ScalarType::Float64
+        )
+    }
+
+    pub fn is_int(&self) -> bool {
+        !self.is_float() && self.scalar != ScalarType::Bool
+    }
+
+    pub fn is_unsigned(&self) -> bool {
+        matches!(
+            self.scalar,
+            ScalarType::UInt8 | ScalarType::UInt16 | ScalarType::UInt32 | ScalarType::UInt64
+        )
+    }
+
+    /// The short name, `float`, `uchar`, ... with the vector width appended for vectors.
+    pub fn name(&self) -> String {
+        let base = match self.scalar {
+            ScalarType::Bool => "bool",
+            ScalarType::Int8 => "char",
+            ScalarType::UInt8 => "uchar",
+            ScalarType::Int16 => "short",
+            ScalarType::UInt16 => "ushort",
+            ScalarType::Int32 => "int",
+            ScalarType::UInt32 => "uint",
+            ScalarType::Int64 => "long",
+            ScalarType::UInt64 => "ulong",
+            ScalarType::Float16 => "half",
+            ScalarType::Float32 => "float",
+            ScalarType::Float64 => "double",
+        };
+        if self.image.is_some() {
+            "imagef".to_string()
+        } else if self.count > 1 {
+            format!("{}{}", base, self.count)
+        } else {
+            base.to_string()
+        }
+    }
+
+    /// Identity of `max` for this type: the lowest representable value.
+    pub fn min_value(&self) -> f64 {
+        match self.scalar {
+            ScalarType::Bool => 0.0,
+            ScalarType::Int8 => i8::MIN as f64,
+            ScalarType::Int16 => i16::MIN as f64,
+            ScalarType::Int32 => i32::MIN as f64,
+            ScalarType::Int64 => i64::MIN as f64,
+            ScalarType::UInt8 | ScalarType::UInt16 | ScalarType::UInt32 | ScalarType::UInt64 => 0.0,
+            ScalarType::Float16 | ScalarType::Float32 | ScalarType::Float64 => f64::NEG_INFINITY,
+        }
+    }
+}
+
+impl Display for DType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.image {
+            Some((h, w)) => write!(f, "dtypes.{}(({}, {}, 4))", self.name(), h, w),
+            None => write!(f, "dtypes.{}", self.name()),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+// forward-mode differentiation with dual numbers. `Dual<Dual<T>>` carries second derivatives,
+// which is all a Hessian-vector product needs
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::helpers::{FloorDiv, ToInteger};
+
+/// The scalar operations forward-mode code can be written against. Implemented for the float types
+/// and for `Dual<T>` itself, so duals nest.
+pub trait Real:
+    Copy
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + From<u8>
+    + FloorDiv<Output = Self>
+    + ToInteger
+{
+    fn from_f64(v: f64) -> Self;
+    fn exp(self) -> Self;
+    fn ln(self) -> Self;
+    fn sin(self) -> Self;
+    fn cos(self) -> Self;
+    fn sqrt(self) -> Self;
+    fn tanh(self) -> Self;
+    fn abs(self) -> Self;
+    fn powi(self, n: i32) -> Self;
+}
+
+macro_rules! impl_real_float {
+    ($t:ty) => {
+        impl Real for $t {
+            fn from_f64(v: f64) -> Self {
+                v as $t
+            }
+            fn exp(self) -> Self {
+                <$t>::exp(self)
+            }
+            fn ln(self) -> Self {
+                <$t>::ln(self)
+            }
+            fn sin(self) -> Self {
+                <$t>::sin(self)
+            }
+            fn cos(self) -> Self {
+                <$t>::cos(self)
+            }
+            fn sqrt(self) -> Self {
+                <$t>::sqrt(self)
+            }
+            fn tanh(self) -> Self {
+                <$t>::tanh(self)
+            }
+            fn abs(self) -> Self {
+                <$t>::abs(self)
+            }
+            fn powi(self, n: i32) -> Self {
+                <$t>::powi(self, n)
+            }
+        }
+    };
+}
+
+impl_real_float!(f32);
+impl_real_float!(f64);
+
+/// `primal + tangent * e` with `e * e = 0`.
+#[derive(Debug, Clone, Copy)]
+pub struct Dual<T> {
+    pub primal: T,
+    pub tangent: T,
+}
+
+impl<T: Real> Dual<T> {
+    pub fn new(primal: T, tangent: T) -> Self {
+        Self { primal, tangent }
+    }
+
+    pub fn constant(primal: T) -> Self {
+        Self::new(primal, T::from(0u8))
+    }
+
+    /// Apply a scalar function given its value and derivative at the primal.
+    fn chain(self, f: T, df: T) -> Self {
+        Self::new(f, df * self.tangent)
+    }
+}
+
+impl<T: Real> From<u8> for Dual<T> {
+    fn from(v: u8) -> Self {
+        Self::constant(T::from(v))
+    }
+}
+
+// comparisons only look at the primal, like every other piecewise function
+impl<T: Real> PartialEq for Dual<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.primal == other.primal
+    }
+}
+
+impl<T: Real> PartialOrd for Dual<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.primal.partial_cmp(&other.primal)
+    }
+}
+
+impl<T: Real> Add for Dual<T> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Self::new(self.primal + rhs.primal, self.tangent + rhs.tangent)
+    }
+}
+
+impl<T: Real> Sub for Dual<T> {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Self::new(self.primal - rhs.primal, self.tangent - rhs.tangent)
+    }
+}
+
+impl<T: Real> Mul for Dual<T> {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Self::new(
+            self.primal * rhs.primal,
+            self.tangent * rhs.primal + self.primal * rhs.tangent,
+        )
+    }
+}
+
+impl<T: Real> Div for Dual<T> {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        Self::new(
+            self.primal / rhs.primal,
+            (self.tangent * rhs.primal - self.primal * rhs.tangent) / (rhs.primal * rhs.primal),
+        )
+    }
+}
+
+impl<T: Real> Neg for Dual<T> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        Self::new(-self.primal, -self.tangent)
+    }
+}
+
+// floor is piecewise constant, so the tangent is zero almost everywhere
+impl<T: Real> FloorDiv for Dual<T> {
+    type Output = Self;
+    fn floor_div(self, rhs: Self) -> Self {
+        Self::constant(self.primal.floor_div(rhs.primal))
+    }
+}
+
+impl<T: Real> ToInteger for Dual<T> {
+    fn to_isize(self) -> isize {
+        self.primal.to_isize()
+    }
+}
+
+impl<T: Real> Real for Dual<T> {
+    fn from_f64(v: f64) -> Self {
+        Self::constant(T::from_f64(v))
+    }
+    fn exp(self) -> Self {
+        let e = self.primal.exp();
+        self.chain(e, e)
+    }
+    fn ln(self) -> Self {
+        self.chain(self.primal.ln(), T::from(1u8) / self.primal)
+    }
+    fn sin(self) -> Self {
+        self.chain(self.primal.sin(), self.primal.cos())
+    }
+    fn cos(self) -> Self {
+        self.chain(self.primal.cos(), -self.primal.sin())
+    }
+    fn sqrt(self) -> Self {
+        let s = self.primal.sqrt();
+        self.chain(s, T::from(1u8) / (T::from(2u8) * s))
+    }
+    fn tanh(self) -> Self {
+        let t = self.primal.tanh();
+        self.chain(t, T::from(1u8) - t * t)
+    }
+    fn abs(self) -> Self {
+        if self.primal < T::from(0u8) {
+            -self
+        } else {
+            self
+        }
+    }
+    fn powi(self, n: i32) -> Self {
+        if n == 0 {
+            return Self::from(1u8);
+        }
+        self.chain(
+            self.primal.powi(n),
+            T::from_f64(n as f64) * self.primal.powi(n - 1),
+        )
+    }
+}
+
+/// Jacobian-vector product: evaluates `f` at `primals` and pushes `tangents` through it.
+/// Returns `(f(primals), J(primals) @ tangents)`.
+pub fn jvp<T, F>(f: F, primals: &[T], tangents: &[T]) -> (Vec<T>, Vec<T>)
+where
+    T: Real,
+    F: Fn(&[Dual<T>]) -> Vec<Dual<T>>,
+{
+    assert_eq!(
+        primals.len(),
+        tangents.len(),
+        "primals and tangents must have the same length"
+    );
+    let xs: Vec<Dual<T>> = primals
+        .iter()
+        .zip(tangents.iter())
+        .map(|(p, t)| Dual::new(*p, *t))
+        .collect();
+    f(&xs).into_iter().map(|d| (d.primal, d.tangent)).unzip()
+}
+
+/// Hessian-vector product `H(primals) @ tangents` of a scalar function, by forward-over-forward.
+/// The inner dual picks out one coordinate of the gradient and the outer one differentiates it along
+/// `tangents`, so this costs one evaluation of `f` per input.
+pub fn hvp<T, F>(f: F, primals: &[T], tangents: &[T]) -> Vec<T>
+where
+    T: Real,
+    F: Fn(&[Dual<Dual<T>>]) -> Dual<Dual<T>>,
+{
+    assert_eq!(
+        primals.len(),
+        tangents.len(),
+        "primals and tangents must have the same length"
+    );
+    let (zero, one) = (T::from(0u8), T::from(1u8));
+    (0..primals.len())
+        .map(|i| {
+            let xs: Vec<Dual<Dual<T>>> = primals
+                .iter()
+                .zip(tangents.iter())
+                .enumerate()
+                .map(|(j, (p, v))| {
+                    Dual::new(
+                        Dual::new(*p, if i == j { one } else { zero }),
+                        Dual::new(*v, zero),
+                    )
+                })
+                .collect();
+            f(&xs).tangent.tangent
+        })
+        .collect()
+}
@@ -0,0 +1,160 @@
+// TinyJit: the first call runs the function as usual, the second records every kernel it runs, and
+// every later call replays those kernels with the new inputs swapped in. Nothing above the kernels
+// (tensors, the autograd tape, ASTs) is built again. The kernels write into the same buffers on every
+// replay, so each call hands back copies of them and results from earlier calls stay as they were
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    features::memory::memory_planner,
+    lazy::{LazyBuffer, Storage},
+    prelude::{DEBUG, JIT},
+    realize::{begin_capture, end_capture, ExecItem},
+    shape::view::View,
+    tensor::Tensor,
+};
+
+pub struct TinyJit<F> {
+    fxn: F,
+    cnt: usize,
+    jit_cache: Vec<ExecItem>,
+    /// (kernel, buffer slot, input) for every slot an input is swapped into
+    input_replace: Vec<(usize, usize, usize)>,
+    /// the view each input was captured with, replays need the same ones
+    expected_sts: Vec<View>,
+    /// the storage each `Variable` was bound into
+    vars: Vec<(String, Storage)>,
+    ret: Vec<Tensor>,
+}
+
+impl<F> TinyJit<F>
+where
+    F: FnMut(&[Tensor], &HashMap<String, isize>) -> Vec<Tensor>,
+{
+    pub fn new(fxn: F) -> Self {
+        Self {
+            fxn,
+            cnt: 0,
+            jit_cache: vec![],
+            input_replace: vec![],
+            expected_sts: vec![],
+            vars: vec![],
+            ret: vec![],
+        }
+    }
+
+    /// Call the function on `inputs`, with `var_vals` as the values of the `Variable`s it binds.
+    /// With `JIT=0` this always runs the function.
+    pub fn call(&mut self, inputs: &[Tensor], var_vals: &HashMap<String, isize>) -> Vec<Tensor> {
+        if JIT.clone() == 0 {
+            return (self.fxn)(inputs, var_vals);
+        }
+        let ret = match self.cnt {
+            0 => (self.fxn)(inputs, var_vals),
+            1 => {
+                begin_capture();
+                let ret = (self.fxn)(inputs, var_vals);
+                let capture = end_capture();
+                let bases: Vec<Storage> = inputs.iter().map(|x| x.lazydata().base).collect();
+                for (j, ei) in capture.items.iter().enumerate() {
+                    for (k, b) in ei.bufs.iter().enumerate() {
+                        if let Some(i) = bases.iter().position(|x| Arc::ptr_eq(x, b)) {
+                            self.input_replace.push((j, k, i));
+                        }
+                    }
+                }
+                self.expected_sts = inputs.iter().map(|x| x.lazydata().st).collect();
+                // everything the kernels hand back has to stay put, the rest can share memory
+                let noopt: Vec<Storage> = bases
+                    .into_iter()
+                    .chain(ret.iter().map(|x| x.lazydata().base))
+                    .chain(capture.vars.iter().map(|(_, v)| v.clone()))
+                    .collect();
+                let mut items = capture.items;
+                memory_planner(&mut items, &noopt);
+                self.jit_cache = items;
+                self.vars = capture.vars;
+                self.ret = ret;
+                if DEBUG.clone() >= 1 {
+                    println!(
+                        "JIT captured {} kernels with {} inputs",
+                        self.jit_cache.len(),
+                        inputs.len()
+                    );
+                }
+                self.copy_ret()
+            }
+            _ => {
+                self.replay(inputs, var_vals);
+                self.copy_ret()
+            }
+        };
+        self.cnt += 1;
+        ret
+    }
+
+    /// The outputs in buffers of their own, the next replay overwrites the captured ones.
+    fn copy_ret(&self) -> Vec<Tensor> {
+        self.ret
+            .iter()
+            .map(|x| {
+                let lb = x.lazydata();
+                let copy = lb.base.read().unwrap().copy_to(&lb.device).unwrap();
+                let lb = LazyBuffer::from_storage(lb.st, Arc::new(RwLock::new(copy)));
+                Tensor::from_lazy(lb, false)
+            })
+            .collect()
+    }
+
+    fn replay(&mut self, inputs: &[Tensor], var_vals: &HashMap<String, isize>) {
+        assert_eq!(
+            inputs.len(),
+            self.expected_sts.len(),
+            "JIT was captured with {} inputs",
+            self.expected_sts.len()
+        );
+        let lbs: Vec<_> = inputs.iter().map(|x| x.lazydata()).collect();
+        for (i, (lb, st)) in lbs.iter().zip(self.expected_sts.iter()).enumerate() {
+            assert_eq!(
+                &lb.st, st,
+                "input {} doesn't match the view it was captured with",
+                i
+            );
+        }
+        for (j, k, i) in &self.input_replace {
+            let bufs = &mut self.jit_cache[*j].bufs;
+            let mut base = lbs[*i].base.clone();
+            // a kernel can't lock one storage twice, e.g. when an output is fed back in
+            if bufs.iter().any(|b| Arc::ptr_eq(b, &base)) && !Arc::ptr_eq(&bufs[*k], &base) {
+                let copy = base.read().unwrap().copy_to(&lbs[*i].device).unwrap();
+                base = Arc::new(RwLock::new(copy));
+            }
+            bufs[*k] = base;
+        }
+        for (expr, storage) in &self.vars {
+            let val = *var_vals
+                .get(expr)
+                .unwrap_or_else(|| panic!("no value for variable {}", expr));
+            storage
+                .write()
+                .unwrap()
+                .copyin(&(val as f64).to_le_bytes())
+                .unwrap();
+        }
+        for ei in &self.jit_cache {
+            ei.prg.exec(&ei.bufs, true);
+        }
+    }
+
+    /// Forget the capture, the next call starts over.
+    pub fn reset(&mut self) {
+        self.cnt = 0;
+        self.jit_cache.clear();
+        self.input_replace.clear();
+        self.expected_sts.clear();
+        self.vars.clear();
+        self.ret.clear();
+    }
+}
@@ -0,0 +1,103 @@
+// memory planner. Every kernel in a schedule writes a fresh buffer, but most of those are dead a few
+// kernels later. Buffers whose lifetimes don't overlap can share one allocation, and greedily handing
+// each buffer the first allocation free at its start uses as few allocations as any assignment can
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{lazy::Storage, prelude::DEBUG, realize::ExecItem};
+
+/// The first and last kernel touching each buffer an item in `items` writes first, in order of
+/// first use. Buffers read before they're written hold data from outside and aren't included.
+pub fn buffer_lifetimes(items: &[ExecItem]) -> Vec<(Storage, usize, usize)> {
+    let mut ret: Vec<(Storage, usize, usize)> = vec![];
+    let mut external: Vec<Storage> = vec![];
+    for (i, ei) in items.iter().enumerate() {
+        for (k, b) in ei.bufs.iter().enumerate() {
+            if let Some(l) = ret.iter_mut().find(|l| Arc::ptr_eq(&l.0, b)) {
+                l.2 = i;
+            } else if k == 0 && !external.iter().any(|x| Arc::ptr_eq(x, b)) {
+                ret.push((b.clone(), i, i));
+            } else {
+                external.push(b.clone());
+            }
+        }
+    }
+    ret
+}
+
+/// The most bytes live at once over a schedule, for allocations given as `(nbytes, first use, last
+/// use)` in kernel indices.
+pub fn peak_memory(allocs: &[(usize, usize, usize)]) -> usize {
+    let len = allocs.iter().map(|(_, _, end)| end + 1).max().unwrap_or(0);
+    (0..len)
+        .map(|i| {
+            allocs
+                .iter()
+                .filter(|(_, start, end)| (*start..=*end).contains(&i))
+                .map(|(nbytes, _, _)| nbytes)
+                .sum()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Point the intermediate buffers of `items` at as few allocations as their lifetimes allow. Buffers
+/// in `noopt` (inputs, outputs, anything read after the schedule) keep their own memory. Only
+/// buffers of the same device and size share, since a kernel's indexing is baked in for its sizes.
+pub fn memory_planner(items: &mut [ExecItem], noopt: &[Storage]) {
+    let lifetimes: Vec<(Storage, usize, usize)> = buffer_lifetimes(items)
+        .into_iter()
+        .filter(|(b, _, _)| !noopt.iter().any(|x| Arc::ptr_eq(x, b)))
+        .collect();
+    // per device and size, the allocations so far and the last kernel each is busy until
+    let mut pools: HashMap<(String, usize), Vec<(Storage, usize)>> = HashMap::new();
+    let mut assigned: Vec<(Storage, Storage)> = vec![];
+    for (b, start, end) in &lifetimes {
+        let key = {
+            let buf = b.read().unwrap();
+            (buf.device.clone(), buf.nbytes())
+        };
+        let pool = pools.entry(key).or_default();
+        let phys = match pool.iter_mut().find(|(_, busy)| *busy < *start) {
+            Some((phys, busy)) => {
+                *busy = *end;
+                phys.clone()
+            }
+            None => {
+                pool.push((b.clone(), *end));
+                b.clone()
+            }
+        };
+        assigned.push((b.clone(), phys));
+    }
+    for ei in items.iter_mut() {
+        for b in ei.bufs.iter_mut() {
+            if let Some((_, phys)) = assigned.iter().find(|(x, _)| Arc::ptr_eq(x, b)) {
+                *b = phys.clone();
+            }
+        }
+    }
+
+    if DEBUG.clone() >= 1 && !lifetimes.is_empty() {
+        let nbytes = |b: &Storage| b.read().unwrap().nbytes();
+        let naive: Vec<(usize, usize, usize)> = lifetimes
+            .iter()
+            .map(|(b, s, e)| (nbytes(b), *s, *e))
+            .collect();
+        // a shared allocation is live from the first use of its first buffer to the last of its last
+        let mut planned: Vec<(Storage, (usize, usize, usize))> = vec![];
+        for ((_, phys), (_, start, end)) in assigned.iter().zip(&lifetimes) {
+            match planned.iter_mut().find(|(p, _)| Arc::ptr_eq(p, phys)) {
+                Some((_, span)) => span.2 = span.2.max(*end),
+                None => planned.push((phys.clone(), (nbytes(phys), *start, *end))),
+            }
+        }
+        let planned: Vec<(usize, usize, usize)> = planned.into_iter().map(|(_, s)| s).collect();
+        println!(
+            "peak memory {:.2} MB -> {:.2} MB, {} -> {} bufs",
+            peak_memory(&naive) as f64 / 1e6,
+            peak_memory(&planned) as f64 / 1e6,
+            naive.len(),
+            planned.len()
+        );
+    }
+}
@@ -0,0 +1,4 @@
+pub mod jit;
+pub mod memory;
+pub mod process_replay;
+pub mod search;
@@ -0,0 +1,144 @@
+// process replay: unless CACHECOLLECTING=0, every kernel lowered is recorded into the cache db with
+// the source it rendered to. Replaying lowers the recorded ASTs again with the code as it is now and
+// diffs the sources, so a refactor that changes what the renderers emit doesn't go unnoticed.
+// `cargo test` records into its own db, so to check a change against the tests' kernels:
+//   CACHEDB=/tmp/replay.db cargo test          (before the change)
+//   CACHEDB=/tmp/replay.db cargo run --bin process_replay   (after it)
+// Each kernel replays under the NOOPT and BEAM it was recorded with, BEAM from the opts it cached
+use anyhow::anyhow;
+use rusqlite::params;
+use serde_pickle::{DeOptions, SerOptions};
+
+use crate::{
+    device::get_device,
+    helpers::{db_connection, VERSION},
+    ops::LazyOp,
+    realize::lowerer_with,
+};
+
+/// One recorded kernel.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+    pub device: String,
+    pub name: String,
+    pub ast: LazyOp,
+    pub src: String,
+    /// the `BEAM` and `NOOPT` it was lowered under
+    pub beam: usize,
+    pub noopt: bool,
+}
+
+fn table() -> String {
+    format!("'process_replay_{}'", *VERSION)
+}
+
+/// Record a kernel. One seen again on the same device and settings replaces its old row.
+pub fn record(r: &Recorded) -> Result<(), anyhow::Error> {
+    let key = format!(
+        "{:x}",
+        md5::compute(format!("{} {} {} {:?}", r.device, r.beam, r.noopt, r.ast))
+    );
+    let ast = serde_pickle::to_vec(&r.ast, SerOptions::default())?;
+    let conn = db_connection();
+    let conn = conn.lock().map_err(|_| anyhow!("Mutex poisioned"))?;
+    let conn = conn.as_ref().expect("Connection not inited");
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (key text PRIMARY KEY, device text, name text, ast blob, src text, beam integer, noopt integer)",
+            table()
+        ),
+        params![],
+    )?;
+    conn.execute(
+        &format!("REPLACE INTO {} VALUES (?, ?, ?, ?, ?, ?, ?)", table()),
+        params![key, r.device, r.name, ast, r.src, r.beam, r.noopt],
+    )?;
+    Ok(())
+}
+
+/// Everything recorded so far, oldest first.
+pub fn recorded() -> Result<Vec<Recorded>, anyhow::Error> {
+    let conn = db_connection();
+    let conn = conn.lock().map_err(|_| anyhow!("Mutex poisioned"))?;
+    let conn = conn.as_ref().expect("Connection not inited");
+    let Ok(mut stmt) = conn.prepare(&format!(
+        "SELECT device, name, ast, src, beam, noopt FROM {} ORDER BY rowid",
+        table()
+    )) else {
+        // nothing was ever recorded
+        return Ok(vec![]);
+    };
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get(0)?,
+            row.get(1)?,
+            row.get::<_, Vec<u8>>(2)?,
+            row.get(3)?,
+            row.get(4)?,
+            row.get(5)?,
+        ))
+    })?;
+    rows.map(|row| {
+        let (device, name, ast, src, beam, noopt) = row?;
+        Ok(Recorded {
+            device,
+            name,
+            ast: serde_pickle::from_slice(&ast, DeOptions::default())?,
+            src,
+            beam,
+            noopt,
+        })
+    })
+    .collect()
+}
+
+/// Lower `r.ast` again the way `Runner::new` did, under the `BEAM` and `NOOPT` it was recorded with,
+/// and diff what it renders to now against the recorded source. None when they're the same.
+pub fn replay(r: &Recorded) -> Result<Option<String>, anyhow::Error> {
+    let dev = get_device(&r.device)?;
+    let compiler = dev
+        .compiler()
+        .ok_or(anyhow!("{} can't run kernels", dev.name()))?;
+    let mut lin = lowerer_with(r.ast.clone(), r.beam, r.noopt);
+    let name = lin.name();
+    let src = compiler.render(&name, lin.linearize());
+    if src == r.src {
+        return Ok(None);
+    }
+    let mut out = vec![
+        format!("--- {} {}", r.device, r.name),
+        format!("+++ {} {}", r.device, name),
+    ];
+    out.extend(diff_lines(&r.src, &src));
+    Ok(Some(out.join("\n")))
+}
+
+/// A line diff, `-` for lines only in `old`, `+` for lines only in `new`, unchanged lines indented.
+pub fn diff_lines(old: &str, new: &str) -> Vec<String> {
+    let (a, b): (Vec<&str>, Vec<&str>) = (old.lines().collect(), new.lines().collect());
+    // lcs[i][j] is the longest common subsequence of a[i..] and b[j..]
+    let mut lcs = vec![vec![0usize; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let (mut i, mut j, mut out) = (0, 0, vec![]);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            out.push(format!(" {}", a[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            out.push(format!("-{}", a[i]));
+            i += 1;
+        } else {
+            out.push(format!("+{}", b[j]));
+            j += 1;
+        }
+    }
+    out
+}
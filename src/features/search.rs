@@ -0,0 +1,292 @@
+// BEAM search: grow the kernel one optimisation at a time, keeping the `amt` fastest candidates of
+// each generation, until no child beats the best kernel so far
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    codegen::{
+        kernel::{Opt, OptOps},
+        linearizer::Linearizer,
+    },
+    dtype::DType,
+    helpers::{cpu_time_execution, diskcache_get, diskcache_put, getenv, CACHELEVEL},
+    ops::LazyOpArg,
+    prelude::DEBUG,
+    runtime::{
+        ops_clang::{self, ClangCompiler, ClangProgram},
+        ops_interp::Val,
+    },
+};
+
+lazy_static! {
+    /// every action the search tries, the ones a kernel can't take are skipped
+    pub static ref ACTIONS: Vec<Opt> = {
+        let mut actions = vec![];
+        for axis in 0..6 {
+            for amt in [0, 2, 3, 4, 5, 7] {
+                actions.push(Opt::new(OptOps::Upcast, axis, amt));
+            }
+            for amt in [2, 3, 4, 8, 16] {
+                actions.push(Opt::new(OptOps::Local, axis, amt));
+            }
+            for amt in [8, 32] {
+                actions.push(Opt::new(OptOps::Padto, axis, amt));
+            }
+            for other in axis + 1..6 {
+                actions.push(Opt::new(OptOps::Swap, axis, other));
+            }
+        }
+        for axis in 0..4 {
+            for amt in [0, 4] {
+                actions.push(Opt::new(OptOps::Unroll, axis, amt));
+            }
+        }
+        for axis in 0..3 {
+            for amt in [2, 4, 8, 16] {
+                actions.push(Opt::new(OptOps::Group, axis, amt));
+            }
+        }
+        actions
+    };
+}
+
+/// A kernel's name and shared object.
+type Compiled = (String, Vec<u8>);
+
+/// What makes two kernels the same loop nest, however the opts got there.
+fn schedule_key(lin: &Linearizer) -> String {
+    format!(
+        "{:?} {:?} {} {} {}",
+        lin.sts, lin.axes, lin.local_dims, lin.group_for_reduces, lin.upcasted
+    )
+}
+
+/// Every kernel one legal action away from `lin`, unrolling at most 256 elements.
+pub fn get_linearizer_actions(lin: &Linearizer) -> Vec<Linearizer> {
+    ACTIONS
+        .iter()
+        .filter_map(|opt| {
+            let mut lin2 = lin.clone();
+            lin2.apply_opt(*opt).ok()?;
+            let upcast: usize = lin2.full_shape()[lin2.first_upcast()..].iter().product();
+            (upcast <= 256).then_some(lin2)
+        })
+        .collect()
+}
+
+/// Buffers for every global of `lin` in `DefineGlobal` order, big enough for any index its views
+/// reach and filled with ones, so nothing divides by zero.
+pub fn bufs_from_lin(lin: &Linearizer) -> Vec<Vec<u8>> {
+    let mut sizes: HashMap<usize, (usize, DType)> = HashMap::new();
+    for (b, st) in lin.bufs.iter().zip(lin.sts.iter()) {
+        let Some(LazyOpArg::MemBuffer(mb)) = &b.arg else {
+            continue;
+        };
+        let ranges: Vec<(usize, usize)> = match &st.mask {
+            Some(m) => m.clone(),
+            None => st.shape.iter().map(|s| (0, *s)).collect(),
+        };
+        let size = if ranges.iter().any(|(b, e)| b >= e) {
+            0
+        } else {
+            let hi = st.offset
+                + ranges
+                    .iter()
+                    .zip(st.strides.iter())
+                    .map(|((b, e), st)| {
+                        if *st > 0 {
+                            (*e as isize - 1) * st
+                        } else {
+                            *b as isize * st
+                        }
+                    })
+                    .sum::<isize>();
+            hi as usize + 1
+        };
+        let entry = sizes.entry(mb.idx).or_insert((0, mb.dtype));
+        entry.0 = entry.0.max(size);
+    }
+    let mut idxs: Vec<usize> = sizes.keys().copied().collect();
+    idxs.sort();
+    idxs.into_iter()
+        .map(|i| {
+            let (size, dtype) = sizes[&i];
+            let mut buf = vec![0u8; size * dtype.itemsize()];
+            for j in 0..size {
+                Val::from_f64(1.0, dtype).store(&mut buf, j, dtype);
+            }
+            buf
+        })
+        .collect()
+}
+
+/// Compile `lins`, one compiler per core. A compile slower than `timeout` is dropped.
+fn compile_all(lins: &[Linearizer], timeout: Duration) -> Vec<Option<Compiled>> {
+    let srcs: Vec<(String, String)> = lins
+        .iter()
+        .map(|lin| {
+            // lower a copy, the candidate itself has to stay open to more opts
+            let mut lin = lin.clone();
+            let name = lin.name();
+            let src = ops_clang::render(&name, lin.linearize());
+            (name, src)
+        })
+        .collect();
+    let results: Vec<Mutex<Option<Compiled>>> = srcs.iter().map(|_| Mutex::new(None)).collect();
+    let next = AtomicUsize::new(0);
+    let compiler = ClangCompiler::new();
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    thread::scope(|s| {
+        for _ in 0..threads.min(srcs.len()) {
+            s.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some((name, src)) = srcs.get(i) else {
+                    break;
+                };
+                if let Ok(lib) = compiler.compile_timeout(src, Some(timeout)) {
+                    *results[i].lock().unwrap() = Some((name.clone(), lib));
+                }
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| r.into_inner().unwrap())
+        .collect()
+}
+
+/// Best of `cnt` runs of `prg` over `bufs` in seconds. Stops after the first run if that one is
+/// already slower than `early_stop`.
+pub fn time_program(prg: &ClangProgram, bufs: &mut [Vec<u8>], cnt: usize, early_stop: f64) -> f64 {
+    let bufs = RefCell::new(bufs);
+    let mut best = f64::INFINITY;
+    for _ in 0..cnt {
+        let tm = cpu_time_execution(
+            || {
+                let mut bufs = bufs.borrow_mut();
+                let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
+                prg.call(&mut refs, false);
+            },
+            true,
+        )
+        .unwrap()
+        .as_secs_f64();
+        best = best.min(tm);
+        if tm > early_stop {
+            break;
+        }
+    }
+    best
+}
+
+/// `time_program` on a thread of its own, over a copy of `bufs`. None when the runs take longer
+/// than `timeout`: a kernel can't be stopped partway, so its thread is left to finish on its own.
+fn time_program_timeout(
+    prg: ClangProgram,
+    bufs: &[Vec<u8>],
+    cnt: usize,
+    early_stop: f64,
+    timeout: Duration,
+) -> Option<f64> {
+    let (tx, rx) = mpsc::channel();
+    let mut bufs = bufs.to_vec();
+    thread::spawn(move || {
+        let _ = tx.send(time_program(&prg, &mut bufs, cnt, early_stop));
+    });
+    rx.recv_timeout(timeout).ok()
+}
+
+/// Compile and time `lins`, fastest first. `BEAM_TIMEOUT_SEC` bounds each candidate's compile and,
+/// separately, its timed runs; a candidate over either is dropped.
+fn time_linearizers(
+    lins: Vec<Linearizer>,
+    bufs: &[Vec<u8>],
+    early_stop: f64,
+) -> Vec<(Linearizer, f64)> {
+    let timeout = Duration::from_secs_f64(
+        getenv("BEAM_TIMEOUT_SEC".to_string(), Some("10".to_string()))
+            .parse()
+            .unwrap(),
+    );
+    let libs = compile_all(&lins, timeout);
+    let mut timed: Vec<(Linearizer, f64)> = lins
+        .into_iter()
+        .zip(libs)
+        .filter_map(|(lin, lib)| {
+            let (name, lib) = lib?;
+            let prg = ClangProgram::new(&name, lib).ok()?;
+            let tm = time_program_timeout(prg, bufs, 3, early_stop, timeout)?;
+            Some((lin, tm))
+        })
+        .collect();
+    timed.sort_by(|a, b| a.1.total_cmp(&b.1));
+    timed
+}
+
+/// The `beam_search` diskcache key of `lin` searched with beam width `amt`.
+pub fn beam_key(lin: &Linearizer, amt: usize) -> HashMap<String, String> {
+    HashMap::from([
+        ("ast".to_string(), serde_json::to_string(&lin.ast).unwrap()),
+        ("amt".to_string(), amt.to_string()),
+    ])
+}
+
+/// BEAM search from `lin` with beam width `amt`, timing on `bufs`. The winning opts are cached by
+/// kernel AST, so a kernel is only ever searched once.
+pub fn beam_search(lin: &Linearizer, bufs: &[Vec<u8>], amt: usize) -> Linearizer {
+    let key = beam_key(lin, amt);
+    if *CACHELEVEL >= 1 {
+        if let Some(opts) = diskcache_get::<Vec<Opt>>("beam_search", Box::new(key.clone())) {
+            let mut ret = lin.clone();
+            if opts.into_iter().all(|opt| ret.apply_opt(opt).is_ok()) {
+                return ret;
+            }
+        }
+    }
+
+    let mut beam = time_linearizers(vec![lin.clone()], bufs, f64::INFINITY);
+    if beam.is_empty() {
+        return lin.clone();
+    }
+    let mut seen: HashSet<String> = HashSet::from([schedule_key(lin)]);
+    loop {
+        let candidates: Vec<Linearizer> = beam
+            .iter()
+            .flat_map(|(lin, _)| get_linearizer_actions(lin))
+            .filter(|lin| seen.insert(schedule_key(lin)))
+            .collect();
+        if candidates.is_empty() {
+            break;
+        }
+        let n = candidates.len();
+        let timed = time_linearizers(candidates, bufs, beam[0].1 * 3.0);
+        if timed.first().is_none_or(|(_, tm)| *tm >= beam[0].1) {
+            break;
+        }
+        beam = timed.into_iter().take(amt).collect();
+        if DEBUG.clone() >= 2 {
+            println!(
+                "{:10.2} us from {:3} candidates {:?}",
+                beam[0].1 * 1e6,
+                n,
+                beam[0].0.applied_opts
+            );
+        }
+    }
+
+    let best = beam.swap_remove(0).0;
+    if *CACHELEVEL >= 1 {
+        let _ = diskcache_put("beam_search", Box::new(key), best.applied_opts.clone());
+    }
+    best
+}
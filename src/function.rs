@@ -0,0 +1,563 @@
+// the differentiable primitives. Each one knows how to run itself forward on `LazyData`, sharded or not,
+// and how to turn the output gradient into gradients for its inputs
+use std::f64::consts::{FRAC_PI_2, LN_2};
+
+use crate::{
+    multi::LazyData,
+    ops::{BinaryOps, ReduceOps, TernaryOps, UnaryOps},
+};
+
+pub trait Function: Send + Sync {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData;
+    /// One entry per input, `None` where the input isn't differentiable.
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>>;
+}
+
+// ************* unary ops *************
+
+#[derive(Default)]
+pub struct Contiguous {}
+impl Function for Contiguous {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].contiguous()
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.clone())]
+    }
+}
+
+/// Stores as a float32 image, gradients pass through as they are.
+pub struct CastImage {
+    height: usize,
+    width: usize,
+}
+impl CastImage {
+    pub fn new(height: usize, width: usize) -> Self {
+        Self { height, width }
+    }
+}
+impl Function for CastImage {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].cast_image(self.height, self.width)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.clone())]
+    }
+}
+
+#[derive(Default)]
+pub struct Neg {}
+impl Function for Neg {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].e(UnaryOps::Neg, &[])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.e(UnaryOps::Neg, &[]))]
+    }
+}
+
+#[derive(Default)]
+pub struct Reciprocal {
+    ret: Option<LazyData>,
+}
+impl Function for Reciprocal {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        let ret = srcs[0].e(UnaryOps::Recip, &[]);
+        self.ret = Some(ret.clone());
+        ret
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let ret = self.ret.as_ref().unwrap();
+        vec![Some(
+            grad_output
+                .e(UnaryOps::Neg, &[])
+                .e(BinaryOps::Mul, &[ret])
+                .e(BinaryOps::Mul, &[ret]),
+        )]
+    }
+}
+
+#[derive(Default)]
+pub struct Sin {
+    x: Option<LazyData>,
+}
+impl Function for Sin {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.x = Some(srcs[0].clone());
+        srcs[0].e(UnaryOps::Sin, &[])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let x = self.x.as_ref().unwrap();
+        vec![Some(
+            x.const_like(FRAC_PI_2)
+                .e(BinaryOps::Sub, &[x])
+                .e(UnaryOps::Sin, &[])
+                .e(BinaryOps::Mul, &[grad_output]),
+        )]
+    }
+}
+
+#[derive(Default)]
+pub struct Relu {
+    ret: Option<LazyData>,
+}
+impl Function for Relu {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        let ret = srcs[0].e(BinaryOps::Max, &[&srcs[0].const_like(0.0)]);
+        self.ret = Some(ret.clone());
+        ret
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let ret = self.ret.as_ref().unwrap();
+        vec![Some(
+            ret.const_like(0.0)
+                .e(BinaryOps::CmpLt, &[ret])
+                .e(BinaryOps::Mul, &[grad_output]),
+        )]
+    }
+}
+
+#[derive(Default)]
+pub struct Log {
+    x: Option<LazyData>,
+}
+impl Function for Log {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.x = Some(srcs[0].clone());
+        srcs[0]
+            .e(UnaryOps::Log2, &[])
+            .e(BinaryOps::Mul, &[&srcs[0].const_like(LN_2)])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(
+            grad_output.e(BinaryOps::Div, &[self.x.as_ref().unwrap()]),
+        )]
+    }
+}
+
+#[derive(Default)]
+pub struct Exp {
+    ret: Option<LazyData>,
+}
+impl Function for Exp {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        let ret = srcs[0]
+            .e(BinaryOps::Mul, &[&srcs[0].const_like(1.0 / LN_2)])
+            .e(UnaryOps::Exp2, &[]);
+        self.ret = Some(ret.clone());
+        ret
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(
+            self.ret.as_ref().unwrap().e(BinaryOps::Mul, &[grad_output]),
+        )]
+    }
+}
+
+#[derive(Default)]
+pub struct Sqrt {
+    ret: Option<LazyData>,
+}
+impl Function for Sqrt {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        let ret = srcs[0].e(UnaryOps::Sqrt, &[]);
+        self.ret = Some(ret.clone());
+        ret
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let ret = self.ret.as_ref().unwrap();
+        vec![Some(grad_output.e(
+            BinaryOps::Div,
+            &[&ret.e(BinaryOps::Mul, &[&ret.const_like(2.0)])],
+        ))]
+    }
+}
+
+// NOTE: the implicit derivative of sigmoid is not stable
+// https://towardsdatascience.com/derivative-of-the-sigmoid-function-536880cf918e
+// TODO: have the backend automatically find this
+#[derive(Default)]
+pub struct Sigmoid {
+    ret: Option<LazyData>,
+}
+impl Function for Sigmoid {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        let x = srcs[0];
+        let ret = x.const_like(1.0).e(
+            BinaryOps::Div,
+            &[&x.const_like(1.0).e(
+                BinaryOps::Add,
+                &[&x.e(BinaryOps::Mul, &[&x.const_like(-1.0 / LN_2)])
+                    .e(UnaryOps::Exp2, &[])],
+            )],
+        );
+        self.ret = Some(ret.clone());
+        ret
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let ret = self.ret.as_ref().unwrap();
+        vec![Some(
+            ret.e(
+                BinaryOps::Mul,
+                &[&ret.const_like(1.0).e(BinaryOps::Sub, &[ret])],
+            )
+            .e(BinaryOps::Mul, &[grad_output]),
+        )]
+    }
+}
+
+// ************* binary ops *************
+
+#[derive(Default)]
+pub struct Less {}
+impl Function for Less {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].e(BinaryOps::CmpLt, &[srcs[1]])
+    }
+    fn backward(&self, _grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![None, None]
+    }
+}
+
+#[derive(Default)]
+pub struct Eq {}
+impl Function for Eq {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].e(BinaryOps::CmpEq, &[srcs[1]])
+    }
+    fn backward(&self, _grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![None, None]
+    }
+}
+
+#[derive(Default)]
+pub struct Add {}
+impl Function for Add {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].e(BinaryOps::Add, &[srcs[1]])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.clone()), Some(grad_output.clone())]
+    }
+}
+
+#[derive(Default)]
+pub struct Sub {}
+impl Function for Sub {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].e(BinaryOps::Sub, &[srcs[1]])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![
+            Some(grad_output.clone()),
+            Some(grad_output.e(UnaryOps::Neg, &[])),
+        ]
+    }
+}
+
+#[derive(Default)]
+pub struct Mul {
+    x: Option<LazyData>,
+    y: Option<LazyData>,
+}
+impl Function for Mul {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.x = Some(srcs[0].clone());
+        self.y = Some(srcs[1].clone());
+        srcs[0].e(BinaryOps::Mul, &[srcs[1]])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let (x, y) = (self.x.as_ref().unwrap(), self.y.as_ref().unwrap());
+        vec![
+            Some(y.e(BinaryOps::Mul, &[grad_output])),
+            Some(x.e(BinaryOps::Mul, &[grad_output])),
+        ]
+    }
+}
+
+#[derive(Default)]
+pub struct Div {
+    x: Option<LazyData>,
+    y: Option<LazyData>,
+}
+impl Function for Div {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.x = Some(srcs[0].clone());
+        self.y = Some(srcs[1].clone());
+        srcs[0].e(BinaryOps::Div, &[srcs[1]])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let (x, y) = (self.x.as_ref().unwrap(), self.y.as_ref().unwrap());
+        vec![
+            Some(grad_output.e(BinaryOps::Div, &[y])),
+            Some(
+                grad_output
+                    .e(UnaryOps::Neg, &[])
+                    .e(BinaryOps::Mul, &[x])
+                    .e(BinaryOps::Div, &[&y.e(BinaryOps::Mul, &[y])]),
+            ),
+        ]
+    }
+}
+
+// ************* ternary ops *************
+
+#[derive(Default)]
+pub struct Where {
+    x: Option<LazyData>,
+}
+impl Function for Where {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.x = Some(srcs[0].clone());
+        srcs[0].e(TernaryOps::Where, &[srcs[1], srcs[2]])
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let x = self.x.as_ref().unwrap();
+        let zero = grad_output.const_like(0.0);
+        vec![
+            None,
+            Some(x.e(TernaryOps::Where, &[grad_output, &zero])),
+            Some(x.e(TernaryOps::Where, &[&zero, grad_output])),
+        ]
+    }
+}
+
+// ************* reduce ops *************
+
+pub struct Sum {
+    axis: Vec<usize>,
+    input_shape: Vec<usize>,
+}
+impl Sum {
+    pub fn new(axis: &[usize]) -> Self {
+        Self {
+            axis: axis.to_vec(),
+            input_shape: vec![],
+        }
+    }
+}
+impl Function for Sum {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.input_shape = srcs[0].shape().to_vec();
+        srcs[0].r(ReduceOps::Sum, &self.axis)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.expand(&self.input_shape))]
+    }
+}
+
+pub struct Max {
+    axis: Vec<usize>,
+    x: Option<LazyData>,
+    ret: Option<LazyData>,
+}
+impl Max {
+    pub fn new(axis: &[usize]) -> Self {
+        Self {
+            axis: axis.to_vec(),
+            x: None,
+            ret: None,
+        }
+    }
+}
+impl Function for Max {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        let ret = srcs[0].r(ReduceOps::Max, &self.axis);
+        self.x = Some(srcs[0].clone());
+        self.ret = Some(ret.clone());
+        ret
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let (x, ret) = (self.x.as_ref().unwrap(), self.ret.as_ref().unwrap());
+        // 1s in locations where the max was chosen (can be two locations)
+        let max_is_1s = x.e(BinaryOps::CmpEq, &[&ret.expand(&x.shape())]);
+        let div = max_is_1s.r(ReduceOps::Sum, &self.axis).expand(&x.shape());
+        vec![Some(
+            max_is_1s
+                .e(BinaryOps::Div, &[&div])
+                .e(BinaryOps::Mul, &[&grad_output.expand(&x.shape())]),
+        )]
+    }
+}
+
+// ************* movement ops *************
+
+// NOTE: this is sum in reverse
+pub struct Expand {
+    shape: Vec<usize>,
+    input_shape: Vec<usize>,
+}
+impl Expand {
+    pub fn new(shape: &[usize]) -> Self {
+        Self {
+            shape: shape.to_vec(),
+            input_shape: vec![],
+        }
+    }
+}
+impl Function for Expand {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.input_shape = srcs[0].shape().to_vec();
+        srcs[0].expand(&self.shape)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let axis: Vec<usize> = (0..self.input_shape.len())
+            .filter(|i| self.input_shape[*i] != self.shape[*i])
+            .collect();
+        vec![Some(grad_output.r(ReduceOps::Sum, &axis))]
+    }
+}
+
+pub struct Reshape {
+    shape: Vec<usize>,
+    input_shape: Vec<usize>,
+}
+impl Reshape {
+    pub fn new(shape: &[usize]) -> Self {
+        Self {
+            shape: shape.to_vec(),
+            input_shape: vec![],
+        }
+    }
+}
+impl Function for Reshape {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.input_shape = srcs[0].shape().to_vec();
+        srcs[0].reshape(&self.shape)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.reshape(&self.input_shape))]
+    }
+}
+
+pub struct Permute {
+    order: Vec<usize>,
+}
+impl Permute {
+    pub fn new(order: &[usize]) -> Self {
+        Self {
+            order: order.to_vec(),
+        }
+    }
+}
+impl Function for Permute {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].permute(&self.order)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        let mut inverse = vec![0; self.order.len()];
+        for (i, o) in self.order.iter().enumerate() {
+            inverse[*o] = i;
+        }
+        vec![Some(grad_output.permute(&inverse))]
+    }
+}
+
+pub struct Pad {
+    arg: Vec<(usize, usize)>,
+    narg: Vec<(usize, usize)>,
+}
+impl Pad {
+    pub fn new(arg: &[(usize, usize)]) -> Self {
+        Self {
+            arg: arg.to_vec(),
+            narg: vec![],
+        }
+    }
+}
+impl Function for Pad {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.narg = srcs[0]
+            .shape()
+            .iter()
+            .zip(self.arg.iter())
+            .map(|(s, (b, _))| (*b, s + b))
+            .collect();
+        srcs[0].pad(&self.arg)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.shrink(&self.narg))]
+    }
+}
+
+pub struct Shrink {
+    arg: Vec<(usize, usize)>,
+    narg: Vec<(usize, usize)>,
+}
+impl Shrink {
+    pub fn new(arg: &[(usize, usize)]) -> Self {
+        Self {
+            arg: arg.to_vec(),
+            narg: vec![],
+        }
+    }
+}
+impl Function for Shrink {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.narg = srcs[0]
+            .shape()
+            .iter()
+            .zip(self.arg.iter())
+            .map(|(s, (b, e))| (*b, s - e))
+            .collect();
+        srcs[0].shrink(&self.arg)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.pad(&self.narg))]
+    }
+}
+
+pub struct Flip {
+    arg: Vec<isize>,
+}
+impl Flip {
+    pub fn new(axis: &[usize], ndim: usize) -> Self {
+        Self {
+            arg: (0..ndim)
+                .map(|i| if axis.contains(&i) { -1 } else { 1 })
+                .collect(),
+        }
+    }
+}
+impl Function for Flip {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        srcs[0].stride(&self.arg)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        vec![Some(grad_output.stride(&self.arg))]
+    }
+}
+
+/// Keep every `|k|`-th element of each axis, walking it backwards when `k < 0`. Flip is the `k = -1` case.
+pub struct Stride {
+    arg: Vec<isize>,
+    in_shape: Vec<usize>,
+}
+impl Stride {
+    pub fn new(arg: &[isize]) -> Self {
+        Self {
+            arg: arg.to_vec(),
+            in_shape: vec![],
+        }
+    }
+}
+impl Function for Stride {
+    fn forward(&mut self, srcs: &[&LazyData]) -> LazyData {
+        self.in_shape = srcs[0].shape().to_vec();
+        srcs[0].stride(&self.arg)
+    }
+    fn backward(&self, grad_output: &LazyData) -> Vec<Option<LazyData>> {
+        // scatter each grad element back to the first slot of its |k|-wide group, then undo the flips
+        let (mut split, mut pad, mut merged) = (vec![], vec![], vec![]);
+        for (g, k) in grad_output.shape().iter().zip(self.arg.iter()) {
+            let k = k.unsigned_abs();
+            split.extend([*g, 1]);
+            pad.extend([(0, 0), (0, k - 1)]);
+            merged.push(g * k);
+        }
+        let up = grad_output.reshape(&split).pad(&pad).reshape(&merged);
+        let narg: Vec<(usize, usize)> = self.in_shape.iter().map(|s| (0, *s)).collect();
+        let flips: Vec<isize> = self.arg.iter().map(|k| k.signum()).collect();
+        vec![Some(up.shrink(&narg).stride(&flips))]
+    }
+}
@@ -0,0 +1,169 @@
+// finite-difference checking of claimed gradients
+use std::fmt::Display;
+
+use crate::{lazy::all_int_indices, tensor::Tensor};
+
+#[derive(Debug, Clone)]
+pub struct GradcheckReport {
+    pub passed: bool,
+    /// the element that is furthest outside the tolerance (or closest to it when everything passed)
+    pub worst_flat: usize,
+    pub worst_index: Vec<usize>,
+    pub analytic: f64,
+    pub numeric: f64,
+    pub abs_err: f64,
+    pub rel_err: f64,
+    pub max_abs_err: f64,
+}
+
+impl Display for GradcheckReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "gradcheck {} worst element {:?} (flat {}): analytic={:e} numeric={:e} abs_err={:e} rel_err={:e}",
+            if self.passed { "passed," } else { "FAILED at" },
+            self.worst_index,
+            self.worst_flat,
+            self.analytic,
+            self.numeric,
+            self.abs_err,
+            self.rel_err
+        )
+    }
+}
+
+/// Central differences `(f(x + eps) - f(x - eps)) / 2eps` for every element of `x`.
+pub fn numerical_grad<F>(f: F, x: &[f64], eps: f64) -> Vec<f64>
+where
+    F: Fn(&[f64]) -> f64,
+{
+    let mut xp = x.to_vec();
+    (0..x.len())
+        .map(|i| {
+            xp[i] = x[i] + eps;
+            let hi = f(&xp);
+            xp[i] = x[i] - eps;
+            let lo = f(&xp);
+            xp[i] = x[i];
+            (hi - lo) / (2.0 * eps)
+        })
+        .collect()
+}
+
+/// Check `grad_fn`, the claimed gradient of the scalar function `f` at `x`, against central differences.
+/// An element passes when `|analytic - numeric| <= atol + rtol * |numeric|`.
+pub fn gradcheck<F, G>(
+    f: F,
+    grad_fn: G,
+    x: &[f64],
+    shape: &[usize],
+    eps: Option<f64>,
+    atol: Option<f64>,
+    rtol: Option<f64>,
+) -> GradcheckReport
+where
+    F: Fn(&[f64]) -> f64,
+    G: Fn(&[f64]) -> Vec<f64>,
+{
+    assert_eq!(
+        x.len(),
+        shape.iter().product::<usize>(),
+        "input of len {} doesn't fit shape {:?}",
+        x.len(),
+        shape
+    );
+    let (eps, atol, rtol) = (
+        eps.unwrap_or(1e-6),
+        atol.unwrap_or(1e-5),
+        rtol.unwrap_or(1e-3),
+    );
+    let analytic = grad_fn(x);
+    assert_eq!(
+        analytic.len(),
+        x.len(),
+        "claimed gradient has the wrong number of elements"
+    );
+    let numeric = numerical_grad(f, x, eps);
+
+    let mut worst = 0;
+    let mut worst_excess = f64::NEG_INFINITY;
+    let mut max_abs_err = 0.0f64;
+    for (i, (a, n)) in analytic.iter().zip(numeric.iter()).enumerate() {
+        let err = (a - n).abs();
+        max_abs_err = max_abs_err.max(err);
+        // NaN never compares as within tolerance
+        let excess = if err.is_nan() {
+            f64::INFINITY
+        } else {
+            err - (atol + rtol * n.abs())
+        };
+        if excess > worst_excess {
+            worst_excess = excess;
+            worst = i;
+        }
+    }
+    let (a, n) = (
+        analytic.get(worst).copied().unwrap_or(0.0),
+        numeric.get(worst).copied().unwrap_or(0.0),
+    );
+    GradcheckReport {
+        passed: worst_excess <= 0.0,
+        worst_flat: worst,
+        worst_index: all_int_indices(shape).nth(worst).unwrap_or_default(),
+        analytic: a,
+        numeric: n,
+        abs_err: (a - n).abs(),
+        rel_err: (a - n).abs() / n.abs().max(f64::MIN_POSITIVE),
+        max_abs_err,
+    }
+}
+
+/// Gradcheck a tensor function with respect to each of its inputs, using `Tensor::backward` as the claimed gradient.
+/// Non-scalar outputs are reduced against fixed, non-uniform weights so no element's gradient can cancel out.
+pub fn gradcheck_tensor<F>(
+    f: F,
+    inputs: &[(Vec<f64>, Vec<usize>)],
+    eps: Option<f64>,
+    atol: Option<f64>,
+    rtol: Option<f64>,
+) -> Vec<GradcheckReport>
+where
+    F: Fn(&[Tensor]) -> Tensor,
+{
+    let scalar = |ts: &[Tensor]| {
+        let out = f(ts);
+        let w: Vec<f64> = (0..out.numel())
+            .map(|i| 1.0 + 0.1 * (i % 7) as f64)
+            .collect();
+        out.mul(&Tensor::new(w, &out.shape())).sum(None, false)
+    };
+    (0..inputs.len())
+        .map(|k| {
+            let build = |x: &[f64], requires_grad: bool| -> Vec<Tensor> {
+                inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (d, s))| {
+                        let data = if i == k { x.to_vec() } else { d.clone() };
+                        Tensor::new(data, s).requires_grad_(requires_grad && i == k)
+                    })
+                    .collect()
+            };
+            gradcheck(
+                |x| Tensor::no_grad(|| scalar(&build(x, false)).item()),
+                |x| {
+                    let ts = build(x, true);
+                    scalar(&ts).backward();
+                    ts[k]
+                        .grad()
+                        .map_or_else(|| vec![0.0; x.len()], |g| g.to_vec())
+                },
+                &inputs[k].0,
+                &inputs[k].1,
+                eps,
+                atol,
+                rtol,
+            )
+        })
+        .collect()
+}
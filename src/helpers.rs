@@ -139,7 +139,7 @@ where
 {
     match color {
         Some(c) => format!(
-            "\u{001b}[{}]m{}\u{001b}[0m",
+            "\u{001b}[{}m{}\u{001b}[0m",
             10 * {
                 if background.unwrap_or(false) {
                     1
@@ -565,7 +565,6 @@ impl GlobalCounters {
         self.global_mem = 0;
         self.time_sum_s = 0.0;
         self.kernel_count = 0;
-        self.mem_used = 0;
     }
 }
 
@@ -1001,7 +1000,11 @@ lazy_static! {
             Some(path.to_str().unwrap().to_owned())
         }
     });
-    pub static ref CACHEDB: String = getenv("CACHEDB".to_string(), {
+    // tests get a db of their own in the temp dir, so they don't read or leave rows in the real
+    // cache unless CACHEDB points them at it
+    pub static ref CACHEDB: String = getenv("CACHEDB".to_string(), if cfg!(test) {
+        Some(temp(&format!("rustgrad-test{}cache.db", std::path::MAIN_SEPARATOR)))
+    } else {
         let mut path = PathBuf::from(_CACHE_DIR.clone());
 
         path.push("rustgrad");
@@ -1067,9 +1070,9 @@ pub fn diskcache_clear() -> Result<(), anyhow::Error> {
     Ok(())
 }
 
-pub fn diskcache_get<'a, T>(table: &str, key: Box<dyn Any>) -> Option<T>
+pub fn diskcache_get<T>(table: &str, key: Box<dyn Any>) -> Option<T>
 where
-    T: serde::Deserialize<'a>,
+    T: serde::de::DeserializeOwned,
 {
     if CACHELEVEL.clone() == 0 {
         return None;
@@ -1139,26 +1142,20 @@ where
             .map(|(_, v)| v),
     );
 
-    // Execute the query
+    // Execute the query, a missing row is a cache miss
     let mut rows = stmt.query(query_params).ok()?;
+    let val: Vec<u8> = rows.next().ok()??.get(0).ok()?;
 
-    let mut buffer = Cursor::new(Vec::new());
-    // Iterate over the results
-    while let Some(row) = rows.next().expect("Failed to fetch row") {
-        let val: String = row.get(0).expect("Failed to get column");
-        serde_pickle::to_writer(&mut buffer, &val, SerOptions::default()).unwrap();
-    }
-
-    Some(serde_pickle::from_reader(buffer, DeOptions::default()).unwrap())
+    serde_pickle::from_slice(&val, DeOptions::default()).ok()
 }
 
 lazy_static! {
     static ref DB_TABLES: Arc<Mutex<HashSet<String>>> = Arc::new(Mutex::new(HashSet::new()));
 }
 
-pub fn diskcache_put<'a, T>(table: &str, key: Box<dyn Any>, val: T) -> Result<T, anyhow::Error>
+pub fn diskcache_put<T>(table: &str, key: Box<dyn Any>, val: T) -> Result<T, anyhow::Error>
 where
-    T: serde::Deserialize<'a> + Serialize,
+    T: Serialize,
 {
     if CACHELEVEL.clone() == 0 {
         return Ok(val);
@@ -1198,11 +1195,12 @@ where
         key_type = Some("str".to_string());
     } else if let Some(hash_map) = key.downcast_ref::<HashMap<String, String>>() {
         key_downcasted = Some(hash_map.clone());
+        key_type = Some("str".to_string());
     }
     let bind = db_connection();
     let conn_ = bind.lock().unwrap();
     let conn = conn_.as_ref().expect("");
-    if DB_TABLES.clone().lock().unwrap().contains(table) {
+    if !DB_TABLES.clone().lock().unwrap().contains(table) {
         let types = {
             let mut hm = HashMap::new();
             hm.insert("str".to_owned(), "text".to_owned());
@@ -1235,12 +1233,8 @@ where
                 .join(", ")
         );
 
-        let query_params = params![];
-
-        let mut stmt = conn.prepare(&put_query).expect("failed to prepare query");
-
-        // Execute the query
-        let _rows = stmt.query(query_params).expect("Failed to execute query");
+        conn.execute(&put_query, params![])
+            .map_err(|e| anyhow!(format!("failed to create table {}", e)))?;
         DB_TABLES.lock().unwrap().insert(table.to_string());
     }
     let put_query = format!(
@@ -1256,27 +1250,20 @@ where
             .join(", "),
         vec!["?"; key_downcasted.as_ref().expect("").keys().len()].join(", ")
     );
-    //let mut buffer = Cursor::new(Vec::new());
-    let v = serde_pickle::to_value(&val).unwrap();
-    let it_v = v.to_string();
-    //let by = buffer.into_inner();
+    // the value is pickled into the blob column, not its text repr
+    let it_v = serde_pickle::to_vec(&val, SerOptions::default())?;
     // Create query parameters by chaining the values of `key_downcasted` with the serialized value
     let query_params = params_from_iter(
         key_downcasted
             .as_ref()
             .expect("")
             .values()
-            .chain(std::iter::once(&it_v)),
+            .map(|v| rusqlite::types::Value::Text(v.clone()))
+            .chain(std::iter::once(rusqlite::types::Value::Blob(it_v))),
     );
 
-    let mut stmt = conn
-        .prepare(&put_query)
-        .map_err(|e| anyhow!(format!("failed to prepare query {}", e)))?;
-
-    // Execute the query
-    let _ = stmt
-        .query(query_params)
-        .map_err(|e| anyhow!(format!("failed to prepare query {}", e)))?;
+    conn.execute(&put_query, query_params)
+        .map_err(|e| anyhow!(format!("failed to put into {} {}", table, e)))?;
     return Ok(val);
 }
 
@@ -0,0 +1,458 @@
+// python style `t[...]` on tensors. Ints, slices and new axes resolve to shrink/stride/reshape, so
+// they stay views of the same buffer; tensor indices go through a one-hot gather
+use std::ops::{Range, RangeFrom, RangeFull, RangeTo};
+
+use crate::tensor::Tensor;
+
+#[derive(Debug, Clone)]
+pub enum Index {
+    /// a single element, negative counts from the end. The axis is dropped
+    Int(isize),
+    /// `start:stop:step` with python semantics, `None` meaning the default for the step's sign
+    Slice {
+        start: Option<isize>,
+        stop: Option<isize>,
+        step: Option<isize>,
+    },
+    /// `None` in python, inserts a size-1 axis
+    NewAxis,
+    /// `...`, as many full slices as needed
+    Ellipsis,
+    /// fancy indexing with integer valued tensors
+    Tensor(Tensor),
+}
+
+impl Index {
+    pub fn slice(start: Option<isize>, stop: Option<isize>, step: Option<isize>) -> Self {
+        Index::Slice { start, stop, step }
+    }
+
+    pub fn full() -> Self {
+        Index::slice(None, None, None)
+    }
+
+    fn consumes_dim(&self) -> bool {
+        !matches!(self, Index::NewAxis | Index::Ellipsis)
+    }
+}
+
+impl From<isize> for Index {
+    fn from(i: isize) -> Self {
+        Index::Int(i)
+    }
+}
+
+impl From<Range<isize>> for Index {
+    fn from(r: Range<isize>) -> Self {
+        Index::slice(Some(r.start), Some(r.end), None)
+    }
+}
+
+impl From<RangeFrom<isize>> for Index {
+    fn from(r: RangeFrom<isize>) -> Self {
+        Index::slice(Some(r.start), None, None)
+    }
+}
+
+impl From<RangeTo<isize>> for Index {
+    fn from(r: RangeTo<isize>) -> Self {
+        Index::slice(None, Some(r.end), None)
+    }
+}
+
+impl From<RangeFull> for Index {
+    fn from(_: RangeFull) -> Self {
+        Index::full()
+    }
+}
+
+impl From<Tensor> for Index {
+    fn from(t: Tensor) -> Self {
+        Index::Tensor(t)
+    }
+}
+
+impl From<&Tensor> for Index {
+    fn from(t: &Tensor) -> Self {
+        Index::Tensor(t.clone())
+    }
+}
+
+/// Python's `slice.indices` plus the length: `(start, step, len)` with start in bounds whenever `len > 0`.
+pub fn normalize_slice(
+    start: Option<isize>,
+    stop: Option<isize>,
+    step: Option<isize>,
+    n: usize,
+) -> (isize, isize, usize) {
+    let n = n as isize;
+    let step = step.unwrap_or(1);
+    assert!(step != 0, "slice step can't be zero");
+    let fix = |v: isize, lo: isize, hi: isize| (if v < 0 { v + n } else { v }).clamp(lo, hi);
+    let (start, len) = if step > 0 {
+        let start = start.map_or(0, |s| fix(s, 0, n));
+        let stop = stop.map_or(n, |s| fix(s, 0, n));
+        (
+            start,
+            ((stop - start).max(0) as usize).div_ceil(step as usize),
+        )
+    } else {
+        let start = start.map_or(n - 1, |s| fix(s, -1, n - 1));
+        let stop = stop.map_or(-1, |s| fix(s, -1, n - 1));
+        (
+            start,
+            ((start - stop).max(0) as usize).div_ceil(step.unsigned_abs()),
+        )
+    };
+    (start, step, len)
+}
+
+/// The movement ops an index spec turns into.
+#[derive(Debug, Clone)]
+pub struct ResolvedIndex {
+    /// per input axis
+    pub shrink: Vec<(usize, usize)>,
+    /// per input axis, applied after the shrink
+    pub stride: Vec<isize>,
+    /// the view's shape after dropping int axes and adding new ones
+    pub shape: Vec<usize>,
+    /// tensor indices and the axis of `shape` they select from
+    pub fancy: Vec<(usize, Tensor)>,
+}
+
+pub fn resolve(shape: &[usize], indices: &[Index]) -> ResolvedIndex {
+    let ellipses = indices
+        .iter()
+        .filter(|i| matches!(i, Index::Ellipsis))
+        .count();
+    assert!(ellipses <= 1, "an index can only have a single ellipsis");
+    let used = indices.iter().filter(|i| i.consumes_dim()).count();
+    assert!(
+        used <= shape.len(),
+        "too many indices for tensor of dimension {}",
+        shape.len()
+    );
+    let mut full: Vec<Index> = vec![];
+    for idx in indices {
+        match idx {
+            Index::Ellipsis => full.extend((0..shape.len() - used).map(|_| Index::full())),
+            i => full.push(i.clone()),
+        }
+    }
+    if ellipses == 0 {
+        full.extend((used..shape.len()).map(|_| Index::full()));
+    }
+
+    let mut ret = ResolvedIndex {
+        shrink: vec![],
+        stride: vec![],
+        shape: vec![],
+        fancy: vec![],
+    };
+    let mut dim = 0;
+    for idx in full {
+        if let Index::NewAxis = idx {
+            ret.shape.push(1);
+            continue;
+        }
+        let n = shape[dim];
+        dim += 1;
+        let (start, step, len) = match &idx {
+            Index::Int(i) => {
+                let j = if *i < 0 { i + n as isize } else { *i };
+                assert!(
+                    0 <= j && j < n as isize,
+                    "index {} is out of bounds for axis {} with size {}",
+                    i,
+                    dim - 1,
+                    n
+                );
+                (j, 1, 1)
+            }
+            Index::Slice { start, stop, step } => normalize_slice(*start, *stop, *step, n),
+            _ => (0, 1, n),
+        };
+        if len == 0 {
+            ret.shrink.push((0, 0));
+            ret.stride.push(1);
+        } else {
+            let last = start + (len as isize - 1) * step;
+            let (b, e) = (start.min(last), start.max(last) + 1);
+            ret.shrink.push((b as usize, e as usize));
+            ret.stride.push(step);
+        }
+        match idx {
+            Index::Int(_) => {}
+            Index::Tensor(t) => {
+                ret.fancy.push((ret.shape.len(), t));
+                ret.shape.push(len);
+            }
+            _ => ret.shape.push(len),
+        }
+    }
+    ret
+}
+
+/// `(idx[..., None] == arange(n))` after checking every index is an in-bounds integer.
+/// Negative indices count from the end.
+fn one_hot(idx: &Tensor, n: usize) -> Tensor {
+    let data: Vec<f64> = idx
+        .to_vec()
+        .into_iter()
+        .map(|v| {
+            assert!(
+                v.fract() == 0.0 && -(n as f64) <= v && v < n as f64,
+                "index {} is out of bounds or not an integer for size {}",
+                v,
+                n
+            );
+            if v < 0.0 {
+                v + n as f64
+            } else {
+                v
+            }
+        })
+        .collect();
+    let shape = idx.shape();
+    let idx = Tensor::new(data, &shape)
+        .to(&idx.device())
+        .reshape(&[shape.clone(), vec![1]].concat());
+    let range = Tensor::arange(n)
+        .to(&idx.device())
+        .reshape(&[vec![1; shape.len()], vec![n]].concat());
+    idx.eq(&range)
+}
+
+fn broadcast_to(value: &Tensor, shape: &[usize]) -> Tensor {
+    let (_, value) = Tensor::zeros(shape).to(&value.device()).broadcasted(value);
+    assert_eq!(
+        value.shape(),
+        shape,
+        "can't assign shape {:?} into an index of shape {:?}",
+        value.shape(),
+        shape
+    );
+    value
+}
+
+/// Undo the shrink and stride `getitem` takes of a tensor of `shape`: `t`, shaped like that view,
+/// spread back out to the elements it was read from with zeros everywhere else.
+fn unshrink(t: &Tensor, r: &ResolvedIndex, shape: &[usize]) -> Tensor {
+    let steps: Vec<usize> = r.stride.iter().map(|s| s.unsigned_abs()).collect();
+    let lens: Vec<usize> = r
+        .shrink
+        .iter()
+        .zip(&steps)
+        .map(|((b, e), s)| (e - b).div_ceil(*s))
+        .collect();
+    let mut t = t.reshape(&lens);
+    let flip: Vec<usize> = (0..lens.len()).filter(|a| r.stride[*a] < 0).collect();
+    if !flip.is_empty() {
+        t = t.flip(&flip);
+    }
+    if steps.iter().any(|s| *s > 1) {
+        // every element followed by step - 1 zeros, cut to the shrunk size
+        let split: Vec<usize> = lens.iter().flat_map(|l| [*l, 1]).collect();
+        let pad: Vec<(usize, usize)> = steps.iter().flat_map(|s| [(0, 0), (0, s - 1)]).collect();
+        let spread: Vec<usize> = lens.iter().zip(&steps).map(|(l, s)| l * s).collect();
+        let cut: Vec<(usize, usize)> = r.shrink.iter().map(|(b, e)| (0, e - b)).collect();
+        t = t.reshape(&split).pad(&pad).reshape(&spread).shrink(&cut);
+    }
+    let pad: Vec<(usize, usize)> = r
+        .shrink
+        .iter()
+        .zip(shape)
+        .map(|((b, e), n)| (*b, n - e))
+        .collect();
+    t.pad(&pad)
+}
+
+/// `(mask, value)` over the view `getitem` gathers from, for a `value` shaped like its result: the
+/// inverse of the one-hot gather. Entries of the index tensors are written in order, so the last
+/// of repeated ones wins.
+fn scatter_fancy(r: &ResolvedIndex, value: &Tensor) -> (Tensor, Tensor) {
+    let mut b = r.fancy[0].1.clone();
+    for (_, t) in &r.fancy[1..] {
+        b = b.broadcasted(t).0;
+    }
+    let bshape = b.shape();
+    let k = r.fancy.len();
+    let axes: Vec<usize> = r.fancy.iter().map(|(a, _)| *a).collect();
+    let others: Vec<usize> = (0..r.shape.len()).filter(|a| !axes.contains(a)).collect();
+    let ns: Vec<usize> = axes.iter().map(|a| r.shape[*a]).collect();
+    let os: Vec<usize> = others.iter().map(|a| r.shape[*a]).collect();
+
+    // the value as O + B, undoing where getitem puts the B axes
+    let nb = bshape.len();
+    let (out_shape, order): (Vec<usize>, Vec<usize>) =
+        if axes.windows(2).all(|w| w[1] == w[0] + 1) && axes[0] > 0 {
+            let p = axes[0];
+            (
+                [&os[..p], &bshape[..], &os[p..]].concat(),
+                (0..p)
+                    .chain(p + nb..os.len() + nb)
+                    .chain(p..p + nb)
+                    .collect(),
+            )
+        } else {
+            (
+                [&bshape[..], &os[..]].concat(),
+                (nb..nb + os.len()).chain(0..nb).collect(),
+            )
+        };
+    let value = broadcast_to(value, &out_shape).permute(&order);
+    let count: usize = bshape.iter().product();
+    let value = value.reshape(&[&os[..], &[count]].concat());
+
+    // one mask of N per entry of B, as in getitem
+    let mut mask: Option<Tensor> = None;
+    for (j, (_, t)) in r.fancy.iter().enumerate() {
+        let t = t.broadcasted(&b).0;
+        let oh_shape = [bshape.clone(), vec![1; j], vec![ns[j]], vec![1; k - j - 1]].concat();
+        let oh = one_hot(&t, ns[j]).reshape(&oh_shape);
+        mask = Some(match mask {
+            Some(m) => m.mul(&oh),
+            None => oh,
+        });
+    }
+    let mask = mask.unwrap().reshape(&[&[count], &ns[..]].concat());
+
+    // O + N, one entry at a time
+    let full = [&os[..], &ns[..]].concat();
+    let zeros = Tensor::zeros(&full).to(&value.device());
+    let (one, mut m_acc, mut v_acc) = (
+        Tensor::scalar(1.0).to(&value.device()),
+        zeros.clone(),
+        zeros,
+    );
+    let ones = [vec![1; os.len()], ns.clone()].concat();
+    for i in 0..count {
+        let m = mask.getitem(&[Index::Int(i as isize)]).reshape(&ones);
+        let v = value
+            .getitem(&[Index::Ellipsis, Index::Int(i as isize)])
+            .reshape(&[&os[..], &vec![1; k][..]].concat());
+        v_acc = m.where_(&v, &v_acc);
+        m_acc = m.where_(&one, &m_acc);
+    }
+    // back to the axis order of the view
+    let src: Vec<usize> = [others, axes].concat();
+    let inverse: Vec<usize> = (0..src.len())
+        .map(|a| src.iter().position(|s| *s == a).unwrap())
+        .collect();
+    (m_acc.permute(&inverse), v_acc.permute(&inverse))
+}
+
+impl Tensor {
+    pub fn getitem(&self, indices: &[Index]) -> Tensor {
+        let r = resolve(&self.shape(), indices);
+        let x = self.shrink(&r.shrink).stride(&r.stride).reshape(&r.shape);
+        if r.fancy.is_empty() {
+            return x;
+        }
+
+        // broadcast the index tensors together into B
+        let mut b = r.fancy[0].1.clone();
+        for (_, t) in &r.fancy[1..] {
+            b = b.broadcasted(t).0;
+        }
+        let bshape = b.shape();
+        let k = r.fancy.len();
+        let axes: Vec<usize> = r.fancy.iter().map(|(a, _)| *a).collect();
+        let others: Vec<usize> = (0..x.ndim()).filter(|a| !axes.contains(a)).collect();
+
+        // x: O + [1]*|B| + N, mask: B + N, summed over N
+        let xs = x.shape();
+        let ns: Vec<usize> = axes.iter().map(|a| xs[*a]).collect();
+        let os: Vec<usize> = others.iter().map(|a| xs[*a]).collect();
+        let x = x
+            .permute(&[others.clone(), axes.clone()].concat())
+            .reshape(&[os.clone(), vec![1; bshape.len()], ns.clone()].concat());
+        let mut mask: Option<Tensor> = None;
+        for (j, (_, t)) in r.fancy.iter().enumerate() {
+            let t = t.broadcasted(&b).0;
+            let oh_shape = [bshape.clone(), vec![1; j], vec![ns[j]], vec![1; k - j - 1]].concat();
+            let oh = one_hot(&t, ns[j]).reshape(&oh_shape);
+            mask = Some(match mask {
+                Some(m) => m.mul(&oh),
+                None => oh,
+            });
+        }
+        let sum_axes: Vec<isize> = (1..=k as isize).map(|a| -a).collect();
+        let out = mask
+            .unwrap()
+            .where_(&x, &Tensor::scalar(0.0).to(&x.device()))
+            .sum(Some(&sum_axes), false);
+
+        // like numpy, adjacent tensor indices keep their place, anything else goes to the front
+        if axes.windows(2).all(|w| w[1] == w[0] + 1) && axes[0] > 0 {
+            let (nb, p) = (bshape.len(), axes[0]);
+            let order: Vec<usize> = (0..p)
+                .chain(os.len()..os.len() + nb)
+                .chain(p..os.len())
+                .collect();
+            return out.permute(&order);
+        }
+        let order: Vec<usize> = (os.len()..os.len() + bshape.len())
+            .chain(0..os.len())
+            .collect();
+        out.permute(&order)
+    }
+
+    /// `self[indices] = value`, with `value` broadcast to the indexed shape. Not recorded on the tape.
+    /// The write goes into the storage under `self`, so views of it see it too, unless `self` is
+    /// expanded or padded and gets new storage. With repeated tensor indices the last value wins.
+    pub fn setitem(&self, indices: &[Index], value: &Tensor) {
+        assert!(
+            !self.requires_grad(),
+            "setitem can't write into a tensor that requires grad"
+        );
+        let shape = self.shape();
+        let r = resolve(&shape, indices);
+        if r.shape.contains(&0) {
+            return;
+        }
+        let new = Tensor::no_grad(|| {
+            let value = value.to(&self.device());
+            // the value and a mask of where it goes, over the view `getitem` takes before gathering
+            let (mask, value) = if r.fancy.is_empty() {
+                let ones = Tensor::ones(&r.shape).to(&value.device());
+                (ones, broadcast_to(&value, &r.shape))
+            } else {
+                scatter_fancy(&r, &value)
+            };
+            // then back through the stride and shrink to the full shape
+            let mask = unshrink(&mask, &r, &shape);
+            mask.where_(&unshrink(&value, &r, &shape), self)
+        });
+        let lb = self.lazydata();
+        if lb.is_writable() {
+            lb.try_store(&new.lazydata())
+                .unwrap_or_else(|e| panic!("{}", e));
+        } else {
+            self.assign(&new);
+        }
+    }
+
+    /// `out[i][j][k] = self[idx[i][j][k]][j][k]` for `dim = 0`, and likewise for the other dims.
+    pub fn gather(&self, idx: &Tensor, dim: isize) -> Tensor {
+        let n = self.ndim() as isize;
+        let d = if dim < 0 { dim + n } else { dim } as usize;
+        let (xs, is) = (self.shape(), idx.shape());
+        assert_eq!(xs.len(), is.len(), "gather index must have the same ndim");
+        assert!(
+            (0..xs.len()).all(|i| i == d || is[i] <= xs[i]),
+            "gather index {:?} doesn't fit {:?} off dim {}",
+            is,
+            xs,
+            d
+        );
+        // self -> I with 1 at d, plus the gathered axis at the end
+        let arg: Vec<(usize, usize)> = (0..xs.len())
+            .map(|i| if i == d { (0, xs[i]) } else { (0, is[i]) })
+            .collect();
+        let x = self.shrink(&arg).unsqueeze(-1).transpose(d as isize, -1);
+        one_hot(idx, xs[d])
+            .where_(&x, &Tensor::scalar(0.0).to(&x.device()))
+            .sum(Some(&[-1]), false)
+    }
+}
@@ -0,0 +1,376 @@
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    device::{canonicalize, default_device, Buffer},
+    dtype::DType,
+    ops::{LazyOp, Op, ReduceOps},
+    realize::{capture_var, exec_ast},
+    shape::{symbolic::Variable, view::View},
+};
+
+pub type Storage = Arc<RwLock<Buffer>>;
+
+/// The array type `Function`s operate on: a `View` over a shared float64 `Buffer` on `device`.
+/// Movement ops only touch the view; elementwise and reduce ops run right away as one kernel each,
+/// into a fresh buffer on the same device.
+#[derive(Clone, Debug)]
+pub struct LazyBuffer {
+    pub st: View,
+    pub base: Storage,
+    pub device: String,
+}
+
+/// Kernel loads for `srcs`. Each distinct storage is one buffer, numbered from 1 since 0 is the output.
+/// Images are read as float32 texels and cast up.
+fn load_srcs(srcs: &[&LazyBuffer]) -> (Vec<LazyOp>, Vec<Storage>) {
+    let mut bufs: Vec<Storage> = vec![];
+    let loads = srcs
+        .iter()
+        .map(|s| {
+            let idx = match bufs.iter().position(|b| Arc::ptr_eq(b, &s.base)) {
+                Some(i) => i,
+                None => {
+                    bufs.push(s.base.clone());
+                    bufs.len() - 1
+                }
+            };
+            load(idx + 1, s)
+        })
+        .collect();
+    (loads, bufs)
+}
+
+fn load(idx: usize, src: &LazyBuffer) -> LazyOp {
+    let dtype = src.dtype();
+    let ld = LazyOp::load(idx, dtype, src.st.clone());
+    if dtype == DType::FLOAT64 {
+        ld
+    } else {
+        LazyOp::cast(ld, DType::FLOAT64)
+    }
+}
+
+/// A fresh buffer of `size` elements of `dtype` on `device`.
+fn alloc(device: &str, size: usize, dtype: DType) -> Storage {
+    let buf = Buffer::new(device, size, dtype).unwrap_or_else(|e| panic!("{}", e));
+    Arc::new(RwLock::new(buf))
+}
+
+fn to_bytes(data: &[f64]) -> Vec<u8> {
+    data.iter().flat_map(|x| x.to_le_bytes()).collect()
+}
+
+/// float64s, or the float32s of an image
+fn from_bytes(bytes: &[u8], dtype: DType) -> Vec<f64> {
+    if dtype.image.is_some() {
+        return bytes
+            .chunks_exact(4)
+            .map(|c| f32::from_le_bytes(c.try_into().unwrap()) as f64)
+            .collect();
+    }
+    bytes
+        .chunks_exact(8)
+        .map(|c| f64::from_le_bytes(c.try_into().unwrap()))
+        .collect()
+}
+
+pub fn all_int_indices(shape: &[usize]) -> impl Iterator<Item = Vec<usize>> + '_ {
+    let size: usize = shape.iter().product();
+    (0..size).map(move |mut i| {
+        let mut idx = vec![0; shape.len()];
+        for (d, s) in shape.iter().enumerate().rev() {
+            idx[d] = i % s;
+            i /= s;
+        }
+        idx
+    })
+}
+
+impl LazyBuffer {
+    pub(crate) fn from_storage(st: View, base: Storage) -> Self {
+        let device = base.read().unwrap().device.clone();
+        Self { st, base, device }
+    }
+
+    fn from_vec_on(data: &[f64], shape: &[usize], device: &str) -> Self {
+        assert_eq!(
+            data.len(),
+            shape.iter().product::<usize>(),
+            "data of len {} doesn't fit shape {:?}",
+            data.len(),
+            shape
+        );
+        let base = alloc(device, data.len(), DType::FLOAT64);
+        base.write().unwrap().copyin(&to_bytes(data)).unwrap();
+        Self::from_storage(View::create(shape, None, 0, None), base)
+    }
+
+    pub fn from_vec(data: Vec<f64>, shape: &[usize]) -> Self {
+        Self::from_vec_on(&data, shape, &default_device())
+    }
+
+    /// A constant is a single element expanded with zero strides, so it costs nothing to broadcast.
+    pub fn full(shape: &[usize], val: f64) -> Self {
+        Self::from_vec(vec![val], &[]).expand_from_scalar(shape)
+    }
+
+    pub fn const_like(&self, val: f64) -> Self {
+        Self::from_vec_on(&[val], &[], &self.device).expand_from_scalar(self.shape())
+    }
+
+    fn expand_from_scalar(&self, shape: &[usize]) -> Self {
+        let st = View::create(shape, Some(&vec![0; shape.len()]), 0, None);
+        Self::from_storage(st, self.base.clone())
+    }
+
+    /// The `shape` elements stored at the top of the file behind a `DISK:<path>` device.
+    pub fn from_disk(device: &str, shape: &[usize]) -> Result<Self, anyhow::Error> {
+        let base = Buffer::new(device, shape.iter().product(), DType::FLOAT64)?;
+        Ok(Self::from_storage(
+            View::create(shape, None, 0, None),
+            Arc::new(RwLock::new(base)),
+        ))
+    }
+
+    /// A contiguous copy on `device`, going through both devices' allocators. Copying to `DISK`
+    /// writes the file; copying from it reads the file back.
+    pub fn to_device(&self, device: &str) -> Result<Self, anyhow::Error> {
+        let device = canonicalize(device);
+        if device == self.device {
+            return Ok(self.clone());
+        }
+        let base = self.base.read().unwrap();
+        if self.st.contiguous && base.size == self.st.size() {
+            let copy = base.copy_to(&device)?;
+            return Ok(Self::from_storage(
+                self.st.clone(),
+                Arc::new(RwLock::new(copy)),
+            ));
+        }
+        drop(base);
+        Ok(Self::from_vec_on(&self.to_vec(), self.shape(), &device))
+    }
+
+    pub fn shape(&self) -> &[usize] {
+        &self.st.shape
+    }
+
+    /// The dtype of the storage: float64, or an image.
+    pub fn dtype(&self) -> DType {
+        self.base.read().unwrap().dtype
+    }
+
+    /// Everything in the underlying buffer, in storage order.
+    fn read_base(&self) -> Vec<f64> {
+        let base = self.base.read().unwrap();
+        let mut bytes = vec![0; base.nbytes()];
+        base.copyout(&mut bytes).unwrap();
+        from_bytes(&bytes, base.dtype)
+    }
+
+    pub fn get(&self, idx: &[usize]) -> f64 {
+        match self.st.index_of(idx) {
+            Some(i) => self.read_base()[i as usize],
+            None => 0.0,
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<f64> {
+        let base = self.read_base();
+        if self.st.contiguous && base.len() == self.st.size() {
+            return base;
+        }
+        all_int_indices(self.shape())
+            .map(|idx| self.st.index_of(&idx).map_or(0.0, |i| base[i as usize]))
+            .collect()
+    }
+
+    /// A bound `Variable` is a scalar whose storage a `TinyJit` replay rewrites on every call.
+    pub fn bind(var: &Variable, val: isize) -> Self {
+        assert!(
+            var.min <= val && val <= var.max,
+            "{} = {} is outside [{}, {}]",
+            var.expr,
+            val,
+            var.min,
+            var.max
+        );
+        let ret = Self::from_vec(vec![val as f64], &[]);
+        capture_var(&var.expr, &ret.base);
+        ret
+    }
+
+    /// Run `src` over `srcs` into fresh contiguous storage of `shape` and `dtype`.
+    fn exec(
+        src: impl FnOnce(Vec<LazyOp>) -> LazyOp,
+        srcs: &[&LazyBuffer],
+        shape: &[usize],
+        dtype: DType,
+    ) -> Result<Self, anyhow::Error> {
+        let device = &srcs[0].device;
+        for s in srcs {
+            assert_eq!(
+                &s.device, device,
+                "srcs on {} and {}, copy one over first",
+                s.device, device
+            );
+        }
+        let (loads, bufs) = load_srcs(srcs);
+        // the kernel writes every element, so the buffer needn't be cleared
+        let out = Self::from_storage(
+            View::create(shape, None, 0, None),
+            alloc(device, shape.iter().product(), dtype),
+        );
+        let ast = LazyOp::store(src(loads), 0, dtype, out.st.clone());
+        exec_ast(
+            ast,
+            std::iter::once(out.base.clone()).chain(bufs).collect(),
+            device,
+        )?;
+        Ok(out)
+    }
+
+    pub fn contiguous(&self) -> Self {
+        self.try_contiguous().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// `contiguous`, handing back the error when the kernel fails to lower or compile.
+    pub fn try_contiguous(&self) -> Result<Self, anyhow::Error> {
+        Self::exec(|mut l| l.remove(0), &[self], self.shape(), DType::FLOAT64)
+    }
+
+    /// A copy stored as a `height` x `width` image, the elements in row major order, four to a
+    /// texel. Kernels reading it cast back up to float64.
+    pub fn cast_image(&self, height: usize, width: usize) -> LazyBuffer {
+        assert_eq!(
+            height * width * 4,
+            self.st.size(),
+            "{:?} doesn't fill a {}x{} image",
+            self.shape(),
+            height,
+            width
+        );
+        // texels on an axis of their own, so the kernel writes them whole
+        let texels = self.reshape(&[height, width, 4]);
+        Self::exec(
+            |mut l| l.remove(0),
+            &[&texels],
+            &[height, width, 4],
+            DType::imagef(height, width),
+        )
+        .unwrap_or_else(|e| panic!("{}", e))
+        .reshape(self.shape())
+    }
+
+    /// Whether `store` can write through this view, which it can when the view reaches every
+    /// element at most once: no mask and nothing expanded.
+    pub fn is_writable(&self) -> bool {
+        self.st.mask.is_none()
+            && self
+                .st
+                .shape
+                .iter()
+                .zip(&self.st.strides)
+                .all(|(n, st)| *n == 1 || *st != 0)
+    }
+
+    /// Write `src` through this view into the storage under it, so every other view of the storage
+    /// sees it.
+    pub fn try_store(&self, src: &LazyBuffer) -> Result<(), anyhow::Error> {
+        assert!(self.is_writable(), "can't store through {:?}", self.st);
+        assert_eq!(self.shape(), src.shape(), "store shape mismatch");
+        assert_eq!(
+            src.device, self.device,
+            "src on {}, copy it to {} first",
+            src.device, self.device
+        );
+        // a kernel can't lock one storage twice
+        let src = if Arc::ptr_eq(&src.base, &self.base) {
+            src.try_contiguous()?
+        } else {
+            src.clone()
+        };
+        let ast = LazyOp::store(load(1, &src), 0, self.dtype(), self.st.clone());
+        exec_ast(ast, vec![self.base.clone(), src.base], &self.device)
+    }
+
+    pub fn e(&self, op: impl Into<Op>, srcs: &[&LazyBuffer]) -> LazyBuffer {
+        self.try_e(op, srcs).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// `e`, handing back the error when the kernel fails to lower or compile.
+    pub fn try_e(
+        &self,
+        op: impl Into<Op>,
+        srcs: &[&LazyBuffer],
+    ) -> Result<LazyBuffer, anyhow::Error> {
+        let op = op.into();
+        for s in srcs {
+            assert_eq!(
+                s.shape(),
+                self.shape(),
+                "all srcs of {:?} must have the same shape",
+                op
+            );
+        }
+        let all: Vec<&LazyBuffer> = std::iter::once(self).chain(srcs.iter().copied()).collect();
+        Self::exec(
+            |l| LazyOp::new(op, l, None),
+            &all,
+            self.shape(),
+            DType::FLOAT64,
+        )
+    }
+
+    /// Reduce over `axis`, keeping the reduced dims as 1.
+    pub fn r(&self, op: ReduceOps, axis: &[usize]) -> LazyBuffer {
+        self.try_r(op, axis).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// `r`, handing back the error when the kernel fails to lower or compile.
+    pub fn try_r(&self, op: ReduceOps, axis: &[usize]) -> Result<LazyBuffer, anyhow::Error> {
+        let new_shape: Vec<usize> = self
+            .shape()
+            .iter()
+            .enumerate()
+            .map(|(i, s)| if axis.contains(&i) { 1 } else { *s })
+            .collect();
+        Self::exec(
+            |mut l| LazyOp::reduce(op, l.remove(0), axis),
+            &[self],
+            &new_shape,
+            DType::FLOAT64,
+        )
+    }
+
+    fn with_view(&self, st: View) -> LazyBuffer {
+        Self::from_storage(st, self.base.clone())
+    }
+
+    pub fn reshape(&self, new_shape: &[usize]) -> LazyBuffer {
+        match self.st.reshape(new_shape) {
+            Some(st) => self.with_view(st),
+            None => self.contiguous().reshape(new_shape),
+        }
+    }
+
+    pub fn permute(&self, axis: &[usize]) -> LazyBuffer {
+        self.with_view(self.st.permute(axis))
+    }
+
+    pub fn expand(&self, new_shape: &[usize]) -> LazyBuffer {
+        self.with_view(self.st.expand(new_shape))
+    }
+
+    pub fn pad(&self, arg: &[(usize, usize)]) -> LazyBuffer {
+        self.with_view(self.st.pad(arg))
+    }
+
+    pub fn shrink(&self, arg: &[(usize, usize)]) -> LazyBuffer {
+        self.with_view(self.st.shrink(arg))
+    }
+
+    pub fn stride(&self, mul: &[isize]) -> LazyBuffer {
+        self.with_view(self.st.stride(mul))
+    }
+}
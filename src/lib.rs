@@ -1,23 +1,83 @@
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
+pub mod codegen;
+pub mod device;
+pub mod dtype;
+pub mod dual;
+pub mod features;
+pub mod function;
+pub mod gradcheck;
 pub mod helpers;
+pub mod index;
+pub mod lazy;
+pub mod multi;
+pub mod ops;
 pub mod prelude;
+pub mod realize;
+pub mod renderer;
+pub mod rewrite;
+pub mod rng;
+pub mod runtime;
 pub mod shape;
+pub mod tensor;
 mod tests {
-    use std::{collections::HashMap, hash::Hash, os::raw::c_void};
+    use std::{
+        collections::{HashMap, HashSet},
+        hash::Hash,
+        os::raw::c_void,
+        sync::Arc,
+    };
 
     use deep_flatten::DeepFlattenExt;
     use diskcache_proc_macro::diskcache;
     use get_shape::get_shape;
-    use helpers::{diskcache_get, fetch, from_mv, mv_address, parse_fetch_paths, to_mv};
+    use helpers::{diskcache_get, diskcache_put, fetch, from_mv, mv_address, parse_fetch_paths, to_mv};
     use memoize::memoize;
     use serde::{Deserialize, Serialize};
 
     use crate::{
-        argfix, create_new_context,
-        helpers::{analyze_samples, extract_callers, round_up},
+        argfix,
+        codegen::{
+            kernel::{Opt, OptOps},
+            linearizer::Linearizer,
+            uops::{flops, graph_rewrite_uops, UArg, UOp, UOps},
+        },
+        create_new_context,
+        device::{
+            canonicalize, default_device, free_cache, get_device, transfer, Allocator, Buffer, LRUAllocator,
+            MallocAllocator, Program, RawBuffer,
+        },
+        dtype::DType,
+        features::{
+            jit::TinyJit,
+            memory::{buffer_lifetimes, memory_planner, peak_memory},
+            process_replay::{diff_lines, record, recorded, replay, Recorded},
+            search::{beam_key, beam_search, bufs_from_lin, get_linearizer_actions},
+        },
+        dual::{hvp, jvp, Dual, Real},
+        gradcheck::{gradcheck, gradcheck_tensor},
+        helpers::{analyze_samples, ansilen, colored, extract_callers, round_up, GlobalCounter},
+        index::{normalize_slice, Index},
+        lazy::{all_int_indices, LazyBuffer, Storage},
+        multi::{naive_all_reduce, ring_all_reduce, MultiLazyBuffer},
         make_pair,
+        realize::{begin_capture, end_capture, get_runner, lowerer, lowerer_with, Runner},
+        renderer::cstyle::{launch_dims, uops_to_cstyle, CStyleLanguage},
+        rewrite::{graph_rewrite, Captures, Rewritable, PatternMatcher, RewriteFn, UPat, AST_SIMPLIFY, UOP_SIMPLIFY},
+        ops::{BinaryOps, LazyOp, Op, ReduceOps, TernaryOps, UnaryOps},
+        rng::{threefry2x32, Threefry},
+        runtime::{
+            ops_clang::{self, compile_uops, find_compiler, ClangCompiler, ClangProgram},
+            ops_llvm::{self, find_llvm, LLVMCompiler},
+            ops_interp::{self, exec_alu_typed, f16_to_f32, f32_to_f16, InterpProgram, Val},
+            pool::ThreadPool,
+        },
+        shape::{
+            symbolic::{Node, Variable},
+            view::View,
+        },
+        tensor::{is_grad_enabled, NoGrad, Tensor},
     };
     use init_c_struct_proc_macro::init_c_struct_t;
     #[test]
@@ -228,6 +288,38 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_diskcache_roundtrip() {
+        if *helpers::CACHELEVEL == 0 {
+            return;
+        }
+        let key = format!("roundtrip_{}", std::process::id());
+        assert_eq!(diskcache_get::<Vec<u8>>("test_roundtrip", Box::new(key.clone())), None);
+        diskcache_put("test_roundtrip", Box::new(key.clone()), vec![0u8, 1, 255]).unwrap();
+        assert_eq!(diskcache_get::<Vec<u8>>("test_roundtrip", Box::new(key.clone())), Some(vec![0u8, 1, 255]));
+        // REPLACE overwrites
+        diskcache_put("test_roundtrip", Box::new(key.clone()), vec![7u8]).unwrap();
+        assert_eq!(diskcache_get::<Vec<u8>>("test_roundtrip", Box::new(key)), Some(vec![7u8]));
+    }
+
+    #[test]
+    fn test_compile_cached() {
+        if *helpers::CACHELEVEL == 0 {
+            return;
+        }
+        let compiler = ClangCompiler::new();
+        let src = format!("void k_{}(void) {{}}", std::process::id());
+        assert_ne!(compiler.cache_key(&src), ClangCompiler { args: vec!["-O0".to_string()] }.cache_key(&src));
+        // a hit hands back the cached bytes without touching the compiler
+        diskcache_put("compile_clang", Box::new(compiler.cache_key(&src)), b"not an elf".to_vec()).unwrap();
+        assert_eq!(compiler.compile_cached(&src).unwrap(), b"not an elf".to_vec());
+        // LLVM's key covers the toolchain too, not just the IR
+        let ir = format!("; k_{}", std::process::id());
+        assert_ne!(LLVMCompiler.cache_key(&ir), format!("{:x}", md5::compute(&ir)));
+        diskcache_put("compile_llvm", Box::new(LLVMCompiler.cache_key(&ir)), b"not an elf".to_vec()).unwrap();
+        assert_eq!(LLVMCompiler.compile_cached(&ir).unwrap(), b"not an elf".to_vec());
+    }
+
     #[diskcache]
     fn expensive_function(arg1: u32, arg2: String) -> Result<MyType, Error> {
         // Perform some expensive computations
@@ -324,6 +416,1309 @@ mod tests {
         assert_eq!(&data[..], retrieved_data);
     }
 
+    #[test]
+    fn test_backward_sums_grads() {
+        // x reaches z along two paths, so dz/dx = y + 1
+        let x = Tensor::new(vec![1.0, 2.0, 3.0], &[3]).requires_grad_(true);
+        let y = Tensor::new(vec![4.0, 5.0, 6.0], &[3]).requires_grad_(true);
+        let z = (&x * &y + &x).sum(None, false);
+        z.backward();
+        assert_eq!(x.grad().unwrap().to_vec(), vec![5.0, 6.0, 7.0]);
+        assert_eq!(y.grad().unwrap().to_vec(), vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_matmul_backward() {
+        let x = Tensor::new(vec![1.0, -2.0, 3.0, 4.0, 5.0, -6.0], &[2, 3]).requires_grad_(true);
+        let w = Tensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[3, 2]).requires_grad_(true);
+        let out = x.dot(&w);
+        assert_eq!(out.to_vec(), vec![10.0, 12.0, -11.0, -8.0]);
+        out.sum(None, false).backward();
+        // d/dx sum(x @ w) = ones @ w.T, d/dw = x.T @ ones
+        assert_eq!(
+            x.grad().unwrap().to_vec(),
+            vec![3.0, 7.0, 11.0, 3.0, 7.0, 11.0]
+        );
+        assert_eq!(
+            w.grad().unwrap().to_vec(),
+            vec![5.0, 5.0, 3.0, 3.0, -3.0, -3.0]
+        );
+    }
+
+    #[test]
+    fn test_no_grad() {
+        let x = Tensor::new(vec![1.0, 2.0], &[2]).requires_grad_(true);
+        {
+            let mut ctx = NoGrad::new();
+            ctx.init();
+            assert!(!is_grad_enabled());
+            let y = &x * 2.0;
+            assert!(!y.requires_grad());
+            assert!(y.is_leaf());
+        }
+        assert!(is_grad_enabled());
+        let y = Tensor::no_grad(|| x.exp());
+        assert!(!y.requires_grad());
+        assert!((&x * 2.0).requires_grad());
+    }
+
+    #[test]
+    fn test_gradcheck_reports_worst_element() {
+        let x: Vec<f64> = (0..6).map(|i| i as f64 - 2.5).collect();
+        let f = |x: &[f64]| x.iter().map(|v| v * v).sum::<f64>();
+        let report = gradcheck(f, |x| x.iter().map(|v| 2.0 * v).collect(), &x, &[2, 3], None, None, None);
+        assert!(report.passed, "{}", report);
+
+        let wrong = |x: &[f64]| {
+            let mut g: Vec<f64> = x.iter().map(|v| 2.0 * v).collect();
+            g[4] += 0.5;
+            g
+        };
+        let report = gradcheck(f, wrong, &x, &[2, 3], None, None, None);
+        assert!(!report.passed);
+        assert_eq!(report.worst_flat, 4);
+        assert_eq!(report.worst_index, vec![1, 1]);
+        assert!((report.abs_err - 0.5).abs() < 1e-6);
+    }
+
+    // every differentiable primitive in function.rs has to be in this table
+    #[test]
+    fn test_gradcheck_primitives() {
+        fn data(shape: &[usize], lo: f64, hi: f64) -> (Vec<f64>, Vec<usize>) {
+            let n: usize = shape.iter().product();
+            // distinct values so max has no ties
+            let d = (0..n)
+                .map(|i| lo + (hi - lo) * (((i * 37 + 11) % 101) as f64 + 0.5) / 101.0)
+                .collect();
+            (d, shape.to_vec())
+        }
+        type Case = (&'static str, Box<dyn Fn(&[Tensor]) -> Tensor>, Vec<(Vec<f64>, Vec<usize>)>);
+        let cases: Vec<Case> = vec![
+            ("neg", Box::new(|t| t[0].neg()), vec![data(&[2, 3], -2.0, 2.0)]),
+            ("reciprocal", Box::new(|t| t[0].reciprocal()), vec![data(&[2, 3], 0.5, 2.0)]),
+            ("sin", Box::new(|t| t[0].sin()), vec![data(&[2, 3], -3.0, 3.0)]),
+            ("relu", Box::new(|t| t[0].relu()), vec![data(&[2, 3], -2.0, 2.0)]),
+            ("log", Box::new(|t| t[0].log()), vec![data(&[2, 3], 0.5, 3.0)]),
+            ("exp", Box::new(|t| t[0].exp()), vec![data(&[2, 3], -2.0, 2.0)]),
+            ("sqrt", Box::new(|t| t[0].sqrt()), vec![data(&[2, 3], 0.5, 3.0)]),
+            ("sigmoid", Box::new(|t| t[0].sigmoid()), vec![data(&[2, 3], -3.0, 3.0)]),
+            ("contiguous", Box::new(|t| t[0].permute(&[1, 0]).contiguous()), vec![data(&[2, 3], -2.0, 2.0)]),
+            ("add", Box::new(|t| t[0].add(&t[1])), vec![data(&[2, 3], -2.0, 2.0), data(&[2, 3], -1.0, 1.0)]),
+            ("sub", Box::new(|t| t[0].sub(&t[1])), vec![data(&[2, 3], -2.0, 2.0), data(&[2, 3], -1.0, 1.0)]),
+            ("mul", Box::new(|t| t[0].mul(&t[1])), vec![data(&[2, 3], -2.0, 2.0), data(&[2, 3], -1.0, 1.0)]),
+            ("div", Box::new(|t| t[0].div(&t[1])), vec![data(&[2, 3], -2.0, 2.0), data(&[2, 3], 0.5, 2.0)]),
+            (
+                "where",
+                Box::new(|t| t[0].lt(&t[0].zeros_like()).where_(&t[1], &t[2])),
+                vec![data(&[2, 3], -1.0, 1.0), data(&[2, 3], -2.0, 2.0), data(&[2, 3], -1.0, 3.0)],
+            ),
+            ("sum", Box::new(|t| t[0].sum(Some(&[1]), false)), vec![data(&[2, 3, 2], -2.0, 2.0)]),
+            ("max", Box::new(|t| t[0].max(Some(&[0, 2]), true)), vec![data(&[2, 3, 2], -2.0, 2.0)]),
+            ("expand", Box::new(|t| t[0].expand(&[2, 4, 3])), vec![data(&[2, 1, 3], -2.0, 2.0)]),
+            ("reshape", Box::new(|t| t[0].reshape(&[3, 2, 2])), vec![data(&[2, 6], -2.0, 2.0)]),
+            ("permute", Box::new(|t| t[0].permute(&[2, 0, 1])), vec![data(&[2, 3, 4], -2.0, 2.0)]),
+            ("pad", Box::new(|t| t[0].pad(&[(1, 0), (2, 1)])), vec![data(&[2, 3], -2.0, 2.0)]),
+            ("shrink", Box::new(|t| t[0].shrink(&[(1, 3), (0, 2)])), vec![data(&[3, 3], -2.0, 2.0)]),
+            ("flip", Box::new(|t| t[0].flip(&[0, 1])), vec![data(&[2, 3], -2.0, 2.0)]),
+            ("stride", Box::new(|t| t[0].stride(&[2, -3])), vec![data(&[5, 7], -2.0, 2.0)]),
+            ("gather", Box::new(|t| t[0].gather(&Tensor::new(vec![2.0, 0.0, -1.0, 1.0], &[2, 2]), 0)), vec![data(&[3, 2], -2.0, 2.0)]),
+            ("dot", Box::new(|t| t[0].dot(&t[1])), vec![data(&[2, 3], -2.0, 2.0), data(&[3, 4], -1.0, 1.0)]),
+        ];
+        for (name, f, inputs) in cases.iter() {
+            for (i, report) in gradcheck_tensor(f, inputs, None, None, None).into_iter().enumerate() {
+                assert!(report.passed, "{} input {}: {}", name, i, report);
+            }
+        }
+    }
+
+    fn rosenbrock<R: Real>(x: &[R]) -> R {
+        let (a, b) = (R::from(1u8), R::from(100u8));
+        x.windows(2).fold(R::from(0u8), |acc, w| {
+            acc + b * (w[1] - w[0] * w[0]).powi(2) + (a - w[0]).powi(2)
+        })
+    }
+
+    #[test]
+    fn test_jvp() {
+        let f = |x: &[Dual<f64>]| vec![x[0] * x[1], x[0].sin() + x[1].exp()];
+        let (out, tangents) = jvp(f, &[2.0, 3.0], &[1.0, 0.0]);
+        assert_eq!(out, vec![6.0, 2.0f64.sin() + 3.0f64.exp()]);
+        assert_eq!(tangents, vec![3.0, 2.0f64.cos()]);
+
+        // the numeric helper traits carry through
+        let r: isize = round_up(Dual::constant(10.8), Dual::constant(2.0));
+        assert_eq!(r, 10);
+    }
+
+    #[test]
+    fn test_hvp_rosenbrock() {
+        let (x, y) = (1.5, 0.5);
+        let hv = hvp(rosenbrock::<Dual<Dual<f64>>>, &[x, y], &[1.0, -2.0]);
+        // H = [[1200x^2 - 400y + 2, -400x], [-400x, 200]]
+        let h = [[1200.0 * x * x - 400.0 * y + 2.0, -400.0 * x], [-400.0 * x, 200.0]];
+        let expected = [h[0][0] - 2.0 * h[0][1], h[1][0] - 2.0 * h[1][1]];
+        for (a, b) in hv.iter().zip(expected.iter()) {
+            assert!((a - b).abs() < 1e-9, "{:?} != {:?}", hv, expected);
+        }
+    }
+
+    #[test]
+    fn test_normalize_slice() {
+        // (start, step, len) against python's range(10)[s]
+        assert_eq!(normalize_slice(None, None, None, 10), (0, 1, 10));
+        assert_eq!(normalize_slice(Some(-3), None, None, 10), (7, 1, 3));
+        assert_eq!(normalize_slice(Some(1), Some(-1), Some(3), 10), (1, 3, 3));
+        assert_eq!(normalize_slice(None, None, Some(-1), 10), (9, -1, 10));
+        assert_eq!(normalize_slice(Some(8), Some(2), Some(-2), 10), (8, -2, 3));
+        assert_eq!(normalize_slice(Some(-100), Some(100), Some(4), 10), (0, 4, 3));
+        assert_eq!(normalize_slice(Some(5), Some(2), None, 10).2, 0);
+    }
+
+    #[test]
+    fn test_getitem_basic() {
+        let t = Tensor::arange(24).reshape(&[2, 3, 4]);
+        // t[1, ::-1, 1:4:2]
+        let x = t.getitem(&[1.into(), Index::slice(None, None, Some(-1)), Index::slice(Some(1), Some(4), Some(2))]);
+        assert_eq!(x.shape(), vec![3, 2]);
+        assert_eq!(x.to_vec(), vec![21.0, 23.0, 17.0, 19.0, 13.0, 15.0]);
+        // still a view of the same buffer
+        assert!(Arc::ptr_eq(&x.lazydata().base, &t.lazydata().base));
+        // t[None, ..., -1]
+        let y = t.getitem(&[Index::NewAxis, Index::Ellipsis, (-1).into()]);
+        assert_eq!(y.shape(), vec![1, 2, 3]);
+        assert_eq!(y.to_vec(), vec![3.0, 7.0, 11.0, 15.0, 19.0, 23.0]);
+        assert_eq!(t.getitem(&[(..).into(), (2..).into(), (..-2).into()]).to_vec(), vec![8.0, 9.0, 20.0, 21.0]);
+    }
+
+    #[test]
+    fn test_getitem_fancy() {
+        let t = Tensor::arange(12).reshape(&[3, 4]);
+        let rows = Tensor::new(vec![2.0, 0.0, -1.0], &[3]);
+        assert_eq!(t.getitem(&[rows.clone().into()]).shape(), vec![3, 4]);
+        assert_eq!(t.getitem(&[(..).into(), rows.clone().into()]).to_vec(), vec![2.0, 0.0, 3.0, 6.0, 4.0, 7.0, 10.0, 8.0, 11.0]);
+        // two index tensors broadcast together and pick single elements
+        let cols = Tensor::new(vec![1.0, 3.0], &[2, 1]);
+        let x = t.getitem(&[rows.into(), cols.into()]);
+        assert_eq!(x.shape(), vec![2, 3]);
+        assert_eq!(x.to_vec(), vec![9.0, 1.0, 9.0, 11.0, 3.0, 11.0]);
+        // non-adjacent tensor indices move to the front, like numpy
+        let u = Tensor::arange(24).reshape(&[2, 3, 4]);
+        let z = u.getitem(&[Tensor::new(vec![1.0, 0.0], &[2]).into(), (..).into(), Tensor::new(vec![0.0, 3.0], &[2]).into()]);
+        assert_eq!(z.shape(), vec![2, 3]);
+        assert_eq!(z.to_vec(), vec![12.0, 16.0, 20.0, 3.0, 7.0, 11.0]);
+        let g = t.gather(&Tensor::new(vec![0.0, 3.0, 1.0, 1.0], &[2, 2]), 1);
+        assert_eq!(g.to_vec(), vec![0.0, 3.0, 5.0, 5.0]);
+    }
+
+    #[test]
+    fn test_setitem() {
+        let t = Tensor::zeros(&[3, 4]).contiguous();
+        let v = t.getitem(&[(1..).into()]);
+        t.setitem(&[(1..3).into(), Index::slice(None, None, Some(-2))], &Tensor::new(vec![1.0, 2.0], &[2]));
+        t.setitem(&[0.into(), Tensor::new(vec![0.0, -1.0], &[2]).into()], &Tensor::scalar(7.0));
+        assert_eq!(t.to_vec(), vec![7.0, 0.0, 0.0, 7.0, 0.0, 2.0, 0.0, 1.0, 0.0, 2.0, 0.0, 1.0]);
+        // like numpy, views of the same storage see the write, and writing into one writes through
+        assert_eq!(v.to_vec(), vec![0.0, 2.0, 0.0, 1.0, 0.0, 2.0, 0.0, 1.0]);
+        let col = t.getitem(&[Index::full(), 2.into()]);
+        col.setitem(&[Index::slice(None, None, Some(-1))], &Tensor::new(vec![3.0, 4.0, 5.0], &[3]));
+        assert_eq!(t.to_vec(), vec![7.0, 0.0, 5.0, 7.0, 0.0, 2.0, 4.0, 1.0, 0.0, 2.0, 3.0, 1.0]);
+        // repeated tensor indices keep the last value, tensor indices next to each other keep their place
+        let u = Tensor::zeros(&[2, 3, 4]).contiguous();
+        let (i, j) = (Tensor::new(vec![0.0, 2.0, 0.0], &[3]), Tensor::new(vec![1.0, 3.0, 1.0], &[3]));
+        u.setitem(&[Index::full(), (&i).into(), (&j).into()], &Tensor::new(vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0], &[2, 3]));
+        let mut expected = vec![0.0; 24];
+        for (b, ix, jx, v) in [(0, 0, 1, 3.0), (0, 2, 3, 2.0), (1, 0, 1, 6.0), (1, 2, 3, 5.0)] {
+            expected[b * 12 + ix * 4 + jx] = v;
+        }
+        assert_eq!(u.to_vec(), expected);
+        assert_eq!(u.getitem(&[Index::full(), (&i).into(), (&j).into()]).to_vec(), vec![3.0, 2.0, 3.0, 6.0, 5.0, 6.0]);
+        // expanded tensors get storage of their own
+        let z = Tensor::zeros(&[2, 2]);
+        z.setitem(&[1.into()], &Tensor::scalar(1.0));
+        assert_eq!(z.to_vec(), vec![0.0, 0.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_symbolic_simplify() {
+        let a = Node::var("a", 0, 7);
+        let b = Node::var("b", 0, 3);
+        let n = a.clone() * 4 + b.clone();
+        assert_eq!(n.bounds(), (0, 31));
+        assert_eq!(n.clone() / 4, a);
+        assert_eq!(n.clone() % 4, b);
+        assert_eq!((n.clone() + 8) % 4, b);
+        assert_eq!((a.clone() * 2 + a.clone() * 3 - a.clone() * 5), Node::Num(0));
+        assert_eq!(b.clone().lt(4), Node::Num(1));
+        assert_eq!(b.clone().ge(0), Node::Num(1));
+        assert_eq!((a.clone() * 3).lt(7), a.clone().lt(3));
+        assert_eq!(Node::ands(vec![Node::Num(1), b.clone().lt(2), b.clone().lt(2)]), b.clone().lt(2));
+        assert_eq!(n.render(), "((a*4)+b)");
+        let vals = HashMap::from([("a".to_string(), 5), ("b".to_string(), 2)]);
+        assert_eq!((n.clone() / 3).eval(&vals), 7);
+        assert_eq!(n.substitute(&HashMap::from([("a".to_string(), Node::Num(1))])), b + 4);
+    }
+
+    #[test]
+    fn test_view_expr_node() {
+        let st = View::create(&[3, 4], None, 0, None).pad(&[(1, 0), (0, 0)]).stride(&[1, -1]);
+        let (i, j) = (Node::var("i", 0, 3), Node::var("j", 0, 3));
+        let (idx, valid) = st.expr_node(&[i.clone(), j.clone()]);
+        let vals = |a: isize, b: isize| HashMap::from([("i".to_string(), a), ("j".to_string(), b)]);
+        for (a, b) in [(0, 0), (1, 0), (3, 2)] {
+            let expect = st.index_of(&[a as usize, b as usize]);
+            assert_eq!(valid.eval(&vals(a, b)) != 0, expect.is_some());
+            if let Some(e) = expect {
+                assert_eq!(idx.eval(&vals(a, b)), e);
+            }
+        }
+    }
+
+    // out[i] = sum_j x[i, j] * 2 for x: [4, 8] stored transposed, with the reduce axis given first
+    fn sum_mul_ast() -> LazyOp {
+        let st = View::create(&[8, 4], None, 0, None).permute(&[1, 0]);
+        let x = LazyOp::load(1, DType::FLOAT32, st);
+        let two = LazyOp::constant(2.0, DType::FLOAT32, View::create(&[1, 1], Some(&[0, 0]), 0, None).expand(&[4, 8]));
+        let mul = LazyOp::new(BinaryOps::Mul, vec![x, two], None);
+        LazyOp::store(LazyOp::reduce(ReduceOps::Sum, mul, &[1]), 0, DType::FLOAT32, View::create(&[4, 1], None, 0, None))
+    }
+
+    fn f32_bytes(x: &[f32]) -> Vec<u8> {
+        x.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    fn bytes_f32(x: &[u8]) -> Vec<f32> {
+        x.chunks(4).map(|c| f32::from_ne_bytes(c.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn test_linearize_reduce() {
+        let mut lin = Linearizer::new(sum_mul_ast());
+        assert_eq!(lin.name(), "r_4_8");
+        let uops = lin.linearize().to_vec();
+        let kinds: Vec<UOps> = uops.iter().map(|u| u.uop).filter(|u| *u != UOps::Const && *u != UOps::Alu).collect();
+        assert_eq!(
+            kinds,
+            vec![UOps::DefineGlobal, UOps::DefineGlobal, UOps::Loop, UOps::DefineAcc, UOps::Loop, UOps::Load, UOps::Phi, UOps::End, UOps::Store, UOps::End]
+        );
+        // operands always come before their users
+        assert!(uops.iter().enumerate().all(|(i, u)| u.vin.iter().all(|v| *v < i)));
+    }
+
+    #[test]
+    fn test_clang_kernel() {
+        if find_compiler().is_none() {
+            return;
+        }
+        let mut lin = Linearizer::new(sum_mul_ast());
+        let name = lin.name();
+        let prg = compile_uops(&name, lin.linearize()).unwrap();
+        let x: Vec<f32> = (0..32).map(|i| i as f32).collect();
+        let (mut out, mut inp) = (vec![0u8; 16], f32_bytes(&x));
+        prg.call(&mut [&mut out, &mut inp], true).unwrap();
+        // x is stored [8, 4], so row i of the kernel's view is column i
+        let expected: Vec<f32> = (0..4).map(|i| 2.0 * (0..8).map(|j| x[j * 4 + i]).sum::<f32>()).collect();
+        assert_eq!(bytes_f32(&out), expected);
+
+        // a padded, flipped load: out[i] = x[3 - i + 1] for i in 1..4, 0 at i = 0
+        let st = View::create(&[4], None, 0, None).shrink(&[(1, 4)]).pad(&[(1, 0)]).stride(&[-1]);
+        let ast = LazyOp::store(LazyOp::load(1, DType::FLOAT32, st), 0, DType::FLOAT32, View::create(&[4], None, 0, None));
+        let mut lin = Linearizer::new(ast);
+        let prg = compile_uops(&lin.name(), lin.linearize()).unwrap();
+        let (mut out, mut inp) = (vec![0u8; 16], f32_bytes(&[10.0, 11.0, 12.0, 13.0]));
+        prg.call(&mut [&mut out, &mut inp], false);
+        assert_eq!(bytes_f32(&out), vec![13.0, 12.0, 11.0, 0.0]);
+    }
+
+    /// Run `lin` on INTERP and, when a C compiler is around, on CLANG. `bufs[0]` is the output.
+    fn run_both(mut lin: Linearizer, bufs: &[Vec<u8>]) -> (Vec<u8>, Option<Vec<u8>>) {
+        let name = lin.name();
+        let uops = lin.linearize().to_vec();
+        let run = |call: &dyn Fn(&mut [&mut [u8]])| {
+            let mut bufs = bufs.to_vec();
+            let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
+            call(&mut refs);
+            bufs[0].clone()
+        };
+        let interp = InterpProgram::new(&name, &ops_interp::render(&uops)).unwrap();
+        let a = run(&|b| {
+            interp.call(b, false);
+        });
+        let c = find_compiler().map(|_| {
+            let prg = compile_uops(&name, &uops).unwrap();
+            run(&|b| {
+                prg.call(b, false);
+            })
+        });
+        (a, c)
+    }
+
+    #[test]
+    fn test_interp_matches_clang() {
+        let x: Vec<f32> = (0..32).map(|i| i as f32 * 0.37 - 5.0).collect();
+        let (a, c) = run_both(Linearizer::new(sum_mul_ast()), &[vec![0; 16], f32_bytes(&x)]);
+        let expected: Vec<f32> = (0..4).map(|i| 2.0 * (0..8).map(|j| x[j * 4 + i]).sum::<f32>()).collect();
+        assert_eq!(bytes_f32(&a), expected);
+        if let Some(c) = c {
+            assert_eq!(a, c);
+        }
+
+        // every float alu op in one elementwise kernel
+        let st = View::create(&[32], None, 0, None);
+        let ld = |i| LazyOp::load(i, DType::FLOAT32, st.clone());
+        let un = |op: UnaryOps, x: LazyOp| LazyOp::new(op, vec![x], None);
+        let bin = |op: BinaryOps, x: LazyOp, y: LazyOp| LazyOp::new(op, vec![x, y], None);
+        let pos = un(UnaryOps::Sqrt, bin(BinaryOps::Mul, ld(1), ld(1)));
+        let terms = vec![
+            un(UnaryOps::Exp2, un(UnaryOps::Neg, pos.clone())),
+            un(UnaryOps::Log2, bin(BinaryOps::Add, pos.clone(), ld(2))),
+            un(UnaryOps::Sin, ld(1)),
+            un(UnaryOps::Recip, bin(BinaryOps::Add, pos.clone(), ld(2))),
+            bin(BinaryOps::Max, ld(1), ld(2)),
+            bin(BinaryOps::Mod, pos.clone(), ld(2)),
+            bin(BinaryOps::Div, ld(1), ld(2)),
+            bin(BinaryOps::Sub, ld(1), ld(2)),
+            LazyOp::new(TernaryOps::Where, vec![bin(BinaryOps::CmpLt, ld(1), ld(2)), ld(1), ld(2)], None),
+            LazyOp::new(TernaryOps::MulAcc, vec![ld(1), ld(2), ld(1)], None),
+            LazyOp::cast(bin(BinaryOps::CmpEq, ld(1), ld(1)), DType::FLOAT32),
+        ];
+        let sum = terms.into_iter().reduce(|a, b| bin(BinaryOps::Add, a, b)).unwrap();
+        let y: Vec<f32> = (0..32).map(|i| 1.0 + (i % 5) as f32).collect();
+        let (a, c) = run_both(Linearizer::new(LazyOp::store(sum, 0, DType::FLOAT32, st.clone())), &[vec![0; 128], f32_bytes(&x), f32_bytes(&y)]);
+        assert!(bytes_f32(&a).iter().all(|v| v.is_finite()));
+        if let Some(c) = c {
+            for (p, q) in bytes_f32(&a).iter().zip(bytes_f32(&c)) {
+                assert!((p - q).abs() <= 1e-5 * p.abs().max(1.0), "{} != {}", p, q);
+            }
+        }
+
+        // int8 wraps around
+        let st = View::create(&[3], None, 0, None);
+        let ast = LazyOp::store(
+            LazyOp::new(BinaryOps::Add, vec![LazyOp::load(1, DType::INT8, st.clone()), LazyOp::constant(100.0, DType::INT8, st.clone())], None),
+            0,
+            DType::INT8,
+            st,
+        );
+        let (a, c) = run_both(Linearizer::new(ast), &[vec![0; 3], [100i8, -100, 27].iter().map(|v| *v as u8).collect()]);
+        assert_eq!(a.iter().map(|v| *v as i8).collect::<Vec<_>>(), vec![-56, 0, 127]);
+        if let Some(c) = c {
+            assert_eq!(a, c);
+        }
+    }
+
+    // out[m, n] = sum_k a[m, k] * b[k, n]
+    fn matmul_ast(m: usize, n: usize, k: usize, rop: ReduceOps) -> LazyOp {
+        let a = LazyOp::load(1, DType::FLOAT32, View::create(&[m, n, k], Some(&[k as isize, 0, 1]), 0, None));
+        let b = LazyOp::load(2, DType::FLOAT32, View::create(&[m, n, k], Some(&[0, 1, n as isize]), 0, None));
+        let mul = LazyOp::new(BinaryOps::Mul, vec![a, b], None);
+        LazyOp::store(LazyOp::reduce(rop, mul, &[2]), 0, DType::FLOAT32, View::create(&[m, n, 1], None, 0, None))
+    }
+
+    #[test]
+    fn test_kernel_opts() {
+        let (m, n, k) = (7, 8, 12);
+        // small integers keep every summation order exact
+        let a: Vec<f32> = (0..m * k).map(|i| (i % 5) as f32 - 2.0).collect();
+        let b: Vec<f32> = (0..k * n).map(|i| (i % 3) as f32).collect();
+        let bufs = [vec![0u8; m * n * 4], f32_bytes(&a), f32_bytes(&b)];
+        let (expected, _) = run_both(Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum)), &bufs);
+        let o = Opt::new;
+        let cases = vec![
+            vec![o(OptOps::Upcast, 1, 4), o(OptOps::Unroll, 0, 4)],
+            vec![o(OptOps::Upcast, 1, 0), o(OptOps::Unroll, 0, 0)],
+            vec![o(OptOps::Local, 1, 2), o(OptOps::Group, 0, 3), o(OptOps::Unroll, 1, 2)],
+            vec![o(OptOps::Padto, 0, 4), o(OptOps::Upcast, 0, 4), o(OptOps::Upcast, 1, 2)],
+            vec![o(OptOps::Padto, 2, 16), o(OptOps::Unroll, 0, 4)],
+            vec![o(OptOps::Swap, 0, 1), o(OptOps::Upcast, 0, 2)],
+        ];
+        for opts in cases {
+            let mut lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum));
+            for opt in &opts {
+                lin.apply_opt(*opt).unwrap_or_else(|e| panic!("{:?} in {:?}: {}", opt, opts, e));
+            }
+            let (a, c) = run_both(lin, &bufs);
+            assert_eq!(bytes_f32(&a), bytes_f32(&expected), "{:?}", opts);
+            if let Some(c) = c {
+                assert_eq!(a, c, "{:?}", opts);
+            }
+        }
+        let mut lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum));
+        lin.hand_coded_optimizations();
+        assert!(lin.upcasted > 0, "{:?}", lin.applied_opts);
+        assert_eq!(run_both(lin, &bufs).0, expected);
+
+        // illegal opts are refused and leave the kernel as it was
+        let mut lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum));
+        for opt in [o(OptOps::Upcast, 0, 4), o(OptOps::Upcast, 2, 4), o(OptOps::Unroll, 0, 5), o(OptOps::Padto, 1, 4), o(OptOps::Padto, 0, 32), o(OptOps::Swap, 0, 0)] {
+            assert!(lin.apply_opt(opt).is_err(), "{:?}", opt);
+        }
+        assert_eq!(lin.full_shape(), vec![m, n, k]);
+        let mut lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Max));
+        assert!(lin.apply_opt(o(OptOps::Padto, 2, 16)).is_err());
+        lin.linearize();
+        assert!(lin.apply_opt(o(OptOps::Upcast, 1, 2)).is_err());
+    }
+    /// Run test `name` again in a child process with `env` set, for settings read once per process.
+    /// The child sees `RUSTGRAD_TEST_CHILD`, and starts from an empty cache db unless `CACHEDB` is set.
+    fn rerun_with(name: &str, env: &[(&str, &str)]) {
+        let db = tempfile::tempdir().unwrap();
+        let mut cmd = std::process::Command::new(std::env::current_exe().unwrap());
+        if std::env::var("CACHEDB").is_err() {
+            cmd.env("CACHEDB", db.path().join("cache.db"));
+        }
+        let status = cmd
+            .args([name, "--exact", "--nocapture", "--test-threads=1"])
+            .env("RUSTGRAD_TEST_CHILD", "1")
+            .envs(env.iter().copied())
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} with {:?}", name, env);
+    }
+
+    #[test]
+    fn test_runner_opts() {
+        // NOOPT is read once per process, so each setting gets a child of its own
+        if std::env::var("RUSTGRAD_TEST_CHILD").is_err() {
+            for noopt in ["0", "1"] {
+                rerun_with("tests::test_runner_opts", &[("NOOPT", noopt), ("BEAM", "0")]);
+            }
+            return;
+        }
+        let n = 16;
+        let a: Vec<f64> = (0..n * n).map(|i| (i % 7) as f64 - 3.0).collect();
+        let b: Vec<f64> = (0..n * n).map(|i| (i % 5) as f64).collect();
+        let expected: Vec<f64> = (0..n * n)
+            .map(|i| (0..n).map(|j| a[i / n * n + j] * b[j * n + i % n]).sum())
+            .collect();
+        begin_capture();
+        let out = Tensor::new(a, &[n, n]).matmul(&Tensor::new(b, &[n, n])).to_vec();
+        let items = end_capture().items;
+        assert_eq!(out, expected);
+        let runner = &items.iter().find(|ei| ei.prg.name.starts_with('r')).unwrap().prg;
+        if crate::prelude::NOOPT.clone() == 1 {
+            assert!(runner.applied_opts.is_empty(), "{:?}", runner.applied_opts);
+        } else {
+            assert!(!runner.applied_opts.is_empty());
+        }
+    }
+
+
+    #[test]
+    fn test_vectorize() {
+        // out = a + b over [8, 8], with the inner axis upcast by 4 and 8
+        let add_ast = |a: View| {
+            let st = View::create(&[8, 8], None, 0, None);
+            let add = LazyOp::new(BinaryOps::Add, vec![LazyOp::load(1, DType::FLOAT32, a), LazyOp::load(2, DType::FLOAT32, st.clone())], None);
+            LazyOp::store(add, 0, DType::FLOAT32, st)
+        };
+        let x: Vec<f32> = (0..80).map(|i| i as f32 * 0.5).collect();
+        let y: Vec<f32> = (0..64).map(|i| 100.0 - i as f32).collect();
+        let bufs = [vec![0u8; 256], f32_bytes(&x), f32_bytes(&y)];
+        for amt in [4, 8] {
+            let mut lin = Linearizer::new(add_ast(View::create(&[8, 8], None, 0, None)));
+            lin.apply_opt(Opt::new(OptOps::Upcast, 1, amt)).unwrap();
+            assert_eq!((0..3).map(|i| lin.vector_width(i)).collect::<Vec<_>>(), vec![amt; 3]);
+            let uops = lin.linearize().to_vec();
+            assert_eq!(uops.iter().filter(|u| u.uop == UOps::Load).count(), 2);
+            assert_eq!(uops.iter().filter(|u| u.uop == UOps::Store).count(), 1);
+            assert!(ops_clang::render("test", &uops).contains(&format!("float{} __attribute__", amt)));
+            let (a, c) = run_both(lin, &bufs);
+            let expected: Vec<f32> = (0..64).map(|i| x[i] + y[i]).collect();
+            assert_eq!(bytes_f32(&a), expected);
+            if let Some(c) = c {
+                assert_eq!(a, c);
+            }
+        }
+
+        // a load starting off a multiple of 4, or through a mask, stays scalar while the rest vectorize
+        let shifted = View::create(&[80], None, 0, None).shrink(&[(1, 65)]).reshape(&[8, 8]).unwrap();
+        let padded = View::create(&[8, 7], None, 0, None).pad(&[(0, 0), (0, 1)]);
+        for (st, expected) in [(shifted, (1..65).map(|i| x[i] + y[i - 1]).collect::<Vec<_>>()), (padded, (0..64).map(|i| if i % 8 < 7 { x[i / 8 * 7 + i % 8] } else { 0.0 } + y[i]).collect())] {
+            let mut lin = Linearizer::new(add_ast(st));
+            lin.apply_opt(Opt::new(OptOps::Upcast, 1, 4)).unwrap();
+            assert_eq!((0..3).map(|i| lin.vector_width(i)).collect::<Vec<_>>(), vec![4, 1, 4]);
+            let (a, c) = run_both(lin, &bufs);
+            assert_eq!(bytes_f32(&a), expected);
+            if let Some(c) = c {
+                assert_eq!(a, c);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let st = View::create(&[4], None, 0, None);
+        let load = LazyOp::load(1, DType::FLOAT32, st.clone());
+        let konst = |v: f64| LazyOp::constant(v, DType::FLOAT32, st.clone());
+        let add = |a: LazyOp, b: LazyOp| LazyOp::new(BinaryOps::Add, vec![a, b], None);
+        let mul = |a: LazyOp, b: LazyOp| LazyOp::new(BinaryOps::Mul, vec![a, b], None);
+        let simplify = |op: &LazyOp| graph_rewrite(op, &AST_SIMPLIFY);
+
+        // identities either way round, and constants folded first
+        assert_eq!(simplify(&add(konst(0.0), mul(load.clone(), konst(1.0)))), load);
+        assert_eq!(simplify(&mul(load.clone(), add(konst(0.5), konst(0.5)))), load);
+        assert_eq!(simplify(&add(konst(2.0), mul(konst(3.0), konst(4.0)))), konst(14.0));
+        // a masked constant is zero in places
+        let padded = View::create(&[2], None, 0, None).pad(&[(1, 1)]);
+        let masked = LazyOp::constant(0.0, DType::FLOAT32, padded);
+        assert_eq!(simplify(&add(load.clone(), masked.clone())), add(load.clone(), masked));
+        // casts: a no-op goes, a widening one is skipped, a narrowing one stays
+        let cast = |x: LazyOp, d: DType| LazyOp::cast(x, d);
+        assert_eq!(simplify(&cast(load.clone(), DType::FLOAT32)), load);
+        let widened = cast(cast(load.clone(), DType::FLOAT64), DType::FLOAT16);
+        assert_eq!(simplify(&widened), cast(load.clone(), DType::FLOAT16));
+        let lossy = cast(cast(load.clone(), DType::INT32), DType::FLOAT64);
+        assert_eq!(simplify(&lossy), lossy);
+        assert_eq!(simplify(&cast(konst(-2.7), DType::INT8)), LazyOp::constant(-2.0, DType::INT8, st.clone()));
+
+        // a table of our own: x - x is 0 only when both sides are the same node
+        let sub = UPat::op(BinaryOps::Sub).src(vec![UPat::var("x"), UPat::var("x")]).named("sub");
+        let zero: RewriteFn<LazyOp> =
+            Box::new(|c: &Captures<LazyOp>| Some(c["sub"].const_like(0.0, c["sub"].dtype())));
+        let pm = PatternMatcher::new(vec![(sub, zero)]);
+        let x_minus = |y: LazyOp| LazyOp::new(BinaryOps::Sub, vec![load.clone(), y], None);
+        assert_eq!(graph_rewrite(&x_minus(load.clone()), &pm), konst(0.0));
+        let other = LazyOp::load(2, DType::FLOAT32, st.clone());
+        assert_eq!(graph_rewrite(&x_minus(other.clone()), &pm), x_minus(other));
+
+        // on uops: integer math folds in C semantics, a division by zero is left to the kernel
+        let u = |uop, dtype: Option<DType>, vin: Vec<usize>, arg| UOp { uop, dtype, vin, arg };
+        let alu = |op: BinaryOps, vin| u(UOps::Alu, Some(DType::INT32), vin, UArg::Op(op.into()));
+        let uops = vec![
+            u(UOps::DefineGlobal, Some(DType::INT32), vec![], UArg::Buffer(0)),
+            u(UOps::Const, Some(DType::INT32), vec![], UArg::Const(7.0)),
+            u(UOps::Const, Some(DType::INT32), vec![], UArg::Const(2.0)),
+            alu(BinaryOps::Div, vec![1, 2]),
+            u(UOps::Const, Some(DType::INT32), vec![], UArg::Const(0.0)),
+            alu(BinaryOps::Mod, vec![1, 4]),
+            alu(BinaryOps::Add, vec![5, 4]),
+            u(UOps::Store, None, vec![0, 4, 3], UArg::None),
+            u(UOps::Store, None, vec![0, 2, 6], UArg::None),
+        ];
+        let out = graph_rewrite_uops(&uops, &UOP_SIMPLIFY);
+        let kinds: Vec<(UOps, &UArg)> = out.iter().map(|u| (u.uop, &u.arg)).collect();
+        assert_eq!(
+            kinds[..5],
+            [
+                (UOps::DefineGlobal, &UArg::Buffer(0)),
+                (UOps::Const, &UArg::Const(7.0)),
+                (UOps::Const, &UArg::Const(2.0)),
+                (UOps::Const, &UArg::Const(3.0)),
+                (UOps::Const, &UArg::Const(0.0)),
+            ]
+        );
+        assert_eq!((out[5].arg.clone(), out[5].vin.clone()), (UArg::Op(BinaryOps::Mod.into()), vec![1, 4]));
+        assert_eq!((out[6].vin.clone(), out[7].vin.clone()), (vec![0, 4, 3], vec![0, 2, 5]));
+        assert_eq!(out.len(), 8);
+    }
+
+    #[test]
+    fn test_gpu_renderers() {
+        // the sources must match src/renderer/golden, rewrite them with UPDATE_GOLDEN=1
+        let st = View::create(&[4, 16], None, 0, None);
+        let add = LazyOp::new(BinaryOps::Add, vec![LazyOp::load(1, DType::FLOAT32, st.clone()), LazyOp::load(2, DType::FLOAT32, st.clone())], None);
+        let mut elementwise = Linearizer::new(LazyOp::store(add, 0, DType::FLOAT32, st));
+        elementwise.apply_opt(Opt::new(OptOps::Upcast, 1, 4)).unwrap();
+        let mut matmul = Linearizer::new(matmul_ast(8, 8, 16, ReduceOps::Sum));
+        for opt in [Opt::new(OptOps::Local, 1, 2), Opt::new(OptOps::Upcast, 1, 4), Opt::new(OptOps::Unroll, 0, 4)] {
+            matmul.apply_opt(opt).unwrap();
+        }
+        let langs = [("cu", CStyleLanguage::cuda()), ("metal", CStyleLanguage::metal()), ("cl", CStyleLanguage::opencl())];
+        for (name, mut lin, dims) in [("E_4_16", elementwise, (vec![4, 4], vec![])), ("r_8_8_16", matmul, (vec![8], vec![2]))] {
+            let uops = lin.linearize().to_vec();
+            assert_eq!(launch_dims(&uops), dims);
+            for (ext, lang) in &langs {
+                let src = uops_to_cstyle(lang, name, &uops) + "\n";
+                let path = format!("{}/src/renderer/golden/{}.{}", env!("CARGO_MANIFEST_DIR"), name, ext);
+                if std::env::var("UPDATE_GOLDEN").is_ok() {
+                    std::fs::write(&path, &src).unwrap();
+                }
+                assert_eq!(src, std::fs::read_to_string(&path).unwrap(), "{} differs", path);
+            }
+        }
+        // OpenCL has float8s, CUDA and Metal load and store those one float at a time
+        for (ext, lang) in &langs {
+            let mut wide = Linearizer::new(matmul_ast(8, 8, 16, ReduceOps::Sum));
+            wide.apply_opt(Opt::new(OptOps::Upcast, 1, 8)).unwrap();
+            wide.vector_widths = lang.vector_widths.clone();
+            let src = uops_to_cstyle(lang, "r_8_8_16_wide", wide.linearize()) + "\n";
+            let path = format!("{}/src/renderer/golden/r_8_8_16_wide.{}", env!("CARGO_MANIFEST_DIR"), ext);
+            if std::env::var("UPDATE_GOLDEN").is_ok() {
+                std::fs::write(&path, &src).unwrap();
+            }
+            assert_eq!(src, std::fs::read_to_string(&path).unwrap(), "{} differs", path);
+            assert_eq!(src.contains("float8"), *ext == "cl", "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_llvm() {
+        // a masked, partly vectorized elementwise kernel and a reduce. The IR must match
+        // src/renderer/golden, rewrite it with UPDATE_GOLDEN=1
+        let st = View::create(&[8, 8], None, 0, None);
+        let padded = View::create(&[8, 7], None, 0, None).pad(&[(0, 0), (0, 1)]);
+        let exp2 = LazyOp::new(UnaryOps::Exp2, vec![LazyOp::load(2, DType::FLOAT32, st.clone())], None);
+        let max = LazyOp::new(BinaryOps::Max, vec![LazyOp::load(1, DType::FLOAT32, padded), exp2], None);
+        let mut elementwise = Linearizer::new(LazyOp::store(max, 0, DType::FLOAT32, st));
+        elementwise.apply_opt(Opt::new(OptOps::Upcast, 1, 4)).unwrap();
+        let mut matmul = Linearizer::new(matmul_ast(8, 8, 16, ReduceOps::Sum));
+        for opt in [Opt::new(OptOps::Local, 1, 2), Opt::new(OptOps::Upcast, 1, 4), Opt::new(OptOps::Unroll, 0, 4)] {
+            matmul.apply_opt(opt).unwrap();
+        }
+        // small integers keep exp2 and every summation order exact
+        let x: Vec<f32> = (0..128).map(|i| (i % 7) as f32 - 3.0).collect();
+        let y: Vec<f32> = (0..128).map(|i| (i % 5) as f32 - 2.0).collect();
+        let bufs = [vec![0u8; 256], f32_bytes(&x), f32_bytes(&y)];
+        for (name, mut lin) in [("E_8_8", elementwise), ("r_8_8_16", matmul)] {
+            let uops = lin.linearize().to_vec();
+            let src = ops_llvm::render(name, &uops);
+            let path = format!("{}/src/renderer/golden/{}.ll", env!("CARGO_MANIFEST_DIR"), name);
+            if std::env::var("UPDATE_GOLDEN").is_ok() {
+                std::fs::write(&path, &src).unwrap();
+            }
+            assert_eq!(src, std::fs::read_to_string(&path).unwrap(), "{} differs", path);
+            if find_llvm().is_none() {
+                continue;
+            }
+            let run = |prg: &dyn Program| {
+                let mut bufs = bufs.to_vec();
+                let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
+                prg.call(&mut refs, &[], false);
+                bufs[0].clone()
+            };
+            let llvm = ClangProgram::new(name, LLVMCompiler.compile(&src).unwrap()).unwrap();
+            let interp = InterpProgram::new(name, &ops_interp::render(&uops)).unwrap();
+            assert_eq!(bytes_f32(&run(&llvm)), bytes_f32(&run(&interp)), "{}", name);
+        }
+
+        // whole models on the LLVM device, in doubles
+        if find_llvm().is_none() {
+            return;
+        }
+        let model = |x: &Tensor| {
+            let a = x.lt(&x.full_like(1.0)).where_(&x.exp(), &x.sqrt().log());
+            &a.sum(Some(&[1]), false) + &x.sin().max(Some(&[1]), false)
+        };
+        let x = Tensor::new((0..24).map(|i| i as f64 * 0.3 + 0.1).collect(), &[4, 6]);
+        let (expected, out) = (model(&x).to_vec(), model(&x.to("LLVM")));
+        assert_eq!(out.device(), "LLVM:0");
+        assert!(out.to_vec().iter().zip(&expected).all(|(a, e)| (a - e).abs() < 1e-9), "{:?} vs {:?}", out.to_vec(), expected);
+    }
+
+    #[test]
+    fn test_process_replay() {
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nc\nd"),
+            [" a", "-b", " c", "+d"].map(String::from)
+        );
+
+        // what a kernel lowers to now replays clean, a source it no longer renders to is a diff
+        let st = View::create(&[3, 5], None, 0, None);
+        let sqrt = LazyOp::new(UnaryOps::Sqrt, vec![LazyOp::load(1, DType::FLOAT32, st.clone())], None);
+        let ast = LazyOp::store(sqrt, 0, DType::FLOAT32, st);
+        let dev = get_device("CLANG").unwrap();
+        let mut lin = lowerer_with(ast.clone(), 0, false);
+        let name = lin.name();
+        let src = dev.compiler().unwrap().render(&name, lin.linearize());
+        let stale = src.replacen("sqrt", "sqrtf", 1);
+        let device = dev.name().to_string();
+        let mut r = Recorded { device, name: name.clone(), ast: ast.clone(), src: stale, beam: 0, noopt: false };
+        let diff = replay(&r).unwrap().expect("the source changed");
+        assert!(diff.starts_with(&format!("--- CLANG:0 {}\n+++ CLANG:0 {}\n", name, name)), "{}", diff);
+        assert!(diff.lines().any(|l| l.starts_with('-') && l.contains("sqrtf(")), "{}", diff);
+        assert!(diff.lines().any(|l| l.starts_with('+') && l.contains("sqrt(")), "{}", diff);
+        r.src = src.clone();
+        assert_eq!(replay(&r).unwrap(), None);
+
+        // a recording round trips through the cache db
+        record(&r).unwrap();
+        let same = |k: &Recorded| k.ast == ast && k.device == r.device;
+        let found = recorded().unwrap().into_iter().find(|k| same(k) && !k.noopt).unwrap();
+        assert_eq!((found.name, found.src, found.beam), (name, src, 0));
+        // the same kernel under other settings is a row of its own, replayed under those settings
+        let mut lin = lowerer_with(ast.clone(), 0, true);
+        let (name, uops) = (lin.name(), lin.linearize());
+        let unopt = Recorded { src: dev.compiler().unwrap().render(&name, uops), name, noopt: true, ..r.clone() };
+        record(&unopt).unwrap();
+        assert_eq!(recorded().unwrap().iter().filter(|k| same(k)).count(), 2);
+        assert_eq!(replay(&unopt).unwrap(), None);
+        assert_eq!(replay(&r).unwrap(), None);
+
+        // what a Runner records, optimised, replays clean
+        if crate::prelude::CACHECOLLECTING.clone() >= 1 {
+            let ast = matmul_ast(8, 8, 16, ReduceOps::Sum);
+            Runner::new(ast.clone(), "CLANG").unwrap();
+            let found = recorded().unwrap().into_iter().find(|k| k.ast == ast && k.device == r.device).unwrap();
+            assert_eq!(replay(&found).unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_devices() {
+        assert_eq!(canonicalize("clang"), "CLANG:0");
+        assert_eq!(canonicalize("interp:1"), "INTERP:1");
+        assert_eq!(canonicalize("DISK:/tmp/a:b"), "DISK:/tmp/a:b");
+        assert!(get_device("FOO").is_err());
+        assert!(Arc::ptr_eq(&get_device("interp").unwrap(), &get_device("INTERP:0").unwrap()));
+        assert!(get_device("DISK:/tmp/x").unwrap().compiler().is_none());
+        assert_eq!(Tensor::scalar(1.0).device(), default_device());
+        // with nothing asked for, compiled kernels when there's a compiler to build them
+        if std::env::var("DEVICE").is_err() && crate::device::DEVICES.iter().all(|d| std::env::var(d).is_err()) {
+            assert_eq!(default_device(), if find_compiler().is_some() { "CLANG:0" } else { "INTERP:0" });
+        }
+
+        // the same model on every CPU backend
+        let model = |x: &Tensor, w: &Tensor| x.matmul(w).relu().sum(Some(&[1]), false);
+        let x = Tensor::new((0..12).map(|i| i as f64 - 5.0).collect(), &[3, 4]);
+        let w = Tensor::new((0..8).map(|i| (i as f64).sin()).collect(), &[4, 2]);
+        let mut devices = vec!["INTERP", "INTERP:1"];
+        if find_compiler().is_some() {
+            devices.push("CLANG");
+        }
+        let outs: Vec<Vec<f64>> = devices
+            .iter()
+            .map(|d| {
+                let out = model(&x.to(d), &w.to(d));
+                assert_eq!(out.device(), canonicalize(d));
+                out.to_vec()
+            })
+            .collect();
+        for out in &outs[1..] {
+            assert!(out.iter().zip(&outs[0]).all(|(a, b)| (a - b).abs() < 1e-9), "{:?} vs {:?}", out, outs[0]);
+        }
+
+        // through a file and back
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let disk = format!("DISK:{}", file.path().display());
+        let on_disk = x.to(&disk);
+        assert_eq!(on_disk.device(), disk);
+        let loaded = LazyBuffer::from_disk(&disk, &[3, 4]).unwrap();
+        assert_eq!(loaded.to_device("INTERP").unwrap().to_vec(), x.to_vec());
+        // DISK can't run kernels, which comes back as an error rather than a panic
+        let err = loaded.try_e(UnaryOps::Neg, &[]).unwrap_err();
+        assert!(err.to_string().contains("can't run kernels"), "{}", err);
+        assert!(get_runner(LazyOp::load(1, DType::FLOAT64, View::create(&[3], None, 0, None)), &disk).is_err());
+        let (src, dst) = (get_device("INTERP").unwrap(), get_device(&disk).unwrap());
+        let mut raw = dst.allocator().alloc(4).unwrap();
+        transfer(src.as_ref(), &RawBuffer::Host(vec![1, 2, 3, 4]), dst.as_ref(), &mut raw).unwrap();
+        let mut back = vec![0; 4];
+        dst.allocator().copyout(&mut back, &raw).unwrap();
+        assert_eq!(back, vec![1, 2, 3, 4]);
+        assert!(transfer(src.as_ref(), &RawBuffer::Host(vec![0; 3]), dst.as_ref(), &mut raw).is_err());
+    }
+
+    #[test]
+    fn test_buffer_lru() {
+        let lru = LRUAllocator::new(MallocAllocator);
+        let a = lru.alloc(64).unwrap();
+        let RawBuffer::Host(v) = &a else { unreachable!() };
+        let ptr = v.as_ptr();
+        lru.free(a);
+        lru.free(lru.alloc(32).unwrap());
+        assert_eq!(lru.cached(), 96);
+        // same size comes back out of the cache, other sizes don't
+        let RawBuffer::Host(v) = lru.alloc(64).unwrap() else { unreachable!() };
+        assert_eq!(v.as_ptr(), ptr);
+        assert_eq!(lru.alloc(16).unwrap().size(), 16);
+        assert_eq!(lru.cached(), 32);
+        lru.free_cache();
+        assert_eq!(lru.cached(), 0);
+
+        // past the limit the least recently freed buffers go
+        let lru = LRUAllocator::with_limit(MallocAllocator, 100);
+        for size in [40, 30, 20, 50] {
+            lru.free(lru.alloc(size).unwrap());
+        }
+        assert_eq!(lru.cached(), 100);
+        // 40 went first, 30 is still there
+        assert_eq!(lru.alloc(40).unwrap().size(), 40);
+        assert_eq!(lru.cached(), 100);
+        assert_eq!(lru.alloc(30).unwrap().size(), 30);
+        assert_eq!(lru.cached(), 70);
+
+        // 100 bytes of device memory: what's cached is given back when an allocation doesn't fit
+        struct Tight(std::sync::atomic::AtomicUsize);
+        impl Allocator for Tight {
+            fn alloc(&self, size: usize) -> Result<RawBuffer, anyhow::Error> {
+                use std::sync::atomic::Ordering;
+                let fits = |used: usize| (used + size <= 100).then_some(used + size);
+                self.0.fetch_update(Ordering::SeqCst, Ordering::SeqCst, fits).map_err(|_| anyhow::anyhow!("out of memory"))?;
+                MallocAllocator.alloc(size)
+            }
+            fn copyin(&self, dst: &mut RawBuffer, src: &[u8]) -> Result<(), anyhow::Error> {
+                MallocAllocator.copyin(dst, src)
+            }
+            fn copyout(&self, dst: &mut [u8], src: &RawBuffer) -> Result<(), anyhow::Error> {
+                MallocAllocator.copyout(dst, src)
+            }
+            fn free(&self, buf: RawBuffer) {
+                self.0.fetch_sub(buf.size(), std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+        let lru = LRUAllocator::new(Tight(0.into()));
+        lru.free(lru.alloc(64).unwrap());
+        assert_eq!(lru.alloc(48).unwrap().size(), 48);
+        assert_eq!(lru.cached(), 0);
+        assert!(lru.alloc(60).is_err());
+
+        // other tests allocate too, so only a big buffer shows up clearly in mem_used
+        let mem_used = || helpers::GlobalCounter.lock().unwrap().mem_used;
+        let big = 1 << 26;
+        let mut buf = Buffer::new("INTERP", big / 4, DType::FLOAT32).unwrap();
+        assert_eq!((buf.size, buf.nbytes(), buf.device.as_str()), (big / 4, big, "INTERP:0"));
+        assert!(mem_used() >= big);
+        buf.copyin(&vec![7; big]).unwrap();
+        let copy = buf.copy_to("INTERP:1").unwrap();
+        assert!(mem_used() >= 2 * big);
+        let mut out = vec![0; big];
+        copy.copyout(&mut out).unwrap();
+        assert!(out.iter().all(|x| *x == 7));
+        drop((buf, copy));
+        assert!(mem_used() < big);
+        free_cache();
+    }
+
+    #[test]
+    fn test_memory_planner() {
+        let x = Tensor::new((0..16).map(|i| i as f64).collect(), &[4, 4]);
+        begin_capture();
+        // a chain: each intermediate dies right after the next kernel reads it
+        let mut y = x.clone();
+        for i in 0..6 {
+            y = &(&y + &y.full_like(i as f64)) * &y;
+        }
+        let out = y.sum(Some(&[1]), false);
+        let mut items = end_capture().items;
+        let expected = out.to_vec();
+        let lifetimes = buffer_lifetimes(&items);
+        assert_eq!(lifetimes.len(), items.len());
+        assert!(lifetimes.iter().all(|(_, s, e)| s <= e));
+        assert_eq!(peak_memory(&[(10, 0, 1), (20, 1, 2), (30, 3, 3), (5, 2, 4)]), 35);
+        assert_eq!(peak_memory(&[]), 0);
+
+        let noopt = vec![x.lazydata().base, out.lazydata().base];
+        drop((y, out));
+        memory_planner(&mut items, &noopt);
+        let mut phys: Vec<Storage> = vec![];
+        for ei in &items {
+            for b in &ei.bufs {
+                if !phys.iter().any(|p| Arc::ptr_eq(p, b)) {
+                    phys.push(b.clone());
+                }
+            }
+        }
+        // x, then three 4x4 buffers taking turns (y, y + i and the next y) instead of twelve
+        assert_eq!(phys.iter().filter(|p| p.read().unwrap().size == 16).count(), 4);
+        for ei in &items {
+            ei.run();
+        }
+        let out = items.last().unwrap().bufs[0].clone();
+        let mut bytes = vec![0; 4 * 8];
+        out.read().unwrap().copyout(&mut bytes).unwrap();
+        let got: Vec<f64> = bytes.chunks(8).map(|c| f64::from_le_bytes(c.try_into().unwrap())).collect();
+        assert_eq!(got, expected);
+    }
+
+    #[test]
+    fn test_kernel_reporting() {
+        assert_eq!(colored("x", Some("red"), None), "\u{1b}[31mx\u{1b}[0m");
+        assert_eq!(colored("x", Some("BLUE"), Some(true)), "\u{1b}[104mx\u{1b}[0m");
+        assert_eq!(ansilen(&colored("abc", Some("green"), None)), 3);
+
+        // a multiply and an add per step of the reduce, whatever the opts
+        let (m, n, k) = (4, 8, 6);
+        let mut lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum));
+        assert_eq!(ansilen(&lin.display_name()), lin.name().len());
+        let mut unrolled = lin.clone();
+        unrolled.apply_opt(Opt::new(OptOps::Upcast, 0, 2)).unwrap();
+        unrolled.apply_opt(Opt::new(OptOps::Unroll, 0, 0)).unwrap();
+        assert_eq!(unrolled.colors(), vec!["blue", "blue", "yellow", "magenta"]);
+        assert_eq!(flops(lin.linearize(), &[]), 2 * m * n * k);
+        assert_eq!(flops(unrolled.linearize(), &[]), 2 * m * n * k);
+
+        // every kernel run is counted
+        let before = GlobalCounter.lock().unwrap().kernel_count;
+        let _ = (&Tensor::ones(&[3]) + &Tensor::ones(&[3])).sum(None, false);
+        assert!(GlobalCounter.lock().unwrap().kernel_count >= before + 2);
+    }
+
+    #[test]
+    fn test_split_globals() {
+        let (m, n, k) = (37, 8, 16);
+        let mut lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum));
+        lin.split_globals = true;
+        lin.apply_opt(Opt::new(OptOps::Upcast, 1, 4)).unwrap();
+        assert!(lin.is_split());
+        let name = lin.name();
+        let uops = lin.linearize().to_vec();
+        assert_eq!(uops.iter().filter(|u| u.uop == UOps::DefineVar).count(), 2);
+        assert_eq!(flops(&uops, &[("gstart", 0), ("gend", m)]), 2 * m * n * k);
+
+        let a: Vec<f32> = (0..m * k).map(|i| (i % 7) as f32 - 3.0).collect();
+        let b: Vec<f32> = (0..k * n).map(|i| (i % 5) as f32 * 0.5).collect();
+        let bufs = vec![vec![0; m * n * 4], f32_bytes(&a), f32_bytes(&b)];
+        let run = |call: &dyn Fn(&mut [&mut [u8]])| {
+            let mut bufs = bufs.clone();
+            let mut refs: Vec<&mut [u8]> = bufs.iter_mut().map(|b| b.as_mut_slice()).collect();
+            call(&mut refs);
+            bytes_f32(&bufs[0])
+        };
+        let interp = InterpProgram::new(&name, &ops_interp::render(&uops)).unwrap();
+        let expected = run(&|b| {
+            interp.call_vars(b, &[0, m as i32], false);
+        });
+        // only the rows in range get written
+        let part = run(&|b| {
+            interp.call_vars(b, &[3, 5], false);
+        });
+        for (i, x) in part.iter().enumerate() {
+            assert_eq!(*x, if (3 * n..5 * n).contains(&i) { expected[i] } else { 0.0 });
+        }
+        if find_compiler().is_some() {
+            let prg = compile_uops(&name, &uops).unwrap();
+            for threads in [1, 2, 7, 64] {
+                let out = run(&|b| {
+                    prg.call_split(b, m, threads, false);
+                });
+                assert_eq!(out, expected, "{} threads", threads);
+            }
+        }
+
+        // pool jobs can borrow from the caller
+        let pool = ThreadPool::new(3);
+        let mut outs = vec![0; 10];
+        pool.scope(outs.iter_mut().enumerate().map(|(i, o)| Box::new(move || *o = i * i) as Box<dyn FnOnce() + Send>).collect());
+        assert_eq!(outs, (0..10).map(|i| i * i).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_tiny_jit() {
+        let n = Variable { expr: "n".to_string(), min: 1, max: 10 };
+        let f = |x: &Tensor, y: &Tensor, n: &Tensor| (&(x * y) + &n.reshape(&[1, 1]).expand(&[2, 3])).sum(None, false);
+        let mut calls = 0;
+        let mut jit = TinyJit::new(|inputs: &[Tensor], var_vals: &HashMap<String, isize>| {
+            calls += 1;
+            let n = Tensor::bind(&Variable { expr: "n".to_string(), min: 1, max: 10 }, var_vals["n"]);
+            vec![f(&inputs[0], &inputs[1], &n)]
+        });
+        for i in 0..5 {
+            let x = Tensor::new((0..6).map(|j| (i * j) as f64).collect(), &[2, 3]);
+            let y = Tensor::new(vec![i as f64 + 1.0; 6], &[2, 3]);
+            let var_vals = HashMap::from([("n".to_string(), i as isize + 1)]);
+            let expected = f(&x, &y, &Tensor::bind(&n, i as isize + 1)).to_vec();
+            assert_eq!(jit.call(&[x, y], &var_vals)[0].to_vec(), expected, "call {}", i);
+        }
+        drop(jit);
+        // warmup and capture, every later call is a replay
+        assert_eq!(calls, if crate::prelude::JIT.clone() == 0 { 5 } else { 2 });
+
+        // outputs kept across calls don't change when the next replay runs
+        let mut jit = TinyJit::new(|inputs: &[Tensor], _: &HashMap<String, isize>| vec![&inputs[0] * &inputs[0]]);
+        let outs: Vec<Tensor> = (0..4)
+            .map(|i| jit.call(&[Tensor::new(vec![i as f64; 3], &[3])], &HashMap::new()).remove(0))
+            .collect();
+        for (i, out) in outs.iter().enumerate() {
+            assert_eq!(out.to_vec(), vec![(i * i) as f64; 3], "call {}", i);
+        }
+    }
+
+    #[test]
+    fn test_beam_search() {
+        let (m, n, k) = (4, 8, 6);
+        let lin = Linearizer::new(matmul_ast(m, n, k, ReduceOps::Sum));
+        let bufs = bufs_from_lin(&lin);
+        assert_eq!(bufs.iter().map(|b| b.len()).collect::<Vec<_>>(), vec![m * n * 4, m * k * 4, k * n * 4]);
+        let actions = get_linearizer_actions(&lin);
+        assert!(!actions.is_empty() && actions.iter().all(|l| l.applied_opts.len() == 1));
+        if find_compiler().is_none() {
+            return;
+        }
+        let best = beam_search(&lin, &bufs, 2);
+        // ones in, so every output is k
+        let (out, _) = run_both(best.clone(), &bufs_from_lin(&lin));
+        assert_eq!(bytes_f32(&out), vec![k as f32; m * n]);
+        // the second search is a diskcache hit with the same opts
+        if *helpers::CACHELEVEL >= 1 {
+            assert_eq!(beam_search(&lin, &bufs, 2).applied_opts, best.applied_opts);
+        }
+    }
+
+    #[test]
+    fn test_runner_beam() {
+        if find_compiler().is_none() || *helpers::CACHELEVEL == 0 {
+            return;
+        }
+        // BEAM is read once per process
+        if std::env::var("RUSTGRAD_TEST_CHILD").is_err() {
+            rerun_with("tests::test_runner_beam", &[("BEAM", "2")]);
+            return;
+        }
+        // a Runner's kernel is searched, and the winning opts cached by AST
+        let ast = matmul_ast(4, 8, 6, ReduceOps::Sum);
+        let key = beam_key(&Linearizer::new(ast.clone()), 2);
+        let searched = Runner::new(ast.clone(), "CLANG").unwrap().applied_opts;
+        assert_eq!(diskcache_get::<Vec<Opt>>("beam_search", Box::new(key.clone())), Some(searched));
+        // lowering it again takes whatever the cache says instead of searching
+        let cached = vec![Opt::new(OptOps::Padto, 2, 8)];
+        diskcache_put("beam_search", Box::new(key), cached.clone()).unwrap();
+        assert_eq!(Runner::new(ast.clone(), "CLANG").unwrap().applied_opts, cached);
+        assert_eq!(lowerer(ast).applied_opts, cached);
+    }
+
+    #[test]
+    fn test_interp_dtypes() {
+        let bin = |op: BinaryOps, dtype, x, y| exec_alu_typed(op.into(), dtype, &[x, y]);
+        assert_eq!(bin(BinaryOps::Add, DType::INT8, Val::Int(127), Val::Int(1)), Val::Int(-128));
+        assert_eq!(bin(BinaryOps::Sub, DType::UINT8, Val::UInt(0), Val::UInt(1)), Val::UInt(255));
+        assert_eq!(bin(BinaryOps::Div, DType::INT32, Val::Int(-7), Val::Int(2)), Val::Int(-3));
+        assert_eq!(bin(BinaryOps::Mod, DType::INT32, Val::Int(-7), Val::Int(2)), Val::Int(-1));
+        assert_eq!(bin(BinaryOps::Mul, DType::UINT32, Val::UInt(1 << 31), Val::UInt(2)), Val::UInt(0));
+        assert_eq!(bin(BinaryOps::CmpLt, DType::BOOL, Val::UInt(3), Val::UInt(5)), Val::Bool(true));
+        assert_eq!(bin(BinaryOps::Div, DType::FLOAT32, Val::Float(1.0), Val::Float(3.0)), Val::Float((1.0f32 / 3.0) as f64));
+        assert_eq!(exec_alu_typed(Op::Unary(UnaryOps::Neg), DType::INT64, &[Val::Int(i64::MIN)]), Val::Int(i64::MIN));
+        assert_eq!(Val::Float(-2.7).cast(DType::INT32), Val::Int(-2));
+        assert_eq!(Val::Float(0.5).cast(DType::BOOL), Val::Bool(true));
+        assert_eq!(Val::Int(-1).cast(DType::UINT16), Val::UInt(65535));
+        // half: exact values, rounding, overflow and subnormals
+        assert_eq!(f32_to_f16(1.0), 0x3c00);
+        assert_eq!(f32_to_f16(65504.0), 0x7bff);
+        assert_eq!(f32_to_f16(65520.0), 0x7c00);
+        assert_eq!(f32_to_f16(-2.0f32.powi(-24)), 0x8001);
+        assert_eq!(f32_to_f16(1e-8), 0);
+        assert_eq!(f16_to_f32(f32_to_f16(0.1)), 0.099975586);
+        for h in [0x0001u16, 0x03ff, 0x0400, 0x3555, 0x7bff, 0xc000] {
+            assert_eq!(f32_to_f16(f16_to_f32(h)), h);
+        }
+        // every dtype round trips through a buffer
+        for dtype in DType::ALL {
+            let mut buf = vec![0u8; dtype.itemsize() * 2];
+            let v = Val::from_f64(3.0, dtype);
+            v.store(&mut buf, 1, dtype);
+            assert_eq!(Val::load(&buf, 1, dtype), v, "{}", dtype);
+        }
+    }
+
+    #[test]
+    fn test_threefry_known_answers() {
+        // Random123 kat_vectors for threefry2x32_20
+        assert_eq!(threefry2x32([0, 0], [0, 0]), [0x6b200159, 0x99ba4efe]);
+        assert_eq!(
+            threefry2x32([0xffffffff, 0xffffffff], [0xffffffff, 0xffffffff]),
+            [0x1cb996fc, 0xbb002be7]
+        );
+        assert_eq!(
+            threefry2x32([0x13198a2e, 0x03707344], [0x243f6a88, 0x85a308d3]),
+            [0xc4923a9c, 0x483df7a0]
+        );
+    }
+
+    #[test]
+    fn test_threefry_chunking_is_bit_identical() {
+        let gen = Threefry::new(1337);
+        let serial = gen.random_bits(5, 1001);
+        for threads in [1, 2, 3, 8, 2000] {
+            assert_eq!(gen.random_bits_parallel(5, 1001, threads), serial);
+        }
+        // a draw starting mid-stream sees the same words
+        assert_eq!(gen.random_bits(505, 10), serial[500..510]);
+        let keys = gen.split(4);
+        assert_eq!(keys, gen.split(4));
+        assert_eq!(keys.iter().collect::<HashSet<_>>().len(), 4);
+        assert_ne!(keys[0].random_bits(0, 4), serial[..4]);
+    }
+
+    #[test]
+    fn test_conv2d() {
+        // WINO is read once per process, the child checks conv2d takes winograd only when it applies
+        if std::env::var("RUSTGRAD_TEST_CHILD").is_err() {
+            rerun_with("tests::test_conv2d", &[("WINO", "1"), ("IMAGE", "0")]);
+        }
+        // images hold float32s
+        let tol = if crate::prelude::IMAGE.clone() >= 1 { 1e-5 } else { 1e-9 };
+        let data = |n: usize, k: f64| (0..n).map(|i| (i as f64 * k).sin()).collect::<Vec<_>>();
+        let (bs, cin, cout, groups, h, w) = (1, 4, 4, 2, 6, 7);
+        let x = Tensor::new(data(bs * cin * h * w, 0.7), &[bs, cin, h, w]);
+        let wt = Tensor::new(data(cout * cin / groups * 9, 1.3), &[cout, cin / groups, 3, 3]);
+        let b = Tensor::new(data(cout, 2.1), &[cout]);
+        let (xv, wv, bv) = (x.to_vec(), wt.to_vec(), b.to_vec());
+        for (stride, dilation, padding) in [(1, 1, 0), (1, 1, 1), (2, 1, 1), (1, 2, 2)] {
+            let out = x.conv2d(&wt, Some(&b), groups, stride, dilation, padding);
+            let (oh, ow) = ((h + 2 * padding - dilation * 2 - 1) / stride + 1, (w + 2 * padding - dilation * 2 - 1) / stride + 1);
+            assert_eq!(out.shape(), vec![bs, cout, oh, ow]);
+            let mut expected = vec![];
+            for idx in all_int_indices(&[bs, cout, oh, ow]) {
+                let (n, o, y, z) = (idx[0], idx[1], idx[2], idx[3]);
+                let mut acc = bv[o];
+                for k in all_int_indices(&[cin / groups, 3, 3]) {
+                    let (c, ky, kx) = (k[0], k[1], k[2]);
+                    let (iy, ix) = ((y * stride + ky * dilation) as isize - padding as isize, (z * stride + kx * dilation) as isize - padding as isize);
+                    if (0..h as isize).contains(&iy) && (0..w as isize).contains(&ix) {
+                        let ci = o / (cout / groups) * (cin / groups) + c;
+                        acc += xv[((n * cin + ci) * h + iy as usize) * w + ix as usize] * wv[((o * (cin / groups) + c) * 3 + ky) * 3 + kx];
+                    }
+                }
+                expected.push(acc);
+            }
+            assert!(out.to_vec().iter().zip(&expected).all(|(a, e)| (a - e).abs() < tol), "{:?}", (stride, dilation, padding));
+        }
+
+        // winograd agrees with the direct convolution, including partial output tiles
+        for padding in [0, 1, 2] {
+            let direct = x.conv2d(&wt, Some(&b), groups, 1, 1, padding).to_vec();
+            let wino = x.conv_winograd(&wt, Some(&b), groups, padding);
+            assert_eq!(wino.shape(), vec![bs, cout, h + 2 * padding - 2, w + 2 * padding - 2]);
+            assert!(wino.to_vec().iter().zip(&direct).all(|(a, e)| (a - e).abs() < tol), "padding {}", padding);
+        }
+
+        // which path conv2d took, by the kernels it ran. IMAGE=1 takes the image path, see test_image
+        if crate::prelude::IMAGE.clone() >= 1 {
+            return;
+        }
+        let kernels = |f: &dyn Fn() -> Tensor| {
+            begin_capture();
+            f();
+            end_capture().items.iter().map(|ei| ei.prg.name.clone()).collect::<Vec<_>>()
+        };
+        let wt5 = Tensor::new(data(cout * cin / groups * 25, 1.3), &[cout, cin / groups, 5, 5]);
+        for (wt, stride, dilation) in [(&wt5, 1, 1), (&wt, 1, 2), (&wt, 2, 1)] {
+            let direct = kernels(&|| x.conv_direct(wt, Some(&b), groups, stride, dilation, 1));
+            assert_eq!(kernels(&|| x.conv2d(wt, Some(&b), groups, stride, dilation, 1)), direct, "{:?}", (stride, dilation));
+        }
+        let conv = kernels(&|| x.conv2d(&wt, Some(&b), groups, 1, 1, 1));
+        let wino = kernels(&|| x.conv_winograd(&wt, Some(&b), groups, 1));
+        assert_ne!(wino, kernels(&|| x.conv_direct(&wt, Some(&b), groups, 1, 1, 1)));
+        assert_eq!(conv == wino, crate::prelude::WINO.clone() == 1);
+    }
+
+    #[test]
+    fn test_image() {
+        // IMAGE is read once per process, the child runs the convs below through the image path
+        if std::env::var("RUSTGRAD_TEST_CHILD").is_err() {
+            rerun_with("tests::test_image", &[("IMAGE", "1")]);
+        }
+        assert_eq!(DType::imagef(2, 3).to_string(), "dtypes.imagef((2, 3, 4))");
+
+        // a float buffer written into a 2x3 image a texel at a time, then read back out plus one
+        let st = View::create(&[2, 3, 4], None, 0, None);
+        let img = DType::imagef(2, 3);
+        let x: Vec<f32> = (0..24).map(|i| i as f32 - 3.5).collect();
+        let mut lin = Linearizer::new(LazyOp::store(LazyOp::load(1, DType::FLOAT32, st.clone()), 0, img, st.clone()));
+        lin.apply_opt(Opt::new(OptOps::Upcast, 2, 4)).unwrap();
+        assert!(ops_clang::render("test", &lin.linearize().to_vec()).contains("write_imagef(data0, 3, ("));
+        let (written, c) = run_both(lin, &[vec![0u8; 96], f32_bytes(&x)]);
+        assert_eq!(bytes_f32(&written), x);
+        if let Some(c) = c {
+            assert_eq!(written, c);
+        }
+        let one = LazyOp::constant(1.0, DType::FLOAT32, st.clone());
+        let add = LazyOp::new(BinaryOps::Add, vec![LazyOp::load(1, img, st.clone()), one], None);
+        let mut lin = Linearizer::new(LazyOp::store(add, 0, DType::FLOAT32, st));
+        lin.apply_opt(Opt::new(OptOps::Upcast, 2, 4)).unwrap();
+        assert!(ops_clang::render("test", &lin.linearize().to_vec()).contains("read_imagef(data1, 3, ("));
+        let (read, c) = run_both(lin, &[vec![0u8; 96], written]);
+        assert_eq!(bytes_f32(&read), x.iter().map(|v| v + 1.0).collect::<Vec<_>>());
+        if let Some(c) = c {
+            assert_eq!(read, c);
+        }
+
+        // conv2d with IMAGE=1 stores its input, weights and output as images and agrees with the
+        // direct conv, with channel counts that need padding
+        let image_mode = crate::prelude::IMAGE.clone() >= 1;
+        let data = |n: usize, k: f64| (0..n).map(|i| (i as f64 * k).sin()).collect::<Vec<_>>();
+        for (cin, cout, groups, hw, k, stride, padding) in [(3, 6, 1, 5, 3, 1, 1), (8, 4, 2, 4, 3, 2, 0), (4, 4, 4, 4, 3, 1, 1), (5, 3, 1, 1, 1, 1, 0)] {
+            let x = Tensor::new(data(cin * hw * hw, 0.7), &[1, cin, hw, hw]);
+            let wt = Tensor::new(data(cout * cin / groups * k * k, 1.3), &[cout, cin / groups, k, k]);
+            let b = Tensor::new(data(cout, 2.1), &[cout]);
+            let direct = x.conv_direct(&wt, Some(&b), groups, stride, 1, padding);
+            begin_capture();
+            let image = x.conv2d(&wt, Some(&b), groups, stride, 1, padding);
+            let images = end_capture().items.iter().filter(|ei| ei.bufs[0].read().unwrap().dtype.image.is_some()).count();
+            assert_eq!(images, if image_mode { 3 } else { 0 });
+            assert_eq!(image.shape(), direct.shape());
+            assert!(image.to_vec().iter().zip(&direct.to_vec()).all(|(a, e)| (a - e).abs() < 1e-5), "{:?}", (cin, cout, groups));
+        }
+    }
+
+    #[test]
+    fn test_multi() {
+        let devices = ["INTERP:1", "INTERP:2", "INTERP:3", "INTERP:4"];
+        let data: Vec<f64> = (0..24).map(|i| (i as f64 * 0.9).sin()).collect();
+        let x = LazyBuffer::from_vec(data.clone(), &[8, 3]);
+        let xs = MultiLazyBuffer::shard(&x, &devices, Some(0)).unwrap();
+        assert_eq!(xs.devices(), devices);
+        assert!(xs.lbs.iter().all(|lb| lb.shape() == [2, 3]));
+        assert_eq!((xs.shape(), xs.to_vec()), (vec![8, 3], data.clone()));
+
+        // elementwise against a replicated buffer runs on each device's slice of it
+        let y = MultiLazyBuffer::shard(&LazyBuffer::from_vec((0..24).map(|i| i as f64).collect(), &[8, 3]), &devices, None).unwrap();
+        let z = xs.e(BinaryOps::Mul, &[&y]);
+        assert_eq!(z.axis, Some(0));
+        assert_eq!(z.to_vec(), data.iter().enumerate().map(|(i, x)| x * i as f64).collect::<Vec<_>>());
+
+        // reducing the split axis all-reduces, the others stay split
+        let col_sums: Vec<f64> = (0..3).map(|j| (0..8).map(|i| data[i * 3 + j]).sum()).collect();
+        let s = xs.r(ReduceOps::Sum, &[0]);
+        assert_eq!((s.axis, s.devices()), (None, xs.devices()));
+        for lb in &s.lbs {
+            assert!(lb.to_vec().iter().zip(&col_sums).all(|(a, b)| (a - b).abs() < 1e-12));
+        }
+        let m = xs.permute(&[1, 0]).r(ReduceOps::Max, &[0]);
+        assert_eq!(m.axis, Some(1));
+        assert_eq!(m.to_vec(), (0..8).map(|i| data[i * 3..i * 3 + 3].iter().cloned().fold(f64::MIN, f64::max)).collect::<Vec<_>>());
+
+        // ring and naive agree, also when the buffer doesn't split evenly into chunks
+        let lbs: Vec<LazyBuffer> = devices[..3].iter().enumerate().map(|(d, dev)| LazyBuffer::from_vec((0..7).map(|i| (i * (d + 2) % 5) as f64).collect(), &[7]).to_device(dev).unwrap()).collect();
+        for op in [ReduceOps::Sum, ReduceOps::Max] {
+            let expected = naive_all_reduce(op, &lbs).unwrap();
+            let ring = ring_all_reduce(op, &lbs).unwrap();
+            for (r, (e, lb)) in ring.iter().zip(expected.iter().zip(&lbs)) {
+                assert_eq!((&r.device, r.to_vec()), (&lb.device, e.to_vec()));
+            }
+        }
+        // nothing to shard over or reduce is an error
+        assert!(MultiLazyBuffer::shard(&x, &[], None).is_err());
+        assert!(naive_all_reduce(ReduceOps::Sum, &[]).is_err() && ring_all_reduce(ReduceOps::Sum, &[]).is_err());
+
+        // a batch sharded over virtual devices of the default one gives the loss and gradients a
+        // single device does, with the gradient of the replicated weights on every device
+        let virt: Vec<String> = (0..4).map(|i| format!("{}:{}", default_device(), i)).collect();
+        let virt: Vec<&str> = virt.iter().map(|d| d.as_str()).collect();
+        let xd: Vec<f64> = (0..24).map(|i| (i as f64 * 0.37).cos()).collect();
+        let wd: Vec<f64> = (0..6).map(|i| (i as f64 * 1.1).sin()).collect();
+        let step = |x: &Tensor, w: &Tensor| {
+            let loss = x.matmul(w).relu().mean(None, false);
+            loss.backward();
+            (loss.item(), w.grad().unwrap())
+        };
+        let (loss, grad) = step(&Tensor::new(xd.clone(), &[8, 3]), &Tensor::new(wd.clone(), &[3, 2]).requires_grad_(true));
+        let x = Tensor::new(xd, &[8, 3]).shard(&virt, Some(0)).unwrap();
+        assert_eq!((x.devices(), x.shape()), (virt.iter().map(|d| d.to_string()).collect(), vec![8, 3]));
+        let w = Tensor::new(wd, &[3, 2]).shard(&virt, None).unwrap().requires_grad_(true);
+        let (sharded_loss, sharded_grad) = step(&x, &w);
+        assert!((loss - sharded_loss).abs() < 1e-12);
+        assert_eq!(sharded_grad.devices(), w.devices());
+        assert!(sharded_grad.to_vec().iter().zip(&grad.to_vec()).all(|(a, b)| (a - b).abs() < 1e-12));
+        assert!(Tensor::new(vec![1.0], &[1]).shard(&[], None).is_err());
+    }
+
+    #[test]
+    fn test_manual_seed_reproducible() {
+        // the only test that draws from the global stream, so nothing races the counter
+        Tensor::manual_seed(42);
+        let a = (Tensor::rand(&[4, 5]).to_vec(), Tensor::randn(&[7]).to_vec());
+        Tensor::manual_seed(42);
+        let b = (Tensor::rand(&[4, 5]).to_vec(), Tensor::randn(&[7]).to_vec());
+        assert_eq!(a, b);
+        assert!(a.0.iter().all(|x| (0.0..1.0).contains(x)));
+        let r = Tensor::randint(&[100], -3, 4).to_vec();
+        assert!(r.iter().all(|x| x.fract() == 0.0 && (-3.0..4.0).contains(x)));
+        let k = Tensor::kaiming_uniform(&[8, 16], None).to_vec();
+        let bound = 3f64.sqrt() * (2.0 / (1.0 + 0.01 * 0.01f64)).sqrt() / 4.0;
+        assert!(k.iter().all(|x| x.abs() <= bound));
+        let g = Tensor::glorot_uniform(&[8, 16]).to_vec();
+        assert!(g.iter().all(|x| x.abs() <= (6.0f64 / 24.0).sqrt()));
+    }
+
 
 #[init_c_struct_t( field1 = i32, field2 = f64, field3 = u8 )]
 struct MyStruct{
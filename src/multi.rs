@@ -0,0 +1,527 @@
+// one logical buffer spread over several devices. Each device holds a shard: an equal slice along
+// `axis`, or the whole thing when `axis` is None. Elementwise ops run shard by shard on each device;
+// reducing over the sharded axis leaves partial results everywhere, combined by an all-reduce.
+// `Tensor::shard` puts one behind a tensor, `LazyData` is what the autograd functions run on
+use anyhow::anyhow;
+
+use crate::{
+    lazy::LazyBuffer,
+    ops::{BinaryOps, Op, ReduceOps},
+    prelude::{DEBUG, RING},
+};
+
+/// `op` over every buffer in `lbs`, one per device, with the result back on every device. Ring
+/// all-reduce with `RING>=1`, otherwise every device pulls the whole of every other buffer.
+pub fn all_reduce(op: ReduceOps, lbs: &[LazyBuffer]) -> Result<Vec<LazyBuffer>, anyhow::Error> {
+    if RING.clone() >= 1 {
+        ring_all_reduce(op, lbs)
+    } else {
+        naive_all_reduce(op, lbs)
+    }
+}
+
+pub fn naive_all_reduce(
+    op: ReduceOps,
+    lbs: &[LazyBuffer],
+) -> Result<Vec<LazyBuffer>, anyhow::Error> {
+    let first = lbs.first().ok_or(anyhow!("no buffers to all-reduce"))?;
+    if DEBUG.clone() >= 2 {
+        println!("NAIVE ALLREDUCE {}x{}", lbs.len(), first.st.size());
+    }
+    lbs.iter()
+        .map(|lb| {
+            let on_lb = lbs
+                .iter()
+                .map(|x| x.to_device(&lb.device))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(on_lb[1..]
+                .iter()
+                .fold(on_lb[0].clone(), |x, y| x.e(op.binary(), &[y])))
+        })
+        .collect()
+}
+
+/// Each of the n devices reduces one of n chunks while passing the others along the ring
+/// (scatter-reduce), then the reduced chunks go round once more (all-gather). Every device sends
+/// and receives `2 * (n - 1) / n` of the buffer, however many devices there are.
+pub fn ring_all_reduce(
+    op: ReduceOps,
+    lbs: &[LazyBuffer],
+) -> Result<Vec<LazyBuffer>, anyhow::Error> {
+    let first = lbs.first().ok_or(anyhow!("no buffers to all-reduce"))?;
+    let (n, shape) = (lbs.len(), first.shape().to_vec());
+    let dim: usize = shape.iter().product();
+    if DEBUG.clone() >= 2 {
+        println!("RING ALLREDUCE {}x{}", n, dim);
+    }
+    let (base, left) = (dim / n, dim % n);
+    let mut chunks = vec![];
+    let mut acc = 0;
+    for i in 0..n {
+        let size = base + (i < left) as usize;
+        if size > 0 {
+            chunks.push((acc, acc + size));
+            acc += size;
+        }
+    }
+    let mut chunked: Vec<Vec<LazyBuffer>> = lbs
+        .iter()
+        .map(|lb| {
+            let flat = lb.reshape(&[dim]);
+            chunks.iter().map(|c| flat.shrink(&[*c])).collect()
+        })
+        .collect();
+
+    // after step s, device i + s + 1 holds chunk i reduced over devices i..=i + s + 1
+    for step in 0..n - 1 {
+        for (i, _) in chunks.iter().enumerate() {
+            let (src, dest) = ((i + step) % n, (i + step + 1) % n);
+            let sent = chunked[src][i].to_device(&lbs[dest].device)?;
+            chunked[dest][i] = chunked[dest][i].e(op.binary(), &[&sent]);
+        }
+    }
+    // chunk i is complete on device i - 1, pass it the rest of the way round
+    for step in 0..n - 1 {
+        for (i, _) in chunks.iter().enumerate() {
+            let (src, dest) = ((i + step + n - 1) % n, (i + step) % n);
+            chunked[dest][i] = chunked[src][i].to_device(&lbs[dest].device)?;
+        }
+    }
+
+    Ok(chunked
+        .into_iter()
+        .map(|parts| {
+            parts
+                .iter()
+                .zip(&chunks)
+                .map(|(c, (s, e))| c.pad(&[(*s, dim - e)]))
+                .reduce(|x, y| x.e(BinaryOps::Add, &[&y]))
+                .unwrap()
+                .reshape(&shape)
+        })
+        .collect())
+}
+
+#[derive(Clone, Debug)]
+pub struct MultiLazyBuffer {
+    /// one buffer per device, in shard order. Never empty, `shard` won't build one without devices
+    pub(crate) lbs: Vec<LazyBuffer>,
+    /// the axis the shards split, None when every device holds all of it
+    pub(crate) axis: Option<usize>,
+}
+
+impl MultiLazyBuffer {
+    /// Split `lb` into equal parts along `axis`, one on each of `devices`, or copy it to all of them
+    /// with no axis.
+    pub fn shard(
+        lb: &LazyBuffer,
+        devices: &[&str],
+        axis: Option<usize>,
+    ) -> Result<Self, anyhow::Error> {
+        if devices.is_empty() {
+            return Err(anyhow!("can't shard {:?} across no devices", lb.shape()));
+        }
+        let lbs = match axis {
+            None => devices
+                .iter()
+                .map(|d| lb.to_device(d))
+                .collect::<Result<_, _>>()?,
+            Some(a) => {
+                let size = lb.shape()[a];
+                if !size.is_multiple_of(devices.len()) {
+                    return Err(anyhow!(
+                        "can't split axis {} of {:?} across {} devices",
+                        a,
+                        lb.shape(),
+                        devices.len()
+                    ));
+                }
+                let sz = size / devices.len();
+                devices
+                    .iter()
+                    .enumerate()
+                    .map(|(i, d)| {
+                        let arg = shard_arg(lb.shape(), a, (i * sz, (i + 1) * sz));
+                        lb.shrink(&arg).to_device(d)
+                    })
+                    .collect::<Result<_, _>>()?
+            }
+        };
+        Ok(Self { lbs, axis })
+    }
+
+    pub fn lbs(&self) -> &[LazyBuffer] {
+        &self.lbs
+    }
+
+    pub fn axis(&self) -> Option<usize> {
+        self.axis
+    }
+
+    pub fn devices(&self) -> Vec<String> {
+        self.lbs.iter().map(|lb| lb.device.clone()).collect()
+    }
+
+    /// The shape of the whole buffer.
+    pub fn shape(&self) -> Vec<usize> {
+        let mut shape = self.lbs[0].shape().to_vec();
+        if let Some(a) = self.axis {
+            shape[a] = self.lbs.iter().map(|lb| lb.shape()[a]).sum();
+        }
+        shape
+    }
+
+    /// Where along `axis` each shard starts and ends.
+    pub fn bounds(&self) -> Vec<(usize, usize)> {
+        let a = self.axis.expect("a replicated buffer has no bounds");
+        let mut acc = 0;
+        self.lbs
+            .iter()
+            .map(|lb| {
+                acc += lb.shape()[a];
+                (acc - lb.shape()[a], acc)
+            })
+            .collect()
+    }
+
+    /// The whole buffer on `device`: every shard copied over, padded to full size and summed.
+    pub fn to_device(&self, device: &str) -> LazyBuffer {
+        let Some(a) = self.axis else {
+            return self.lbs[0].to_device(device).unwrap();
+        };
+        let shape = self.shape();
+        self.lbs
+            .iter()
+            .zip(self.bounds())
+            .map(|(lb, (s, e))| {
+                let mut arg = vec![(0, 0); shape.len()];
+                arg[a] = (s, shape[a] - e);
+                lb.to_device(device).unwrap().pad(&arg)
+            })
+            .reduce(|x, y| x.e(BinaryOps::Add, &[&y]))
+            .unwrap()
+    }
+
+    pub fn to_vec(&self) -> Vec<f64> {
+        self.to_device(&self.lbs[0].device).to_vec()
+    }
+
+    /// This buffer's shard on device `i`, cut from the full copy there when it's replicated.
+    fn shard_on(&self, i: usize, axis: Option<usize>, bounds: &[(usize, usize)]) -> LazyBuffer {
+        match (self.axis, axis) {
+            (None, Some(a)) => self.lbs[i].shrink(&shard_arg(self.lbs[i].shape(), a, bounds[i])),
+            _ => self.lbs[i].clone(),
+        }
+    }
+
+    /// `op` shard by shard. Sources on the same devices either share the split or are replicated,
+    /// in which case each device uses its slice.
+    pub fn e(&self, op: impl Into<Op>, srcs: &[&MultiLazyBuffer]) -> MultiLazyBuffer {
+        let op = op.into();
+        let all: Vec<&MultiLazyBuffer> =
+            std::iter::once(self).chain(srcs.iter().copied()).collect();
+        for s in &all {
+            assert_eq!(
+                s.devices(),
+                self.devices(),
+                "srcs of {:?} on different devices",
+                op
+            );
+            assert_eq!(
+                s.shape(),
+                self.shape(),
+                "all srcs of {:?} must have the same shape",
+                op
+            );
+        }
+        let axis = all.iter().find_map(|s| s.axis);
+        assert!(
+            all.iter().all(|s| s.axis.is_none() || s.axis == axis),
+            "srcs of {:?} are split along different axes",
+            op
+        );
+        let bounds = all
+            .iter()
+            .find(|s| s.axis.is_some())
+            .map_or(vec![], |s| s.bounds());
+        let lbs = (0..self.lbs.len())
+            .map(|i| {
+                let shards: Vec<LazyBuffer> =
+                    all.iter().map(|s| s.shard_on(i, axis, &bounds)).collect();
+                let rest: Vec<&LazyBuffer> = shards[1..].iter().collect();
+                shards[0].e(op, &rest)
+            })
+            .collect();
+        MultiLazyBuffer { lbs, axis }
+    }
+
+    /// Reduce over `axis`, keeping the reduced dims as 1. Reducing the split axis all-reduces the
+    /// per-device results, leaving the result on every device.
+    pub fn r(&self, op: ReduceOps, axis: &[usize]) -> MultiLazyBuffer {
+        let lbs: Vec<LazyBuffer> = self.lbs.iter().map(|lb| lb.r(op, axis)).collect();
+        match self.axis {
+            Some(a) if axis.contains(&a) => MultiLazyBuffer {
+                lbs: all_reduce(op, &lbs).unwrap_or_else(|e| panic!("{}", e)),
+                axis: None,
+            },
+            _ => MultiLazyBuffer {
+                lbs,
+                axis: self.axis,
+            },
+        }
+    }
+
+    fn map(&self, f: impl Fn(&LazyBuffer) -> LazyBuffer) -> MultiLazyBuffer {
+        MultiLazyBuffer {
+            lbs: self.lbs.iter().map(f).collect(),
+            axis: self.axis,
+        }
+    }
+
+    pub fn const_like(&self, val: f64) -> MultiLazyBuffer {
+        self.map(|lb| lb.const_like(val))
+    }
+
+    pub fn contiguous(&self) -> MultiLazyBuffer {
+        self.map(|lb| lb.contiguous())
+    }
+
+    /// Reshape each shard. The split axis becomes the axis of `new_shape` that starts at the same
+    /// element and splits into as many equal parts, so each device keeps the elements it has.
+    pub fn reshape(&self, new_shape: &[usize]) -> MultiLazyBuffer {
+        let Some(a) = self.axis else {
+            return self.map(|lb| lb.reshape(new_shape));
+        };
+        let (shape, n) = (self.shape(), self.lbs.len());
+        let before: usize = shape[..a].iter().product();
+        let per_shard = shape[a..].iter().product::<usize>() / n;
+        let axis = (0..new_shape.len())
+            .find(|i| {
+                new_shape[..*i].iter().product::<usize>() == before
+                    && new_shape[*i].is_multiple_of(n)
+                    && new_shape[*i] / n * new_shape[i + 1..].iter().product::<usize>() == per_shard
+            })
+            .unwrap_or_else(|| {
+                panic!(
+                    "can't reshape {:?} split along axis {} to {:?}",
+                    shape, a, new_shape
+                )
+            });
+        let mut shard_shape = new_shape.to_vec();
+        shard_shape[axis] /= n;
+        MultiLazyBuffer {
+            lbs: self.lbs.iter().map(|lb| lb.reshape(&shard_shape)).collect(),
+            axis: Some(axis),
+        }
+    }
+
+    /// Pad axes other than the split one.
+    pub fn pad(&self, arg: &[(usize, usize)]) -> MultiLazyBuffer {
+        if let Some(a) = self.axis {
+            assert_eq!(arg[a], (0, 0), "can't pad the split axis {}", a);
+        }
+        self.map(|lb| lb.pad(arg))
+    }
+
+    /// Shrink axes other than the split one, which has to be kept whole.
+    pub fn shrink(&self, arg: &[(usize, usize)]) -> MultiLazyBuffer {
+        let Some(a) = self.axis else {
+            return self.map(|lb| lb.shrink(arg));
+        };
+        assert_eq!(
+            arg[a],
+            (0, self.shape()[a]),
+            "can't shrink the split axis {}",
+            a
+        );
+        self.map(|lb| {
+            let mut arg = arg.to_vec();
+            arg[a] = (0, lb.shape()[a]);
+            lb.shrink(&arg)
+        })
+    }
+
+    /// Stride axes other than the split one.
+    pub fn stride(&self, mul: &[isize]) -> MultiLazyBuffer {
+        if let Some(a) = self.axis {
+            assert_eq!(mul[a], 1, "can't stride the split axis {}", a);
+        }
+        self.map(|lb| lb.stride(mul))
+    }
+
+    pub fn permute(&self, order: &[usize]) -> MultiLazyBuffer {
+        MultiLazyBuffer {
+            lbs: self.lbs.iter().map(|lb| lb.permute(order)).collect(),
+            axis: self
+                .axis
+                .map(|a| order.iter().position(|o| *o == a).unwrap()),
+        }
+    }
+
+    /// Expand size-1 axes, which can't be the split one.
+    pub fn expand(&self, new_shape: &[usize]) -> MultiLazyBuffer {
+        let lbs = self
+            .lbs
+            .iter()
+            .map(|lb| {
+                let mut shape = new_shape.to_vec();
+                if let Some(a) = self.axis {
+                    assert_eq!(
+                        shape[a],
+                        self.shape()[a],
+                        "can't expand the split axis {}",
+                        a
+                    );
+                    shape[a] = lb.shape()[a];
+                }
+                lb.expand(&shape)
+            })
+            .collect();
+        MultiLazyBuffer {
+            lbs,
+            axis: self.axis,
+        }
+    }
+}
+
+/// A shrink of `shape` to `bounds` along `axis`, keeping the rest whole.
+fn shard_arg(shape: &[usize], axis: usize, bounds: (usize, usize)) -> Vec<(usize, usize)> {
+    let mut arg: Vec<(usize, usize)> = shape.iter().map(|s| (0, *s)).collect();
+    arg[axis] = bounds;
+    arg
+}
+
+/// What a `Tensor` holds: a buffer on one device, or one sharded over several. Ops mixing the two
+/// copy the single buffers to every device first.
+#[derive(Clone, Debug)]
+pub enum LazyData {
+    Single(LazyBuffer),
+    Multi(MultiLazyBuffer),
+}
+
+impl From<LazyBuffer> for LazyData {
+    fn from(lb: LazyBuffer) -> Self {
+        LazyData::Single(lb)
+    }
+}
+
+impl From<MultiLazyBuffer> for LazyData {
+    fn from(mlb: MultiLazyBuffer) -> Self {
+        LazyData::Multi(mlb)
+    }
+}
+
+impl LazyData {
+    fn map(
+        &self,
+        single: impl FnOnce(&LazyBuffer) -> LazyBuffer,
+        multi: impl FnOnce(&MultiLazyBuffer) -> MultiLazyBuffer,
+    ) -> LazyData {
+        match self {
+            LazyData::Single(lb) => single(lb).into(),
+            LazyData::Multi(mlb) => multi(mlb).into(),
+        }
+    }
+
+    pub fn shape(&self) -> Vec<usize> {
+        match self {
+            LazyData::Single(lb) => lb.shape().to_vec(),
+            LazyData::Multi(mlb) => mlb.shape(),
+        }
+    }
+
+    pub fn devices(&self) -> Vec<String> {
+        match self {
+            LazyData::Single(lb) => vec![lb.device.clone()],
+            LazyData::Multi(mlb) => mlb.devices(),
+        }
+    }
+
+    /// The whole buffer on `device`, gathering the shards of a sharded one.
+    pub fn to_device(&self, device: &str) -> Result<LazyBuffer, anyhow::Error> {
+        match self {
+            LazyData::Single(lb) => lb.to_device(device),
+            LazyData::Multi(mlb) => Ok(mlb.to_device(device)),
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<f64> {
+        match self {
+            LazyData::Single(lb) => lb.to_vec(),
+            LazyData::Multi(mlb) => mlb.to_vec(),
+        }
+    }
+
+    pub fn const_like(&self, val: f64) -> LazyData {
+        self.map(|lb| lb.const_like(val), |mlb| mlb.const_like(val))
+    }
+
+    pub fn contiguous(&self) -> LazyData {
+        self.map(|lb| lb.contiguous(), |mlb| mlb.contiguous())
+    }
+
+    /// Images live on one device.
+    pub fn cast_image(&self, height: usize, width: usize) -> LazyData {
+        self.map(
+            |lb| lb.cast_image(height, width),
+            |_| panic!("can't store a sharded buffer as an image"),
+        )
+    }
+
+    pub fn e(&self, op: impl Into<Op>, srcs: &[&LazyData]) -> LazyData {
+        let op = op.into();
+        let all: Vec<&LazyData> = std::iter::once(self).chain(srcs.iter().copied()).collect();
+        let Some(devices) = all.iter().find_map(|s| match s {
+            LazyData::Multi(mlb) => Some(mlb.devices()),
+            LazyData::Single(_) => None,
+        }) else {
+            let lbs: Vec<&LazyBuffer> = all
+                .iter()
+                .map(|s| match s {
+                    LazyData::Single(lb) => lb,
+                    LazyData::Multi(_) => unreachable!(),
+                })
+                .collect();
+            return lbs[0].e(op, &lbs[1..]).into();
+        };
+        let devices: Vec<&str> = devices.iter().map(|d| d.as_str()).collect();
+        let mlbs: Vec<MultiLazyBuffer> =
+            all.iter()
+                .map(|s| match s {
+                    LazyData::Single(lb) => MultiLazyBuffer::shard(lb, &devices, None)
+                        .unwrap_or_else(|e| panic!("{}", e)),
+                    LazyData::Multi(mlb) => mlb.clone(),
+                })
+                .collect();
+        let rest: Vec<&MultiLazyBuffer> = mlbs[1..].iter().collect();
+        mlbs[0].e(op, &rest).into()
+    }
+
+    pub fn r(&self, op: ReduceOps, axis: &[usize]) -> LazyData {
+        self.map(|lb| lb.r(op, axis), |mlb| mlb.r(op, axis))
+    }
+
+    pub fn reshape(&self, new_shape: &[usize]) -> LazyData {
+        self.map(|lb| lb.reshape(new_shape), |mlb| mlb.reshape(new_shape))
+    }
+
+    pub fn permute(&self, order: &[usize]) -> LazyData {
+        self.map(|lb| lb.permute(order), |mlb| mlb.permute(order))
+    }
+
+    pub fn expand(&self, new_shape: &[usize]) -> LazyData {
+        self.map(|lb| lb.expand(new_shape), |mlb| mlb.expand(new_shape))
+    }
+
+    pub fn pad(&self, arg: &[(usize, usize)]) -> LazyData {
+        self.map(|lb| lb.pad(arg), |mlb| mlb.pad(arg))
+    }
+
+    pub fn shrink(&self, arg: &[(usize, usize)]) -> LazyData {
+        self.map(|lb| lb.shrink(arg), |mlb| mlb.shrink(arg))
+    }
+
+    pub fn stride(&self, mul: &[isize]) -> LazyData {
+        self.map(|lb| lb.stride(mul), |mlb| mlb.stride(mul))
+    }
+}
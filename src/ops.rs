@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{dtype::DType, shape::view::View};
+
+// the primitive ops every backend has to implement. Everything in `function.rs` and `tensor.rs` is built out of these
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum UnaryOps {
+    Exp2,
+    Log2,
+    Sin,
+    Sqrt,
+    Neg,
+    Recip,
+    Cast,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BinaryOps {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Max,
+    Mod,
+    CmpLt,
+    CmpEq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TernaryOps {
+    Where,
+    MulAcc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ReduceOps {
+    Sum,
+    Max,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum BufferOps {
+    Load,
+    Const,
+    Store,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Op {
+    Unary(UnaryOps),
+    Binary(BinaryOps),
+    Ternary(TernaryOps),
+    Reduce(ReduceOps),
+    Buffer(BufferOps),
+}
+
+impl From<UnaryOps> for Op {
+    fn from(op: UnaryOps) -> Self {
+        Op::Unary(op)
+    }
+}
+impl From<BinaryOps> for Op {
+    fn from(op: BinaryOps) -> Self {
+        Op::Binary(op)
+    }
+}
+impl From<TernaryOps> for Op {
+    fn from(op: TernaryOps) -> Self {
+        Op::Ternary(op)
+    }
+}
+impl From<ReduceOps> for Op {
+    fn from(op: ReduceOps) -> Self {
+        Op::Reduce(op)
+    }
+}
+
+impl From<BufferOps> for Op {
+    fn from(op: BufferOps) -> Self {
+        Op::Buffer(op)
+    }
+}
+
+impl ReduceOps {
+    /// The identity element the accumulator starts from.
+    pub fn identity(&self) -> f64 {
+        match self {
+            ReduceOps::Sum => 0.0,
+            ReduceOps::Max => f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn binary(&self) -> BinaryOps {
+        match self {
+            ReduceOps::Sum => BinaryOps::Add,
+            ReduceOps::Max => BinaryOps::Max,
+        }
+    }
+}
+
+/// Reference semantics of an elementwise op on `f64` scalars.
+pub fn exec_alu(op: Op, srcs: &[f64]) -> f64 {
+    let b = |x: bool| if x { 1.0 } else { 0.0 };
+    match op {
+        Op::Unary(u) => {
+            let x = srcs[0];
+            match u {
+                UnaryOps::Exp2 => x.exp2(),
+                UnaryOps::Log2 => x.log2(),
+                UnaryOps::Sin => x.sin(),
+                UnaryOps::Sqrt => x.sqrt(),
+                UnaryOps::Neg => -x,
+                UnaryOps::Recip => 1.0 / x,
+                UnaryOps::Cast => x,
+            }
+        }
+        Op::Binary(bo) => {
+            let (x, y) = (srcs[0], srcs[1]);
+            match bo {
+                BinaryOps::Add => x + y,
+                BinaryOps::Sub => x - y,
+                BinaryOps::Mul => x * y,
+                BinaryOps::Div => x / y,
+                BinaryOps::Max => x.max(y),
+                BinaryOps::Mod => x % y,
+                BinaryOps::CmpLt => b(x < y),
+                BinaryOps::CmpEq => b(x == y),
+            }
+        }
+        Op::Ternary(t) => match t {
+            TernaryOps::Where => {
+                if srcs[0] != 0.0 {
+                    srcs[1]
+                } else {
+                    srcs[2]
+                }
+            }
+            TernaryOps::MulAcc => srcs[0] * srcs[1] + srcs[2],
+        },
+        Op::Reduce(r) => exec_alu(r.binary().into(), srcs),
+        Op::Buffer(b) => panic!("{:?} is not an alu op", b),
+    }
+}
+
+// ************* kernel ASTs *************
+
+/// Buffer `idx` of a kernel, read or written through `st`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct MemBuffer {
+    pub idx: usize,
+    pub dtype: DType,
+    pub st: View,
+}
+
+/// A constant broadcast through `st`. Masked out positions read as zero.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConstBuffer {
+    pub val: f64,
+    pub dtype: DType,
+    pub st: View,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum LazyOpArg {
+    MemBuffer(MemBuffer),
+    ConstBuffer(ConstBuffer),
+    /// reduce axes
+    Axis(Vec<usize>),
+    /// cast target
+    DType(DType),
+}
+
+/// One kernel: a tree of ops rooted at a `Store`, with `Load`/`Const` leaves.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LazyOp {
+    pub op: Op,
+    pub src: Vec<LazyOp>,
+    pub arg: Option<LazyOpArg>,
+}
+
+impl LazyOp {
+    pub fn new(op: impl Into<Op>, src: Vec<LazyOp>, arg: Option<LazyOpArg>) -> Self {
+        Self {
+            op: op.into(),
+            src,
+            arg,
+        }
+    }
+
+    pub fn load(idx: usize, dtype: DType, st: View) -> Self {
+        Self::new(
+            BufferOps::Load,
+            vec![],
+            Some(LazyOpArg::MemBuffer(MemBuffer { idx, dtype, st })),
+        )
+    }
+
+    pub fn constant(val: f64, dtype: DType, st: View) -> Self {
+        Self::new(
+            BufferOps::Const,
+            vec![],
+            Some(LazyOpArg::ConstBuffer(ConstBuffer { val, dtype, st })),
+        )
+    }
+
+    pub fn store(src: LazyOp, idx: usize, dtype: DType, st: View) -> Self {
+        Self::new(
+            BufferOps::Store,
+            vec![src],
+            Some(LazyOpArg::MemBuffer(MemBuffer { idx, dtype, st })),
+        )
+    }
+
+    pub fn reduce(op: ReduceOps, src: LazyOp, axis: &[usize]) -> Self {
+        Self::new(op, vec![src], Some(LazyOpArg::Axis(axis.to_vec())))
+    }
+
+    pub fn cast(src: LazyOp, dtype: DType) -> Self {
+        Self::new(UnaryOps::Cast, vec![src], Some(LazyOpArg::DType(dtype)))
+    }
+
+    /// Every node of the tree, parents before children.
+    pub fn lazyops(&self) -> Vec<&LazyOp> {
+        std::iter::once(self)
+            .chain(self.src.iter().flat_map(|s| s.lazyops()))
+            .collect()
+    }
+
+    /// One line per node, children indented under their parent, for `DEBUG>=3`.
+    pub fn print_tree(&self) {
+        fn walk(op: &LazyOp, depth: usize) {
+            let arg = match &op.arg {
+                None => String::new(),
+                Some(LazyOpArg::MemBuffer(m)) => format!(
+                    "data{} {} shape:{:?} strides:{:?}",
+                    m.idx, m.dtype, m.st.shape, m.st.strides
+                ),
+                Some(LazyOpArg::ConstBuffer(c)) => {
+                    format!("{} {} shape:{:?}", c.val, c.dtype, c.st.shape)
+                }
+                Some(LazyOpArg::Axis(a)) => format!("axis:{:?}", a),
+                Some(LazyOpArg::DType(d)) => d.to_string(),
+            };
+            println!("{}{:?} {}", "  ".repeat(depth), op.op, arg);
+            for s in &op.src {
+                walk(s, depth + 1);
+            }
+        }
+        walk(self, 0);
+    }
+
+    /// The dtype this node produces.
+    pub fn dtype(&self) -> DType {
+        match (&self.op, &self.arg) {
+            // an image holds floats, its layout only matters to the loads and stores
+            (_, Some(LazyOpArg::MemBuffer(m))) => m.dtype.scalar(),
+            (_, Some(LazyOpArg::ConstBuffer(c))) => c.dtype,
+            (_, Some(LazyOpArg::DType(d))) => *d,
+            (Op::Binary(BinaryOps::CmpLt | BinaryOps::CmpEq), _) => DType::BOOL,
+            (Op::Ternary(TernaryOps::Where), _) => self.src[1].dtype(),
+            _ => self.src[0].dtype(),
+        }
+    }
+}
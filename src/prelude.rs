@@ -11,8 +11,11 @@ lazy_static! {
     pub static ref BEAM: ContextVar = create_context_var!("BEAM", 0);
     pub static ref NOOPT: ContextVar = create_context_var!("NOOPT", 0);
     pub static ref JIT: ContextVar = create_context_var!("JIT", 1);
+    pub static ref THREADS: ContextVar = create_context_var!("THREADS", 0);
     pub static ref WINO: ContextVar = create_context_var!("WINO", 0);
-    pub static ref THREEFRY: ContextVar = create_context_var!("THEEFRY", 0);
+    /// random numbers always come from the Threefry stream in `rng`. This only picks how they're
+    /// computed: across all cores with THREEFRY>=1, on the calling thread otherwise, bit-identical
+    pub static ref THREEFRY: ContextVar = create_context_var!("THREEFRY", 0);
     pub static ref CACHECOLLECTING: ContextVar = create_context_var!("CACHECOLLECTING", 1);
     pub static ref GRAPH: ContextVar = create_context_var!("GRAPH", 0);
     pub static ref GRAPHPATH: String = getenv("GRAPHPATH".to_string(), Some("/tmp/net".to_owned()));
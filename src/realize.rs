@@ -0,0 +1,241 @@
+// runs the kernels behind LazyBuffer ops. Every elementwise op, reduce and copy is one kernel AST,
+// lowered once per distinct AST and device and then run over the storages it touches. A `TinyJit` capture
+// records each run so it can be replayed without rebuilding anything
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+
+use crate::{
+    codegen::{
+        kernel::{get_linearizer, get_linearizer_with, Opt},
+        linearizer::Linearizer,
+        uops::{flops, print_uops},
+    },
+    device::{get_device, Program},
+    features::process_replay::{record, Recorded},
+    helpers::{ansilen, colored, GlobalCounter},
+    lazy::Storage,
+    ops::LazyOp,
+    prelude::{BEAM, CACHECOLLECTING, DEBUG, NOOPT},
+};
+
+/// Loop iterations each thread gets at least, so small kernels stay on one thread.
+const WORK_PER_THREAD: usize = 1 << 15;
+
+/// One lowered kernel, ready to run.
+pub struct Runner {
+    pub name: String,
+    /// `name` coloured by axis kind, for `DEBUG` output
+    pub display_name: String,
+    pub device: String,
+    pub global_size: Vec<usize>,
+    /// size of the outer global loop when it can be split across threads
+    pub split: Option<usize>,
+    /// loop iterations per run
+    pub work: usize,
+    /// ops per run, see `flops`
+    pub op_estimate: usize,
+    /// the opts the kernel was lowered with
+    pub applied_opts: Vec<Opt>,
+    pub prg: Box<dyn Program>,
+    first_run: AtomicBool,
+}
+
+impl Runner {
+    pub fn new(ast: LazyOp, device: &str) -> Result<Self, anyhow::Error> {
+        let dev = get_device(device)?;
+        let (Some(compiler), Some(runtime)) = (dev.compiler(), dev.runtime()) else {
+            return Err(anyhow::anyhow!("{} can't run kernels", dev.name()));
+        };
+        if DEBUG.clone() >= 3 {
+            ast.print_tree();
+        }
+        let mut lin = lowerer(ast.clone());
+        let (name, display_name) = (lin.name(), lin.display_name());
+        let global_size = lin.full_shape()[..lin.global_dims()].to_vec();
+        let split = lin.is_split().then(|| global_size[0]);
+        let work = lin.full_shape().iter().product();
+        let applied_opts = lin.applied_opts.clone();
+        let uops = lin.linearize();
+        if DEBUG.clone() >= 5 {
+            print_uops(uops);
+        }
+        if CACHECOLLECTING.clone() >= 1 {
+            record(&Recorded {
+                device: dev.name().to_string(),
+                name: name.clone(),
+                ast: ast.clone(),
+                src: compiler.render(&name, uops),
+                beam: BEAM.value.max(0) as usize,
+                noopt: NOOPT.clone() == 1,
+            })?;
+        }
+        let lib = compiler.compile_uops(&name, uops)?;
+        Ok(Self {
+            prg: runtime.load(&name, &lib)?,
+            op_estimate: flops(uops, &[("gstart", 0), ("gend", split.unwrap_or(0))]),
+            applied_opts,
+            name,
+            display_name,
+            device: dev.name().to_string(),
+            global_size,
+            split,
+            work,
+            first_run: AtomicBool::new(true),
+        })
+    }
+
+    /// Run over `bufs`, in buffer index order. No storage may appear twice. Every run is counted in
+    /// `GlobalCounters`; `DEBUG>=2` times it and prints a line, magenta when `jit` replays it and
+    /// green the first time the kernel runs.
+    pub fn exec(&self, bufs: &[Storage], jit: bool) {
+        let mut guards: Vec<_> = bufs.iter().map(|b| b.write().unwrap()).collect();
+        let mem_estimate: usize = guards.iter().map(|g| g.nbytes()).sum();
+        let mut raw: Vec<&mut [u8]> = guards.iter_mut().map(|g| g.as_bytes_mut()).collect();
+        let wait = DEBUG.clone() >= 2;
+        let et = match self.split {
+            Some(outer) => {
+                let threads = (self.work / WORK_PER_THREAD).max(1);
+                self.prg.call_split(&mut raw, outer, threads, wait)
+            }
+            None => self.prg.call(&mut raw, &[], wait),
+        };
+        drop(guards);
+
+        let mut gc = GlobalCounter.lock().unwrap();
+        gc.kernel_count += 1;
+        gc.global_ops += self.op_estimate as isize;
+        gc.global_mem += mem_estimate;
+        gc.time_sum_s += et.unwrap_or(0.0);
+        let first_run = self.first_run.swap(false, Ordering::Relaxed);
+        let Some(et) = et else { return };
+        let color = if jit {
+            Some("magenta")
+        } else if first_run {
+            Some("green")
+        } else {
+            None
+        };
+        let ptm = if et > 0.01 {
+            colored(format!("{:9.2}ms", et * 1e3), Some("yellow"), None)
+        } else {
+            format!("{:9.2}us", et * 1e6)
+        };
+        let et = et.max(1e-20);
+        println!(
+            "{} {}{} arg {:3} mem {:5.2} GB global {:<16} tm {}/{:9.2}ms ({:8.2} GFLOPS, {:7.2} GB/s)",
+            colored(
+                format!("*** {:<7} {:4}", self.device, gc.kernel_count),
+                color,
+                None
+            ),
+            self.display_name,
+            " ".repeat(38usize.saturating_sub(ansilen(&self.display_name))),
+            bufs.len(),
+            gc.mem_used as f64 / 1e9,
+            format!("{:?}", self.global_size),
+            ptm,
+            gc.time_sum_s * 1e3,
+            self.op_estimate as f64 / (et * 1e9),
+            mem_estimate as f64 / (et * 1e9),
+        );
+    }
+}
+
+/// The linearizer `Runner` lowers `ast` with, optimised by `get_linearizer`, before `linearize`.
+pub fn lowerer(ast: LazyOp) -> Linearizer {
+    let mut lin = get_linearizer(ast);
+    lin.split_globals = true;
+    lin
+}
+
+/// `lowerer` as it would be under `BEAM=beam` and `NOOPT=noopt`.
+pub fn lowerer_with(ast: LazyOp, beam: usize, noopt: bool) -> Linearizer {
+    let mut lin = get_linearizer_with(ast, beam, noopt);
+    lin.split_globals = true;
+    lin
+}
+
+lazy_static! {
+    /// by md5 of the device and AST, so the key stays small however big the AST
+    static ref RUNNERS: Mutex<HashMap<[u8; 16], Arc<Runner>>> = Mutex::new(HashMap::new());
+}
+
+/// The runner for `ast` on `device`, lowering it the first time it's seen.
+pub fn get_runner(ast: LazyOp, device: &str) -> Result<Arc<Runner>, anyhow::Error> {
+    let key = md5::compute(format!("{} {:?}", device, ast)).0;
+    if let Some(r) = RUNNERS.lock().unwrap().get(&key) {
+        return Ok(r.clone());
+    }
+    let r = Arc::new(Runner::new(ast, device)?);
+    RUNNERS.lock().unwrap().insert(key, r.clone());
+    Ok(r)
+}
+
+/// A kernel bound to the storages it runs over.
+#[derive(Clone)]
+pub struct ExecItem {
+    pub prg: Arc<Runner>,
+    pub bufs: Vec<Storage>,
+}
+
+impl ExecItem {
+    pub fn run(&self) {
+        self.prg.exec(&self.bufs, false);
+    }
+}
+
+/// Everything that ran while capturing: the kernels in order, and the storages `Variable`s were bound into.
+#[derive(Default)]
+pub struct Capture {
+    pub items: Vec<ExecItem>,
+    pub vars: Vec<(String, Storage)>,
+}
+
+thread_local! {
+    static CAPTURING: RefCell<Option<Capture>> = const { RefCell::new(None) };
+}
+
+/// Record every kernel run on this thread until `end_capture`.
+pub fn begin_capture() {
+    CAPTURING.with(|c| {
+        let mut c = c.borrow_mut();
+        assert!(c.is_none(), "already capturing");
+        *c = Some(Capture::default());
+    });
+}
+
+pub fn end_capture() -> Capture {
+    CAPTURING.with(|c| c.borrow_mut().take().expect("not capturing"))
+}
+
+/// Note that `storage` holds the value of variable `expr`.
+pub fn capture_var(expr: &str, storage: &Storage) {
+    CAPTURING.with(|c| {
+        if let Some(c) = c.borrow_mut().as_mut() {
+            c.vars.push((expr.to_string(), storage.clone()));
+        }
+    });
+}
+
+/// Run `ast` on `device` over `bufs`, output first. Fails when the kernel can't be built.
+pub fn exec_ast(ast: LazyOp, bufs: Vec<Storage>, device: &str) -> Result<(), anyhow::Error> {
+    let ei = ExecItem {
+        prg: get_runner(ast, device)?,
+        bufs,
+    };
+    ei.run();
+    CAPTURING.with(|c| {
+        if let Some(c) = c.borrow_mut().as_mut() {
+            c.items.push(ei);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,532 @@
+// uops -> C-like source. The per-language differences (qualifiers, intrinsics, type names) live in
+// `CStyleLanguage`, the walk over the uops is shared. On the GPU languages the global and local loops
+// become thread indices, launched with `launch_dims`
+use std::collections::HashMap;
+
+use crate::{
+    codegen::uops::{UArg, UOp, UOps},
+    dtype::{DType, ScalarType},
+    ops::{BinaryOps, Op, TernaryOps, UnaryOps},
+};
+
+#[derive(Debug, Clone)]
+pub struct CStyleLanguage {
+    pub kernel_prefix: String,
+    pub buffer_prefix: String,
+    pub buffer_suffix: String,
+    pub arg_int_prefix: String,
+    /// lines emitted before the kernel, includes and helper macros
+    pub prekernel: Vec<String>,
+    pub type_map: HashMap<ScalarType, String>,
+    /// kernel arguments after the buffers and vars, where Metal gets its thread indices
+    pub extra_args: Vec<String>,
+    /// the group and in-group thread index along x, y and z. Empty keeps the loops
+    pub gid: Vec<String>,
+    pub lid: Vec<String>,
+    /// vector widths the language has built in, empty typedefs them all. Kernels for it have to be
+    /// linearized with the same `Linearizer::vector_widths`
+    pub vector_widths: Vec<usize>,
+    /// a vector from its scalars, `{type}` and `{vals}` filled in
+    pub vector_literal: String,
+    /// vector element names, empty indexes them with `[]`
+    pub lanes: Vec<String>,
+}
+
+impl CStyleLanguage {
+    pub fn clang() -> Self {
+        Self {
+            kernel_prefix: String::new(),
+            buffer_prefix: String::new(),
+            buffer_suffix: " restrict".to_string(),
+            arg_int_prefix: "const int".to_string(),
+            prekernel: vec![
+                "#include <tgmath.h>".to_string(),
+                "#include <stdbool.h>".to_string(),
+                "#define max(x,y) (((x)>(y))?(x):(y))".to_string(),
+            ],
+            type_map: HashMap::from([
+                (ScalarType::Int8, "signed char".to_string()),
+                (ScalarType::UInt8, "unsigned char".to_string()),
+                (ScalarType::UInt16, "unsigned short".to_string()),
+                (ScalarType::UInt32, "unsigned int".to_string()),
+                (ScalarType::UInt64, "unsigned long".to_string()),
+                (ScalarType::Float16, "_Float16".to_string()),
+            ]),
+            extra_args: vec![],
+            gid: vec![],
+            lid: vec![],
+            vector_widths: vec![],
+            vector_literal: "({type}){{vals}}".to_string(),
+            lanes: vec![],
+        }
+    }
+
+    pub fn cuda() -> Self {
+        let xyz = |v: &str| ["x", "y", "z"].map(|d| format!("{}.{}", v, d)).to_vec();
+        Self {
+            kernel_prefix: "extern \"C\" __global__ ".to_string(),
+            buffer_prefix: String::new(),
+            buffer_suffix: String::new(),
+            arg_int_prefix: "const int".to_string(),
+            prekernel: vec![
+                "#include <cuda_fp16.h>".to_string(),
+                "#define INFINITY (__int_as_float(0x7f800000))".to_string(),
+                "#define NAN (__int_as_float(0x7fffffff))".to_string(),
+            ],
+            type_map: HashMap::from([
+                (ScalarType::Int8, "signed char".to_string()),
+                (ScalarType::UInt8, "unsigned char".to_string()),
+                (ScalarType::UInt16, "unsigned short".to_string()),
+                (ScalarType::UInt32, "unsigned int".to_string()),
+                (ScalarType::Int64, "long long".to_string()),
+                (ScalarType::UInt64, "unsigned long long".to_string()),
+            ]),
+            extra_args: vec![],
+            gid: xyz("blockIdx"),
+            lid: xyz("threadIdx"),
+            vector_widths: vec![2, 4],
+            vector_literal: "make_{type}({vals})".to_string(),
+            lanes: ["x", "y", "z", "w"].map(String::from).to_vec(),
+        }
+    }
+
+    pub fn metal() -> Self {
+        let xyz = |v: &str| ["x", "y", "z"].map(|d| format!("{}.{}", v, d)).to_vec();
+        Self {
+            kernel_prefix: "kernel ".to_string(),
+            buffer_prefix: "device ".to_string(),
+            buffer_suffix: String::new(),
+            arg_int_prefix: "constant int&".to_string(),
+            prekernel: vec![
+                "#include <metal_stdlib>".to_string(),
+                "using namespace metal;".to_string(),
+            ],
+            type_map: HashMap::new(),
+            extra_args: vec![
+                "uint3 gid [[threadgroup_position_in_grid]]".to_string(),
+                "uint3 lid [[thread_position_in_threadgroup]]".to_string(),
+            ],
+            gid: xyz("gid"),
+            lid: xyz("lid"),
+            vector_widths: vec![2, 3, 4],
+            vector_literal: "{type}({vals})".to_string(),
+            lanes: ["x", "y", "z", "w"].map(String::from).to_vec(),
+        }
+    }
+
+    pub fn opencl() -> Self {
+        let xyz = |f: &str| (0..3).map(|d| format!("{}({})", f, d)).collect();
+        Self {
+            kernel_prefix: "__kernel ".to_string(),
+            buffer_prefix: "__global ".to_string(),
+            buffer_suffix: String::new(),
+            arg_int_prefix: "const int".to_string(),
+            prekernel: vec!["#pragma OPENCL EXTENSION cl_khr_fp16 : enable".to_string()],
+            type_map: HashMap::new(),
+            extra_args: vec![],
+            gid: xyz("get_group_id"),
+            lid: xyz("get_local_id"),
+            vector_widths: vec![2, 3, 4, 8, 16],
+            vector_literal: "({type})({vals})".to_string(),
+            lanes: (0..16).map(|i| format!("s{:x}", i)).collect(),
+        }
+    }
+
+    /// Vectors go by their short name, `float4`, typedef'd by `render_vector_type`.
+    pub fn render_dtype(&self, dtype: DType) -> String {
+        if dtype.image.is_some() {
+            return self.render_dtype(dtype.scalar());
+        }
+        if dtype.count > 1 {
+            return dtype.name();
+        }
+        match self.type_map.get(&dtype.scalar) {
+            Some(t) => t.clone(),
+            None => dtype.name(),
+        }
+    }
+
+    /// The typedef for a vector dtype, None when the language has it built in. clang spells it
+    /// `ext_vector_type`, gcc `vector_size`. Either way it's only aligned like its elements, so a
+    /// vector can be read from anywhere in a buffer.
+    pub fn render_vector_type(&self, dtype: DType) -> Option<String> {
+        if !self.vector_widths.is_empty() {
+            assert!(
+                self.vector_widths.contains(&dtype.count),
+                "no built in {} vectors, linearize with these vector_widths",
+                dtype.name()
+            );
+            return None;
+        }
+        let (scalar, name) = (self.render_dtype(dtype.scalar()), dtype.name());
+        let align = dtype.scalar().itemsize();
+        [
+            "#if defined(__clang__)".to_string(),
+            format!(
+                "typedef {} {} __attribute__((ext_vector_type({}), aligned({})));",
+                scalar, name, dtype.count, align
+            ),
+            "#else".to_string(),
+            format!(
+                "typedef {} {} __attribute__((vector_size({}), aligned({})));",
+                scalar,
+                name,
+                dtype.itemsize(),
+                align
+            ),
+            "#endif".to_string(),
+        ]
+        .join("\n")
+        .into()
+    }
+
+    pub fn render_vector(&self, dtype: DType, vals: &[String]) -> String {
+        self.vector_literal
+            .replace("{type}", &self.render_dtype(dtype))
+            .replace("{vals}", &vals.join(","))
+    }
+
+    pub fn render_gep(&self, x: &str, lane: usize) -> String {
+        match self.lanes.get(lane) {
+            Some(l) => format!("{}.{}", x, l),
+            None if self.lanes.is_empty() => format!("{}[{}]", x, lane),
+            None => panic!("no lane {} in a vector here", lane),
+        }
+    }
+
+    /// Image texels at int2 coordinates, emulated over row major host memory of `w` texel rows.
+    /// Vector literals have commas in them, so coords and values are passed parenthesized.
+    pub fn render_image_helpers(&self) -> Vec<String> {
+        let (x, y) = (self.render_gep("(c)", 0), self.render_gep("(c)", 1));
+        let texel = format!(
+            "(*(({}float4*)((img)+({}*(w)+{})*4)))",
+            self.buffer_prefix, y, x
+        );
+        vec![
+            format!("#define read_imagef(img, w, c) {}", texel),
+            format!("#define write_imagef(img, w, c, v) ({} = (v))", texel),
+        ]
+    }
+
+    pub fn render_const(&self, x: f64, dtype: DType) -> String {
+        if dtype.is_float() {
+            let v = if x.is_nan() {
+                "NAN".to_string()
+            } else if x.is_infinite() {
+                if x > 0.0 { "INFINITY" } else { "-INFINITY" }.to_string()
+            } else if dtype == DType::FLOAT64 {
+                format!("{:?}", x)
+            } else {
+                format!("{:?}f", x as f32)
+            };
+            if dtype == DType::FLOAT32 || dtype == DType::FLOAT64 {
+                return v;
+            }
+            return self.render_cast(&v, dtype);
+        }
+        if dtype == DType::BOOL {
+            return if x != 0.0 { "1" } else { "0" }.to_string();
+        }
+        let v = if dtype.is_unsigned() {
+            format!("{}u", x as u64)
+        } else {
+            (x as i64).to_string()
+        };
+        match dtype.itemsize() {
+            8 => format!("{}ll", v),
+            4 => v,
+            _ => self.render_cast(&v, dtype),
+        }
+    }
+
+    pub fn render_cast(&self, x: &str, dtype: DType) -> String {
+        format!("({})({})", self.render_dtype(dtype), x)
+    }
+
+    pub fn code_for_op(&self, op: Op, srcs: &[String], dtype: DType) -> String {
+        let s = srcs;
+        match op {
+            Op::Unary(u) => match u {
+                UnaryOps::Neg => format!("(-{})", s[0]),
+                UnaryOps::Exp2 => format!("exp2({})", s[0]),
+                UnaryOps::Log2 => format!("log2({})", s[0]),
+                UnaryOps::Sin => format!("sin({})", s[0]),
+                UnaryOps::Sqrt => format!("sqrt({})", s[0]),
+                UnaryOps::Recip => format!("(1/{})", s[0]),
+                UnaryOps::Cast => self.render_cast(&s[0], dtype),
+            },
+            Op::Binary(b) => match b {
+                BinaryOps::Add => format!("({}+{})", s[0], s[1]),
+                BinaryOps::Sub => format!("({}-{})", s[0], s[1]),
+                // bools multiply as a logical and, which `-Wall` wants spelled out
+                BinaryOps::Mul if dtype == DType::BOOL => format!("({}&&{})", s[0], s[1]),
+                BinaryOps::Mul => format!("({}*{})", s[0], s[1]),
+                BinaryOps::Div => format!("({}/{})", s[0], s[1]),
+                BinaryOps::Max => format!("max({},{})", s[0], s[1]),
+                BinaryOps::Mod if dtype.is_float() => format!("fmod({},{})", s[0], s[1]),
+                BinaryOps::Mod => format!("({}%{})", s[0], s[1]),
+                BinaryOps::CmpLt => format!("({}<{})", s[0], s[1]),
+                BinaryOps::CmpEq => format!("({}=={})", s[0], s[1]),
+            },
+            Op::Ternary(t) => match t {
+                TernaryOps::Where => format!("({}?{}:{})", s[0], s[1], s[2]),
+                TernaryOps::MulAcc => format!("(({}*{})+{})", s[0], s[1], s[2]),
+            },
+            Op::Reduce(_) | Op::Buffer(_) => panic!("{:?} isn't an alu op", op),
+        }
+    }
+
+    pub fn render_kernel(
+        &self,
+        function_name: &str,
+        kernel: &[String],
+        bufs: &[(String, DType)],
+        vars: &[String],
+        prekernel: &[String],
+    ) -> String {
+        let args: Vec<String> = bufs
+            .iter()
+            .map(|(name, dtype)| {
+                format!(
+                    "{}{}*{} {}",
+                    self.buffer_prefix,
+                    self.render_dtype(*dtype),
+                    self.buffer_suffix,
+                    name
+                )
+            })
+            .chain(
+                vars.iter()
+                    .map(|v| format!("{} {}", self.arg_int_prefix, v)),
+            )
+            .chain(self.extra_args.iter().cloned())
+            .collect();
+        let mut lines = self.prekernel.clone();
+        lines.extend(prekernel.iter().cloned());
+        lines.push(format!(
+            "{}void {}({}) {{",
+            self.kernel_prefix,
+            function_name,
+            args.join(", ")
+        ));
+        lines.extend(kernel.iter().cloned());
+        lines.push("}".to_string());
+        lines.join("\n")
+    }
+}
+
+/// The `Loop`s over global and local axes, each ordered x, y, z: the last axis is the fastest.
+fn thread_loops(uops: &[UOp]) -> [Vec<usize>; 2] {
+    ["gidx", "lidx"].map(|prefix| {
+        uops.iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, u)| {
+                u.uop == UOps::Loop && matches!(&u.arg, UArg::Name(n) if n.starts_with(prefix))
+            })
+            .map(|(i, _)| i)
+            .collect()
+    })
+}
+
+/// The number of groups and threads per group along x, y and z a kernel rendered for a GPU language
+/// launches with.
+pub fn launch_dims(uops: &[UOp]) -> (Vec<usize>, Vec<usize>) {
+    let size = |l: &usize| match uops[uops[*l].vin[1]].arg {
+        UArg::Const(c) => c as usize,
+        _ => panic!("thread dims need constant sizes"),
+    };
+    let [global, local] = thread_loops(uops);
+    (
+        global.iter().map(size).collect(),
+        local.iter().map(size).collect(),
+    )
+}
+
+/// Render `uops` as a C-style kernel called `function_name`. ALU results with a single user are inlined.
+pub fn uops_to_cstyle(lang: &CStyleLanguage, function_name: &str, uops: &[UOp]) -> String {
+    let mut uses = vec![0usize; uops.len()];
+    for u in uops {
+        for v in &u.vin {
+            uses[*v] += 1;
+        }
+    }
+    let mut r: Vec<String> = vec![String::new(); uops.len()];
+    let mut kernel: Vec<String> = vec![];
+    let mut bufs: Vec<(String, DType)> = vec![];
+    let mut vars: Vec<String> = vec![];
+    let mut vector_types: Vec<DType> = vec![];
+    let mut depth = 1;
+    let mut threads: HashMap<usize, &str> = HashMap::new();
+    if !lang.gid.is_empty() {
+        for (loops, ids) in thread_loops(uops).iter().zip([&lang.gid, &lang.lid]) {
+            assert!(
+                loops.len() <= ids.len(),
+                "more than {} thread dims",
+                ids.len()
+            );
+            threads.extend(loops.iter().copied().zip(ids.iter().map(|s| s.as_str())));
+        }
+    }
+    let mut counters: HashMap<&str, usize> = HashMap::new();
+    let mut ssa = |prefix: &'static str| {
+        let c = counters.entry(prefix).or_insert(0);
+        *c += 1;
+        format!("{}{}", prefix, *c - 1)
+    };
+    for (i, u) in uops.iter().enumerate() {
+        let ind = "  ".repeat(depth);
+        let src: Vec<String> = u.vin.iter().map(|v| r[*v].clone()).collect();
+        if let Some(dtype) = u.dtype.filter(|d| d.count > 1 && !vector_types.contains(d)) {
+            vector_types.push(dtype);
+        }
+        match u.uop {
+            UOps::DefineGlobal => {
+                let UArg::Buffer(idx) = u.arg else {
+                    panic!("DefineGlobal needs a buffer arg")
+                };
+                r[i] = format!("data{}", idx);
+                bufs.push((r[i].clone(), u.dtype.unwrap()));
+            }
+            UOps::DefineVar => {
+                let UArg::Name(name) = &u.arg else {
+                    panic!("DefineVar needs a name")
+                };
+                r[i] = name.clone();
+                vars.push(name.clone());
+            }
+            UOps::Loop => {
+                let UArg::Name(name) = &u.arg else {
+                    panic!("Loop needs a name")
+                };
+                r[i] = name.clone();
+                if let Some(id) = threads.get(&i) {
+                    assert_eq!(
+                        uops[u.vin[0]].arg,
+                        UArg::Const(0.0),
+                        "{} doesn't start at 0",
+                        name
+                    );
+                    kernel.push(format!("{}int {} = {}; /* {} */", ind, name, id, src[1]));
+                    continue;
+                }
+                kernel.push(format!(
+                    "{}for (int {} = {}; {} < {}; {}++) {{",
+                    ind, name, src[0], name, src[1], name
+                ));
+                depth += 1;
+            }
+            UOps::If => {
+                kernel.push(format!("{}if ({}) {{", ind, src[0]));
+                depth += 1;
+            }
+            UOps::End if threads.contains_key(&u.vin[0]) => {}
+            UOps::End => {
+                depth -= 1;
+                kernel.push(format!("{}}}", "  ".repeat(depth)));
+            }
+            UOps::Const => {
+                let UArg::Const(c) = u.arg else {
+                    panic!("Const needs a value")
+                };
+                r[i] = lang.render_const(c, u.dtype.unwrap());
+            }
+            UOps::DefineAcc => {
+                let UArg::Const(c) = u.arg else {
+                    panic!("DefineAcc needs a start value")
+                };
+                let dtype = u.dtype.unwrap();
+                r[i] = ssa("acc");
+                kernel.push(format!(
+                    "{}{} {} = {};",
+                    ind,
+                    lang.render_dtype(dtype),
+                    r[i],
+                    lang.render_const(c, dtype)
+                ));
+            }
+            UOps::Alu | UOps::Cast => {
+                let dtype = u.dtype.unwrap();
+                let op = match u.arg {
+                    UArg::Op(op) => op,
+                    _ => Op::Unary(UnaryOps::Cast),
+                };
+                let code = lang.code_for_op(op, &src, dtype);
+                if uses[i] == 1 {
+                    r[i] = code;
+                } else {
+                    r[i] = ssa(if u.uop == UOps::Alu { "alu" } else { "cast" });
+                    kernel.push(format!(
+                        "{}{} {} = {};",
+                        ind,
+                        lang.render_dtype(dtype),
+                        r[i],
+                        code
+                    ));
+                }
+            }
+            UOps::Load => {
+                let dtype = u.dtype.unwrap();
+                let image = uops[u.vin[0]].dtype.unwrap().image;
+                let val = if let (Some((_, w)), 2) = (image, uops[u.vin[1]].dtype.unwrap().count) {
+                    format!("read_imagef({}, {}, ({}))", src[0], w, src[1])
+                } else if dtype.count > 1 {
+                    let t = lang.render_dtype(dtype);
+                    format!("*(({}{}*)({}+{}))", lang.buffer_prefix, t, src[0], src[1])
+                } else if src.len() > 2 {
+                    format!("({})?({}[{}]):{}", src[2], src[0], src[1], src[3])
+                } else {
+                    format!("{}[{}]", src[0], src[1])
+                };
+                r[i] = ssa("val");
+                kernel.push(format!(
+                    "{}{} {} = {};",
+                    ind,
+                    lang.render_dtype(dtype),
+                    r[i],
+                    val
+                ));
+            }
+            UOps::Phi => {
+                kernel.push(format!("{}{} = {};", ind, src[0], src[1]));
+                r[i] = src[0].clone();
+            }
+            UOps::Store => match uops[u.vin[2]].dtype.filter(|d| d.count > 1) {
+                Some(_) if uops[u.vin[1]].dtype.unwrap().count == 2 => {
+                    let (_, w) = uops[u.vin[0]].dtype.unwrap().image.unwrap();
+                    kernel.push(format!(
+                        "{}write_imagef({}, {}, ({}), ({}));",
+                        ind, src[0], w, src[1], src[2]
+                    ))
+                }
+                Some(dtype) => kernel.push(format!(
+                    "{}*(({}{}*)({}+{})) = {};",
+                    ind,
+                    lang.buffer_prefix,
+                    lang.render_dtype(dtype),
+                    src[0],
+                    src[1],
+                    src[2]
+                )),
+                None => kernel.push(format!("{}{}[{}] = {};", ind, src[0], src[1], src[2])),
+            },
+            UOps::Gep => {
+                let UArg::Index(lane) = u.arg else {
+                    panic!("Gep needs a lane")
+                };
+                r[i] = lang.render_gep(&src[0], lane);
+            }
+            UOps::Vectorize => {
+                r[i] = lang.render_vector(u.dtype.unwrap(), &src);
+            }
+        }
+    }
+    let mut prekernel: Vec<String> = vector_types
+        .into_iter()
+        .filter_map(|d| lang.render_vector_type(d))
+        .collect();
+    if bufs.iter().any(|(_, d)| d.image.is_some()) {
+        prekernel.extend(lang.render_image_helpers());
+    }
+    lang.render_kernel(function_name, &kernel, &bufs, &vars, &prekernel)
+}
@@ -0,0 +1,9 @@
+#pragma OPENCL EXTENSION cl_khr_fp16 : enable
+__kernel void E_4_16(__global float* data0, __global float* data1, __global float* data2) {
+  int gidx0 = get_group_id(1); /* 4 */
+  int gidx1 = get_group_id(0); /* 4 */
+  int alu0 = ((gidx0*16)+(gidx1*4));
+  float4 val0 = *((__global float4*)(data1+alu0));
+  float4 val1 = *((__global float4*)(data2+alu0));
+  *((__global float4*)(data0+alu0)) = (float4)((val0.s0+val1.s0),(val0.s1+val1.s1),(val0.s2+val1.s2),(val0.s3+val1.s3));
+}
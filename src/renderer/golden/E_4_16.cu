@@ -0,0 +1,11 @@
+#include <cuda_fp16.h>
+#define INFINITY (__int_as_float(0x7f800000))
+#define NAN (__int_as_float(0x7fffffff))
+extern "C" __global__ void E_4_16(float* data0, float* data1, float* data2) {
+  int gidx0 = blockIdx.y; /* 4 */
+  int gidx1 = blockIdx.x; /* 4 */
+  int alu0 = ((gidx0*16)+(gidx1*4));
+  float4 val0 = *((float4*)(data1+alu0));
+  float4 val1 = *((float4*)(data2+alu0));
+  *((float4*)(data0+alu0)) = make_float4((val0.x+val1.x),(val0.y+val1.y),(val0.z+val1.z),(val0.w+val1.w));
+}
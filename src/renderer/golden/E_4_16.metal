@@ -0,0 +1,10 @@
+#include <metal_stdlib>
+using namespace metal;
+kernel void E_4_16(device float* data0, device float* data1, device float* data2, uint3 gid [[threadgroup_position_in_grid]], uint3 lid [[thread_position_in_threadgroup]]) {
+  int gidx0 = gid.y; /* 4 */
+  int gidx1 = gid.x; /* 4 */
+  int alu0 = ((gidx0*16)+(gidx1*4));
+  float4 val0 = *((device float4*)(data1+alu0));
+  float4 val1 = *((device float4*)(data2+alu0));
+  *((device float4*)(data0+alu0)) = float4((val0.x+val1.x),(val0.y+val1.y),(val0.z+val1.z),(val0.w+val1.w));
+}
@@ -0,0 +1,86 @@
+declare float @llvm.exp2.f32(float)
+
+define void @E_8_8(ptr noalias %data0, ptr noalias %data1, ptr noalias %data2) {
+entry:
+  %gidx0.addr = alloca i32
+  %gidx1.addr = alloca i32
+  store i32 0, ptr %gidx0.addr
+  br label %gidx0.loop
+gidx0.loop:
+  %gidx0 = load i32, ptr %gidx0.addr
+  %gidx0.ok = icmp slt i32 %gidx0, 8
+  br i1 %gidx0.ok, label %gidx0.body, label %gidx0.exit
+gidx0.body:
+  store i32 0, ptr %gidx1.addr
+  br label %gidx1.loop
+gidx1.loop:
+  %gidx1 = load i32, ptr %gidx1.addr
+  %gidx1.ok = icmp slt i32 %gidx1, 2
+  br i1 %gidx1.ok, label %gidx1.body, label %gidx1.exit
+gidx1.body:
+  %t0 = mul i32 %gidx0, 7
+  %t1 = mul i32 %gidx1, 4
+  %t2 = add i32 %t0, %t1
+  %t3 = getelementptr inbounds float, ptr %data1, i32 %t2
+  %t4 = load float, ptr %t3, align 4
+  %t5 = mul i32 %gidx0, 8
+  %t6 = add i32 %t5, %t1
+  %t7 = getelementptr inbounds float, ptr %data2, i32 %t6
+  %t8 = load <4 x float>, ptr %t7, align 4
+  %t9 = extractelement <4 x float> %t8, i32 0
+  %t10 = call float @llvm.exp2.f32(float %t9)
+  %t11 = fcmp ogt float %t4, %t10
+  %t12 = select i1 %t11, float %t4, float %t10
+  %t13 = add i32 %t2, 1
+  %t14 = getelementptr inbounds float, ptr %data1, i32 %t13
+  %t15 = load float, ptr %t14, align 4
+  %t16 = extractelement <4 x float> %t8, i32 1
+  %t17 = call float @llvm.exp2.f32(float %t16)
+  %t18 = fcmp ogt float %t15, %t17
+  %t19 = select i1 %t18, float %t15, float %t17
+  %t20 = add i32 %t2, 2
+  %t21 = getelementptr inbounds float, ptr %data1, i32 %t20
+  %t22 = load float, ptr %t21, align 4
+  %t23 = extractelement <4 x float> %t8, i32 2
+  %t24 = call float @llvm.exp2.f32(float %t23)
+  %t25 = fcmp ogt float %t22, %t24
+  %t26 = select i1 %t25, float %t22, float %t24
+  %t27 = add i32 %t2, 3
+  %t28 = add i32 %t1, 3
+  %t29 = icmp slt i32 %t28, 7
+  %t30 = select i1 %t29, i32 %t27, i32 0
+  %t31 = getelementptr inbounds float, ptr %data1, i32 %t30
+  %t32 = load float, ptr %t31, align 4
+  %t33 = select i1 %t29, float %t32, float 0x0000000000000000
+  %t34 = extractelement <4 x float> %t8, i32 3
+  %t35 = call float @llvm.exp2.f32(float %t34)
+  %t36 = fcmp ogt float %t33, %t35
+  %t37 = select i1 %t36, float %t33, float %t35
+  %t38 = insertelement <4 x float> poison, float %t12, i32 0
+  %t39 = insertelement <4 x float> %t38, float %t19, i32 1
+  %t40 = insertelement <4 x float> %t39, float %t26, i32 2
+  %t41 = insertelement <4 x float> %t40, float %t37, i32 3
+  %t42 = getelementptr inbounds float, ptr %data0, i32 %t6
+  store <4 x float> %t41, ptr %t42, align 4
+  %gidx1.next = add i32 %gidx1, 1
+  store i32 %gidx1.next, ptr %gidx1.addr
+  br label %gidx1.loop
+gidx1.exit:
+  %gidx0.next = add i32 %gidx0, 1
+  store i32 %gidx0.next, ptr %gidx0.addr
+  br label %gidx0.loop
+gidx0.exit:
+  ret void
+}
+
+define void @E_8_8_entry(ptr %bufs, ptr %vars) {
+entry:
+  %b0.p = getelementptr inbounds ptr, ptr %bufs, i32 0
+  %b0 = load ptr, ptr %b0.p
+  %b1.p = getelementptr inbounds ptr, ptr %bufs, i32 1
+  %b1 = load ptr, ptr %b1.p
+  %b2.p = getelementptr inbounds ptr, ptr %bufs, i32 2
+  %b2 = load ptr, ptr %b2.p
+  call void @E_8_8(ptr %b0, ptr %b1, ptr %b2)
+  ret void
+}
@@ -0,0 +1,38 @@
+#pragma OPENCL EXTENSION cl_khr_fp16 : enable
+__kernel void r_8_8_16(__global float* data0, __global float* data1, __global float* data2) {
+  int gidx0 = get_group_id(0); /* 8 */
+  int lidx0 = get_local_id(0); /* 2 */
+  float acc0 = 0.0f;
+  float acc1 = 0.0f;
+  float acc2 = 0.0f;
+  float acc3 = 0.0f;
+  for (int ridx0 = 0; ridx0 < 4; ridx0++) {
+    float4 val0 = *((__global float4*)(data1+((gidx0*16)+(ridx0*4))));
+    int alu0 = (lidx0+(ridx0*32));
+    float val1 = data2[alu0];
+    float val2 = data2[(alu0+8)];
+    float val3 = data2[(alu0+16)];
+    float val4 = data2[(alu0+24)];
+    acc0 = ((((acc0+(val0.s0*val1))+(val0.s1*val2))+(val0.s2*val3))+(val0.s3*val4));
+    float val5 = data2[(alu0+2)];
+    float val6 = data2[(alu0+10)];
+    float val7 = data2[(alu0+18)];
+    float val8 = data2[(alu0+26)];
+    acc1 = ((((acc1+(val0.s0*val5))+(val0.s1*val6))+(val0.s2*val7))+(val0.s3*val8));
+    float val9 = data2[(alu0+4)];
+    float val10 = data2[(alu0+12)];
+    float val11 = data2[(alu0+20)];
+    float val12 = data2[(alu0+28)];
+    acc2 = ((((acc2+(val0.s0*val9))+(val0.s1*val10))+(val0.s2*val11))+(val0.s3*val12));
+    float val13 = data2[(alu0+6)];
+    float val14 = data2[(alu0+14)];
+    float val15 = data2[(alu0+22)];
+    float val16 = data2[(alu0+30)];
+    acc3 = ((((acc3+(val0.s0*val13))+(val0.s1*val14))+(val0.s2*val15))+(val0.s3*val16));
+  }
+  int alu1 = ((gidx0*8)+lidx0);
+  data0[alu1] = acc0;
+  data0[(alu1+2)] = acc1;
+  data0[(alu1+4)] = acc2;
+  data0[(alu1+6)] = acc3;
+}
@@ -0,0 +1,175 @@
+define void @r_8_8_16(ptr noalias %data0, ptr noalias %data1, ptr noalias %data2) {
+entry:
+  %gidx0.addr = alloca i32
+  %lidx0.addr = alloca i32
+  %acc8 = alloca float
+  %acc9 = alloca float
+  %acc10 = alloca float
+  %acc11 = alloca float
+  %ridx0.addr = alloca i32
+  store i32 0, ptr %gidx0.addr
+  br label %gidx0.loop
+gidx0.loop:
+  %gidx0 = load i32, ptr %gidx0.addr
+  %gidx0.ok = icmp slt i32 %gidx0, 8
+  br i1 %gidx0.ok, label %gidx0.body, label %gidx0.exit
+gidx0.body:
+  store i32 0, ptr %lidx0.addr
+  br label %lidx0.loop
+lidx0.loop:
+  %lidx0 = load i32, ptr %lidx0.addr
+  %lidx0.ok = icmp slt i32 %lidx0, 2
+  br i1 %lidx0.ok, label %lidx0.body, label %lidx0.exit
+lidx0.body:
+  store float 0x0000000000000000, ptr %acc8
+  store float 0x0000000000000000, ptr %acc9
+  store float 0x0000000000000000, ptr %acc10
+  store float 0x0000000000000000, ptr %acc11
+  store i32 0, ptr %ridx0.addr
+  br label %ridx0.loop
+ridx0.loop:
+  %ridx0 = load i32, ptr %ridx0.addr
+  %ridx0.ok = icmp slt i32 %ridx0, 4
+  br i1 %ridx0.ok, label %ridx0.body, label %ridx0.exit
+ridx0.body:
+  %t0 = mul i32 %gidx0, 16
+  %t1 = mul i32 %ridx0, 4
+  %t2 = add i32 %t0, %t1
+  %t3 = getelementptr inbounds float, ptr %data1, i32 %t2
+  %t4 = load <4 x float>, ptr %t3, align 4
+  %t5 = extractelement <4 x float> %t4, i32 0
+  %t6 = mul i32 %ridx0, 32
+  %t7 = add i32 %lidx0, %t6
+  %t8 = getelementptr inbounds float, ptr %data2, i32 %t7
+  %t9 = load float, ptr %t8, align 4
+  %t10 = fmul float %t5, %t9
+  %t11 = load float, ptr %acc8
+  %t12 = fadd float %t11, %t10
+  %t13 = extractelement <4 x float> %t4, i32 1
+  %t14 = add i32 %t7, 8
+  %t15 = getelementptr inbounds float, ptr %data2, i32 %t14
+  %t16 = load float, ptr %t15, align 4
+  %t17 = fmul float %t13, %t16
+  %t18 = fadd float %t12, %t17
+  %t19 = extractelement <4 x float> %t4, i32 2
+  %t20 = add i32 %t7, 16
+  %t21 = getelementptr inbounds float, ptr %data2, i32 %t20
+  %t22 = load float, ptr %t21, align 4
+  %t23 = fmul float %t19, %t22
+  %t24 = fadd float %t18, %t23
+  %t25 = extractelement <4 x float> %t4, i32 3
+  %t26 = add i32 %t7, 24
+  %t27 = getelementptr inbounds float, ptr %data2, i32 %t26
+  %t28 = load float, ptr %t27, align 4
+  %t29 = fmul float %t25, %t28
+  %t30 = fadd float %t24, %t29
+  store float %t30, ptr %acc8
+  %t31 = add i32 %t7, 2
+  %t32 = getelementptr inbounds float, ptr %data2, i32 %t31
+  %t33 = load float, ptr %t32, align 4
+  %t34 = fmul float %t5, %t33
+  %t35 = load float, ptr %acc9
+  %t36 = fadd float %t35, %t34
+  %t37 = add i32 %t7, 10
+  %t38 = getelementptr inbounds float, ptr %data2, i32 %t37
+  %t39 = load float, ptr %t38, align 4
+  %t40 = fmul float %t13, %t39
+  %t41 = fadd float %t36, %t40
+  %t42 = add i32 %t7, 18
+  %t43 = getelementptr inbounds float, ptr %data2, i32 %t42
+  %t44 = load float, ptr %t43, align 4
+  %t45 = fmul float %t19, %t44
+  %t46 = fadd float %t41, %t45
+  %t47 = add i32 %t7, 26
+  %t48 = getelementptr inbounds float, ptr %data2, i32 %t47
+  %t49 = load float, ptr %t48, align 4
+  %t50 = fmul float %t25, %t49
+  %t51 = fadd float %t46, %t50
+  store float %t51, ptr %acc9
+  %t52 = add i32 %t7, 4
+  %t53 = getelementptr inbounds float, ptr %data2, i32 %t52
+  %t54 = load float, ptr %t53, align 4
+  %t55 = fmul float %t5, %t54
+  %t56 = load float, ptr %acc10
+  %t57 = fadd float %t56, %t55
+  %t58 = add i32 %t7, 12
+  %t59 = getelementptr inbounds float, ptr %data2, i32 %t58
+  %t60 = load float, ptr %t59, align 4
+  %t61 = fmul float %t13, %t60
+  %t62 = fadd float %t57, %t61
+  %t63 = add i32 %t7, 20
+  %t64 = getelementptr inbounds float, ptr %data2, i32 %t63
+  %t65 = load float, ptr %t64, align 4
+  %t66 = fmul float %t19, %t65
+  %t67 = fadd float %t62, %t66
+  %t68 = add i32 %t7, 28
+  %t69 = getelementptr inbounds float, ptr %data2, i32 %t68
+  %t70 = load float, ptr %t69, align 4
+  %t71 = fmul float %t25, %t70
+  %t72 = fadd float %t67, %t71
+  store float %t72, ptr %acc10
+  %t73 = add i32 %t7, 6
+  %t74 = getelementptr inbounds float, ptr %data2, i32 %t73
+  %t75 = load float, ptr %t74, align 4
+  %t76 = fmul float %t5, %t75
+  %t77 = load float, ptr %acc11
+  %t78 = fadd float %t77, %t76
+  %t79 = add i32 %t7, 14
+  %t80 = getelementptr inbounds float, ptr %data2, i32 %t79
+  %t81 = load float, ptr %t80, align 4
+  %t82 = fmul float %t13, %t81
+  %t83 = fadd float %t78, %t82
+  %t84 = add i32 %t7, 22
+  %t85 = getelementptr inbounds float, ptr %data2, i32 %t84
+  %t86 = load float, ptr %t85, align 4
+  %t87 = fmul float %t19, %t86
+  %t88 = fadd float %t83, %t87
+  %t89 = add i32 %t7, 30
+  %t90 = getelementptr inbounds float, ptr %data2, i32 %t89
+  %t91 = load float, ptr %t90, align 4
+  %t92 = fmul float %t25, %t91
+  %t93 = fadd float %t88, %t92
+  store float %t93, ptr %acc11
+  %ridx0.next = add i32 %ridx0, 1
+  store i32 %ridx0.next, ptr %ridx0.addr
+  br label %ridx0.loop
+ridx0.exit:
+  %t94 = mul i32 %gidx0, 8
+  %t95 = add i32 %t94, %lidx0
+  %t96 = getelementptr inbounds float, ptr %data0, i32 %t95
+  %t97 = load float, ptr %acc8
+  store float %t97, ptr %t96, align 4
+  %t98 = add i32 %t95, 2
+  %t99 = getelementptr inbounds float, ptr %data0, i32 %t98
+  %t100 = load float, ptr %acc9
+  store float %t100, ptr %t99, align 4
+  %t101 = add i32 %t95, 4
+  %t102 = getelementptr inbounds float, ptr %data0, i32 %t101
+  %t103 = load float, ptr %acc10
+  store float %t103, ptr %t102, align 4
+  %t104 = add i32 %t95, 6
+  %t105 = getelementptr inbounds float, ptr %data0, i32 %t104
+  %t106 = load float, ptr %acc11
+  store float %t106, ptr %t105, align 4
+  %lidx0.next = add i32 %lidx0, 1
+  store i32 %lidx0.next, ptr %lidx0.addr
+  br label %lidx0.loop
+lidx0.exit:
+  %gidx0.next = add i32 %gidx0, 1
+  store i32 %gidx0.next, ptr %gidx0.addr
+  br label %gidx0.loop
+gidx0.exit:
+  ret void
+}
+
+define void @r_8_8_16_entry(ptr %bufs, ptr %vars) {
+entry:
+  %b0.p = getelementptr inbounds ptr, ptr %bufs, i32 0
+  %b0 = load ptr, ptr %b0.p
+  %b1.p = getelementptr inbounds ptr, ptr %bufs, i32 1
+  %b1 = load ptr, ptr %b1.p
+  %b2.p = getelementptr inbounds ptr, ptr %bufs, i32 2
+  %b2 = load ptr, ptr %b2.p
+  call void @r_8_8_16(ptr %b0, ptr %b1, ptr %b2)
+  ret void
+}
@@ -0,0 +1,39 @@
+#include <metal_stdlib>
+using namespace metal;
+kernel void r_8_8_16(device float* data0, device float* data1, device float* data2, uint3 gid [[threadgroup_position_in_grid]], uint3 lid [[thread_position_in_threadgroup]]) {
+  int gidx0 = gid.x; /* 8 */
+  int lidx0 = lid.x; /* 2 */
+  float acc0 = 0.0f;
+  float acc1 = 0.0f;
+  float acc2 = 0.0f;
+  float acc3 = 0.0f;
+  for (int ridx0 = 0; ridx0 < 4; ridx0++) {
+    float4 val0 = *((device float4*)(data1+((gidx0*16)+(ridx0*4))));
+    int alu0 = (lidx0+(ridx0*32));
+    float val1 = data2[alu0];
+    float val2 = data2[(alu0+8)];
+    float val3 = data2[(alu0+16)];
+    float val4 = data2[(alu0+24)];
+    acc0 = ((((acc0+(val0.x*val1))+(val0.y*val2))+(val0.z*val3))+(val0.w*val4));
+    float val5 = data2[(alu0+2)];
+    float val6 = data2[(alu0+10)];
+    float val7 = data2[(alu0+18)];
+    float val8 = data2[(alu0+26)];
+    acc1 = ((((acc1+(val0.x*val5))+(val0.y*val6))+(val0.z*val7))+(val0.w*val8));
+    float val9 = data2[(alu0+4)];
+    float val10 = data2[(alu0+12)];
+    float val11 = data2[(alu0+20)];
+    float val12 = data2[(alu0+28)];
+    acc2 = ((((acc2+(val0.x*val9))+(val0.y*val10))+(val0.z*val11))+(val0.w*val12));
+    float val13 = data2[(alu0+6)];
+    float val14 = data2[(alu0+14)];
+    float val15 = data2[(alu0+22)];
+    float val16 = data2[(alu0+30)];
+    acc3 = ((((acc3+(val0.x*val13))+(val0.y*val14))+(val0.z*val15))+(val0.w*val16));
+  }
+  int alu1 = ((gidx0*8)+lidx0);
+  data0[alu1] = acc0;
+  data0[(alu1+2)] = acc1;
+  data0[(alu1+4)] = acc2;
+  data0[(alu1+6)] = acc3;
+}
@@ -0,0 +1,25 @@
+#pragma OPENCL EXTENSION cl_khr_fp16 : enable
+__kernel void r_8_8_16_wide(__global float* data0, __global float* data1, __global float* data2) {
+  int gidx0 = get_group_id(0); /* 8 */
+  float acc0 = 0.0f;
+  float acc1 = 0.0f;
+  float acc2 = 0.0f;
+  float acc3 = 0.0f;
+  float acc4 = 0.0f;
+  float acc5 = 0.0f;
+  float acc6 = 0.0f;
+  float acc7 = 0.0f;
+  for (int ridx0 = 0; ridx0 < 16; ridx0++) {
+    float val0 = data1[((gidx0*16)+ridx0)];
+    float8 val1 = *((__global float8*)(data2+(ridx0*8)));
+    acc0 = (acc0+(val0*val1.s0));
+    acc1 = (acc1+(val0*val1.s1));
+    acc2 = (acc2+(val0*val1.s2));
+    acc3 = (acc3+(val0*val1.s3));
+    acc4 = (acc4+(val0*val1.s4));
+    acc5 = (acc5+(val0*val1.s5));
+    acc6 = (acc6+(val0*val1.s6));
+    acc7 = (acc7+(val0*val1.s7));
+  }
+  *((__global float8*)(data0+(gidx0*8))) = (float8)(acc0,acc1,acc2,acc3,acc4,acc5,acc6,acc7);
+}
@@ -0,0 +1,43 @@
+#include <cuda_fp16.h>
+#define INFINITY (__int_as_float(0x7f800000))
+#define NAN (__int_as_float(0x7fffffff))
+extern "C" __global__ void r_8_8_16_wide(float* data0, float* data1, float* data2) {
+  int gidx0 = blockIdx.x; /* 8 */
+  float acc0 = 0.0f;
+  float acc1 = 0.0f;
+  float acc2 = 0.0f;
+  float acc3 = 0.0f;
+  float acc4 = 0.0f;
+  float acc5 = 0.0f;
+  float acc6 = 0.0f;
+  float acc7 = 0.0f;
+  for (int ridx0 = 0; ridx0 < 16; ridx0++) {
+    float val0 = data1[((gidx0*16)+ridx0)];
+    int alu0 = (ridx0*8);
+    float val1 = data2[alu0];
+    acc0 = (acc0+(val0*val1));
+    float val2 = data2[(alu0+1)];
+    acc1 = (acc1+(val0*val2));
+    float val3 = data2[(alu0+2)];
+    acc2 = (acc2+(val0*val3));
+    float val4 = data2[(alu0+3)];
+    acc3 = (acc3+(val0*val4));
+    float val5 = data2[(alu0+4)];
+    acc4 = (acc4+(val0*val5));
+    float val6 = data2[(alu0+5)];
+    acc5 = (acc5+(val0*val6));
+    float val7 = data2[(alu0+6)];
+    acc6 = (acc6+(val0*val7));
+    float val8 = data2[(alu0+7)];
+    acc7 = (acc7+(val0*val8));
+  }
+  int alu1 = (gidx0*8);
+  data0[alu1] = acc0;
+  data0[(alu1+1)] = acc1;
+  data0[(alu1+2)] = acc2;
+  data0[(alu1+3)] = acc3;
+  data0[(alu1+4)] = acc4;
+  data0[(alu1+5)] = acc5;
+  data0[(alu1+6)] = acc6;
+  data0[(alu1+7)] = acc7;
+}
@@ -0,0 +1,408 @@
+// uops -> textual LLVM IR. Loop counters and accumulators live in allocas for mem2reg to promote, so
+// the IR needs no phis. Pointers are opaque, `ptr`
+use crate::{
+    codegen::uops::{UArg, UOp, UOps},
+    dtype::{DType, ScalarType},
+    ops::{BinaryOps, Op, TernaryOps, UnaryOps},
+    runtime::ops_interp::f32_to_f16,
+};
+
+pub fn render_dtype(dtype: DType) -> String {
+    let scalar = match dtype.scalar {
+        ScalarType::Bool => "i1",
+        ScalarType::Int8 | ScalarType::UInt8 => "i8",
+        ScalarType::Int16 | ScalarType::UInt16 => "i16",
+        ScalarType::Int32 | ScalarType::UInt32 => "i32",
+        ScalarType::Int64 | ScalarType::UInt64 => "i64",
+        ScalarType::Float16 => "half",
+        ScalarType::Float32 => "float",
+        ScalarType::Float64 => "double",
+    };
+    if dtype.count > 1 {
+        format!("<{} x {}>", dtype.count, scalar)
+    } else {
+        scalar.to_string()
+    }
+}
+
+/// Floats are written as hex, the only exact spelling LLVM takes for every value.
+pub fn render_const(x: f64, dtype: DType) -> String {
+    match dtype.scalar {
+        ScalarType::Bool => if x != 0.0 { "true" } else { "false" }.to_string(),
+        ScalarType::Float16 => format!("0xH{:04X}", f32_to_f16(x as f32)),
+        ScalarType::Float32 => format!("0x{:016X}", (x as f32 as f64).to_bits()),
+        ScalarType::Float64 => format!("0x{:016X}", x.to_bits()),
+        _ if dtype.is_unsigned() => (x as u64).to_string(),
+        _ => (x as i64).to_string(),
+    }
+}
+
+/// Bools compare unsigned, false < true.
+fn is_unsigned(dtype: DType) -> bool {
+    dtype.is_unsigned() || dtype.scalar() == DType::BOOL
+}
+
+struct Kernel<'a> {
+    uops: &'a [UOp],
+    /// each uop's value, an SSA name or a constant. Accumulators hold their alloca
+    r: Vec<String>,
+    args: Vec<String>,
+    /// hoisted to the entry block, so a loop doesn't grow the stack every trip
+    allocas: Vec<String>,
+    body: Vec<String>,
+    declares: Vec<String>,
+    tmps: usize,
+}
+
+impl<'a> Kernel<'a> {
+    fn tmp(&mut self) -> String {
+        self.tmps += 1;
+        format!("%t{}", self.tmps - 1)
+    }
+
+    fn emit(&mut self, line: String) {
+        self.body.push(format!("  {}", line));
+    }
+
+    /// `ins` into a new SSA value.
+    fn assign(&mut self, ins: String) -> String {
+        let ret = self.tmp();
+        self.emit(format!("{} = {}", ret, ins));
+        ret
+    }
+
+    fn label(&mut self, name: &str) {
+        self.body.push(format!("{}:", name));
+    }
+
+    fn dtype(&self, v: usize) -> DType {
+        self.uops[v].dtype.unwrap()
+    }
+
+    /// The value of uop `v`, read out of its alloca for accumulators.
+    fn val(&mut self, v: usize) -> String {
+        match self.uops[v].uop {
+            UOps::DefineAcc | UOps::Phi => {
+                let ty = render_dtype(self.dtype(v));
+                let acc = self.r[v].clone();
+                self.assign(format!("load {}, ptr {}", ty, acc))
+            }
+            _ => self.r[v].clone(),
+        }
+    }
+
+    fn intrinsic(&mut self, name: &str, dtype: DType) -> String {
+        let suffix = match dtype.scalar {
+            ScalarType::Float16 => "f16",
+            ScalarType::Float32 => "f32",
+            ScalarType::Float64 => "f64",
+            _ => panic!("no llvm.{} for {}", name, dtype),
+        };
+        let (f, ty) = (format!("@llvm.{}.{}", name, suffix), render_dtype(dtype));
+        let declare = format!("declare {} {}({})", ty, f, ty);
+        if !self.declares.contains(&declare) {
+            self.declares.push(declare);
+        }
+        f
+    }
+
+    fn cast(&mut self, x: &str, from: DType, to: DType) -> String {
+        let (ft, tt) = (render_dtype(from), render_dtype(to));
+        if ft == tt {
+            return x.to_string();
+        }
+        if to.scalar() == DType::BOOL {
+            let zero = render_const(0.0, from);
+            let cmp = if from.is_float() {
+                "fcmp une"
+            } else {
+                "icmp ne"
+            };
+            return self.assign(format!("{} {} {}, {}", cmp, ft, x, zero));
+        }
+        let op = match (from.is_float(), to.is_float()) {
+            (true, true) if from.itemsize() < to.itemsize() => "fpext",
+            (true, true) => "fptrunc",
+            (true, false) if to.is_unsigned() => "fptoui",
+            (true, false) => "fptosi",
+            (false, true) if is_unsigned(from) => "uitofp",
+            (false, true) => "sitofp",
+            _ if from.itemsize() > to.itemsize() => "trunc",
+            _ if is_unsigned(from) => "zext",
+            _ => "sext",
+        };
+        self.assign(format!("{} {} {} to {}", op, ft, x, tt))
+    }
+
+    /// `op` on `s` giving `dtype`. `src` is the type of the first operand, a where's condition.
+    fn alu(&mut self, op: Op, s: &[String], src: DType, dtype: DType) -> String {
+        let (ty, st) = (render_dtype(dtype), render_dtype(src));
+        let float = src.is_float();
+        let bool_ = src.scalar() == DType::BOOL;
+        let unsigned = is_unsigned(src);
+        let binary = |name: &str| format!("{} {} {}, {}", name, st, s[0], s[1]);
+        let ins = match op {
+            Op::Unary(u) => match u {
+                UnaryOps::Neg if float => format!("fneg {} {}", ty, s[0]),
+                UnaryOps::Neg => format!("sub {} 0, {}", ty, s[0]),
+                UnaryOps::Exp2 | UnaryOps::Log2 | UnaryOps::Sin | UnaryOps::Sqrt => {
+                    let name = format!("{:?}", u).to_lowercase();
+                    let f = self.intrinsic(&name, dtype);
+                    format!("call {} {}({} {})", ty, f, ty, s[0])
+                }
+                UnaryOps::Recip => format!("fdiv {} {}, {}", ty, render_const(1.0, dtype), s[0]),
+                UnaryOps::Cast => return self.cast(&s[0], src, dtype),
+            },
+            Op::Binary(b) => match b {
+                BinaryOps::Add if float => binary("fadd"),
+                BinaryOps::Add if bool_ => binary("or"),
+                BinaryOps::Add => binary("add"),
+                BinaryOps::Sub if float => binary("fsub"),
+                BinaryOps::Sub if bool_ => binary("xor"),
+                BinaryOps::Sub => binary("sub"),
+                BinaryOps::Mul if float => binary("fmul"),
+                BinaryOps::Mul if bool_ => binary("and"),
+                BinaryOps::Mul => binary("mul"),
+                BinaryOps::Div if float => binary("fdiv"),
+                BinaryOps::Div if unsigned => binary("udiv"),
+                BinaryOps::Div => binary("sdiv"),
+                BinaryOps::Mod if float => binary("frem"),
+                BinaryOps::Mod if unsigned => binary("urem"),
+                BinaryOps::Mod => binary("srem"),
+                // like C's `x > y ? x : y`, which the other renderers use
+                BinaryOps::Max => {
+                    let gt = match (float, unsigned) {
+                        (true, _) => binary("fcmp ogt"),
+                        (_, true) => binary("icmp ugt"),
+                        _ => binary("icmp sgt"),
+                    };
+                    let c = self.assign(gt);
+                    format!("select i1 {}, {} {}, {} {}", c, ty, s[0], ty, s[1])
+                }
+                BinaryOps::CmpLt if float => binary("fcmp olt"),
+                BinaryOps::CmpLt if unsigned => binary("icmp ult"),
+                BinaryOps::CmpLt => binary("icmp slt"),
+                BinaryOps::CmpEq if float => binary("fcmp oeq"),
+                BinaryOps::CmpEq => binary("icmp eq"),
+            },
+            Op::Ternary(TernaryOps::Where) => {
+                let c = self.cast(&s[0], src, DType::BOOL);
+                format!("select i1 {}, {} {}, {} {}", c, ty, s[1], ty, s[2])
+            }
+            Op::Ternary(TernaryOps::MulAcc) => {
+                let (mul, add) = if float {
+                    ("fmul", "fadd")
+                } else {
+                    ("mul", "add")
+                };
+                let m = self.assign(format!("{} {} {}, {}", mul, ty, s[0], s[1]));
+                format!("{} {} {}, {}", add, ty, m, s[2])
+            }
+            Op::Reduce(_) | Op::Buffer(_) => panic!("{:?} isn't an alu op", op),
+        };
+        self.assign(ins)
+    }
+
+    /// A pointer to element `idx` of buffer `buf`. An image's int2 texel coords become the index of
+    /// the texel's first float.
+    fn address(&mut self, buf: usize, idx: usize) -> String {
+        let bt = render_dtype(self.dtype(buf).scalar());
+        let mut i = self.r[idx].clone();
+        if let (Some((_, w)), 2) = (self.dtype(buf).image, self.dtype(idx).count) {
+            let x = self.assign(format!("extractelement <2 x i32> {}, i32 0", i));
+            let y = self.assign(format!("extractelement <2 x i32> {}, i32 1", i));
+            let row = self.assign(format!("mul i32 {}, {}", y, w));
+            let texel = self.assign(format!("add i32 {}, {}", row, x));
+            i = self.assign(format!("mul i32 {}, 4", texel));
+        }
+        let b = self.r[buf].clone();
+        self.assign(format!(
+            "getelementptr inbounds {}, ptr {}, i32 {}",
+            bt, b, i
+        ))
+    }
+
+    fn lower(&mut self, i: usize) {
+        let u = &self.uops[i];
+        match u.uop {
+            UOps::DefineGlobal => {
+                let UArg::Buffer(idx) = u.arg else {
+                    panic!("DefineGlobal needs a buffer arg")
+                };
+                self.r[i] = format!("%data{}", idx);
+                self.args.push(format!("ptr noalias {}", self.r[i]));
+            }
+            UOps::DefineVar => {
+                let UArg::Name(name) = &u.arg else {
+                    panic!("DefineVar needs a name")
+                };
+                self.r[i] = format!("%{}", name);
+                self.args.push(format!("i32 {}", self.r[i]));
+            }
+            UOps::Const => {
+                let UArg::Const(c) = u.arg else {
+                    panic!("Const needs a value")
+                };
+                self.r[i] = render_const(c, self.dtype(i));
+            }
+            UOps::DefineAcc => {
+                let UArg::Const(c) = u.arg else {
+                    panic!("DefineAcc needs a start value")
+                };
+                let ty = render_dtype(self.dtype(i));
+                self.r[i] = format!("%acc{}", i);
+                self.allocas
+                    .push(format!("  {} = alloca {}", self.r[i], ty));
+                let init = render_const(c, self.dtype(i));
+                self.emit(format!("store {} {}, ptr %acc{}", ty, init, i));
+            }
+            UOps::Loop => {
+                let UArg::Name(name) = &u.arg else {
+                    panic!("Loop needs a name")
+                };
+                let (lo, hi) = (self.val(u.vin[0]), self.val(u.vin[1]));
+                self.allocas.push(format!("  %{}.addr = alloca i32", name));
+                self.emit(format!("store i32 {}, ptr %{}.addr", lo, name));
+                self.emit(format!("br label %{}.loop", name));
+                self.label(&format!("{}.loop", name));
+                self.r[i] = format!("%{}", name);
+                self.emit(format!("%{} = load i32, ptr %{}.addr", name, name));
+                self.emit(format!("%{}.ok = icmp slt i32 %{}, {}", name, name, hi));
+                self.emit(format!(
+                    "br i1 %{}.ok, label %{}.body, label %{}.exit",
+                    name, name, name
+                ));
+                self.label(&format!("{}.body", name));
+            }
+            UOps::If => {
+                let c = self.val(u.vin[0]);
+                let c = self.cast(&c, self.dtype(u.vin[0]), DType::BOOL);
+                self.emit(format!(
+                    "br i1 {}, label %if{}.then, label %if{}.end",
+                    c, i, i
+                ));
+                self.label(&format!("if{}.then", i));
+            }
+            UOps::End => {
+                let start = u.vin[0];
+                match &self.uops[start].arg {
+                    UArg::Name(name) if self.uops[start].uop == UOps::Loop => {
+                        self.emit(format!("%{}.next = add i32 %{}, 1", name, name));
+                        self.emit(format!("store i32 %{}.next, ptr %{}.addr", name, name));
+                        self.emit(format!("br label %{}.loop", name));
+                        self.label(&format!("{}.exit", name));
+                    }
+                    _ => {
+                        self.emit(format!("br label %if{}.end", start));
+                        self.label(&format!("if{}.end", start));
+                    }
+                }
+            }
+            UOps::Alu | UOps::Cast => {
+                let op = match u.arg {
+                    UArg::Op(op) => op,
+                    _ => Op::Unary(UnaryOps::Cast),
+                };
+                let src: Vec<String> = u.vin.iter().map(|v| self.val(*v)).collect();
+                self.r[i] = self.alu(op, &src, self.dtype(u.vin[0]), self.dtype(i));
+            }
+            UOps::Load => {
+                let ty = render_dtype(self.dtype(i));
+                let align = self.dtype(i).scalar().itemsize();
+                let p = match u.vin.get(2) {
+                    // a masked load reads element 0 instead, then picks the alternative
+                    Some(valid) => {
+                        let valid = self.val(*valid);
+                        let (b, idx) = (self.r[u.vin[0]].clone(), self.r[u.vin[1]].clone());
+                        let safe = self.assign(format!("select i1 {}, i32 {}, i32 0", valid, idx));
+                        let bt = render_dtype(self.dtype(u.vin[0]).scalar());
+                        self.assign(format!(
+                            "getelementptr inbounds {}, ptr {}, i32 {}",
+                            bt, b, safe
+                        ))
+                    }
+                    None => self.address(u.vin[0], u.vin[1]),
+                };
+                let v = self.assign(format!("load {}, ptr {}, align {}", ty, p, align));
+                self.r[i] = match u.vin.get(2) {
+                    Some(valid) => {
+                        let (valid, alt) = (self.val(*valid), self.val(u.vin[3]));
+                        self.assign(format!("select i1 {}, {} {}, {} {}", valid, ty, v, ty, alt))
+                    }
+                    None => v,
+                };
+            }
+            UOps::Store => {
+                let p = self.address(u.vin[0], u.vin[1]);
+                let dtype = self.dtype(u.vin[2]);
+                let v = self.val(u.vin[2]);
+                self.emit(format!(
+                    "store {} {}, ptr {}, align {}",
+                    render_dtype(dtype),
+                    v,
+                    p,
+                    dtype.scalar().itemsize()
+                ));
+            }
+            UOps::Phi => {
+                let (acc, ty) = (self.r[u.vin[0]].clone(), render_dtype(self.dtype(i)));
+                let v = self.val(u.vin[1]);
+                self.emit(format!("store {} {}, ptr {}", ty, v, acc));
+                self.r[i] = acc;
+            }
+            UOps::Gep => {
+                let UArg::Index(lane) = u.arg else {
+                    panic!("Gep needs a lane")
+                };
+                let v = self.val(u.vin[0]);
+                let ty = render_dtype(self.dtype(u.vin[0]));
+                self.r[i] = self.assign(format!("extractelement {} {}, i32 {}", ty, v, lane));
+            }
+            UOps::Vectorize => {
+                let ty = render_dtype(self.dtype(i));
+                let st = render_dtype(self.dtype(i).scalar());
+                let mut v = "poison".to_string();
+                for (lane, s) in u.vin.iter().enumerate() {
+                    let s = self.val(*s);
+                    v = self.assign(format!(
+                        "insertelement {} {}, {} {}, i32 {}",
+                        ty, v, st, s, lane
+                    ));
+                }
+                self.r[i] = v;
+            }
+        }
+    }
+}
+
+/// Render `uops` as an LLVM IR function `@<function_name>` taking the buffers as pointers and the
+/// vars as i32s, in order.
+pub fn uops_to_llvm_ir(function_name: &str, uops: &[UOp]) -> String {
+    let mut k = Kernel {
+        uops,
+        r: vec![String::new(); uops.len()],
+        args: vec![],
+        allocas: vec![],
+        body: vec![],
+        declares: vec![],
+        tmps: 0,
+    };
+    for i in 0..uops.len() {
+        k.lower(i);
+    }
+    let mut lines = k.declares;
+    if !lines.is_empty() {
+        lines.push(String::new());
+    }
+    lines.push(format!(
+        "define void @{}({}) {{",
+        function_name,
+        k.args.join(", ")
+    ));
+    lines.push("entry:".to_string());
+    lines.extend(k.allocas);
+    lines.extend(k.body);
+    lines.push("  ret void".to_string());
+    lines.push("}".to_string());
+    lines.join("\n")
+}
@@ -0,0 +1,2 @@
+pub mod cstyle;
+pub mod llvmir;
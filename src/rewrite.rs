@@ -0,0 +1,382 @@
+// graph rewriting. A `UPat` matches a node by op, dtype and the patterns of its sources, capturing
+// nodes by name; a `PatternMatcher` is a table of patterns and what to replace their matches with,
+// applied bottom up until nothing matches. Kernel ASTs and uops both implement `Rewritable`
+use std::collections::HashMap;
+
+use lazy_static::lazy_static;
+
+use crate::{
+    codegen::uops::UNode,
+    dtype::DType,
+    ops::{BinaryOps, LazyOp, LazyOpArg, Op, TernaryOps, UnaryOps},
+    runtime::ops_interp::{exec_alu_typed, Val},
+};
+
+/// What the engine sees of a graph node. Nodes are values, a rewrite builds new ones.
+pub trait Rewritable: Clone + PartialEq {
+    /// None for nodes no pattern can name, like loops
+    fn op(&self) -> Option<Op>;
+    fn dtype(&self) -> Option<DType>;
+    fn src(&self) -> &[Self];
+    /// This node over other sources.
+    fn with_src(&self, src: Vec<Self>) -> Self;
+    /// The value of a constant node, None for anything else.
+    fn konst(&self) -> Option<f64>;
+    /// A constant `val` of `dtype` where this node is.
+    fn const_like(&self, val: f64, dtype: DType) -> Self;
+    /// Identity of a node with several parents, so it's rewritten once. Trees don't need one.
+    fn id(&self) -> Option<usize> {
+        None
+    }
+}
+
+pub type Captures<N> = HashMap<String, N>;
+pub type RewriteFn<N> = Box<dyn Fn(&Captures<N>) -> Option<N> + Send + Sync>;
+
+/// Matches nodes. Every field left unset matches anything.
+#[derive(Debug, Clone, Default)]
+pub struct UPat {
+    pub op: Option<Vec<Op>>,
+    pub dtype: Option<Vec<DType>>,
+    pub src: Option<Vec<UPat>>,
+    /// the node has to be a constant, of `val` when that's set
+    pub konst: bool,
+    pub val: Option<f64>,
+    /// two sources that may match in either order
+    pub commutative: bool,
+    /// capture the node under this name. A name used twice has to match the same node
+    pub name: Option<String>,
+}
+
+impl UPat {
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    pub fn var(name: &str) -> Self {
+        Self::any().named(name)
+    }
+
+    /// Any constant, captured as `name`.
+    pub fn cvar(name: &str) -> Self {
+        Self {
+            konst: true,
+            ..Self::var(name)
+        }
+    }
+
+    pub fn cnst(val: f64) -> Self {
+        Self {
+            konst: true,
+            val: Some(val),
+            ..Self::any()
+        }
+    }
+
+    pub fn op(op: impl Into<Op>) -> Self {
+        Self::ops(&[op.into()])
+    }
+
+    pub fn ops(ops: &[Op]) -> Self {
+        Self {
+            op: Some(ops.to_vec()),
+            ..Self::any()
+        }
+    }
+
+    pub fn src(self, src: Vec<UPat>) -> Self {
+        Self {
+            src: Some(src),
+            ..self
+        }
+    }
+
+    pub fn commutative(self) -> Self {
+        Self {
+            commutative: true,
+            ..self
+        }
+    }
+
+    pub fn named(self, name: &str) -> Self {
+        Self {
+            name: Some(name.to_string()),
+            ..self
+        }
+    }
+
+    pub fn dtype(self, dtypes: &[DType]) -> Self {
+        Self {
+            dtype: Some(dtypes.to_vec()),
+            ..self
+        }
+    }
+
+    /// Whether `n` matches, adding the captures to `caps`. On a failed match `caps` may hold some of
+    /// them.
+    pub fn matches<N: Rewritable>(&self, n: &N, caps: &mut Captures<N>) -> bool {
+        if self
+            .op
+            .as_ref()
+            .is_some_and(|ops| !n.op().is_some_and(|o| ops.contains(&o)))
+            || self
+                .dtype
+                .as_ref()
+                .is_some_and(|d| !n.dtype().is_some_and(|x| d.contains(&x)))
+            || (self.konst && n.konst().is_none())
+            || self.val.is_some_and(|v| n.konst() != Some(v))
+        {
+            return false;
+        }
+        if let Some(name) = &self.name {
+            match caps.get(name) {
+                Some(c) if c != n => return false,
+                Some(_) => {}
+                None => {
+                    caps.insert(name.clone(), n.clone());
+                }
+            }
+        }
+        let Some(pats) = &self.src else {
+            return true;
+        };
+        if pats.len() != n.src().len() {
+            return false;
+        }
+        let mut orders = vec![n.src().to_vec()];
+        if self.commutative && pats.len() == 2 {
+            orders.push(n.src().iter().rev().cloned().collect());
+        }
+        let before = caps.clone();
+        for src in orders {
+            if pats.iter().zip(&src).all(|(p, s)| p.matches(s, caps)) {
+                return true;
+            }
+            *caps = before.clone();
+        }
+        false
+    }
+}
+
+pub struct PatternMatcher<N> {
+    pub patterns: Vec<(UPat, RewriteFn<N>)>,
+}
+
+impl<N: Rewritable> PatternMatcher<N> {
+    pub fn new(patterns: Vec<(UPat, RewriteFn<N>)>) -> Self {
+        Self { patterns }
+    }
+
+    /// The replacement from the first pattern that matches `n` and whose rewrite takes it.
+    pub fn rewrite(&self, n: &N) -> Option<N> {
+        self.patterns.iter().find_map(|(pat, f)| {
+            let mut caps = Captures::new();
+            if pat.matches(n, &mut caps) {
+                f(&caps)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Rewrite `node` and everything under it until no pattern matches anywhere.
+pub fn graph_rewrite<N: Rewritable>(node: &N, pm: &PatternMatcher<N>) -> N {
+    graph_rewrite_cached(node, pm, &mut HashMap::new())
+}
+
+/// `graph_rewrite` sharing results by `Rewritable::id` across calls. The cache keeps the nodes it
+/// was keyed on alive, so an id can't be reused while it's there.
+pub fn graph_rewrite_cached<N: Rewritable>(
+    node: &N,
+    pm: &PatternMatcher<N>,
+    cache: &mut HashMap<usize, (N, N)>,
+) -> N {
+    if let Some((_, n)) = node.id().and_then(|k| cache.get(&k)) {
+        return n.clone();
+    }
+    let src: Vec<N> = node
+        .src()
+        .iter()
+        .map(|s| graph_rewrite_cached(s, pm, cache))
+        .collect();
+    let mut n = if src.iter().zip(node.src()).all(|(a, b)| a == b) {
+        node.clone()
+    } else {
+        node.with_src(src)
+    };
+    while let Some(m) = pm.rewrite(&n) {
+        n = graph_rewrite_cached(&m, pm, cache);
+    }
+    for k in [node, &n] {
+        if let Some(id) = k.id() {
+            cache.insert(id, (k.clone(), n.clone()));
+        }
+    }
+    n
+}
+
+fn rw<N, F: Fn(&Captures<N>) -> Option<N> + Send + Sync + 'static>(f: F) -> RewriteFn<N> {
+    Box::new(f)
+}
+
+/// Whether every value of `from` survives a cast to `to`.
+fn can_safe_cast(from: DType, to: DType) -> bool {
+    match (from.is_float(), to.is_float()) {
+        _ if from == DType::BOOL => true,
+        (true, true) => to.itemsize() >= from.itemsize(),
+        (false, true) => to.itemsize() > from.itemsize(),
+        (true, false) => false,
+        (false, false) if from.is_unsigned() == to.is_unsigned() => {
+            to.itemsize() >= from.itemsize()
+        }
+        (false, false) => from.is_unsigned() && to.itemsize() > from.itemsize(),
+    }
+}
+
+/// An ALU op over constants, in the dtypes of the op and its sources. None when C leaves it
+/// undefined, an integer division by zero.
+fn const_fold<N: Rewritable>(x: &N) -> Option<N> {
+    let (op, dtype) = (x.op()?, x.dtype()?);
+    let srcs: Vec<Val> = x
+        .src()
+        .iter()
+        .map(|s| Val::from_f64(s.konst().unwrap(), s.dtype().unwrap()))
+        .collect();
+    let by_zero = matches!(op, Op::Binary(BinaryOps::Div | BinaryOps::Mod))
+        && !dtype.is_float()
+        && !srcs[1].truthy();
+    if by_zero || dtype.count > 1 {
+        return None;
+    }
+    let val = exec_alu_typed(op, dtype, &srcs).as_f64();
+    Some(x.const_like(val, dtype))
+}
+
+/// The algebraic simplifications, for any graph.
+pub fn simplifications<N: Rewritable + 'static>() -> PatternMatcher<N> {
+    let alu: Vec<Op> = [
+        UnaryOps::Exp2,
+        UnaryOps::Log2,
+        UnaryOps::Sin,
+        UnaryOps::Sqrt,
+        UnaryOps::Neg,
+        UnaryOps::Recip,
+        UnaryOps::Cast,
+    ]
+    .map(Op::from)
+    .into_iter()
+    .chain(
+        [
+            BinaryOps::Add,
+            BinaryOps::Sub,
+            BinaryOps::Mul,
+            BinaryOps::Div,
+            BinaryOps::Max,
+            BinaryOps::Mod,
+            BinaryOps::CmpLt,
+            BinaryOps::CmpEq,
+        ]
+        .map(Op::from),
+    )
+    .chain([TernaryOps::Where, TernaryOps::MulAcc].map(Op::from))
+    .collect();
+    let x = || rw(|c: &Captures<N>| Some(c["x"].clone()));
+    let mut patterns = vec![
+        // x + 0, x * 1, x - 0 and x / 1 are x
+        (
+            UPat::op(BinaryOps::Add)
+                .src(vec![UPat::var("x"), UPat::cnst(0.0)])
+                .commutative(),
+            x(),
+        ),
+        (
+            UPat::op(BinaryOps::Mul)
+                .src(vec![UPat::var("x"), UPat::cnst(1.0)])
+                .commutative(),
+            x(),
+        ),
+        (
+            UPat::op(BinaryOps::Sub).src(vec![UPat::var("x"), UPat::cnst(0.0)]),
+            x(),
+        ),
+        (
+            UPat::op(BinaryOps::Div).src(vec![UPat::var("x"), UPat::cnst(1.0)]),
+            x(),
+        ),
+        // a cast to the dtype it already has
+        (
+            UPat::op(UnaryOps::Cast)
+                .src(vec![UPat::var("x")])
+                .named("cast"),
+            rw(|c: &Captures<N>| (c["cast"].dtype() == c["x"].dtype()).then(|| c["x"].clone())),
+        ),
+        // a cast of a cast that lost nothing
+        (
+            UPat::op(UnaryOps::Cast)
+                .src(vec![UPat::op(UnaryOps::Cast)
+                    .src(vec![UPat::var("x")])
+                    .named("inner")])
+                .named("outer"),
+            rw(|c: &Captures<N>| {
+                let (x, inner) = (c["x"].dtype()?, c["inner"].dtype()?);
+                can_safe_cast(x, inner).then(|| c["outer"].with_src(vec![c["x"].clone()]))
+            }),
+        ),
+    ];
+    // constants in, a constant out
+    for n in 1..=3 {
+        patterns.push((
+            UPat::ops(&alu)
+                .src((0..n).map(|i| UPat::cvar(&format!("c{}", i))).collect())
+                .named("x"),
+            rw(|c: &Captures<N>| const_fold(&c["x"])),
+        ));
+    }
+    PatternMatcher::new(patterns)
+}
+
+lazy_static! {
+    pub static ref AST_SIMPLIFY: PatternMatcher<LazyOp> = simplifications();
+    pub static ref UOP_SIMPLIFY: PatternMatcher<UNode> = simplifications();
+}
+
+impl Rewritable for LazyOp {
+    fn op(&self) -> Option<Op> {
+        Some(self.op)
+    }
+
+    fn dtype(&self) -> Option<DType> {
+        Some(LazyOp::dtype(self))
+    }
+
+    fn src(&self) -> &[Self] {
+        &self.src
+    }
+
+    fn with_src(&self, src: Vec<Self>) -> Self {
+        LazyOp::new(self.op, src, self.arg.clone())
+    }
+
+    /// Only an unmasked constant is the same everywhere.
+    fn konst(&self) -> Option<f64> {
+        match &self.arg {
+            Some(LazyOpArg::ConstBuffer(c)) if c.st.mask.is_none() => Some(c.val),
+            _ => None,
+        }
+    }
+
+    /// Broadcast through the view of the first leaf under this node.
+    fn const_like(&self, val: f64, dtype: DType) -> Self {
+        let st = self
+            .lazyops()
+            .into_iter()
+            .find_map(|op| match &op.arg {
+                Some(LazyOpArg::MemBuffer(m)) => Some(m.st.clone()),
+                Some(LazyOpArg::ConstBuffer(c)) => Some(c.st.clone()),
+                _ => None,
+            })
+            .expect("a kernel AST ends in loads and consts");
+        LazyOp::constant(val, dtype, st)
+    }
+}
@@ -0,0 +1,135 @@
+// counter-based random numbers. Every output word is a pure function of (key, index), so the stream is
+// the same no matter how the work is chunked across threads or kernels
+use std::{
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use lazy_static::lazy_static;
+
+use crate::prelude::THREEFRY;
+
+const ROTATIONS: [u32; 8] = [13, 15, 26, 6, 17, 29, 16, 24];
+const SKEIN_KS_PARITY32: u32 = 0x1BD11BDA;
+
+/// Threefry-2x32 with 20 rounds, as in Random123 and JAX.
+pub fn threefry2x32(key: [u32; 2], ctr: [u32; 2]) -> [u32; 2] {
+    let ks = [key[0], key[1], SKEIN_KS_PARITY32 ^ key[0] ^ key[1]];
+    let mut x = [ctr[0].wrapping_add(ks[0]), ctr[1].wrapping_add(ks[1])];
+    for r in 0..20 {
+        x[0] = x[0].wrapping_add(x[1]);
+        x[1] = x[1].rotate_left(ROTATIONS[r % 8]) ^ x[0];
+        if r % 4 == 3 {
+            let s = r / 4 + 1;
+            x[0] = x[0].wrapping_add(ks[s % 3]);
+            x[1] = x[1].wrapping_add(ks[(s + 1) % 3]).wrapping_add(s as u32);
+        }
+    }
+    x
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Threefry {
+    pub key: [u32; 2],
+}
+
+impl Threefry {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            key: [seed as u32, (seed >> 32) as u32],
+        }
+    }
+
+    /// `n` independent generators derived from this one.
+    pub fn split(&self, n: usize) -> Vec<Threefry> {
+        (0..n)
+            .map(|i| Threefry {
+                key: threefry2x32(self.key, [i as u32, ((i as u64) >> 32) as u32 ^ 0x5eed]),
+            })
+            .collect()
+    }
+
+    /// A new generator that deterministically mixes `data` into this key.
+    pub fn fold_in(&self, data: u32) -> Threefry {
+        Threefry {
+            key: threefry2x32(self.key, [data, 0]),
+        }
+    }
+
+    /// The random word at absolute position `idx` of this generator's stream.
+    pub fn word(&self, idx: u64) -> u32 {
+        let block = idx / 2;
+        threefry2x32(self.key, [block as u32, (block >> 32) as u32])[(idx % 2) as usize]
+    }
+
+    /// Words `start..start + n` of the stream.
+    pub fn random_bits(&self, start: u64, n: usize) -> Vec<u32> {
+        (start..start + n as u64).map(|i| self.word(i)).collect()
+    }
+
+    /// Same as `random_bits`, computed in `threads` contiguous chunks at once.
+    pub fn random_bits_parallel(&self, start: u64, n: usize, threads: usize) -> Vec<u32> {
+        let chunk = n.div_ceil(threads.max(1)).max(1);
+        let mut out = vec![0u32; n];
+        std::thread::scope(|s| {
+            for (i, part) in out.chunks_mut(chunk).enumerate() {
+                s.spawn(move || {
+                    let base = start + (i * chunk) as u64;
+                    for (j, w) in part.iter_mut().enumerate() {
+                        *w = self.word(base + j as u64);
+                    }
+                });
+            }
+        });
+        out
+    }
+}
+
+pub struct RngState {
+    pub seed: u64,
+    pub counter: u64,
+}
+
+lazy_static! {
+    pub static ref RNG_STATE: Arc<Mutex<RngState>> = Arc::new(Mutex::new(RngState {
+        seed: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        counter: 0,
+    }));
+}
+
+/// Reset the global generator. Every draw after this is a pure function of `seed` and the draws before it.
+pub fn manual_seed(seed: u64) {
+    let mut state = RNG_STATE.lock().unwrap();
+    state.seed = seed;
+    state.counter = 0;
+}
+
+/// Take the next `n` words from the global stream. With `THREEFRY>=1` they are generated across
+/// all cores; the words are bit-identical either way.
+pub fn next_random_bits(n: usize) -> Vec<u32> {
+    let (gen, start) = {
+        let mut state = RNG_STATE.lock().unwrap();
+        let start = state.counter;
+        state.counter += n as u64;
+        (Threefry::new(state.seed), start)
+    };
+    if THREEFRY.clone() >= 1 {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        gen.random_bits_parallel(start, n, threads)
+    } else {
+        gen.random_bits(start, n)
+    }
+}
+
+/// Uniform `f64`s in `[0, 1)` with 53 random bits each.
+pub fn next_uniform(n: usize) -> Vec<f64> {
+    next_random_bits(2 * n)
+        .chunks(2)
+        .map(|w| {
+            let bits = ((w[0] as u64) << 32) | w[1] as u64;
+            (bits >> 11) as f64 * (1.0 / (1u64 << 53) as f64)
+        })
+        .collect()
+}
@@ -0,0 +1,5 @@
+pub mod ops_clang;
+pub mod ops_disk;
+pub mod ops_interp;
+pub mod ops_llvm;
+pub mod pool;
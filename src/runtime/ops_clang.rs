@@ -0,0 +1,291 @@
+// CLANG: kernels rendered to C, built into a shared object by the system compiler and dlopened
+use std::{
+    fs::File,
+    io::Write,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use libloading::Library;
+
+use crate::{
+    codegen::uops::{UOp, UOps},
+    device::{Compiled, Compiler, LRUAllocator, MallocAllocator, Program, Runtime},
+    helpers::{cpu_objdump, diskcache_get, diskcache_put, CACHELEVEL},
+    prelude::DEBUG,
+    renderer::cstyle::{uops_to_cstyle, CStyleLanguage},
+    runtime::pool::POOL,
+};
+
+type EntryFn = unsafe extern "C" fn(*const *mut u8, *const i32);
+
+/// Render `uops` for CLANG. Besides the kernel this emits `<name>_entry(void **bufs, const int *vars)`,
+/// which unpacks the buffer and var arrays, so the runtime can call any kernel through one signature.
+pub fn render(function_name: &str, uops: &[UOp]) -> String {
+    let lang = CStyleLanguage::clang();
+    let src = uops_to_cstyle(&lang, function_name, uops);
+    let args: Vec<String> = uops
+        .iter()
+        .filter(|u| u.uop == UOps::DefineGlobal)
+        .enumerate()
+        .map(|(i, u)| format!("({}*)bufs[{}]", lang.render_dtype(u.dtype.unwrap()), i))
+        .chain(
+            (0..uops.iter().filter(|u| u.uop == UOps::DefineVar).count())
+                .map(|i| format!("vars[{}]", i)),
+        )
+        .collect();
+    format!(
+        "{}\nvoid {}_entry(void **bufs, const int *vars) {{ {}({}); }}\n",
+        src,
+        function_name,
+        function_name,
+        args.join(", ")
+    )
+}
+
+lazy_static! {
+    static ref COMPILER: Option<&'static str> = ["clang", "cc"].into_iter().find(|c| {
+        Command::new(c)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    });
+}
+
+/// The C compiler to use: `clang` when it's installed, `cc` otherwise. Looked up once.
+pub fn find_compiler() -> Option<&'static str> {
+    *COMPILER
+}
+
+pub struct ClangCompiler {
+    pub args: Vec<String>,
+}
+
+impl Default for ClangCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClangCompiler {
+    pub fn new() -> Self {
+        Self {
+            args: [
+                "-shared",
+                "-march=native",
+                "-O2",
+                "-Wall",
+                "-Werror",
+                "-fPIC",
+            ]
+            .map(String::from)
+            .to_vec(),
+        }
+    }
+
+    /// C source in, shared object bytes out.
+    pub fn compile(&self, src: &str) -> Result<Vec<u8>, anyhow::Error> {
+        self.compile_timeout(src, None)
+    }
+
+    /// `compile`, killing the compiler if it runs longer than `timeout`.
+    pub fn compile_timeout(
+        &self,
+        src: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let cc = find_compiler().ok_or(anyhow!("no C compiler found, need clang or cc"))?;
+        let dir = tempfile::tempdir()?;
+        let out = dir.path().join("kernel.so");
+        // stderr goes to a file so a chatty compiler can't fill the pipe while we poll it
+        let errors = dir.path().join("stderr");
+        let mut child = Command::new(cc)
+            .args(&self.args)
+            .args(["-x", "c", "-", "-o"])
+            .arg(&out)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(File::create(&errors)?)
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .ok_or(anyhow!("failed to open {} stdin", cc))?
+            .write_all(src.as_bytes())?;
+        let st = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if timeout.is_some_and(|t| st.elapsed() > t) {
+                child.kill()?;
+                child.wait()?;
+                return Err(anyhow!("{} timed out after {:?}", cc, timeout.unwrap()));
+            }
+            thread::sleep(Duration::from_millis(1));
+        };
+        if !status.success() {
+            return Err(anyhow!(
+                "{} failed:\n{}\n{}",
+                cc,
+                std::fs::read_to_string(errors)?,
+                src
+            ));
+        }
+        Ok(std::fs::read(out)?)
+    }
+
+    /// The diskcache key of `src`, flags included so a flag change never hits a stale object.
+    pub fn cache_key(&self, src: &str) -> String {
+        format!(
+            "{:x}",
+            md5::compute(format!("{}\n{}", self.args.join(" "), src))
+        )
+    }
+
+    /// `compile` through the `compile_clang` diskcache, a hit never runs the compiler.
+    pub fn compile_cached(&self, src: &str) -> Result<Vec<u8>, anyhow::Error> {
+        if *CACHELEVEL == 0 {
+            return self.compile(src);
+        }
+        let key = self.cache_key(src);
+        if let Some(lib) = diskcache_get("compile_clang", Box::new(key.clone())) {
+            return Ok(lib);
+        }
+        diskcache_put("compile_clang", Box::new(key), self.compile(src)?)
+    }
+}
+
+pub struct ClangProgram {
+    pub name: String,
+    pub lib: Vec<u8>,
+    // the library has to outlive `entry`
+    _library: Library,
+    entry: EntryFn,
+}
+
+impl ClangProgram {
+    pub fn new(name: &str, lib: Vec<u8>) -> Result<Self, anyhow::Error> {
+        if DEBUG.clone() >= 5 {
+            cpu_objdump(&lib)?;
+        }
+        let mut file = tempfile::Builder::new().suffix(".so").tempfile()?;
+        file.write_all(&lib)?;
+        // SAFETY: the library is one of our own kernels, with no initialisers
+        let library = unsafe { Library::new(file.path())? };
+        let entry: EntryFn =
+            unsafe { *library.get::<EntryFn>(format!("{}_entry", name).as_bytes())? };
+        Ok(Self {
+            name: name.to_string(),
+            lib,
+            _library: library,
+            entry,
+        })
+    }
+
+    /// Run the kernel over `bufs`, in `DefineGlobal` order. Returns the wall time in seconds when `wait` is set.
+    pub fn call(&self, bufs: &mut [&mut [u8]], wait: bool) -> Option<f64> {
+        self.call_vars(bufs, &[], wait)
+    }
+
+    /// `call` with values for the kernel's `DefineVar`s, in order.
+    pub fn call_vars(&self, bufs: &mut [&mut [u8]], vars: &[i32], wait: bool) -> Option<f64> {
+        let ptrs: Vec<*mut u8> = bufs.iter_mut().map(|b| b.as_mut_ptr()).collect();
+        let st = Instant::now();
+        // SAFETY: the kernel only indexes inside the buffers the linearizer sized it for
+        unsafe { (self.entry)(ptrs.as_ptr(), vars.as_ptr()) };
+        wait.then(|| st.elapsed().as_secs_f64())
+    }
+}
+
+/// The buffer pointers, shared by the threads of one split call.
+struct BufPtrs(*const *mut u8);
+// SAFETY: every thread writes a disjoint range of the outer loop, and the call waits for all of them
+unsafe impl Send for BufPtrs {}
+unsafe impl Sync for BufPtrs {}
+
+/// Render, compile (through the diskcache) and load `uops` in one go. `DEBUG>=4` prints the source.
+pub fn compile_uops(function_name: &str, uops: &[UOp]) -> Result<ClangProgram, anyhow::Error> {
+    let src = render(function_name, uops);
+    if DEBUG.clone() >= 4 {
+        println!("{}", src);
+    }
+    ClangProgram::new(function_name, ClangCompiler::new().compile_cached(&src)?)
+}
+
+impl Compiler for ClangCompiler {
+    fn render(&self, name: &str, uops: &[UOp]) -> String {
+        render(name, uops)
+    }
+
+    fn compile_uops(&self, name: &str, uops: &[UOp]) -> Result<Vec<u8>, anyhow::Error> {
+        let src = self.render(name, uops);
+        if DEBUG.clone() >= 4 {
+            println!("{}", src);
+        }
+        self.compile_cached(&src)
+    }
+}
+
+impl Program for ClangProgram {
+    fn call(&self, bufs: &mut [&mut [u8]], vars: &[i32], wait: bool) -> Option<f64> {
+        self.call_vars(bufs, vars, wait)
+    }
+
+    /// Splits the outer loop into `threads` near equal ranges and runs them on the pool.
+    fn call_split(
+        &self,
+        bufs: &mut [&mut [u8]],
+        outer: usize,
+        threads: usize,
+        wait: bool,
+    ) -> Option<f64> {
+        let threads = threads.min(POOL.size).min(outer);
+        if threads <= 1 {
+            return self.call_vars(bufs, &[0, outer as i32], wait);
+        }
+        let ptrs: Vec<*mut u8> = bufs.iter_mut().map(|b| b.as_mut_ptr()).collect();
+        let shared = BufPtrs(ptrs.as_ptr());
+        let (shared, entry) = (&shared, self.entry);
+        let st = Instant::now();
+        POOL.scope(
+            (0..threads)
+                .map(|t| {
+                    let vars = [
+                        (outer * t / threads) as i32,
+                        (outer * (t + 1) / threads) as i32,
+                    ];
+                    Box::new(move || {
+                        // SAFETY: as in `call_vars`, and the ranges don't overlap
+                        unsafe { entry(shared.0, vars.as_ptr()) }
+                    }) as Box<dyn FnOnce() + Send>
+                })
+                .collect(),
+        );
+        wait.then(|| st.elapsed().as_secs_f64())
+    }
+}
+
+pub struct ClangRuntime;
+
+impl Runtime for ClangRuntime {
+    fn load(&self, name: &str, lib: &[u8]) -> Result<Box<dyn Program>, anyhow::Error> {
+        Ok(Box::new(ClangProgram::new(name, lib.to_vec())?))
+    }
+}
+
+/// The CLANG device, as long as there's a C compiler to build its kernels with.
+pub fn device(name: &str) -> Result<Compiled, anyhow::Error> {
+    find_compiler().ok_or(anyhow!("{} needs clang or cc on the PATH", name))?;
+    Ok(Compiled {
+        name: name.to_string(),
+        allocator: Box::new(LRUAllocator::new(MallocAllocator)),
+        compiler: Box::new(ClangCompiler::new()),
+        runtime: Box::new(ClangRuntime),
+    })
+}
@@ -0,0 +1,112 @@
+// DISK: a file as device memory. It can't run kernels, tensors go through it to be saved or loaded
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    sync::Mutex,
+};
+
+use anyhow::anyhow;
+
+use crate::device::{Allocator, Device, RawBuffer};
+
+pub struct DiskAllocator {
+    pub path: String,
+    file: Mutex<Option<File>>,
+}
+
+impl DiskAllocator {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Run `f` on the file, opening (or creating) it the first time and growing it to at least `size` bytes.
+    fn with_file<T>(
+        &self,
+        size: u64,
+        f: impl FnOnce(&mut File) -> std::io::Result<T>,
+    ) -> Result<T, anyhow::Error> {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            *file = Some(
+                OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(&self.path)?,
+            );
+        }
+        let file = file.as_mut().unwrap();
+        if file.metadata()?.len() < size {
+            file.set_len(size)?;
+        }
+        Ok(f(file)?)
+    }
+}
+
+impl Allocator for DiskAllocator {
+    /// Every buffer starts at the top of the file, so a load sees what the last store wrote.
+    fn alloc(&self, size: usize) -> Result<RawBuffer, anyhow::Error> {
+        self.with_file(size as u64, |_| Ok(()))?;
+        Ok(RawBuffer::Disk { offset: 0, size })
+    }
+
+    fn copyin(&self, dst: &mut RawBuffer, src: &[u8]) -> Result<(), anyhow::Error> {
+        let RawBuffer::Disk { offset, size } = *dst else {
+            return Err(anyhow!("{:?} isn't on disk", dst));
+        };
+        anyhow::ensure!(
+            size == src.len(),
+            "can't copy {} bytes into {}",
+            src.len(),
+            size
+        );
+        self.with_file(offset + size as u64, |f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.write_all(src)
+        })
+    }
+
+    fn copyout(&self, dst: &mut [u8], src: &RawBuffer) -> Result<(), anyhow::Error> {
+        let RawBuffer::Disk { offset, size } = *src else {
+            return Err(anyhow!("{:?} isn't on disk", src));
+        };
+        anyhow::ensure!(
+            size == dst.len(),
+            "can't copy {} bytes into {}",
+            size,
+            dst.len()
+        );
+        self.with_file(offset + size as u64, |f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(dst)
+        })
+    }
+}
+
+pub struct DiskDevice {
+    pub name: String,
+    pub allocator: DiskAllocator,
+}
+
+impl DiskDevice {
+    pub fn new(name: &str, path: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            allocator: DiskAllocator::new(path),
+        }
+    }
+}
+
+impl Device for DiskDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn allocator(&self) -> &dyn Allocator {
+        &self.allocator
+    }
+}
@@ -0,0 +1,508 @@
+// INTERP: runs uops one at a time in rust. Slow, but needs no toolchain and follows C semantics for
+// every dtype, which makes it the reference the compiled backends are checked against
+use std::time::Instant;
+
+use crate::{
+    codegen::uops::{UArg, UOp, UOps},
+    device::{Compiled, Compiler, LRUAllocator, MallocAllocator, Program, Runtime},
+    dtype::{DType, ScalarType},
+    ops::{BinaryOps, Op, TernaryOps, UnaryOps},
+};
+
+/// IEEE binary16 bits to f32.
+pub fn f16_to_f32(h: u16) -> f32 {
+    let sign = ((h as u32) & 0x8000) << 16;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let man = (h & 0x3ff) as u32;
+    match exp {
+        0 => {
+            let v = man as f32 * 2f32.powi(-24);
+            if sign != 0 {
+                -v
+            } else {
+                v
+            }
+        }
+        31 => f32::from_bits(sign | 0x7f80_0000 | (man << 13)),
+        _ => f32::from_bits(sign | ((exp + 112) << 23) | (man << 13)),
+    }
+}
+
+/// f32 to IEEE binary16 bits, rounding to nearest even.
+pub fn f32_to_f16(x: f32) -> u16 {
+    let bits = x.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exp = ((bits >> 23) & 0xff) as i32;
+    let man = bits & 0x7f_ffff;
+    if exp == 255 {
+        return sign | 0x7c00 | if man != 0 { 0x200 } else { 0 };
+    }
+    let e = exp - 127 + 15;
+    if e >= 31 {
+        return sign | 0x7c00;
+    }
+    let round = |m: u32, shift: u32| {
+        let (q, rem, half) = (m >> shift, m & ((1 << shift) - 1), 1 << (shift - 1));
+        if rem > half || (rem == half && q & 1 == 1) {
+            q + 1
+        } else {
+            q
+        }
+    };
+    if e <= 0 {
+        if e < -10 {
+            return sign;
+        }
+        return sign | round(man | 0x80_0000, (14 - e) as u32) as u16;
+    }
+    // a carry out of the mantissa correctly bumps the exponent, up to inf
+    sign | round(((e as u32) << 23) | man, 13) as u16
+}
+
+/// A value held by one uop, wide enough for every dtype.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Val {
+    Float(f64),
+    Int(i64),
+    UInt(u64),
+    Bool(bool),
+}
+
+impl Val {
+    pub fn as_f64(self) -> f64 {
+        match self {
+            Val::Float(x) => x,
+            Val::Int(x) => x as f64,
+            Val::UInt(x) => x as f64,
+            Val::Bool(x) => x as u8 as f64,
+        }
+    }
+
+    fn as_i64(self) -> i64 {
+        match self {
+            Val::Float(x) => x as i64,
+            Val::Int(x) => x,
+            Val::UInt(x) => x as i64,
+            Val::Bool(x) => x as i64,
+        }
+    }
+
+    fn as_u64(self) -> u64 {
+        match self {
+            Val::Float(x) => x as u64,
+            Val::Int(x) => x as u64,
+            Val::UInt(x) => x,
+            Val::Bool(x) => x as u64,
+        }
+    }
+
+    pub fn truthy(self) -> bool {
+        match self {
+            Val::Float(x) => x != 0.0,
+            Val::Int(x) => x != 0,
+            Val::UInt(x) => x != 0,
+            Val::Bool(x) => x,
+        }
+    }
+
+    /// C cast semantics: floats truncate toward zero, ints wrap to the width of `dtype`.
+    pub fn cast(self, dtype: DType) -> Val {
+        match dtype.scalar {
+            ScalarType::Bool => Val::Bool(self.truthy()),
+            ScalarType::Float16 => Val::Float(f16_to_f32(f32_to_f16(self.as_f64() as f32)) as f64),
+            ScalarType::Float32 => Val::Float(self.as_f64() as f32 as f64),
+            ScalarType::Float64 => Val::Float(self.as_f64()),
+            ScalarType::Int8 => Val::Int(self.as_i64() as i8 as i64),
+            ScalarType::Int16 => Val::Int(self.as_i64() as i16 as i64),
+            ScalarType::Int32 => Val::Int(self.as_i64() as i32 as i64),
+            ScalarType::Int64 => Val::Int(self.as_i64()),
+            ScalarType::UInt8 => Val::UInt(self.as_u64() as u8 as u64),
+            ScalarType::UInt16 => Val::UInt(self.as_u64() as u16 as u64),
+            ScalarType::UInt32 => Val::UInt(self.as_u64() as u32 as u64),
+            ScalarType::UInt64 => Val::UInt(self.as_u64()),
+        }
+    }
+
+    pub fn from_f64(x: f64, dtype: DType) -> Val {
+        Val::Float(x).cast(dtype)
+    }
+
+    pub fn load(buf: &[u8], idx: usize, dtype: DType) -> Val {
+        let n = dtype.itemsize();
+        let b = buf.get(idx * n..(idx + 1) * n).unwrap_or_else(|| {
+            panic!(
+                "load of {} at {} is out of bounds for a buffer of {} bytes",
+                dtype,
+                idx,
+                buf.len()
+            )
+        });
+        match dtype.scalar {
+            ScalarType::Bool => Val::Bool(b[0] != 0),
+            ScalarType::Int8 => Val::Int(b[0] as i8 as i64),
+            ScalarType::UInt8 => Val::UInt(b[0] as u64),
+            ScalarType::Int16 => Val::Int(i16::from_ne_bytes([b[0], b[1]]) as i64),
+            ScalarType::UInt16 => Val::UInt(u16::from_ne_bytes([b[0], b[1]]) as u64),
+            ScalarType::Int32 => Val::Int(i32::from_ne_bytes(b.try_into().unwrap()) as i64),
+            ScalarType::UInt32 => Val::UInt(u32::from_ne_bytes(b.try_into().unwrap()) as u64),
+            ScalarType::Int64 => Val::Int(i64::from_ne_bytes(b.try_into().unwrap())),
+            ScalarType::UInt64 => Val::UInt(u64::from_ne_bytes(b.try_into().unwrap())),
+            ScalarType::Float16 => Val::Float(f16_to_f32(u16::from_ne_bytes([b[0], b[1]])) as f64),
+            ScalarType::Float32 => Val::Float(f32::from_ne_bytes(b.try_into().unwrap()) as f64),
+            ScalarType::Float64 => Val::Float(f64::from_ne_bytes(b.try_into().unwrap())),
+        }
+    }
+
+    pub fn store(self, buf: &mut [u8], idx: usize, dtype: DType) {
+        let n = dtype.itemsize();
+        let len = buf.len();
+        let b = buf.get_mut(idx * n..(idx + 1) * n).unwrap_or_else(|| {
+            panic!(
+                "store of {} at {} is out of bounds for a buffer of {} bytes",
+                dtype, idx, len
+            )
+        });
+        let v = self.cast(dtype);
+        match dtype.scalar {
+            ScalarType::Bool => b[0] = v.truthy() as u8,
+            ScalarType::Int8 => b[0] = v.as_i64() as i8 as u8,
+            ScalarType::UInt8 => b[0] = v.as_u64() as u8,
+            ScalarType::Int16 => b.copy_from_slice(&(v.as_i64() as i16).to_ne_bytes()),
+            ScalarType::UInt16 => b.copy_from_slice(&(v.as_u64() as u16).to_ne_bytes()),
+            ScalarType::Int32 => b.copy_from_slice(&(v.as_i64() as i32).to_ne_bytes()),
+            ScalarType::UInt32 => b.copy_from_slice(&(v.as_u64() as u32).to_ne_bytes()),
+            ScalarType::Int64 => b.copy_from_slice(&v.as_i64().to_ne_bytes()),
+            ScalarType::UInt64 => b.copy_from_slice(&v.as_u64().to_ne_bytes()),
+            ScalarType::Float16 => b.copy_from_slice(&f32_to_f16(v.as_f64() as f32).to_ne_bytes()),
+            ScalarType::Float32 => b.copy_from_slice(&(v.as_f64() as f32).to_ne_bytes()),
+            ScalarType::Float64 => b.copy_from_slice(&v.as_f64().to_ne_bytes()),
+        }
+    }
+}
+
+/// One ALU op with C semantics in `dtype`, the dtype of the result.
+pub fn exec_alu_typed(op: Op, dtype: DType, srcs: &[Val]) -> Val {
+    if let Op::Ternary(t) = op {
+        return match t {
+            TernaryOps::Where => {
+                if srcs[0].truthy() {
+                    srcs[1]
+                } else {
+                    srcs[2]
+                }
+            }
+            TernaryOps::MulAcc => {
+                let m = exec_alu_typed(BinaryOps::Mul.into(), dtype, &srcs[..2]);
+                exec_alu_typed(BinaryOps::Add.into(), dtype, &[m, srcs[2]])
+            }
+        }
+        .cast(dtype);
+    }
+    if let Op::Binary(b @ (BinaryOps::CmpLt | BinaryOps::CmpEq)) = op {
+        let (x, y) = (srcs[0], srcs[1]);
+        let r = match (x, y) {
+            (Val::Float(_), _) | (_, Val::Float(_)) => {
+                let (x, y) = (x.as_f64(), y.as_f64());
+                if b == BinaryOps::CmpLt {
+                    x < y
+                } else {
+                    x == y
+                }
+            }
+            (Val::UInt(_), _) | (_, Val::UInt(_)) => {
+                let (x, y) = (x.as_u64(), y.as_u64());
+                if b == BinaryOps::CmpLt {
+                    x < y
+                } else {
+                    x == y
+                }
+            }
+            _ => {
+                let (x, y) = (x.as_i64(), y.as_i64());
+                if b == BinaryOps::CmpLt {
+                    x < y
+                } else {
+                    x == y
+                }
+            }
+        };
+        return Val::Bool(r);
+    }
+    if dtype.is_float() {
+        let s: Vec<f64> = srcs.iter().map(|v| v.as_f64()).collect();
+        let r = match op {
+            Op::Unary(u) => {
+                let x = s[0];
+                match u {
+                    UnaryOps::Exp2 => x.exp2(),
+                    UnaryOps::Log2 => x.log2(),
+                    UnaryOps::Sin => x.sin(),
+                    UnaryOps::Sqrt => x.sqrt(),
+                    UnaryOps::Neg => -x,
+                    UnaryOps::Recip => 1.0 / x,
+                    UnaryOps::Cast => x,
+                }
+            }
+            Op::Binary(b) => {
+                let (x, y) = (s[0], s[1]);
+                match b {
+                    BinaryOps::Add => x + y,
+                    BinaryOps::Sub => x - y,
+                    BinaryOps::Mul => x * y,
+                    BinaryOps::Div => x / y,
+                    BinaryOps::Max => x.max(y),
+                    BinaryOps::Mod => x % y,
+                    BinaryOps::CmpLt | BinaryOps::CmpEq => unreachable!(),
+                }
+            }
+            _ => panic!("{:?} isn't an alu op", op),
+        };
+        return Val::Float(r).cast(dtype);
+    }
+    // integers and bools: wrapping arithmetic in 64 bits, then truncated to the dtype
+    let unsigned = dtype.is_unsigned();
+    let r = match op {
+        Op::Unary(u) => match u {
+            UnaryOps::Neg => Val::Int(srcs[0].as_i64().wrapping_neg()),
+            UnaryOps::Cast => srcs[0],
+            UnaryOps::Recip => Val::Int(match srcs[0].as_i64() {
+                0 => 0,
+                x => 1 / x,
+            }),
+            _ => Val::Float(exec_alu_typed(op, DType::FLOAT64, srcs).as_f64()),
+        },
+        Op::Binary(b) if unsigned => {
+            let (x, y) = (srcs[0].as_u64(), srcs[1].as_u64());
+            Val::UInt(match b {
+                BinaryOps::Add => x.wrapping_add(y),
+                BinaryOps::Sub => x.wrapping_sub(y),
+                BinaryOps::Mul => x.wrapping_mul(y),
+                BinaryOps::Div => x.checked_div(y).unwrap_or(0),
+                BinaryOps::Max => x.max(y),
+                BinaryOps::Mod => x.checked_rem(y).unwrap_or(0),
+                BinaryOps::CmpLt | BinaryOps::CmpEq => unreachable!(),
+            })
+        }
+        Op::Binary(b) => {
+            let (x, y) = (srcs[0].as_i64(), srcs[1].as_i64());
+            Val::Int(match b {
+                BinaryOps::Add => x.wrapping_add(y),
+                BinaryOps::Sub => x.wrapping_sub(y),
+                BinaryOps::Mul => x.wrapping_mul(y),
+                // C division truncates toward zero
+                BinaryOps::Div => x.checked_div(y).unwrap_or(0),
+                BinaryOps::Max => x.max(y),
+                BinaryOps::Mod => x.checked_rem(y).unwrap_or(0),
+                BinaryOps::CmpLt | BinaryOps::CmpEq => unreachable!(),
+            })
+        }
+        _ => panic!("{:?} isn't an alu op", op),
+    };
+    r.cast(dtype)
+}
+
+/// Pickled uops are the "binary" for INTERP. Pickle, unlike json, keeps inf and nan constants.
+pub fn render(uops: &[UOp]) -> Vec<u8> {
+    serde_pickle::to_vec(&uops, serde_pickle::SerOptions::default()).unwrap()
+}
+
+pub struct InterpProgram {
+    pub name: String,
+    pub uops: Vec<UOp>,
+    /// for each `Loop`/`If`, the position of its `End`
+    ends: Vec<usize>,
+}
+
+impl InterpProgram {
+    pub fn new(name: &str, lib: &[u8]) -> Result<Self, anyhow::Error> {
+        let uops: Vec<UOp> = serde_pickle::from_slice(lib, serde_pickle::DeOptions::default())?;
+        let mut ends = vec![0; uops.len()];
+        let mut stack = vec![];
+        for (i, u) in uops.iter().enumerate() {
+            match u.uop {
+                UOps::Loop | UOps::If => stack.push(i),
+                UOps::End => {
+                    let s = stack
+                        .pop()
+                        .ok_or(anyhow::anyhow!("End at {} closes nothing", i))?;
+                    ends[s] = i;
+                }
+                _ => {}
+            }
+        }
+        anyhow::ensure!(stack.is_empty(), "unclosed scopes in {}", name);
+        Ok(Self {
+            name: name.to_string(),
+            uops,
+            ends,
+        })
+    }
+
+    /// The element a load or store `u` starts at: its index, or the first element of the texel at
+    /// its (x, y) on an image.
+    fn vector_idx(&self, lanes: &[Vec<Val>], vals: &[Val], u: &UOp) -> usize {
+        let idx = match self.uops[u.vin[0]].dtype.unwrap().image {
+            Some((_, w)) if !lanes[u.vin[1]].is_empty() => {
+                let (x, y) = (lanes[u.vin[1]][0].as_i64(), lanes[u.vin[1]][1].as_i64());
+                (y * w as i64 + x) * 4
+            }
+            _ => vals[u.vin[1]].as_i64(),
+        };
+        assert!(idx >= 0, "negative index {} in {}", idx, self.name);
+        idx as usize
+    }
+
+    /// Run over `bufs`, in `DefineGlobal` order. Returns the wall time in seconds when `wait` is set.
+    pub fn call(&self, bufs: &mut [&mut [u8]], wait: bool) -> Option<f64> {
+        self.call_vars(bufs, &[], wait)
+    }
+
+    /// `call` with values for the kernel's `DefineVar`s, in order.
+    pub fn call_vars(&self, bufs: &mut [&mut [u8]], vars: &[i32], wait: bool) -> Option<f64> {
+        let st = Instant::now();
+        let mut nvar = 0;
+        let uops = &self.uops;
+        let mut vals: Vec<Val> = vec![Val::Int(0); uops.len()];
+        // the lanes of vector uops, empty for scalar ones
+        let mut lanes: Vec<Vec<Val>> = vec![vec![]; uops.len()];
+        let mut buf_of: Vec<usize> = vec![0; uops.len()];
+        let mut nbuf = 0;
+        let mut pc = 0;
+        while pc < uops.len() {
+            let u = &uops[pc];
+            let src = |i: usize| vals[u.vin[i]];
+            match u.uop {
+                UOps::DefineGlobal => {
+                    buf_of[pc] = nbuf;
+                    nbuf += 1;
+                }
+                UOps::DefineVar => {
+                    vals[pc] = Val::Int(vars[nvar] as i64);
+                    nvar += 1;
+                }
+                UOps::Const | UOps::DefineAcc => {
+                    let UArg::Const(c) = u.arg else {
+                        panic!("{:?} needs a value", u.uop)
+                    };
+                    vals[pc] = Val::from_f64(c, u.dtype.unwrap());
+                }
+                UOps::Loop => {
+                    let (start, end) = (src(0), src(1));
+                    if start.as_i64() >= end.as_i64() {
+                        pc = self.ends[pc] + 1;
+                        continue;
+                    }
+                    vals[pc] = start.cast(u.dtype.unwrap());
+                }
+                UOps::If => {
+                    if !src(0).truthy() {
+                        pc = self.ends[pc] + 1;
+                        continue;
+                    }
+                }
+                UOps::End => {
+                    let s = u.vin[0];
+                    if uops[s].uop == UOps::Loop {
+                        let next = vals[s].as_i64() + 1;
+                        if next < vals[uops[s].vin[1]].as_i64() {
+                            vals[s] = Val::Int(next);
+                            pc = s + 1;
+                            continue;
+                        }
+                    }
+                }
+                UOps::Load if u.dtype.is_some_and(|d| d.count > 1) => {
+                    let dtype = u.dtype.unwrap();
+                    let idx = self.vector_idx(&lanes, &vals, u);
+                    let buf = &bufs[buf_of[u.vin[0]]];
+                    lanes[pc] = (0..dtype.count)
+                        .map(|l| Val::load(buf, idx + l, dtype.scalar()))
+                        .collect();
+                }
+                UOps::Load => {
+                    let dtype = u.dtype.unwrap();
+                    vals[pc] = if u.vin.len() > 2 && !src(2).truthy() {
+                        src(3)
+                    } else {
+                        let idx = src(1).as_i64();
+                        assert!(idx >= 0, "negative load index {} in {}", idx, self.name);
+                        Val::load(bufs[buf_of[u.vin[0]]], idx as usize, dtype)
+                    };
+                }
+                UOps::Store => {
+                    let idx = self.vector_idx(&lanes, &vals, u);
+                    let dtype = uops[u.vin[0]].dtype.unwrap();
+                    let buf = &mut bufs[buf_of[u.vin[0]]];
+                    if lanes[u.vin[2]].is_empty() {
+                        src(2).store(buf, idx, dtype);
+                    }
+                    for (l, v) in lanes[u.vin[2]].iter().enumerate() {
+                        v.store(buf, idx + l, dtype);
+                    }
+                }
+                UOps::Gep => {
+                    let UArg::Index(lane) = u.arg else {
+                        panic!("Gep needs a lane")
+                    };
+                    vals[pc] = lanes[u.vin[0]][lane];
+                }
+                UOps::Vectorize => lanes[pc] = u.vin.iter().map(|v| vals[*v]).collect(),
+                UOps::Alu => {
+                    let UArg::Op(op) = u.arg else {
+                        panic!("Alu needs an op")
+                    };
+                    let mut srcs = [Val::Int(0); 3];
+                    for (s, v) in srcs.iter_mut().zip(&u.vin) {
+                        *s = vals[*v];
+                    }
+                    vals[pc] = exec_alu_typed(op, u.dtype.unwrap(), &srcs[..u.vin.len()]);
+                }
+                UOps::Cast => vals[pc] = src(0).cast(u.dtype.unwrap()),
+                UOps::Phi => {
+                    let v = src(1);
+                    vals[u.vin[0]] = v;
+                    vals[pc] = v;
+                }
+            }
+            pc += 1;
+        }
+        wait.then(|| st.elapsed().as_secs_f64())
+    }
+}
+
+pub struct InterpCompiler;
+
+impl Compiler for InterpCompiler {
+    /// The uops it runs, one per line.
+    fn render(&self, _name: &str, uops: &[UOp]) -> String {
+        let lines: Vec<String> = uops.iter().map(|u| u.to_string()).collect();
+        lines.join("\n")
+    }
+
+    fn compile_uops(&self, _name: &str, uops: &[UOp]) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(render(uops))
+    }
+}
+
+impl Program for InterpProgram {
+    fn call(&self, bufs: &mut [&mut [u8]], vars: &[i32], wait: bool) -> Option<f64> {
+        self.call_vars(bufs, vars, wait)
+    }
+}
+
+pub struct InterpRuntime;
+
+impl Runtime for InterpRuntime {
+    fn load(&self, name: &str, lib: &[u8]) -> Result<Box<dyn Program>, anyhow::Error> {
+        Ok(Box::new(InterpProgram::new(name, lib)?))
+    }
+}
+
+pub fn device(name: &str) -> Compiled {
+    Compiled {
+        name: name.to_string(),
+        allocator: Box::new(LRUAllocator::new(MallocAllocator)),
+        compiler: Box::new(InterpCompiler),
+        runtime: Box::new(InterpRuntime),
+    }
+}
@@ -0,0 +1,260 @@
+// LLVM: kernels rendered to LLVM IR, optimized by `opt` when it's there and built into a shared
+// object by `llc` and the system linker, or by clang in one go. Loaded and run like CLANG's
+use std::process::{Command, Stdio};
+
+use anyhow::anyhow;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::{
+    codegen::uops::{UOp, UOps},
+    device::{Compiled, Compiler, LRUAllocator, MallocAllocator},
+    helpers::{diskcache_get, diskcache_put, CACHELEVEL},
+    prelude::DEBUG,
+    renderer::llvmir::uops_to_llvm_ir,
+    runtime::ops_clang::{find_compiler, ClangRuntime},
+};
+
+lazy_static! {
+    static ref LLC: Option<u32> = llvm_version("llc");
+    /// what `-mcpu=native` means here, so objects built for another CPU aren't reused
+    static ref HOST_CPU: String = Command::new("llc")
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| {
+            let text = String::from_utf8_lossy(&out.stdout).to_string();
+            Some(Regex::new(r"Host CPU: (\S+)").unwrap().captures(&text)?[1].to_string())
+        })
+        .unwrap_or_default();
+    static ref OPT: Option<u32> = llvm_version("opt");
+    // older clangs only read typed pointers
+    static ref CLANG: Option<u32> = llvm_version("clang").filter(|v| *v >= 15);
+}
+
+/// The major version of an LLVM tool, None when it isn't installed.
+fn llvm_version(tool: &str) -> Option<u32> {
+    let out = Command::new(tool)
+        .arg("--version")
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let text = String::from_utf8_lossy(&out.stdout);
+    Regex::new(r"version (\d+)").unwrap().captures(&text)?[1]
+        .parse()
+        .ok()
+}
+
+/// LLVM 14 reads `ptr` only when asked to.
+fn opaque_pointers(version: u32) -> &'static [&'static str] {
+    if version < 15 {
+        &["-opaque-pointers"]
+    } else {
+        &[]
+    }
+}
+
+/// What builds the IR: `llc` when there's also a linker, otherwise `clang`.
+pub fn find_llvm() -> Option<&'static str> {
+    if LLC.is_some() && find_compiler().is_some() {
+        Some("llc")
+    } else {
+        CLANG.map(|_| "clang")
+    }
+}
+
+/// Render `uops` for LLVM. Like CLANG's, this adds `<name>_entry(void **bufs, const int *vars)`,
+/// so the runtime calls every kernel the same way.
+pub fn render(function_name: &str, uops: &[UOp]) -> String {
+    let nbufs = uops.iter().filter(|u| u.uop == UOps::DefineGlobal).count();
+    let nvars = uops.iter().filter(|u| u.uop == UOps::DefineVar).count();
+    let mut entry = vec![
+        format!(
+            "define void @{}_entry(ptr %bufs, ptr %vars) {{",
+            function_name
+        ),
+        "entry:".to_string(),
+    ];
+    for i in 0..nbufs {
+        entry.push(format!(
+            "  %b{}.p = getelementptr inbounds ptr, ptr %bufs, i32 {}",
+            i, i
+        ));
+        entry.push(format!("  %b{} = load ptr, ptr %b{}.p", i, i));
+    }
+    for i in 0..nvars {
+        entry.push(format!(
+            "  %v{}.p = getelementptr inbounds i32, ptr %vars, i32 {}",
+            i, i
+        ));
+        entry.push(format!("  %v{} = load i32, ptr %v{}.p", i, i));
+    }
+    let args: Vec<String> = (0..nbufs)
+        .map(|i| format!("ptr %b{}", i))
+        .chain((0..nvars).map(|i| format!("i32 %v{}", i)))
+        .collect();
+    entry.push(format!(
+        "  call void @{}({})",
+        function_name,
+        args.join(", ")
+    ));
+    entry.push("  ret void".to_string());
+    entry.push("}".to_string());
+    format!(
+        "{}\n\n{}\n",
+        uops_to_llvm_ir(function_name, uops),
+        entry.join("\n")
+    )
+}
+
+/// Run `cmd`, its stderr and the IR it was given in the error when it fails.
+fn run(cmd: &mut Command, src: &str) -> Result<(), anyhow::Error> {
+    let out = cmd.stdout(Stdio::null()).output()?;
+    if !out.status.success() {
+        return Err(anyhow!(
+            "{:?} failed:\n{}\n{}",
+            cmd.get_program(),
+            String::from_utf8_lossy(&out.stderr),
+            src
+        ));
+    }
+    Ok(())
+}
+
+/// `opt` flags for LLVM `version`. LLVM 14's loop passes crash on opaque pointers, so it only gets
+/// the basics.
+fn opt_args(version: u32) -> Vec<&'static str> {
+    let passes = if version < 15 {
+        "-passes=mem2reg,instcombine,simplifycfg"
+    } else {
+        "-O2"
+    };
+    [opaque_pointers(version), &[passes, "-S"]].concat()
+}
+
+fn llc_args() -> Vec<&'static str> {
+    [
+        opaque_pointers(LLC.unwrap()),
+        &[
+            "-O2",
+            "-mcpu=native",
+            "-filetype=obj",
+            "-relocation-model=pic",
+        ],
+    ]
+    .concat()
+}
+
+const CLANG_ARGS: [&str; 6] = ["-shared", "-O2", "-march=native", "-fPIC", "-x", "ir"];
+
+pub struct LLVMCompiler;
+
+impl LLVMCompiler {
+    /// The diskcache key of `src`: the tools that build it, their versions and flags and the host
+    /// CPU included, so a different toolchain or machine never hits a stale object.
+    pub fn cache_key(&self, src: &str) -> String {
+        let tools = match find_llvm() {
+            Some("llc") => format!(
+                "opt {:?} {:?}\nllc {:?} {:?}\n{} -shared",
+                *OPT,
+                OPT.map(opt_args),
+                *LLC,
+                llc_args(),
+                find_compiler().unwrap()
+            ),
+            Some(_) => format!("clang {:?} {:?}", *CLANG, CLANG_ARGS),
+            None => String::new(),
+        };
+        format!(
+            "{:x}",
+            md5::compute(format!("{}\n{}\n{}", tools, *HOST_CPU, src))
+        )
+    }
+
+    /// LLVM IR in, shared object bytes out.
+    pub fn compile(&self, src: &str) -> Result<Vec<u8>, anyhow::Error> {
+        let dir = tempfile::tempdir()?;
+        let [ll, opt_ll, obj, lib] =
+            ["kernel.ll", "kernel.opt.ll", "kernel.o", "kernel.so"].map(|f| dir.path().join(f));
+        std::fs::write(&ll, src)?;
+        match find_llvm() {
+            Some("llc") => {
+                let mut ir = ll.clone();
+                if let Some(v) = *OPT {
+                    run(
+                        Command::new("opt")
+                            .args(opt_args(v))
+                            .arg(&ll)
+                            .arg("-o")
+                            .arg(&opt_ll),
+                        src,
+                    )?;
+                    ir = opt_ll;
+                }
+                run(
+                    Command::new("llc")
+                        .args(llc_args())
+                        .arg(&ir)
+                        .arg("-o")
+                        .arg(&obj),
+                    src,
+                )?;
+                run(
+                    Command::new(find_compiler().unwrap())
+                        .arg("-shared")
+                        .arg(&obj)
+                        .arg("-o")
+                        .arg(&lib),
+                    src,
+                )?;
+            }
+            Some(_) => run(
+                Command::new("clang")
+                    .args(CLANG_ARGS)
+                    .arg(&ll)
+                    .arg("-o")
+                    .arg(&lib),
+                src,
+            )?,
+            None => return Err(anyhow!("no LLVM toolchain found, need llc or clang 15+")),
+        }
+        Ok(std::fs::read(lib)?)
+    }
+
+    /// `compile` through the `compile_llvm` diskcache.
+    pub fn compile_cached(&self, src: &str) -> Result<Vec<u8>, anyhow::Error> {
+        if *CACHELEVEL == 0 {
+            return self.compile(src);
+        }
+        let key = self.cache_key(src);
+        if let Some(lib) = diskcache_get("compile_llvm", Box::new(key.clone())) {
+            return Ok(lib);
+        }
+        diskcache_put("compile_llvm", Box::new(key), self.compile(src)?)
+    }
+}
+
+impl Compiler for LLVMCompiler {
+    fn render(&self, name: &str, uops: &[UOp]) -> String {
+        render(name, uops)
+    }
+
+    fn compile_uops(&self, name: &str, uops: &[UOp]) -> Result<Vec<u8>, anyhow::Error> {
+        let src = self.render(name, uops);
+        if DEBUG.clone() >= 4 {
+            println!("{}", src);
+        }
+        self.compile_cached(&src)
+    }
+}
+
+/// The LLVM device, as long as there's a toolchain to build its kernels with.
+pub fn device(name: &str) -> Result<Compiled, anyhow::Error> {
+    find_llvm().ok_or(anyhow!("{} needs llc and a linker, or clang 15+", name))?;
+    Ok(Compiled {
+        name: name.to_string(),
+        allocator: Box::new(LRUAllocator::new(MallocAllocator)),
+        compiler: Box::new(LLVMCompiler),
+        runtime: Box::new(ClangRuntime),
+    })
+}
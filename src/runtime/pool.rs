@@ -0,0 +1,69 @@
+// the worker threads CPU kernels are split across. Started once, sized by `THREADS` (0 is one per
+// core), and shared by every kernel, so a split costs a channel send per part instead of a spawn
+use std::{
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{mpsc, Arc, Mutex},
+    thread,
+};
+
+use lazy_static::lazy_static;
+
+use crate::prelude::THREADS;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    pub size: usize,
+    tx: Mutex<mpsc::Sender<Job>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> Self {
+        let (tx, rx) = mpsc::channel::<Job>();
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..size {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("rustgrad-worker-{}", i))
+                .spawn(move || loop {
+                    let job = rx.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("failed to start a pool thread");
+        }
+        Self {
+            size,
+            tx: Mutex::new(tx),
+        }
+    }
+
+    /// Run every job on the pool and return once all of them have finished, so jobs may borrow
+    /// from the caller. Panics if any job did.
+    pub fn scope<'a>(&self, jobs: Vec<Box<dyn FnOnce() + Send + 'a>>) {
+        let (done_tx, done_rx) = mpsc::channel();
+        let n = jobs.len();
+        for job in jobs {
+            let done = done_tx.clone();
+            let job: Box<dyn FnOnce() + Send + 'a> = Box::new(move || {
+                let ok = catch_unwind(AssertUnwindSafe(job)).is_ok();
+                let _ = done.send(ok);
+            });
+            // SAFETY: we wait below until every job has run, so nothing it borrows for 'a is used
+            // after this returns
+            let job: Job = unsafe { std::mem::transmute(job) };
+            self.tx.lock().unwrap().send(job).unwrap();
+        }
+        let ok = (0..n).filter(|_| done_rx.recv().unwrap()).count() == n;
+        assert!(ok, "a job on the thread pool panicked");
+    }
+}
+
+lazy_static! {
+    pub static ref POOL: ThreadPool = ThreadPool::new(match THREADS.value {
+        n if n > 0 => n as usize,
+        _ => thread::available_parallelism().map_or(1, |n| n.get()),
+    });
+}
@@ -1 +1,2 @@
 pub mod symbolic;
+pub mod view;
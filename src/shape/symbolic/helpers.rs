@@ -1,7 +1,18 @@
-use std::any::Any;
+use std::collections::HashMap;
 
 use super::Node;
 
-pub fn create_lt_node_r_int<B, Min, Max>(lhs: Box<dyn Node<B, Min, Max>>, b: isize){
-    todo!()
-}
\ No newline at end of file
+/// `sym_infer` from tinygrad: the value of `n` under `var_vals`, or the number itself.
+pub fn sym_infer(n: &Node, var_vals: &HashMap<String, isize>) -> isize {
+    n.eval(var_vals)
+}
+
+/// `lhs < b` as a node, folding to a constant when the bounds decide it.
+pub fn create_lt_node(lhs: Node, b: isize) -> Node {
+    lhs.lt(b)
+}
+
+/// `lhs >= b` as a node.
+pub fn create_ge_node(lhs: Node, b: isize) -> Node {
+    lhs.ge(b)
+}
@@ -1,216 +1,394 @@
-use std::{any::Any, collections::{HashMap, HashSet}, fmt::Debug, hash::Hash, ops::{Add, Mul, Neg, Sub}, path::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    ops::{Add, Div, Mul, Neg, Rem, Sub},
+};
+
+use serde::{Deserialize, Serialize};
 pub mod helpers;
-pub trait Node<B, Min, Max>: Any
-where Max: PartialEq<Min>, Min: PartialEq<isize>
-{
-    fn get_b<'a>(&'a self) -> &'a B;
-    fn get_min<'a>(&'a self) -> &'a Min;
-    fn get_max<'a>(&'a self) -> &'a Max;
-    fn render(&self, ops: Option<RenderOps<B, Min, Max>>, ctx: String) -> String;
-    fn vars(&self) -> HashSet<Variable>;
-    fn substitute(&mut self, var_vals: HashMap<Variable, SubstitutableVals>) -> Result<(), anyhow::Error>;
-    fn unbind(&mut self) -> Option<isize>;
-    fn key(&self) -> String{
-        return self.render(None, "DEBUG".to_string());
-    }
-}
 
-pub trait CastAny
-    where Self: Sized + 'static
-{
-    fn as_any(self: Box<Self>) -> Box<dyn Any>{
-        self
-    }
+/// An integer valued symbol with a known inclusive range, e.g. a loop index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Variable {
+    pub expr: String,
+    pub min: isize,
+    pub max: isize,
 }
-pub trait NodeEq<B, Min, Max>: Node<B, Min, Max>
-where Max: PartialEq<Min> + 'static, Min: PartialEq<isize> + 'static, B: 'static
-{
-    fn eq_n(lhs: &dyn Node<B, Min, Max>, rhs: &dyn Node<B, Min, Max>) -> bool{
-        lhs.key() == rhs.key()
-    } 
+
+/// Integer expressions for index math. Every constructor simplifies, so two nodes that are
+/// obviously equal usually compare equal and bounds stay as tight as we can cheaply make them.
+/// Division and modulo are floored and the divisor is always positive.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Node {
+    Num(isize),
+    Var(Variable),
+    Mul(Box<Node>, isize),
+    Div(Box<Node>, isize),
+    Mod(Box<Node>, isize),
+    Lt(Box<Node>, isize),
+    Sum(Vec<Node>),
+    And(Vec<Node>),
 }
-pub trait SumSeq<B, Min, Max>: Node<B, Min, Max>
-    where Max: PartialEq<Min>, Min: PartialEq<isize>, dyn Node<B, Min, Max>: Sized + Clone
-{
-    fn sum(nodes: Vec<Box<dyn Node<B, Min, Max>>>) -> Box<dyn Node<B, Min, Max>>{
-        if nodes.is_empty(){
-            // return NumNode(0)
+
+impl Node {
+    pub fn num(n: isize) -> Node {
+        Node::Num(n)
+    }
+
+    /// A variable in `[min, max]`. Collapses to a constant when the range is a single value.
+    pub fn var(expr: &str, min: isize, max: isize) -> Node {
+        assert!(
+            min <= max,
+            "invalid range [{}, {}] for variable {}",
+            min,
+            max,
+            expr
+        );
+        if min == max {
+            return Node::Num(min);
         }
+        Node::Var(Variable {
+            expr: expr.to_string(),
+            min,
+            max,
+        })
+    }
 
-        if nodes.len() == 1{
-            return nodes[0].clone();
+    pub fn min(&self) -> isize {
+        self.bounds().0
+    }
+
+    pub fn max(&self) -> isize {
+        self.bounds().1
+    }
+
+    pub fn bounds(&self) -> (isize, isize) {
+        match self {
+            Node::Num(n) => (*n, *n),
+            Node::Var(v) => (v.min, v.max),
+            Node::Mul(x, a) => {
+                let (lo, hi) = x.bounds();
+                if *a >= 0 {
+                    (lo * a, hi * a)
+                } else {
+                    (hi * a, lo * a)
+                }
+            }
+            Node::Div(x, b) => {
+                let (lo, hi) = x.bounds();
+                (lo.div_euclid(*b), hi.div_euclid(*b))
+            }
+            Node::Mod(x, b) => {
+                let (lo, hi) = x.bounds();
+                if lo >= 0 {
+                    (0, hi.min(b - 1))
+                } else {
+                    (0, b - 1)
+                }
+            }
+            Node::Lt(..) | Node::And(..) => (0, 1),
+            Node::Sum(ns) => ns.iter().fold((0, 0), |(lo, hi), n| {
+                let (a, b) = n.bounds();
+                (lo + a, hi + b)
+            }),
         }
+    }
 
-        let mut mul_groups: HashMap<dyn Node<B, Min, Max>, isize> = HashMap::new();
-        let mut num_node_sum = 0;
+    pub fn is_num(&self) -> bool {
+        matches!(self, Node::Num(_))
+    }
 
-        todo!()
+    /// Flatten nested sums, fold the constants and merge `x*a + x*b` into `x*(a+b)`.
+    pub fn sum(nodes: Vec<Node>) -> Node {
+        let mut flat = vec![];
+        let mut stack: Vec<Node> = nodes.into_iter().rev().collect();
+        while let Some(n) = stack.pop() {
+            match n {
+                Node::Sum(ns) => stack.extend(ns.into_iter().rev()),
+                n => flat.push(n),
+            }
+        }
+        let mut num = 0;
+        let mut terms: Vec<(Node, isize)> = vec![];
+        for n in flat {
+            let (base, coef) = match n {
+                Node::Num(x) => {
+                    num += x;
+                    continue;
+                }
+                Node::Mul(x, a) => (*x, a),
+                n => (n, 1),
+            };
+            match terms.iter_mut().find(|(b, _)| *b == base) {
+                Some(t) => t.1 += coef,
+                None => terms.push((base, coef)),
+            }
+        }
+        let mut ret: Vec<Node> = terms
+            .into_iter()
+            .filter(|(_, c)| *c != 0)
+            .map(|(b, c)| b.mul_int(c))
+            .collect();
+        if num != 0 || ret.is_empty() {
+            ret.push(Node::Num(num));
+        }
+        if ret.len() == 1 {
+            return ret.pop().unwrap();
+        }
+        Node::Sum(ret)
     }
-}
 
-impl<B, Min, Max> CastAny for dyn Node<B, Min, Max>
-where dyn Node<B, Min, Max>: Sized, Max: 'static, Min: 'static, B: 'static
-{
+    /// Conjunction of boolean (0/1) nodes.
+    pub fn ands(nodes: Vec<Node>) -> Node {
+        let mut ret: Vec<Node> = vec![];
+        let mut stack: Vec<Node> = nodes.into_iter().rev().collect();
+        while let Some(n) = stack.pop() {
+            match n {
+                Node::And(ns) => stack.extend(ns.into_iter().rev()),
+                Node::Num(0) => return Node::Num(0),
+                Node::Num(_) => {}
+                n => {
+                    if !ret.contains(&n) {
+                        ret.push(n)
+                    }
+                }
+            }
+        }
+        match ret.len() {
+            0 => Node::Num(1),
+            1 => ret.pop().unwrap(),
+            _ => Node::And(ret),
+        }
+    }
 
-}
-impl<B, Min, Max> Debug for dyn Node<B, Min, Max>
-    where (B, Min, Max) : 'static, Max: PartialEq<Min>, Min: PartialEq<isize>
-{
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.render(None, "REPR".to_string()))
+    pub fn mul_int(self, b: isize) -> Node {
+        match (self, b) {
+            (_, 0) => Node::Num(0),
+            (n, 1) => n,
+            (Node::Num(n), b) => Node::Num(n * b),
+            (Node::Mul(x, a), b) => x.mul_int(a * b),
+            (Node::Sum(ns), b) => Node::sum(ns.into_iter().map(|n| n.mul_int(b)).collect()),
+            (n, b) => Node::Mul(Box::new(n), b),
+        }
     }
-}
 
-impl<B, Min, Max> ToString for dyn Node<B, Min, Max>
-where (B, Min, Max) : 'static, Max: PartialEq<Min>, Min: PartialEq<isize>
-{
-    fn to_string(&self) -> String {
-        return format!("<{}>", self.key())
+    pub fn floordiv(self, b: isize) -> Node {
+        assert!(b != 0, "division by zero in {}", self);
+        if b < 0 {
+            return (-self).floordiv(-b);
+        }
+        if b == 1 {
+            return self;
+        }
+        let (lo, hi) = self.bounds();
+        if lo.div_euclid(b) == hi.div_euclid(b) {
+            return Node::Num(lo.div_euclid(b));
+        }
+        match self {
+            Node::Mul(x, a) if a % b == 0 => x.mul_int(a / b),
+            Node::Mul(x, a) if a > 0 && b % a == 0 => x.floordiv(b / a),
+            Node::Div(x, a) => x.floordiv(a * b),
+            Node::Sum(ns) => {
+                // floor((q*b + r) / b) = q + floor(r / b)
+                let (q, r): (Vec<Node>, Vec<Node>) = ns.into_iter().partition(|n| match n {
+                    Node::Num(x) => x % b == 0,
+                    Node::Mul(_, a) => a % b == 0,
+                    _ => false,
+                });
+                if q.is_empty() {
+                    return Node::Div(Box::new(Node::Sum(r)), b);
+                }
+                let q: Vec<Node> = q.into_iter().map(|n| n.floordiv(b)).collect();
+                Node::sum(q) + Node::sum(r).floordiv(b)
+            }
+            n => Node::Div(Box::new(n), b),
+        }
     }
-}
 
-impl<B, Min, Max> Hash for dyn Node<B, Min, Max>
-where (B, Min, Max) : 'static, Max: PartialEq<Min>, Min: PartialEq<isize>
-{
-    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.key().hash(state)
+    pub fn modulo(self, b: isize) -> Node {
+        assert!(b > 0, "modulo needs a positive divisor, got {}", b);
+        if b == 1 {
+            return Node::Num(0);
+        }
+        let (lo, hi) = self.bounds();
+        if lo.div_euclid(b) == hi.div_euclid(b) {
+            return self - lo.div_euclid(b) * b;
+        }
+        match self {
+            Node::Mul(_, a) if a % b == 0 => Node::Num(0),
+            Node::Mod(x, a) if a % b == 0 => x.modulo(b),
+            Node::Sum(ns) => {
+                // (x + k*b) % b = x % b
+                let mut changed = false;
+                let kept: Vec<Node> = ns
+                    .into_iter()
+                    .filter_map(|t| match t {
+                        Node::Num(x) if x.rem_euclid(b) != x => {
+                            changed = true;
+                            Some(Node::Num(x.rem_euclid(b)))
+                        }
+                        Node::Mul(_, a) if a % b == 0 => {
+                            changed = true;
+                            None
+                        }
+                        t => Some(t),
+                    })
+                    .collect();
+                if changed {
+                    return Node::sum(kept).modulo(b);
+                }
+                Node::Mod(Box::new(Node::Sum(kept)), b)
+            }
+            n => Node::Mod(Box::new(n), b),
+        }
     }
-}
 
-impl<B, Min, Max> Into<bool> for &dyn Node<B, Min, Max>
-where (B, Min, Max) : 'static, Max: PartialEq<Min>, Min: PartialEq<isize>
-{
-    fn into(self) -> bool {
-        !(self.get_max() == self.get_min() && self.get_min() == &0)
+    pub fn lt(self, b: isize) -> Node {
+        let (lo, hi) = self.bounds();
+        if hi < b {
+            return Node::Num(1);
+        }
+        if lo >= b {
+            return Node::Num(0);
+        }
+        match self {
+            // x*a < b <=> x < ceil(b/a) for positive a
+            Node::Mul(x, a) if a > 0 => x.lt(-((-b).div_euclid(a))),
+            n => Node::Lt(Box::new(n), b),
+        }
     }
-}
 
-impl<B, Min, Max> Neg for Box<dyn Node<B, Min, Max>>
-where
-    Self: Mul<isize, Output = Self> + Sized,
-{
-    type Output = Self;
+    pub fn ge(self, b: isize) -> Node {
+        (-self).lt(-b + 1)
+    }
 
-    fn neg(self) -> Self::Output {
-        self * -1
+    /// Every variable in the expression, in first-seen order.
+    pub fn vars(&self) -> Vec<Variable> {
+        let mut ret = vec![];
+        let mut seen = HashSet::new();
+        self.visit(&mut |n| {
+            if let Node::Var(v) = n {
+                if seen.insert(v.expr.clone()) {
+                    ret.push(v.clone());
+                }
+            }
+        });
+        ret
     }
-}
 
-impl<B, Min, Max> Add for Box<dyn Node<B, Min, Max>>
-    where dyn Node<B, Min, Max>: SumSeq<B, Min, Max> + Sized + Clone,
-    Max: PartialEq<Min>, Min: PartialEq<isize>,
-    Self: SumSeq<B, Min, Max> + Sized + Clone
-{
-    type Output = Self;
-    fn add(self, rhs: Self) -> Self::Output {
-        Self::sum(vec![self, rhs])
+    fn visit(&self, f: &mut impl FnMut(&Node)) {
+        f(self);
+        match self {
+            Node::Mul(x, _) | Node::Div(x, _) | Node::Mod(x, _) | Node::Lt(x, _) => x.visit(f),
+            Node::Sum(ns) | Node::And(ns) => ns.iter().for_each(|n| n.visit(f)),
+            _ => {}
+        }
     }
-}
 
-impl <B, Min, Max> Add<isize> for Box<dyn Node<B, Min, Max>>
-    where dyn Node<B, Min, Max>: SumSeq<B, Min, Max> + Sized + Clone,
-    Max: PartialEq<Min>, Min: PartialEq<isize>,
-    Self: SumSeq<B, Min, Max> + Sized + Clone
-{
-    type Output = Self;
-    fn add(self, rhs: isize) -> Self::Output {
-        Self::sum(vec![self, Box::new(NumNode::new(rhs))])
+    /// Replace variables by name and re-simplify.
+    pub fn substitute(&self, var_vals: &HashMap<String, Node>) -> Node {
+        match self {
+            Node::Num(_) => self.clone(),
+            Node::Var(v) => var_vals.get(&v.expr).cloned().unwrap_or(self.clone()),
+            Node::Mul(x, a) => x.substitute(var_vals).mul_int(*a),
+            Node::Div(x, b) => x.substitute(var_vals).floordiv(*b),
+            Node::Mod(x, b) => x.substitute(var_vals).modulo(*b),
+            Node::Lt(x, b) => x.substitute(var_vals).lt(*b),
+            Node::Sum(ns) => Node::sum(ns.iter().map(|n| n.substitute(var_vals)).collect()),
+            Node::And(ns) => Node::ands(ns.iter().map(|n| n.substitute(var_vals)).collect()),
+        }
     }
-}
 
-impl <B, Min, Max> Add<Box<dyn Node<B, Min, Max>>> for isize
-    where dyn Node<B, Min, Max>: SumSeq<B, Min, Max> + Sized + Clone,
-    Max: PartialEq<Min>, Min: PartialEq<isize>,
-    Box<(dyn Node<B, Min, Max>)>: SumSeq<B, Min, Max>
-{
-    type Output = Box<(dyn Node<B, Min, Max>)>;
-    fn add(self, rhs: Box<dyn Node<B, Min, Max>>) -> Self::Output {
-        rhs + self
-    }    
-}
+    /// Evaluate with every variable bound. Panics on an unbound variable.
+    pub fn eval(&self, var_vals: &HashMap<String, isize>) -> isize {
+        match self {
+            Node::Num(n) => *n,
+            Node::Var(v) => *var_vals
+                .get(&v.expr)
+                .unwrap_or_else(|| panic!("variable {} is unbound", v.expr)),
+            Node::Mul(x, a) => x.eval(var_vals) * a,
+            Node::Div(x, b) => x.eval(var_vals).div_euclid(*b),
+            Node::Mod(x, b) => x.eval(var_vals).rem_euclid(*b),
+            Node::Lt(x, b) => (x.eval(var_vals) < *b) as isize,
+            Node::Sum(ns) => ns.iter().map(|n| n.eval(var_vals)).sum(),
+            Node::And(ns) => ns.iter().all(|n| n.eval(var_vals) != 0) as isize,
+        }
+    }
 
-impl <B, Min, Max> Sub for Box<dyn Node<B, Min, Max>>
-where dyn Node<B, Min, Max>: SumSeq<B, Min, Max> + Sized + Clone,
-Max: PartialEq<Min>, Min: PartialEq<isize>,
-Self: SumSeq<B, Min, Max> + Sized + Clone +  Neg<Output = Self>
-{
-    type Output = Self;
-    fn sub(self, rhs: Self) -> Self::Output {
-        self + (-rhs)
+    /// C-like rendering, also used as the node's key.
+    pub fn render(&self) -> String {
+        match self {
+            Node::Num(n) => n.to_string(),
+            Node::Var(v) => v.expr.clone(),
+            Node::Mul(x, a) => format!("({}*{})", x.render(), a),
+            Node::Div(x, b) => format!("({}//{})", x.render(), b),
+            Node::Mod(x, b) => format!("({}%{})", x.render(), b),
+            Node::Lt(x, b) => format!("({}<{})", x.render(), b),
+            Node::Sum(ns) => format!(
+                "({})",
+                ns.iter().map(|n| n.render()).collect::<Vec<_>>().join("+")
+            ),
+            Node::And(ns) => format!(
+                "({})",
+                ns.iter()
+                    .map(|n| n.render())
+                    .collect::<Vec<_>>()
+                    .join(" and ")
+            ),
+        }
     }
 }
 
-impl <B, Min, Max> Sub<isize> for Box<dyn Node<B, Min, Max>>
-    where dyn Node<B, Min, Max>: SumSeq<B, Min, Max> + Sized + Clone,
-    Max: PartialEq<Min>, Min: PartialEq<isize>,
-    Self: SumSeq<B, Min, Max> + Sized + Clone
-{
-    type Output = Self;
-    fn sub(self, rhs: isize) -> Self::Output {
-        self + (-rhs)
+impl Display for Node {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.render())
     }
 }
 
-impl <B, Min, Max> Sub<Box<dyn Node<B, Min, Max>>> for isize
-    where dyn Node<B, Min, Max>: SumSeq<B, Min, Max> + Sized + Clone,
-    Max: PartialEq<Min>, Min: PartialEq<isize>,
-    Box<(dyn Node<B, Min, Max>)>: SumSeq<B, Min, Max> + Neg<Output = Box<(dyn Node<B, Min, Max>)>>
-{
-    type Output = Box<(dyn Node<B, Min, Max>)>;
-    fn sub(self, rhs: Box<dyn Node<B, Min, Max>>) -> Self::Output {
-        -rhs + self
-    }    
+impl From<isize> for Node {
+    fn from(n: isize) -> Self {
+        Node::Num(n)
+    }
 }
 
-impl <B, Min, Max> PartialEq for Box<dyn Node<B, Min, Max>>
-    where Max: PartialEq<Min>, Min: PartialEq<isize>, Self: NodeEq<B, Min, Max>
-{
-    fn eq(&self, other: &Box<dyn Node<B, Min, Max>>) -> bool {
-        Self::eq_n(self, other)
+impl Neg for Node {
+    type Output = Node;
+    fn neg(self) -> Node {
+        self.mul_int(-1)
     }
 }
 
-
-pub struct RenderOps<B, Min, Max> {
-    render_map: HashMap<&'static str, fn(&dyn Node<B, Min, Max>, Option<&str>) -> String>,
-}
-pub struct Variable{}
-pub struct NumNode{}
-impl<B, Min, Max> Node<B, Min, Max> for NumNode
-where Max: PartialEq<Min>, Min: PartialEq<isize>,
-{
-    fn get_b<'a>(&'a self) -> &'a B {
-        todo!()
-    }
-    fn get_max<'a>(&'a self) -> &'a Max {
-        todo!()
-    }
-    fn get_min<'a>(&'a self) -> &'a Min {
-        todo!()
-    }
-    fn key(&self) -> String {
-        todo!()
+impl<T: Into<Node>> Add<T> for Node {
+    type Output = Node;
+    fn add(self, rhs: T) -> Node {
+        Node::sum(vec![self, rhs.into()])
     }
-    fn render(&self, ops: Option<RenderOps<B, Min, Max>>, ctx: String) -> String {
-        todo!()
-    }
-    fn substitute(&mut self, var_vals: HashMap<Variable, SubstitutableVals>) -> Result<(), anyhow::Error> {
-        todo!()
+}
+
+impl<T: Into<Node>> Sub<T> for Node {
+    type Output = Node;
+    fn sub(self, rhs: T) -> Node {
+        Node::sum(vec![self, -rhs.into()])
     }
-    fn unbind(&mut self) -> Option<isize> {
-        todo!()
+}
+
+impl Mul<isize> for Node {
+    type Output = Node;
+    fn mul(self, rhs: isize) -> Node {
+        self.mul_int(rhs)
     }
-    fn vars(&self) -> HashSet<Variable> {
-        todo!()
+}
+
+impl Div<isize> for Node {
+    type Output = Node;
+    fn div(self, rhs: isize) -> Node {
+        self.floordiv(rhs)
     }
 }
-impl NumNode{
-    pub fn new(num: isize) -> Self{
-        todo!()
+
+impl Rem<isize> for Node {
+    type Output = Node;
+    fn rem(self, rhs: isize) -> Node {
+        self.modulo(rhs)
     }
 }
-pub struct SumNode{}
-pub enum SubstitutableVals{
-    Variable(Variable),
-    NumNode(NumNode)
-}
\ No newline at end of file
@@ -0,0 +1,336 @@
+use serde::{Deserialize, Serialize};
+
+use super::symbolic::Node;
+
+/// Row-major strides for `shape`, with size-1 axes canonicalised to stride 0.
+pub fn strides_for_shape(shape: &[usize]) -> Vec<isize> {
+    let mut strides = vec![0; shape.len()];
+    let mut acc = 1isize;
+    for (i, s) in shape.iter().enumerate().rev() {
+        strides[i] = if *s == 1 { 0 } else { acc };
+        acc *= *s as isize;
+    }
+    strides
+}
+
+/// A strided window (shape, strides, offset and an optional validity mask) over a flat buffer.
+/// Every movement op is a pure transform of this struct, so none of them copy data.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct View {
+    pub shape: Vec<usize>,
+    pub strides: Vec<isize>,
+    pub offset: isize,
+    pub mask: Option<Vec<(usize, usize)>>,
+    pub contiguous: bool,
+}
+
+impl View {
+    pub fn create(
+        shape: &[usize],
+        strides: Option<&[isize]>,
+        offset: isize,
+        mask: Option<Vec<(usize, usize)>>,
+    ) -> Self {
+        let strides: Vec<isize> = match strides {
+            Some(st) => st
+                .iter()
+                .zip(shape.iter())
+                .map(|(st, s)| if *s == 1 { 0 } else { *st })
+                .collect(),
+            None => strides_for_shape(shape),
+        };
+        // a mask covering the whole shape is no mask at all
+        let mask = mask.filter(|m| {
+            m.iter()
+                .zip(shape.iter())
+                .any(|((b, e), s)| *b != 0 || e != s)
+        });
+        let contiguous = offset == 0 && mask.is_none() && strides == strides_for_shape(shape);
+        Self {
+            shape: shape.to_vec(),
+            strides,
+            offset,
+            mask,
+            contiguous,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// Position in the underlying buffer for a multi-index, or `None` if it falls in a masked (padded) region.
+    pub fn index_of(&self, idx: &[usize]) -> Option<isize> {
+        if let Some(mask) = &self.mask {
+            if idx
+                .iter()
+                .zip(mask.iter())
+                .any(|(i, (b, e))| i < b || i >= e)
+            {
+                return None;
+            }
+        }
+        Some(
+            self.offset
+                + idx
+                    .iter()
+                    .zip(self.strides.iter())
+                    .map(|(i, st)| *i as isize * st)
+                    .sum::<isize>(),
+        )
+    }
+
+    /// Symbolic buffer position and validity of the element at `idxs`, one node per axis.
+    pub fn expr_node(&self, idxs: &[Node]) -> (Node, Node) {
+        assert_eq!(idxs.len(), self.shape.len(), "need one index per axis");
+        let idx = Node::sum(
+            std::iter::once(Node::Num(self.offset))
+                .chain(
+                    idxs.iter()
+                        .zip(self.shape.iter().zip(self.strides.iter()))
+                        .filter(|(_, (sh, st))| **sh != 1 && **st != 0)
+                        .map(|(i, (_, st))| i.clone() * *st),
+                )
+                .collect(),
+        );
+        let valid = match &self.mask {
+            Some(m) => Node::ands(
+                m.iter()
+                    .zip(idxs.iter())
+                    .flat_map(|((b, e), i)| [i.clone().ge(*b as isize), i.clone().lt(*e as isize)])
+                    .collect(),
+            ),
+            None => Node::Num(1),
+        };
+        (idx, valid)
+    }
+
+    fn unsafe_resize(&self, arg: &[(isize, isize)], mask: Option<Vec<(isize, isize)>>) -> View {
+        let offset = self.offset
+            + arg
+                .iter()
+                .zip(self.strides.iter())
+                .map(|((b, _), st)| b * st)
+                .sum::<isize>();
+        let mut nmask: Option<Vec<(isize, isize)>> = self.mask.as_ref().map(|m| {
+            m.iter()
+                .zip(arg.iter())
+                .map(|((mx, my), (b, e))| {
+                    let len = e - b;
+                    (
+                        (*mx as isize - b).clamp(0, len),
+                        (*my as isize - b).clamp(0, len),
+                    )
+                })
+                .collect()
+        });
+        if let Some(m) = mask {
+            nmask = Some(match nmask {
+                Some(n) => n
+                    .into_iter()
+                    .zip(m)
+                    .map(|((a, b), (c, d))| (a.max(c), b.min(d)))
+                    .collect(),
+                None => m,
+            });
+        }
+        let shape: Vec<usize> = arg.iter().map(|(b, e)| (e - b) as usize).collect();
+        let mask = nmask.map(|m| {
+            m.into_iter()
+                .map(|(b, e)| (b.max(0) as usize, e.max(b).max(0) as usize))
+                .collect()
+        });
+        View::create(&shape, Some(&self.strides), offset, mask)
+    }
+
+    pub fn pad(&self, arg: &[(usize, usize)]) -> View {
+        assert_eq!(
+            arg.len(),
+            self.shape.len(),
+            "pad arg must match the number of dims"
+        );
+        if arg.iter().all(|(b, e)| *b == 0 && *e == 0) {
+            return self.clone();
+        }
+        let zvarg: Vec<(isize, isize)> = arg
+            .iter()
+            .zip(self.shape.iter())
+            .map(|((b, e), s)| (-(*b as isize), (*s + *e) as isize))
+            .collect();
+        let mask: Vec<(isize, isize)> = arg
+            .iter()
+            .zip(self.shape.iter())
+            .map(|((b, _), s)| (*b as isize, (*s + *b) as isize))
+            .collect();
+        self.unsafe_resize(&zvarg, Some(mask))
+    }
+
+    pub fn shrink(&self, arg: &[(usize, usize)]) -> View {
+        assert_eq!(
+            arg.len(),
+            self.shape.len(),
+            "shrink arg must match the number of dims"
+        );
+        assert!(
+            arg.iter()
+                .zip(self.shape.iter())
+                .all(|((b, e), s)| b <= e && e <= s),
+            "invalid shrink {:?} for {:?}",
+            arg,
+            self.shape
+        );
+        let arg: Vec<(isize, isize)> = arg
+            .iter()
+            .map(|(b, e)| (*b as isize, *e as isize))
+            .collect();
+        self.unsafe_resize(&arg, None)
+    }
+
+    pub fn expand(&self, new_shape: &[usize]) -> View {
+        assert_eq!(
+            new_shape.len(),
+            self.shape.len(),
+            "expand must keep the number of dims"
+        );
+        assert!(
+            self.shape
+                .iter()
+                .zip(new_shape.iter())
+                .all(|(s, x)| s == x || *s == 1),
+            "can't expand {:?} into {:?}",
+            self.shape,
+            new_shape
+        );
+        if self.shape.contains(&0) {
+            return View::create(new_shape, None, 0, None);
+        }
+        let mask = self.mask.as_ref().map(|m| {
+            m.iter()
+                .zip(self.shape.iter().zip(new_shape.iter()))
+                .map(|(mm, (s, ns))| {
+                    if s != ns {
+                        if mm.0 < mm.1 {
+                            (0, *ns)
+                        } else {
+                            (0, 0)
+                        }
+                    } else {
+                        *mm
+                    }
+                })
+                .collect()
+        });
+        View::create(new_shape, Some(&self.strides), self.offset, mask)
+    }
+
+    pub fn permute(&self, axis: &[usize]) -> View {
+        let mut sorted = axis.to_vec();
+        sorted.sort();
+        assert!(
+            sorted.iter().copied().eq(0..self.shape.len()),
+            "invalid permutation {:?} of len {}",
+            axis,
+            self.shape.len()
+        );
+        let shape: Vec<usize> = axis.iter().map(|a| self.shape[*a]).collect();
+        let strides: Vec<isize> = axis.iter().map(|a| self.strides[*a]).collect();
+        let mask = self
+            .mask
+            .as_ref()
+            .map(|m| axis.iter().map(|a| m[*a]).collect());
+        View::create(&shape, Some(&strides), self.offset, mask)
+    }
+
+    /// Multiply each stride by `mul`. A negative factor walks the axis backwards (flip),
+    /// and a factor `k` with `|k| > 1` keeps every k-th element.
+    pub fn stride(&self, mul: &[isize]) -> View {
+        assert!(mul.iter().all(|m| *m != 0), "stride can't be zero");
+        let strides: Vec<isize> = self
+            .strides
+            .iter()
+            .zip(mul.iter())
+            .map(|(z, m)| z * m)
+            .collect();
+        let new_shape: Vec<usize> = self
+            .shape
+            .iter()
+            .zip(mul.iter())
+            .map(|(s, m)| s.div_ceil(m.unsigned_abs()))
+            .collect();
+        let offset: isize = self
+            .shape
+            .iter()
+            .zip(self.strides.iter().zip(mul.iter()))
+            .filter(|(_, (_, m))| **m < 0)
+            .map(|(s, (z, _))| (*s as isize - 1) * z)
+            .sum();
+        let mask = self.mask.as_ref().map(|m| {
+            m.iter()
+                .zip(self.shape.iter().zip(mul.iter()))
+                .map(|((mx, my), (s, m))| {
+                    let am = m.unsigned_abs();
+                    let (lo, hi) = if *m > 0 { (*mx, *my) } else { (s - my, s - mx) };
+                    (lo.div_ceil(am), hi.div_ceil(am))
+                })
+                .collect()
+        });
+        View::create(&new_shape, Some(&strides), self.offset + offset, mask)
+    }
+
+    /// Zero-copy reshape. Returns `None` when the new shape can't be expressed as a single view,
+    /// in which case the caller has to make the data contiguous first.
+    pub fn reshape(&self, new_shape: &[usize]) -> Option<View> {
+        assert_eq!(
+            self.size(),
+            new_shape.iter().product::<usize>(),
+            "size mismatch, can't reshape {:?} -> {:?}",
+            self.shape,
+            new_shape
+        );
+        if self.shape == new_shape {
+            return Some(self.clone());
+        }
+        if self.size() == 0 {
+            return Some(View::create(new_shape, None, 0, None));
+        }
+        if self.mask.is_none() && self.strides == strides_for_shape(&self.shape) {
+            return Some(View::create(new_shape, None, self.offset, None));
+        }
+        // only adding or removing size-1 axes is supported on strided/masked views
+        let old: Vec<usize> = (0..self.shape.len())
+            .filter(|i| self.shape[*i] != 1)
+            .collect();
+        let new_nz: Vec<usize> = new_shape.iter().copied().filter(|s| *s != 1).collect();
+        if old
+            .iter()
+            .map(|i| self.shape[*i])
+            .ne(new_nz.iter().copied())
+        {
+            return None;
+        }
+        if let Some(m) = &self.mask {
+            if (0..self.shape.len()).any(|i| self.shape[i] == 1 && m[i] != (0, 1)) {
+                return None;
+            }
+        }
+        let mut it = old.iter();
+        let mut strides = vec![];
+        let mut mask = vec![];
+        for s in new_shape {
+            if *s == 1 {
+                strides.push(0);
+                mask.push((0, 1));
+            } else {
+                let i = *it.next().unwrap();
+                strides.push(self.strides[i]);
+                mask.push(self.mask.as_ref().map_or((0, *s), |m| m[i]));
+            }
+        }
+        Some(View::create(
+            new_shape,
+            Some(&strides),
+            self.offset,
+            self.mask.as_ref().map(|_| mask),
+        ))
+    }
+}
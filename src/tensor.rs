@@ -0,0 +1,1155 @@
+// inspired by https://github.com/karpathy/micrograd/blob/master/micrograd/engine.py
+use std::{
+    cell::RefCell,
+    collections::HashSet,
+    fmt::Debug,
+    ops::{Add, Div, Mul, Neg, Sub},
+    sync::{Arc, RwLock},
+};
+
+use crate::{
+    device::canonicalize,
+    function::{self, Function},
+    lazy::LazyBuffer,
+    multi::{LazyData, MultiLazyBuffer},
+    prelude::{IMAGE, WINO},
+    rng,
+    shape::symbolic::Variable,
+};
+
+// winograd F(4x4,3x3) transforms, see https://arxiv.org/abs/1509.09308. Each maps between 6x6 input
+// tiles, 3x3 filters and 4x4 output tiles one axis at a time, row major
+const WINOGRAD_BT: [f64; 36] = [
+    4., 0., -5., 0., 1., 0., //
+    0., -4., -4., 1., 1., 0., //
+    0., 4., -4., -1., 1., 0., //
+    0., -2., -1., 2., 1., 0., //
+    0., 2., -1., -2., 1., 0., //
+    0., 4., 0., -5., 0., 1., //
+];
+const WINOGRAD_G: [f64; 18] = [
+    1. / 4.,
+    0.,
+    0., //
+    -1. / 6.,
+    -1. / 6.,
+    -1. / 6., //
+    -1. / 6.,
+    1. / 6.,
+    -1. / 6., //
+    1. / 24.,
+    1. / 12.,
+    1. / 6., //
+    1. / 24.,
+    -1. / 12.,
+    1. / 6., //
+    0.,
+    0.,
+    1., //
+];
+const WINOGRAD_AT: [f64; 24] = [
+    1., 1., 1., 1., 1., 0., //
+    0., 1., -1., 2., -2., 0., //
+    0., 1., 1., 4., 4., 0., //
+    0., 1., -1., 8., -8., 1., //
+];
+
+thread_local! {
+    // same push/pop discipline as `helpers::ContextStack`, but per thread so a `no_grad` scope
+    // on one thread can't drop the tape another thread is recording
+    static GRAD_MODE_STACK: RefCell<Vec<bool>> = RefCell::new(vec![true]);
+}
+
+pub fn is_grad_enabled() -> bool {
+    GRAD_MODE_STACK.with(|s| *s.borrow().last().unwrap())
+}
+
+/// RAII scope that disables tape recording until it is dropped.
+/// Like `Context`, nothing happens until `init` is called.
+pub struct NoGrad {
+    inited: bool,
+}
+
+impl NoGrad {
+    pub fn new() -> Self {
+        Self { inited: false }
+    }
+
+    pub fn init(&mut self) {
+        if !self.inited {
+            GRAD_MODE_STACK.with(|s| s.borrow_mut().push(false));
+            self.inited = true;
+        }
+    }
+}
+
+impl Default for NoGrad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for NoGrad {
+    fn drop(&mut self) {
+        if self.inited {
+            GRAD_MODE_STACK.with(|s| s.borrow_mut().pop());
+        }
+    }
+}
+
+/// The tape entry of a non-leaf tensor: the function that produced it and its inputs.
+pub struct Ctx {
+    pub func: Box<dyn Function>,
+    pub parents: Vec<Tensor>,
+}
+
+struct TensorInner {
+    lazydata: LazyData,
+    requires_grad: bool,
+    grad: Option<Tensor>,
+    ctx: Option<Arc<Ctx>>,
+}
+
+#[derive(Clone)]
+pub struct Tensor {
+    inner: Arc<RwLock<TensorInner>>,
+}
+
+impl Debug for Tensor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "<Tensor shape={:?} requires_grad={}>",
+            self.shape(),
+            self.requires_grad()
+        )
+    }
+}
+
+impl Tensor {
+    pub fn from_lazy(lazydata: impl Into<LazyData>, requires_grad: bool) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(TensorInner {
+                lazydata: lazydata.into(),
+                requires_grad,
+                grad: None,
+                ctx: None,
+            })),
+        }
+    }
+
+    /// A scalar holding `val` as the value of `var`. Under a `TinyJit` replay the value is
+    /// rebound from the call's `var_vals`.
+    pub fn bind(var: &Variable, val: isize) -> Self {
+        Self::from_lazy(LazyBuffer::bind(var, val), false)
+    }
+
+    pub fn new(data: Vec<f64>, shape: &[usize]) -> Self {
+        Self::from_lazy(LazyBuffer::from_vec(data, shape), false)
+    }
+
+    pub fn scalar(val: f64) -> Self {
+        Self::full(&[], val)
+    }
+
+    pub fn full(shape: &[usize], val: f64) -> Self {
+        Self::from_lazy(LazyBuffer::full(shape, val), false)
+    }
+
+    pub fn zeros(shape: &[usize]) -> Self {
+        Self::full(shape, 0.0)
+    }
+
+    pub fn ones(shape: &[usize]) -> Self {
+        Self::full(shape, 1.0)
+    }
+
+    pub fn arange(stop: usize) -> Self {
+        Self::new((0..stop).map(|i| i as f64).collect(), &[stop])
+    }
+
+    pub fn full_like(&self, val: f64) -> Self {
+        Self::from_lazy(self.data().const_like(val), false)
+    }
+
+    pub fn zeros_like(&self) -> Self {
+        self.full_like(0.0)
+    }
+
+    pub fn ones_like(&self) -> Self {
+        self.full_like(1.0)
+    }
+
+    // ***** rng hlops *****
+
+    /// Seed the global Threefry stream; see `rng::manual_seed`.
+    pub fn manual_seed(seed: u64) {
+        rng::manual_seed(seed)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn rand(shape: &[usize]) -> Self {
+        Self::new(rng::next_uniform(shape.iter().product()), shape)
+    }
+
+    /// Standard normal, by Box-Muller.
+    pub fn randn(shape: &[usize]) -> Self {
+        let n: usize = shape.iter().product();
+        let u = rng::next_uniform(2 * n);
+        let data = (0..n)
+            .map(|i| {
+                (2.0 * std::f64::consts::PI * u[i]).cos() * (-2.0 * (1.0 - u[n + i]).ln()).sqrt()
+            })
+            .collect();
+        Self::new(data, shape)
+    }
+
+    /// Integers in `[low, high)`.
+    pub fn randint(shape: &[usize], low: i64, high: i64) -> Self {
+        assert!(
+            low < high,
+            "randint needs low < high, got [{}, {})",
+            low,
+            high
+        );
+        let span = (high - low) as f64;
+        let data = rng::next_uniform(shape.iter().product())
+            .into_iter()
+            .map(|u| ((u * span).floor() + low as f64).min((high - 1) as f64))
+            .collect();
+        Self::new(data, shape)
+    }
+
+    pub fn uniform(shape: &[usize], low: Option<f64>, high: Option<f64>) -> Self {
+        let (low, high) = (low.unwrap_or(0.0), high.unwrap_or(1.0));
+        let data = rng::next_uniform(shape.iter().product())
+            .into_iter()
+            .map(|u| low + u * (high - low))
+            .collect();
+        Self::new(data, shape)
+    }
+
+    pub fn normal(shape: &[usize], mean: Option<f64>, std: Option<f64>) -> Self {
+        let (mean, std) = (mean.unwrap_or(0.0), std.unwrap_or(1.0));
+        let t = Self::randn(shape);
+        Self::new(
+            t.to_vec().into_iter().map(|x| mean + std * x).collect(),
+            shape,
+        )
+    }
+
+    fn fan_in(shape: &[usize]) -> f64 {
+        shape.iter().skip(1).product::<usize>() as f64
+    }
+
+    /// https://pytorch.org/docs/stable/_modules/torch/nn/init.html#kaiming_uniform_
+    pub fn kaiming_uniform(shape: &[usize], a: Option<f64>) -> Self {
+        let a = a.unwrap_or(0.01);
+        let bound = 3f64.sqrt() * (2.0 / (1.0 + a * a)).sqrt() / Self::fan_in(shape).sqrt();
+        Self::uniform(shape, Some(-bound), Some(bound))
+    }
+
+    /// https://pytorch.org/docs/stable/_modules/torch/nn/init.html#kaiming_normal_
+    pub fn kaiming_normal(shape: &[usize], a: Option<f64>) -> Self {
+        let a = a.unwrap_or(0.01);
+        let std = (2.0 / (1.0 + a * a)).sqrt() / Self::fan_in(shape).sqrt();
+        Self::normal(shape, Some(0.0), Some(std))
+    }
+
+    /// https://www.tensorflow.org/api_docs/python/tf/keras/initializers/GlorotUniform
+    pub fn glorot_uniform(shape: &[usize]) -> Self {
+        let fan_out = shape.first().copied().unwrap_or(1) as f64;
+        let bound = (6.0 / (fan_out + Self::fan_in(shape))).sqrt();
+        Self::uniform(shape, Some(-bound), Some(bound))
+    }
+
+    // ***** data handlers ****
+
+    /// The buffer of a tensor on one device.
+    pub fn lazydata(&self) -> LazyBuffer {
+        match self.data() {
+            LazyData::Single(lb) => lb,
+            LazyData::Multi(mlb) => panic!("tensor is sharded over {:?}", mlb.devices()),
+        }
+    }
+
+    /// What the tensor holds, sharded or not.
+    pub fn data(&self) -> LazyData {
+        self.inner.read().unwrap().lazydata.clone()
+    }
+
+    /// The device, or the first of them for a sharded tensor.
+    pub fn device(&self) -> String {
+        self.devices().remove(0)
+    }
+
+    pub fn devices(&self) -> Vec<String> {
+        self.inner.read().unwrap().lazydata.devices()
+    }
+
+    /// A copy on `device`, gathering the shards of a sharded tensor. Like `detach`, nothing flows
+    /// back through the copy.
+    pub fn to(&self, device: &str) -> Tensor {
+        let data = self.data();
+        if matches!(&data, LazyData::Single(lb) if lb.device == canonicalize(device)) {
+            return self.clone();
+        }
+        let lazydata = data.to_device(device).unwrap_or_else(|e| panic!("{}", e));
+        Self::from_lazy(lazydata, self.requires_grad())
+    }
+
+    /// Split into equal parts along `axis`, one on each of `devices`, or copied to all of them with
+    /// no axis. Ops on it then run on every device, see `MultiLazyBuffer`. Like `to`, nothing flows
+    /// back through the copy.
+    pub fn shard(&self, devices: &[&str], axis: Option<usize>) -> Result<Tensor, anyhow::Error> {
+        let lazydata =
+            MultiLazyBuffer::shard(&self.data().to_device(&self.device())?, devices, axis)?;
+        Ok(Self::from_lazy(lazydata, self.requires_grad()))
+    }
+
+    pub fn shape(&self) -> Vec<usize> {
+        self.inner.read().unwrap().lazydata.shape()
+    }
+
+    pub fn ndim(&self) -> usize {
+        self.shape().len()
+    }
+
+    pub fn numel(&self) -> usize {
+        self.shape().iter().product()
+    }
+
+    pub fn to_vec(&self) -> Vec<f64> {
+        self.data().to_vec()
+    }
+
+    pub fn item(&self) -> f64 {
+        assert_eq!(self.numel(), 1, "must have one element for item");
+        self.to_vec()[0]
+    }
+
+    pub fn requires_grad(&self) -> bool {
+        self.inner.read().unwrap().requires_grad
+    }
+
+    pub fn requires_grad_(self, requires_grad: bool) -> Self {
+        self.inner.write().unwrap().requires_grad = requires_grad;
+        self
+    }
+
+    pub fn grad(&self) -> Option<Tensor> {
+        self.inner.read().unwrap().grad.clone()
+    }
+
+    pub fn zero_grad(&self) {
+        self.inner.write().unwrap().grad = None;
+    }
+
+    pub fn detach(&self) -> Tensor {
+        Tensor::from_lazy(self.data(), false)
+    }
+
+    /// Swap in new data of the same shape. Clones of this tensor see it; tensors computed from the old data don't.
+    pub fn assign(&self, x: &Tensor) {
+        assert_eq!(
+            self.shape(),
+            x.shape(),
+            "assign shape mismatch {:?} <- {:?}",
+            self.shape(),
+            x.shape()
+        );
+        self.inner.write().unwrap().lazydata = x.data();
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.inner.read().unwrap().ctx.is_none()
+    }
+
+    fn ptr(&self) -> usize {
+        Arc::as_ptr(&self.inner) as usize
+    }
+
+    /// Run `f` with tape recording disabled.
+    pub fn no_grad<R>(f: impl FnOnce() -> R) -> R {
+        let mut ctx = NoGrad::new();
+        ctx.init();
+        f()
+    }
+
+    /// Run `func` forward on the inputs and, if any of them needs a gradient, record it on the tape.
+    pub fn apply(mut func: impl Function + 'static, parents: &[&Tensor]) -> Tensor {
+        let srcs: Vec<LazyData> = parents.iter().map(|p| p.data()).collect();
+        let ret = func.forward(&srcs.iter().collect::<Vec<_>>());
+        let requires_grad = is_grad_enabled() && parents.iter().any(|p| p.requires_grad());
+        let out = Tensor::from_lazy(ret, requires_grad);
+        if requires_grad {
+            out.inner.write().unwrap().ctx = Some(Arc::new(Ctx {
+                func: Box::new(func),
+                parents: parents.iter().map(|p| (*p).clone()).collect(),
+            }));
+        }
+        out
+    }
+
+    // ***** toposort and backward pass *****
+
+    /// Tensors reachable through the tape, in topological order (inputs before outputs).
+    pub fn deepwalk(&self) -> Vec<Tensor> {
+        fn walk(node: &Tensor, visited: &mut HashSet<usize>, nodes: &mut Vec<Tensor>) {
+            visited.insert(node.ptr());
+            let ctx = node.inner.read().unwrap().ctx.clone();
+            if let Some(ctx) = ctx {
+                for p in ctx.parents.iter() {
+                    if !visited.contains(&p.ptr()) {
+                        walk(p, visited, nodes);
+                    }
+                }
+                nodes.push(node.clone());
+            }
+        }
+        let mut nodes = vec![];
+        walk(self, &mut HashSet::new(), &mut nodes);
+        nodes
+    }
+
+    /// Fill `.grad` on every tensor with `requires_grad` that this scalar depends on.
+    /// Gradients flowing into the same tensor along several paths are summed.
+    pub fn backward(&self) {
+        assert_eq!(
+            self.numel(),
+            1,
+            "backward can only be called for scalar tensors, but it has shape {:?}",
+            self.shape()
+        );
+        // fill in the first grad with one. don't use Tensor::ones because we don't need contiguous
+        // this is "implicit gradient creation"
+        let ones = self.ones_like();
+        self.inner.write().unwrap().grad = Some(ones);
+
+        for t0 in self.deepwalk().into_iter().rev() {
+            let Some(grad) = t0.grad() else { continue };
+            let ctx = t0.inner.read().unwrap().ctx.clone().unwrap();
+            let grads = ctx.func.backward(&grad.data());
+            assert_eq!(
+                grads.len(),
+                ctx.parents.len(),
+                "backward must return a grad slot per input"
+            );
+            for (t, g) in ctx.parents.iter().zip(grads) {
+                let Some(g) = g else { continue };
+                if !t.requires_grad() {
+                    continue;
+                }
+                assert_eq!(g.shape(), t.shape(), "grad shape must match tensor shape");
+                let mut inner = t.inner.write().unwrap();
+                let acc = match &inner.grad {
+                    Some(old) => old.data().e(crate::ops::BinaryOps::Add, &[&g]),
+                    None => g,
+                };
+                inner.grad = Some(Tensor::from_lazy(acc, false));
+            }
+            t0.inner.write().unwrap().ctx = None;
+        }
+    }
+
+    // ***** movement mlops *****
+
+    pub fn reshape(&self, shape: &[usize]) -> Tensor {
+        if shape == self.shape().as_slice() {
+            return self.clone();
+        }
+        Tensor::apply(function::Reshape::new(shape), &[self])
+    }
+
+    pub fn expand(&self, shape: &[usize]) -> Tensor {
+        if shape == self.shape().as_slice() {
+            return self.clone();
+        }
+        Tensor::apply(function::Expand::new(shape), &[self])
+    }
+
+    pub fn permute(&self, order: &[usize]) -> Tensor {
+        Tensor::apply(function::Permute::new(order), &[self])
+    }
+
+    pub fn flip(&self, axis: &[usize]) -> Tensor {
+        Tensor::apply(function::Flip::new(axis, self.ndim()), &[self])
+    }
+
+    pub fn stride(&self, arg: &[isize]) -> Tensor {
+        if arg.iter().all(|k| *k == 1) {
+            return self.clone();
+        }
+        Tensor::apply(function::Stride::new(arg), &[self])
+    }
+
+    pub fn pad(&self, arg: &[(usize, usize)]) -> Tensor {
+        if arg.iter().all(|(b, e)| *b == 0 && *e == 0) {
+            return self.clone();
+        }
+        Tensor::apply(function::Pad::new(arg), &[self])
+    }
+
+    pub fn shrink(&self, arg: &[(usize, usize)]) -> Tensor {
+        if arg
+            .iter()
+            .zip(self.shape().iter())
+            .all(|((b, e), s)| *b == 0 && e == s)
+        {
+            return self.clone();
+        }
+        Tensor::apply(function::Shrink::new(arg), &[self])
+    }
+
+    // ***** movement hlops *****
+
+    fn resolve_dim(&self, dim: isize) -> usize {
+        let n = self.ndim() as isize;
+        let d = if dim < 0 { dim + n } else { dim };
+        assert!(
+            0 <= d && d < n.max(1),
+            "dim {} out of range for {} dims",
+            dim,
+            n
+        );
+        d as usize
+    }
+
+    pub fn transpose(&self, ax1: isize, ax2: isize) -> Tensor {
+        let (a, b) = (self.resolve_dim(ax1), self.resolve_dim(ax2));
+        let mut order: Vec<usize> = (0..self.ndim()).collect();
+        order.swap(a, b);
+        self.permute(&order)
+    }
+
+    pub fn unsqueeze(&self, dim: isize) -> Tensor {
+        let n = self.ndim() as isize;
+        let d = if dim < 0 { dim + n + 1 } else { dim } as usize;
+        let mut shape = self.shape();
+        shape.insert(d, 1);
+        self.reshape(&shape)
+    }
+
+    pub fn flatten(&self) -> Tensor {
+        self.reshape(&[self.numel()])
+    }
+
+    pub fn contiguous(&self) -> Tensor {
+        Tensor::apply(function::Contiguous::default(), &[self])
+    }
+
+    /// Stored as a `height` x `width` float32 image, see `LazyBuffer::cast_image`.
+    pub fn cast_image(&self, height: usize, width: usize) -> Tensor {
+        Tensor::apply(function::CastImage::new(height, width), &[self])
+    }
+
+    // ***** reduce ops *****
+
+    fn reduce<F: Function + 'static>(
+        &self,
+        fxn: impl FnOnce(&[usize]) -> F,
+        axis: Option<&[isize]>,
+        keepdim: bool,
+    ) -> Tensor {
+        let axis: Vec<usize> = match axis {
+            Some(axis) => axis.iter().map(|a| self.resolve_dim(*a)).collect(),
+            None => (0..self.ndim()).collect(),
+        };
+        let ret = Tensor::apply(fxn(&axis), &[self]);
+        if keepdim {
+            return ret;
+        }
+        let shape: Vec<usize> = self
+            .shape()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !axis.contains(i))
+            .map(|(_, s)| s)
+            .collect();
+        ret.reshape(&shape)
+    }
+
+    pub fn sum(&self, axis: Option<&[isize]>, keepdim: bool) -> Tensor {
+        self.reduce(function::Sum::new, axis, keepdim)
+    }
+
+    pub fn max(&self, axis: Option<&[isize]>, keepdim: bool) -> Tensor {
+        self.reduce(function::Max::new, axis, keepdim)
+    }
+
+    pub fn min(&self, axis: Option<&[isize]>, keepdim: bool) -> Tensor {
+        self.neg().max(axis, keepdim).neg()
+    }
+
+    pub fn mean(&self, axis: Option<&[isize]>, keepdim: bool) -> Tensor {
+        let out = self.sum(axis, keepdim);
+        let count = self.numel() / out.numel().max(1);
+        let scale = out.full_like(1.0 / count as f64);
+        out.mul(&scale)
+    }
+
+    // ***** processing ops *****
+
+    /// Sliding windows of size `k` over the last `k.len()` axes: `[..., o..., k...]`, where each
+    /// output position `o` sees the window starting `stride` steps in, spread out by `dilation`.
+    fn pool(&self, k: &[usize], stride: &[usize], dilation: &[usize]) -> Tensor {
+        let shape = self.shape();
+        let (noop, i_) = shape.split_at(shape.len() - k.len());
+        let nd = k.len();
+        let full = |s: &[usize]| -> Vec<(usize, usize)> { s.iter().map(|s| (0, *s)).collect() };
+        let with = |rest: Vec<usize>| [noop.to_vec(), rest].concat();
+        if k.iter().zip(stride).any(|(k, s)| k > s) || dilation.iter().any(|d| *d != 1) {
+            let o: Vec<usize> = (0..nd)
+                .map(|j| (i_[j] - dilation[j] * (k[j] - 1) - 1) / stride[j] + 1)
+                .collect();
+            // repeat each axis enough times that every window is a run of the repeats, no padding
+            let e: Vec<usize> = (0..nd)
+                .map(|j| (k[j] * (i_[j] + dilation[j])).div_ceil(i_[j]))
+                .collect();
+            let xup = self
+                .reshape(&with(i_.iter().flat_map(|i| [1, *i]).collect()))
+                .expand(&with((0..nd).flat_map(|j| [e[j], i_[j]]).collect()))
+                .reshape(&with((0..nd).map(|j| e[j] * i_[j]).collect()));
+            // each window starts `i + d` after the last one, which slides it along by `d`
+            let xup = xup
+                .shrink(
+                    &[
+                        full(noop),
+                        (0..nd).map(|j| (0, k[j] * (i_[j] + dilation[j]))).collect(),
+                    ]
+                    .concat(),
+                )
+                .reshape(&with(
+                    (0..nd).flat_map(|j| [k[j], i_[j] + dilation[j]]).collect(),
+                ))
+                .shrink(
+                    &[
+                        full(noop),
+                        (0..nd)
+                            .flat_map(|j| [(0, k[j]), (0, o[j] * stride[j])])
+                            .collect(),
+                    ]
+                    .concat(),
+                );
+            // take every `stride`th window
+            let xup = xup
+                .reshape(&with(
+                    (0..nd).flat_map(|j| [k[j], o[j], stride[j]]).collect(),
+                ))
+                .shrink(
+                    &[
+                        full(noop),
+                        (0..nd)
+                            .flat_map(|j| [(0, k[j]), (0, o[j]), (0, 1)])
+                            .collect(),
+                    ]
+                    .concat(),
+                )
+                .reshape(&with((0..nd).flat_map(|j| [k[j], o[j]]).collect()));
+            let order: Vec<usize> = (0..noop.len())
+                .chain((0..nd).map(|j| noop.len() + j * 2 + 1))
+                .chain((0..nd).map(|j| noop.len() + j * 2))
+                .collect();
+            return xup.permute(&order);
+        }
+        // windows that don't overlap are just a reshape
+        let o: Vec<usize> = (0..nd)
+            .map(|j| (i_[j] + stride[j] - k[j]) / stride[j])
+            .collect();
+        let pad: Vec<(usize, usize)> = (0..nd)
+            .map(|j| (0, (o[j] * stride[j]).saturating_sub(i_[j])))
+            .collect();
+        self.pad(&[vec![(0, 0); noop.len()], pad].concat())
+            .shrink(&[full(noop), (0..nd).map(|j| (0, o[j] * stride[j])).collect()].concat())
+            .reshape(&with((0..nd).flat_map(|j| [o[j], stride[j]]).collect()))
+            .shrink(
+                &[
+                    full(noop),
+                    (0..nd).flat_map(|j| [(0, o[j]), (0, k[j])]).collect(),
+                ]
+                .concat(),
+            )
+    }
+
+    /// Convolve `[bs, groups * cin, ...]` with `weight`, `[cout, cin, ...]`, over as many trailing
+    /// axes as the weight has. 2D convolutions take the image path with `IMAGE>=1`, otherwise 3x3
+    /// ones with stride and dilation 1 go through winograd when `WINO=1`.
+    pub fn conv2d(
+        &self,
+        weight: &Tensor,
+        bias: Option<&Tensor>,
+        groups: usize,
+        stride: usize,
+        dilation: usize,
+        padding: usize,
+    ) -> Tensor {
+        let (xs, ws) = (self.shape(), weight.shape());
+        let (cin_, cin, hw) = (xs[1], ws[1], &ws[2..]);
+        assert!(
+            groups * cin == cin_ && xs.len() == ws.len(),
+            "input tensor shape {:?} does not match the shape of the weights {:?} with {} groups",
+            xs,
+            ws,
+            groups
+        );
+        if IMAGE.clone() >= 1 && hw.len() == 2 {
+            return self.image_conv2d(weight, bias, groups, stride, dilation, padding);
+        }
+        if WINO.clone() == 1 && hw.iter().all(|k| *k == 3) && stride == 1 && dilation == 1 {
+            return self.conv_winograd(weight, bias, groups, padding);
+        }
+        self.conv_direct(weight, bias, groups, stride, dilation, padding)
+    }
+
+    /// `conv2d` as one broadcast multiply and sum over pooled windows of the padded input.
+    pub(crate) fn conv_direct(
+        &self,
+        weight: &Tensor,
+        bias: Option<&Tensor>,
+        groups: usize,
+        stride: usize,
+        dilation: usize,
+        padding: usize,
+    ) -> Tensor {
+        let (xs, ws) = (self.shape(), weight.shape());
+        let (bs, cout, cin, hw) = (xs[0], ws[0], ws[1], &ws[2..]);
+        let nd = hw.len();
+        let pad: Vec<(usize, usize)> = [vec![(0, 0); 2], vec![(padding, padding); nd]].concat();
+        // (bs, groups * cin, oy, ox, H, W)
+        let x = self
+            .pad(&pad)
+            .pool(hw, &vec![stride; nd], &vec![dilation; nd]);
+        let (rcout, oyx) = (cout / groups, x.shape()[2..2 + nd].to_vec());
+        // broadcast to (bs, groups, rcout, oy, ox, cin, H, W) and reduce the last 1 + nd axes
+        let order: Vec<usize> = [0, 1, 3]
+            .into_iter()
+            .chain(4..4 + nd)
+            .chain([2])
+            .chain(4 + nd..4 + 2 * nd)
+            .collect();
+        let x = x
+            .reshape(&[&[bs, groups, cin, 1], &oyx[..], hw].concat())
+            .expand(&[&[bs, groups, cin, rcout], &oyx[..], hw].concat())
+            .permute(&order);
+        let w = weight.reshape(&[&[1, groups, rcout], &vec![1; nd][..], &[cin], hw].concat());
+        let axes: Vec<isize> = (1..=1 + nd as isize).map(|i| -i).collect();
+        let ret = x
+            .mul(&w)
+            .sum(Some(&axes), true)
+            .reshape(&[&[bs, cout], &oyx[..]].concat());
+        match bias {
+            Some(b) => ret.add(&b.reshape(&[&[1, cout], &vec![1; nd][..]].concat())),
+            None => ret,
+        }
+    }
+
+    /// `conv2d` laid out the way it runs on images: the input packed into a `(bs * iy, ix * cin / 4)`
+    /// image of RGBA texels, the weights into `(cout / 4, H * W * cin)` and the output into
+    /// `(bs * oy, ox * cout / 4)`. Channels are padded up to multiples of 4 to fill the texels and cut
+    /// off again at the end.
+    pub(crate) fn image_conv2d(
+        &self,
+        weight: &Tensor,
+        bias: Option<&Tensor>,
+        groups: usize,
+        stride: usize,
+        dilation: usize,
+        padding: usize,
+    ) -> Tensor {
+        let (xs, ws) = (self.shape(), weight.shape());
+        let (bs, iy, ix, kh, kw) = (xs[0], xs[2], xs[3], ws[2], ws[3]);
+        let (mut cout, mut cin, mut rcout) = (ws[0], ws[1], ws[0] / groups);
+        let mut x = self.clone();
+        let mut w = weight.reshape(&[groups, rcout, cin, kh, kw]);
+        let pad_axis = |t: &Tensor, axis: usize, amt: usize| {
+            let mut arg = vec![(0, 0); t.ndim()];
+            arg[axis] = (0, amt);
+            t.pad(&arg)
+        };
+
+        // input channels up to a multiple of 4, unless each group has one and the groups fill texels
+        if !(cin.is_multiple_of(4) || (cin == 1 && groups.is_multiple_of(4))) {
+            let added = 4 - cin % 4;
+            w = pad_axis(&w, 2, added);
+            x = pad_axis(&x.reshape(&[bs, groups, cin, iy, ix]), 2, added);
+            cin += added;
+            x = x.reshape(&[bs, groups * cin, iy, ix]);
+        }
+        let mut added_output_channels = 0;
+        if !(rcout.is_multiple_of(4) || (rcout == 1 && groups.is_multiple_of(4))) {
+            added_output_channels = 4 - rcout % 4;
+            rcout += added_output_channels;
+            cout = groups * rcout;
+            w = pad_axis(&w, 1, added_output_channels);
+        }
+
+        // packed into the images, channels last
+        let x = x
+            .permute(&[0, 2, 3, 1])
+            .cast_image(bs * iy, ix * groups * cin / 4);
+        let cin_last = iy == 1 && ix == 1;
+        let w = if cin == 1 {
+            w.reshape(&[cout / 4, 4, kh, kw]).permute(&[0, 2, 3, 1])
+        } else if cin_last {
+            w.reshape(&[cout / 4, 4, cin / 4, 4, kh, kw])
+                .permute(&[0, 4, 2, 5, 1, 3])
+        } else {
+            w.reshape(&[cout / 4, 4, cin / 4, 4, kh, kw])
+                .permute(&[0, 4, 2, 5, 3, 1])
+        }
+        .cast_image(cout / 4, kh * kw * cin);
+
+        // expand out
+        let (rcin_hi, rcin_lo) = if cin >= 4 { (cin / 4, 4) } else { (1, 1) };
+        let cout_expand = [
+            if cin == 1 { groups / 4 } else { groups },
+            if cin == 1 { 4 } else { 1 },
+            if rcout >= 4 { rcout / 4 } else { 1 },
+            if rcout >= 4 { 4 } else { 1 },
+        ];
+        let x = x.reshape(&[bs, iy, ix, groups, rcin_hi, rcin_lo]);
+        let w = if cin_last {
+            w.reshape(&[cout / 4, kh, rcin_hi, kw, 4, rcin_lo])
+        } else {
+            w.reshape(&[cout / 4, kh, rcin_hi, kw, rcin_lo, 4])
+                .permute(&[0, 1, 2, 3, 5, 4])
+        };
+
+        // (bs, groups, rcin_hi, rcin_lo, oy, ox, H, W)
+        let x = x
+            .pad(&[
+                (0, 0),
+                (padding, padding),
+                (padding, padding),
+                (0, 0),
+                (0, 0),
+                (0, 0),
+            ])
+            .permute(&[0, 3, 4, 5, 1, 2])
+            .pool(&[kh, kw], &[stride; 2], &[dilation; 2]);
+        let (oy, ox) = (x.shape()[4], x.shape()[5]);
+        let x = x.permute(&[0, 4, 5, 1, 2, 3, 6, 7]).reshape(
+            &[
+                &[bs, oy, ox, cout_expand[0], cout_expand[1], 1, 1],
+                &[rcin_hi, rcin_lo, kh, kw][..],
+            ]
+            .concat(),
+        );
+        let w = w
+            .permute(&[0, 4, 2, 5, 1, 3])
+            .reshape(&[&[1, 1, 1], &cout_expand[..], &[rcin_hi, rcin_lo, kh, kw]].concat());
+
+        // the conv, out in the output image's layout
+        let mut ret = x.mul(&w).sum(Some(&[-4, -3, -2, -1]), false);
+        ret = ret.cast_image(bs * oy, ox * cout / 4);
+        if added_output_channels != 0 {
+            ret = ret.reshape(&[bs, oy, ox, groups, rcout]).shrink(&[
+                (0, bs),
+                (0, oy),
+                (0, ox),
+                (0, groups),
+                (0, rcout - added_output_channels),
+            ]);
+            cout = groups * (rcout - added_output_channels);
+        }
+        let ret = ret.reshape(&[bs, oy, ox, cout]).permute(&[0, 3, 1, 2]);
+        match bias {
+            Some(b) => ret.add(&b.reshape(&[1, cout, 1, 1])),
+            None => ret,
+        }
+    }
+
+    /// `mat` times each of the first `dims` axes in turn, each a broadcasted multiply and a sum.
+    /// `mat` is row major with as many columns as those axes are long.
+    fn apply_matrix(&self, mat: &[f64], dims: usize) -> Tensor {
+        let mut t = self.clone();
+        for _ in 0..dims {
+            let shape = t.shape();
+            let (c, rest) = (shape[0], shape.len() - 1);
+            let m = Tensor::new(mat.to_vec(), &[mat.len() / c, c])
+                .to(&t.device())
+                .reshape(&[&[mat.len() / c, c], &vec![1; rest][..]].concat());
+            t = m.mul(&t.unsqueeze(0)).sum(Some(&[1]), false);
+            // move the new axis behind the ones still to transform, so the next pass gets the next
+            let order: Vec<usize> = (1..dims).chain([0]).chain(dims..t.ndim()).collect();
+            t = t.permute(&order);
+        }
+        t
+    }
+
+    /// `conv2d` for 3x3 filters as winograd F(4x4,3x3): each 4x4 output tile is the elementwise
+    /// product of a 6x6 transform of its input tile and of the filter, transformed back. That's 36
+    /// multiplies per tile and channel instead of 144.
+    pub(crate) fn conv_winograd(
+        &self,
+        weight: &Tensor,
+        bias: Option<&Tensor>,
+        groups: usize,
+        padding: usize,
+    ) -> Tensor {
+        let (xs, ws) = (self.shape(), weight.shape());
+        let (bs, cout, cin, nd) = (xs[0], ws[0], ws[1], ws.len() - 2);
+        let rcout = cout / groups;
+        let oyx: Vec<usize> = xs[2..].iter().map(|i| i + 2 * padding - 2).collect();
+        // pad on the right up to whole output tiles, then cut 6x6 input tiles every 4
+        let pad: Vec<(usize, usize)> = [
+            vec![(0, 0); 2],
+            oyx.iter()
+                .map(|o| (padding, padding + (4 - o % 4) % 4))
+                .collect(),
+        ]
+        .concat();
+        let d = self
+            .pad(&pad)
+            .pool(&vec![6; nd], &vec![4; nd], &vec![1; nd]);
+        let n = d.ndim();
+        // tile axes to the front: (6, 6, bs, groups * cin, ty, tx)
+        let d = d.permute(&(n - nd..n).chain(0..n - nd).collect::<Vec<_>>());
+        let tyx = d.shape()[n - nd..].to_vec();
+        let g = weight.permute(&(2..2 + nd).chain(0..2).collect::<Vec<_>>());
+
+        let six = vec![6; nd];
+        let gfactors = g
+            .apply_matrix(&WINOGRAD_G, nd)
+            .reshape(&[&six[..], &[1, groups, rcout, cin], &vec![1; nd][..]].concat());
+        let dfactors = d
+            .apply_matrix(&WINOGRAD_BT, nd)
+            .reshape(&[&six[..], &[bs, groups, 1, cin], &tyx[..]].concat());
+        // sum over cin: (6, 6, bs, groups, rcout, ty, tx), then back to (4, 4, ...)
+        let ret = gfactors
+            .mul(&dfactors)
+            .sum(Some(&[nd as isize + 3]), false)
+            .apply_matrix(&WINOGRAD_AT, nd);
+        // interleave tiles and their insides: (bs, groups, rcout, ty, 4, tx, 4)
+        let order: Vec<usize> = (nd..nd + 3)
+            .chain((0..nd).flat_map(|i| [nd + 3 + i, i]))
+            .collect();
+        let ret = ret
+            .permute(&order)
+            .reshape(
+                &[
+                    &[bs, cout],
+                    &tyx.iter().map(|t| t * 4).collect::<Vec<_>>()[..],
+                ]
+                .concat(),
+            )
+            .shrink(
+                &[
+                    &[(0, bs), (0, cout)],
+                    &oyx.iter().map(|o| (0, *o)).collect::<Vec<_>>()[..],
+                ]
+                .concat(),
+            );
+        match bias {
+            Some(b) => ret.add(&b.reshape(&[&[1, cout], &vec![1; nd][..]].concat())),
+            None => ret,
+        }
+    }
+
+    pub fn dot(&self, w: &Tensor) -> Tensor {
+        let (n1, n2) = (self.ndim(), w.ndim());
+        assert!(
+            n1 != 0 && n2 != 0,
+            "both arguments to matmul need to be at least 1D"
+        );
+        let (xs, ws) = (self.shape(), w.shape());
+        assert_eq!(
+            xs[n1 - 1],
+            ws[n2.saturating_sub(2)],
+            "input tensor shapes {:?} and {:?} cannot be multiplied",
+            xs,
+            ws
+        );
+        let ones = [1].repeat((n1 - 1).min(n2 - 1).min(1));
+        let x = self.reshape(&[&xs[..n1 - 1], &ones, &xs[n1 - 1..]].concat());
+        let w = w
+            .reshape(&[&ws[..n2.saturating_sub(2)], &ones, &ws[n2 - n2.min(2)..]].concat())
+            .transpose(-1, -(n2.min(2) as isize));
+        x.mul(&w).sum(Some(&[-1]), false)
+    }
+
+    pub fn matmul(&self, w: &Tensor) -> Tensor {
+        self.dot(w)
+    }
+
+    // ***** unary mlops *****
+
+    pub fn neg(&self) -> Tensor {
+        Tensor::apply(function::Neg::default(), &[self])
+    }
+
+    pub fn reciprocal(&self) -> Tensor {
+        Tensor::apply(function::Reciprocal::default(), &[self])
+    }
+
+    pub fn relu(&self) -> Tensor {
+        Tensor::apply(function::Relu::default(), &[self])
+    }
+
+    pub fn log(&self) -> Tensor {
+        Tensor::apply(function::Log::default(), &[self])
+    }
+
+    pub fn exp(&self) -> Tensor {
+        Tensor::apply(function::Exp::default(), &[self])
+    }
+
+    pub fn sqrt(&self) -> Tensor {
+        Tensor::apply(function::Sqrt::default(), &[self])
+    }
+
+    pub fn sin(&self) -> Tensor {
+        Tensor::apply(function::Sin::default(), &[self])
+    }
+
+    pub fn sigmoid(&self) -> Tensor {
+        Tensor::apply(function::Sigmoid::default(), &[self])
+    }
+
+    // ***** math functions (unary) *****
+
+    pub fn cos(&self) -> Tensor {
+        self.full_like(std::f64::consts::FRAC_PI_2).sub(self).sin()
+    }
+
+    pub fn square(&self) -> Tensor {
+        self.mul(self)
+    }
+
+    pub fn tanh(&self) -> Tensor {
+        self.mul(&self.full_like(2.0))
+            .sigmoid()
+            .mul(&self.full_like(2.0))
+            .sub(&self.ones_like())
+    }
+
+    pub fn abs(&self) -> Tensor {
+        self.relu().add(&self.neg().relu())
+    }
+
+    // ***** broadcasted elementwise mlops *****
+
+    pub(crate) fn broadcasted(&self, y: &Tensor) -> (Tensor, Tensor) {
+        let (mut x, mut y) = (self.clone(), y.clone());
+        let (xs, ys) = (x.shape(), y.shape());
+        if xs.len() < ys.len() {
+            x = x.reshape(&[vec![1; ys.len() - xs.len()], xs].concat());
+        } else if ys.len() < xs.len() {
+            y = y.reshape(&[vec![1; xs.len() - ys.len()], ys].concat());
+        }
+        let shape: Vec<usize> = x
+            .shape()
+            .iter()
+            .zip(y.shape().iter())
+            .map(|(a, b)| {
+                assert!(
+                    a == b || *a == 1 || *b == 1,
+                    "shapes {:?} and {:?} don't broadcast",
+                    self.shape(),
+                    y.shape()
+                );
+                *a.max(b)
+            })
+            .collect();
+        (x.expand(&shape), y.expand(&shape))
+    }
+
+    pub fn add(&self, y: &Tensor) -> Tensor {
+        let (x, y) = self.broadcasted(y);
+        Tensor::apply(function::Add::default(), &[&x, &y])
+    }
+
+    pub fn sub(&self, y: &Tensor) -> Tensor {
+        let (x, y) = self.broadcasted(y);
+        Tensor::apply(function::Sub::default(), &[&x, &y])
+    }
+
+    pub fn mul(&self, y: &Tensor) -> Tensor {
+        let (x, y) = self.broadcasted(y);
+        Tensor::apply(function::Mul::default(), &[&x, &y])
+    }
+
+    pub fn div(&self, y: &Tensor) -> Tensor {
+        let (x, y) = self.broadcasted(y);
+        Tensor::apply(function::Div::default(), &[&x, &y])
+    }
+
+    pub fn lt(&self, y: &Tensor) -> Tensor {
+        let (x, y) = self.broadcasted(y);
+        Tensor::apply(function::Less::default(), &[&x, &y])
+    }
+
+    pub fn eq(&self, y: &Tensor) -> Tensor {
+        let (x, y) = self.broadcasted(y);
+        Tensor::apply(function::Eq::default(), &[&x, &y])
+    }
+
+    pub fn maximum(&self, y: &Tensor) -> Tensor {
+        self.lt(y).where_(y, self)
+    }
+
+    pub fn minimum(&self, y: &Tensor) -> Tensor {
+        self.neg().maximum(&y.neg()).neg()
+    }
+
+    pub fn where_(&self, x: &Tensor, y: &Tensor) -> Tensor {
+        let (cond, x) = self.broadcasted(x);
+        let (cond, y) = cond.broadcasted(y);
+        let (x, y) = x.broadcasted(&y);
+        Tensor::apply(function::Where::default(), &[&cond, &x, &y])
+    }
+}
+
+macro_rules! impl_tensor_binop {
+    ($trait:ident, $fxn:ident) => {
+        impl $trait<&Tensor> for &Tensor {
+            type Output = Tensor;
+            fn $fxn(self, rhs: &Tensor) -> Tensor {
+                Tensor::$fxn(self, rhs)
+            }
+        }
+        impl $trait<&Tensor> for Tensor {
+            type Output = Tensor;
+            fn $fxn(self, rhs: &Tensor) -> Tensor {
+                Tensor::$fxn(&self, rhs)
+            }
+        }
+        impl $trait<Tensor> for Tensor {
+            type Output = Tensor;
+            fn $fxn(self, rhs: Tensor) -> Tensor {
+                Tensor::$fxn(&self, &rhs)
+            }
+        }
+        impl $trait<f64> for &Tensor {
+            type Output = Tensor;
+            fn $fxn(self, rhs: f64) -> Tensor {
+                Tensor::$fxn(self, &self.full_like(rhs))
+            }
+        }
+        impl $trait<f64> for Tensor {
+            type Output = Tensor;
+            fn $fxn(self, rhs: f64) -> Tensor {
+                Tensor::$fxn(&self, &self.full_like(rhs))
+            }
+        }
+    };
+}
+
+impl_tensor_binop!(Add, add);
+impl_tensor_binop!(Sub, sub);
+impl_tensor_binop!(Mul, mul);
+impl_tensor_binop!(Div, div);
+
+impl Neg for &Tensor {
+    type Output = Tensor;
+    fn neg(self) -> Tensor {
+        Tensor::neg(self)
+    }
+}
+
+impl Neg for Tensor {
+    type Output = Tensor;
+    fn neg(self) -> Tensor {
+        Tensor::neg(&self)
+    }
+}